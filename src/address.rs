@@ -0,0 +1,58 @@
+//! The addresses that base stations are known by. macOS hides the Bluetooth addresses, and
+//! CoreBluetooth identifies each peripheral by a UUID of its own instead, so there the address is
+//! made from that UUID. Everything else keys on the address, without caring about the platform.
+
+use btleplug::{
+    api::{BDAddr, Peripheral, PeripheralProperties},
+    platform::PeripheralId,
+};
+use uuid::Uuid;
+
+/// The properties of the peripheral, with the address it is known by.
+pub async fn properties<P: Peripheral>(p: &P) -> btleplug::Result<Option<PeripheralProperties>> {
+    let mut props = p.properties().await?;
+    if let (Some(props), Some(uuid)) = (&mut props, uuid(&p.id())) {
+        props.address = from_uuid(uuid);
+    }
+    Ok(props)
+}
+
+/// Parses a Bluetooth address, such as `AA:BB:CC:DD:EE:FF`, or a peripheral UUID as shown on
+/// macOS, such as `1b4e28ba-2fa1-11d2-883f-0016d3cca427`.
+pub fn parse(arg: &str) -> Option<BDAddr> {
+    if let Ok(address) = BDAddr::from_str_delim(arg) {
+        return Some(address);
+    }
+    // Only in the hyphenated form, so that names of hex digits are still names.
+    match arg.len() {
+        36 => Uuid::try_parse(arg).ok().map(from_uuid),
+        _ => None,
+    }
+}
+
+/// How the peripheral is shown, to be targeted by: its address, or its UUID on macOS.
+pub fn shown<P: Peripheral>(p: &P, address: BDAddr) -> String {
+    match uuid(&p.id()) {
+        Some(uuid) => uuid.to_string(),
+        None => address.to_string(),
+    }
+}
+
+/// The last six bytes of the UUID, which tell peripherals apart as well as addresses do.
+fn from_uuid(uuid: Uuid) -> BDAddr {
+    let bytes: [u8; 6] = uuid.as_bytes()[10..].try_into().expect("six bytes");
+    BDAddr::from(bytes)
+}
+
+#[cfg(target_os = "macos")]
+fn uuid(id: &PeripheralId) -> Option<Uuid> {
+    // btleplug only gives the UUID away in the debug form, `PeripheralId(<uuid>)`.
+    let debugged = format!("{:?}", id);
+    let uuid = debugged.strip_prefix("PeripheralId(")?.strip_suffix(')')?;
+    Uuid::try_parse(uuid).ok()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn uuid(_: &PeripheralId) -> Option<Uuid> {
+    None
+}
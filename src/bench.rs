@@ -0,0 +1,238 @@
+//! `bench`: times each step of reaching and controlling the base stations, from starting to scan
+//! to writing, for tuning adapters and their placement. The steps are timed by the library as it
+//! goes, and shown with `-v` by any command.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Result;
+use btleplug::api::{Central, Peripheral};
+use futures::StreamExt;
+use tokio::{
+    sync::oneshot,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    discover, fold, release, Error, Filter, Lighthouse, Options, Outcome, PowerCommand, PowerState,
+    Protocol,
+};
+
+/// The steps timed, in the order they happen.
+const STEPS: [&str; 5] = ["ADVERTISED", "CONNECT", "SERVICES", "READ", "WRITE"];
+
+/// How long each step took for a base station in one iteration, `None` for the steps skipped,
+/// such as reading a V1 base station.
+type Sample = [Option<Duration>; STEPS.len()];
+
+/// Reads the power state, and with `write` writes it back as it is, so that the base stations
+/// are left as they were. Nothing is written with `--dry-run`.
+async fn measure<P: Peripheral>(
+    lh: &Lighthouse<P>,
+    options: &Options,
+    write: bool,
+) -> Result<Sample, Error> {
+    let timings = lh.timings;
+    let mut sample = [
+        timings.advertised,
+        Some(timings.connected),
+        Some(timings.services_discovered),
+        None,
+        None,
+    ];
+    // V1 base stations cannot be read, so there is no state to write back either.
+    if let Protocol::V1 = lh.protocol {
+        return Ok(sample);
+    }
+    let started = Instant::now();
+    let raw_state = lh.read_power(options.io_timeout).await?;
+    sample[3] = Some(started.elapsed());
+    if !write || options.dry_run {
+        return Ok(sample);
+    }
+    // Booting and stopping cannot be written, so those are left alone.
+    if let Ok(command) = PowerCommand::try_from(PowerState::from(raw_state)) {
+        let started = Instant::now();
+        lh.write_power_bytes(&[command.into()], options.write_type, options.io_timeout)
+            .await?;
+        sample[4] = Some(started.elapsed());
+    }
+    Ok(sample)
+}
+
+/// The time of a step, or with several iterations its minimum, median and maximum.
+fn spread(mut times: Vec<Duration>) -> String {
+    times.sort();
+    let ms = |time: &Duration| time.as_millis().to_string();
+    match times.as_slice() {
+        [] => "n/a".to_owned(),
+        [time] => format!("{}ms", ms(time)),
+        [min, .., max] => format!("{}/{}/{}ms", ms(min), ms(&times[times.len() / 2]), ms(max)),
+    }
+}
+
+fn print_table(samples: &BTreeMap<String, (String, Vec<Sample>)>, iterations: usize) {
+    if iterations > 1 {
+        println!("min/median/max over {} iterations", iterations);
+    }
+    let mut header = vec!["NAME".to_owned()];
+    header.extend(STEPS.iter().map(|step| step.to_string()));
+    header.push("TOTAL".to_owned());
+    let mut rows = vec![header];
+    for (name, samples) in samples.values() {
+        let mut row = vec![name.clone()];
+        for step in 0..STEPS.len() {
+            row.push(spread(
+                samples.iter().filter_map(|sample| sample[step]).collect(),
+            ));
+        }
+        let totals = samples
+            .iter()
+            .map(|sample| sample.iter().flatten().sum())
+            .collect();
+        row.push(spread(totals));
+        rows.push(row);
+    }
+    let mut widths = vec![0; rows[0].len()];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Runs `iterations` times, scanning anew each time until every base station asked for has
+/// been timed or the timeout passes, and prints the table once done or interrupted. Writing is
+/// timed only with `write`.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &Filter,
+    options: &Options,
+    iterations: usize,
+    write: bool,
+    timeout: Option<Duration>,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let mut samples: BTreeMap<String, (String, Vec<Sample>)> = BTreeMap::new();
+    let mut missing = Vec::new();
+    let mut failed = 0;
+    let mut timed_out = 0;
+    let mut signals = signals;
+    for iteration in 1..=iterations {
+        let mut filter = filter.clone();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let handled = Mutex::new(BTreeSet::new());
+        let unnamed = filter.addresses();
+        let mut discoveries = discover(central, options, &unnamed, &handled, false).await?;
+        let mut interrupted = false;
+        while !filter.is_completed() {
+            let next = async {
+                match deadline {
+                    Some(deadline) => timeout_at(deadline, discoveries.next())
+                        .await
+                        .ok()
+                        .flatten(),
+                    None => discoveries.next().await,
+                }
+            };
+            let discovery = tokio::select! {
+                discovery = next => discovery,
+                Ok(_) = &mut signals => {
+                    interrupted = true;
+                    break;
+                }
+            };
+            match discovery {
+                Some(Ok(mut lh)) => {
+                    let matched = filter.is_matched(&lh.name, lh.address)
+                        && !filter.is_excluded(&lh.name, lh.address);
+                    if !matched {
+                        lh.disconnect().await.ok();
+                        continue;
+                    }
+                    if let Err(error) = filter.claim(&lh.name, lh.address) {
+                        eprintln!("{}, ignored", error);
+                        lh.disconnect().await.ok();
+                        continue;
+                    }
+                    if !filter.take(lh.address) {
+                        lh.disconnect().await.ok();
+                        continue;
+                    }
+                    let result = measure(&lh, options, write).await;
+                    lh.disconnect().await.ok();
+                    filter.complete(&lh.name, lh.address);
+                    match result {
+                        Ok(sample) => {
+                            let entry = samples.entry(fold(&lh.name));
+                            entry
+                                .or_insert_with(|| (lh.name, Vec::new()))
+                                .1
+                                .push(sample);
+                        }
+                        Err(error) => {
+                            eprintln!("{}: {}", lh.name, error);
+                            if error.is_timeout() {
+                                timed_out += 1;
+                            } else {
+                                failed += 1;
+                            }
+                        }
+                    }
+                }
+                Some(Err(error)) => {
+                    let address = error.address.unwrap_or_default();
+                    if filter.is_matched(&error.name, address) && filter.take(address) {
+                        eprintln!("{}: {}", error.name, error.cause);
+                        if error.timed_out() {
+                            timed_out += 1;
+                        } else {
+                            failed += 1;
+                        }
+                        filter.complete(&error.name, address);
+                    }
+                }
+                None => break,
+            }
+        }
+        drop(discoveries);
+        release(central, options.io_timeout).await;
+        missing = filter.remaining();
+        if interrupted {
+            break;
+        }
+        log::info!("iteration {} of {} done", iteration, iterations);
+    }
+    print_table(&samples, iterations);
+    let outcome = Outcome {
+        missing,
+        failed,
+        timed_out,
+    };
+    outcome.into_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn several_iterations_show_min_median_and_max() {
+        let ms = Duration::from_millis;
+        assert_eq!(spread(Vec::new()), "n/a");
+        assert_eq!(spread(vec![ms(412)]), "412ms");
+        assert_eq!(spread(vec![ms(801), ms(412), ms(520)]), "412/520/801ms");
+    }
+}
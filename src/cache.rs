@@ -0,0 +1,428 @@
+//! The station cache: the base stations found before, with their addresses, the adapter that
+//! found them, when they were first and last seen, and their last known RF channel.
+//!
+//! It is a tab-separated file under the platform cache directory, rewritten once a run is over
+//! with what changed meanwhile, so that discovering base stations never waits on the disk. A
+//! corrupt file is never an error: the lines that cannot be read are dropped, and the next write
+//! replaces them.
+
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use btleplug::api::BDAddr;
+
+use crate::fold;
+
+const HEADER: &str =
+    "# lighthousectl station cache: name, address, adapter, first seen, last seen, channel";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// As advertised by the base station.
+    pub name: String,
+    pub address: BDAddr,
+    pub adapter: String,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    pub channel: Option<u8>,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<_> = line.split('\t').collect();
+        let [name, address, adapter, first_seen, last_seen, channel] = fields[..] else {
+            return None;
+        };
+        let time = |field: &str| Some(UNIX_EPOCH + Duration::from_secs(field.parse().ok()?));
+        Some(Self {
+            name: name.to_owned(),
+            address: BDAddr::from_str_delim(address).ok()?,
+            adapter: adapter.to_owned(),
+            first_seen: time(first_seen)?,
+            last_seen: time(last_seen)?,
+            channel: match channel {
+                "-" => None,
+                channel => Some(channel.parse().ok()?),
+            },
+        })
+    }
+
+    fn line(&self) -> String {
+        let secs = |time: SystemTime| {
+            let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            since.as_secs().to_string()
+        };
+        let channel = self
+            .channel
+            .map_or_else(|| "-".to_owned(), |channel| channel.to_string());
+        // Tabs and line breaks in advertised names would break the line up.
+        let name: String = self
+            .name
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        let adapter: String = self
+            .adapter
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            name,
+            self.address,
+            adapter,
+            secs(self.first_seen),
+            secs(self.last_seen),
+            channel
+        )
+    }
+}
+
+/// The entries by folded name.
+type Entries = BTreeMap<String, Entry>;
+
+fn parse(text: &str, path: &Path) -> Entries {
+    let mut entries = Entries::new();
+    let mut corrupt = 0;
+    for line in text.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match Entry::parse(line) {
+            Some(entry) => {
+                entries.insert(fold(&entry.name), entry);
+            }
+            None => corrupt += 1,
+        }
+    }
+    if corrupt > 0 {
+        log::warn!(
+            "ignoring {} unreadable lines of the station cache {}",
+            corrupt,
+            path.display()
+        );
+    }
+    entries
+}
+
+fn format(entries: &Entries) -> String {
+    let mut text = format!("{}\n", HEADER);
+    for entry in entries.values() {
+        text.push_str(&entry.line());
+        text.push('\n');
+    }
+    text
+}
+
+pub struct Cache {
+    /// `None` with `--no-cache`, which keeps everything in memory.
+    path: Option<PathBuf>,
+    /// The adapter in use, recorded with the base stations it finds.
+    adapter: String,
+    entries: Mutex<Entries>,
+    /// What to write on [`flush`](Self::flush) by folded name, `None` for the forgotten base
+    /// stations.
+    changes: Mutex<BTreeMap<String, Option<Entry>>>,
+}
+
+impl Cache {
+    pub fn open(path: Option<PathBuf>, adapter: String) -> Self {
+        let entries = path.as_deref().map(read).unwrap_or_default();
+        Self {
+            path,
+            adapter,
+            entries: Mutex::new(entries),
+            changes: Mutex::default(),
+        }
+    }
+
+    /// The cached base station of that name, if any.
+    pub fn get(&self, name: &str) -> Option<Entry> {
+        self.entries.lock().unwrap().get(&fold(name)).cloned()
+    }
+
+    /// Records that the base station has just been discovered, returning its entry.
+    pub fn seen(&self, name: &str, address: BDAddr) -> Entry {
+        let now = SystemTime::now();
+        self.update(name, |entry| match entry {
+            Some(entry) if entry.address == address => Entry {
+                name: name.to_owned(),
+                adapter: self.adapter.clone(),
+                last_seen: now,
+                ..entry
+            },
+            // The channel may have changed along with the address, after a factory reset.
+            _ => Entry {
+                name: name.to_owned(),
+                address,
+                adapter: self.adapter.clone(),
+                first_seen: now,
+                last_seen: now,
+                channel: None,
+            },
+        })
+    }
+
+    /// Records the RF channel read from or written to a base station that has been seen.
+    pub fn set_channel(&self, name: &str, channel: u8) {
+        if self.get(name).is_some() {
+            self.update(name, |entry| Entry {
+                channel: Some(channel),
+                ..entry.expect("seen before")
+            });
+        }
+    }
+
+    /// Drops a base station, such as one that no longer answers at its address.
+    /// Returns whether it was cached.
+    pub fn forget(&self, name: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let forgotten = entries.remove(&fold(name)).is_some();
+        if forgotten {
+            self.changes.lock().unwrap().insert(fold(name), None);
+        }
+        forgotten
+    }
+
+    /// Changes the entry of a base station in memory, to be written on [`flush`](Self::flush).
+    fn update(&self, name: &str, change: impl FnOnce(Option<Entry>) -> Entry) -> Entry {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = change(entries.get(&fold(name)).cloned());
+        entries.insert(fold(name), entry.clone());
+        let mut changes = self.changes.lock().unwrap();
+        changes.insert(fold(name), Some(entry.clone()));
+        entry
+    }
+
+    /// Writes the changes into the cache file at once, keeping the entries that other runs wrote
+    /// meanwhile. Done when the cache is dropped, at the end of the run.
+    pub fn flush(&self) {
+        let changes = std::mem::take(&mut *self.changes.lock().unwrap());
+        let Some(path) = self.path.as_deref().filter(|_| !changes.is_empty()) else {
+            return;
+        };
+        let _lock = lock(path);
+        let mut stored = read(path);
+        for (key, change) in changes {
+            match change {
+                Some(entry) => stored.insert(key, entry),
+                None => stored.remove(&key),
+            };
+        }
+        if let Err(error) = write(path, &stored) {
+            log::warn!(
+                "failed to update the station cache {}: {}",
+                path.display(),
+                error
+            );
+        }
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Reads the cache file, which is empty if it does not exist yet.
+fn read(path: &Path) -> Entries {
+    match fs::read_to_string(path) {
+        Ok(text) => parse(&text, path),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Entries::new(),
+        Err(error) => {
+            log::warn!("ignoring the station cache {}: {}", path.display(), error);
+            Entries::new()
+        }
+    }
+}
+
+/// Replaces the cache file at once, so that runs at the same time never see half of it.
+fn write(path: &Path, entries: &Entries) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.tmp", std::process::id()));
+    fs::write(&temporary, format(entries))?;
+    fs::rename(&temporary, path)
+}
+
+/// Held while the cache file is read and rewritten, so that runs at the same time, such as a
+/// daemon and `cache forget`, do not undo each other's changes.
+#[cfg(unix)]
+fn lock(path: &Path) -> Option<fs::File> {
+    use std::os::fd::AsRawFd;
+
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).ok()?;
+    }
+    let file = fs::File::create(lock_path).ok()?;
+    // Released when the file is closed.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        log::warn!(
+            "failed to lock the station cache: {}",
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+    Some(file)
+}
+
+/// Without `flock`, the rename in [`write`] still keeps the file whole.
+#[cfg(not(unix))]
+fn lock(_: &Path) -> Option<fs::File> {
+    None
+}
+
+/// `cache list`: prints the cached base stations, without touching Bluetooth.
+pub fn list(path: &Path) -> Result<()> {
+    let entries = read(path);
+    if entries.is_empty() {
+        eprintln!("no base stations are cached in {}", path.display());
+        return Ok(());
+    }
+    let mut rows = vec![[
+        "NAME".to_owned(),
+        "ADDRESS".to_owned(),
+        "ADAPTER".to_owned(),
+        "FIRST SEEN".to_owned(),
+        "LAST SEEN".to_owned(),
+        "CHANNEL".to_owned(),
+    ]];
+    rows.extend(entries.values().map(|entry| {
+        [
+            entry.name.clone(),
+            entry.address.to_string(),
+            entry.adapter.clone(),
+            humantime::format_rfc3339_seconds(entry.first_seen).to_string(),
+            humantime::format_rfc3339_seconds(entry.last_seen).to_string(),
+            entry
+                .channel
+                .map_or_else(|| "n/a".to_owned(), |channel| channel.to_string()),
+        ]
+    }));
+    let mut widths = [0; 6];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+/// The names of the cached base stations, for the completion scripts.
+pub fn names(path: &Path) -> Vec<String> {
+    read(path).into_values().map(|entry| entry.name).collect()
+}
+
+/// `cache forget`: drops the named base stations, or every one with `all`.
+pub fn forget(path: &Path, names: &[String], all: bool) -> Result<()> {
+    let _lock = lock(path);
+    let mut entries = read(path);
+    let mut unknown = Vec::new();
+    if all {
+        entries.clear();
+    }
+    for name in names {
+        let key = fold(name);
+        // Addresses work too, since they are how the cache is usually checked.
+        let by_address = crate::address::parse(name).and_then(|address| {
+            let entry = entries.values().find(|entry| entry.address == address)?;
+            Some(fold(&entry.name))
+        });
+        if entries
+            .remove(by_address.as_ref().unwrap_or(&key))
+            .is_none()
+        {
+            unknown.push(name.as_str());
+        }
+    }
+    write(path, &entries)?;
+    if !unknown.is_empty() {
+        bail!("not in the station cache: {}", unknown.join(", "));
+    }
+    Ok(())
+}
+
+/// `$XDG_CACHE_HOME/lighthousectl/stations`, falling back to `~/Library/Caches` on macOS,
+/// `~/.cache` or `%LOCALAPPDATA%`.
+pub fn default_path() -> Option<PathBuf> {
+    let home = || env::var_os("HOME").map(PathBuf::from);
+    let dir = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| match cfg!(target_os = "macos") {
+            true => home().map(|home| home.join("Library").join("Caches")),
+            false => home().map(|home| home.join(".cache")),
+        })
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+    Some(dir.join("lighthousectl").join("stations"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreadable_lines_are_dropped() {
+        let text = format!(
+            "{}\nLHB-4A3C91F2\tAA:BB:CC:DD:EE:FF\thci0\t1714564800\t1714568400\t7\n\
+             LHB-1D0E77B5\tnot an address\thci0\t1714564800\t1714568400\t-\n\
+             garbage\n\
+             LHB-93D0B1E4\t11:22:33:44:55:66\thci0\t1714564800\t1714564800\t-\n",
+            HEADER
+        );
+        let entries = parse(&text, Path::new("stations"));
+        assert_eq!(entries.len(), 2);
+        let entry = &entries["lhb-4a3c91f2"];
+        assert_eq!(entry.channel, Some(7));
+        assert_eq!(
+            entry.last_seen,
+            UNIX_EPOCH + Duration::from_secs(1714568400)
+        );
+        assert_eq!(entries["lhb-93d0b1e4"].channel, None);
+        assert_eq!(parse(&format(&entries), Path::new("stations")), entries);
+    }
+
+    #[test]
+    fn changes_are_written_once_dropped_along_with_other_runs() {
+        let dir = env::temp_dir().join(format!("lighthousectl-cache-{}", std::process::id()));
+        let path = dir.join("stations");
+        let address = |last| BDAddr::from([0xaa, 0xbb, 0xcc, 0xdd, 0xee, last]);
+        let cache = Cache::open(Some(path.clone()), "hci0".to_owned());
+        cache.seen("LHB-4A3C91F2", address(1));
+        cache.seen("LHB-93D0B1E4", address(2));
+        cache.set_channel("LHB-4A3C91F2", 7);
+        assert!(cache.forget("LHB-93D0B1E4"));
+        assert!(!path.exists());
+        // Another run writing meanwhile.
+        let other = Cache::open(Some(path.clone()), "hci1".to_owned());
+        other.seen("LHB-1D0E77B5", address(3));
+        drop(other);
+        drop(cache);
+        let entries = read(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        let names: Vec<_> = entries.keys().map(String::as_str).collect();
+        assert_eq!(names, ["lhb-1d0e77b5", "lhb-4a3c91f2"]);
+        assert_eq!(entries["lhb-4a3c91f2"].channel, Some(7));
+        assert_eq!(entries["lhb-1d0e77b5"].adapter, "hci1");
+    }
+}
@@ -0,0 +1,768 @@
+//! `completions <SHELL>`: shell completion scripts, generated from the clap definition of the
+//! command line so that new flags are completed without touching this file.
+//!
+//! Base station names are completed from the station cache, through the hidden `__complete-names`
+//! command, which never touches Bluetooth so that completing stays instant.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use clap::{Arg, ArgAction, Command, ValueHint};
+
+const BIN: &str = "lighthousectl";
+
+/// What the completion scripts need to know about a flag.
+struct Flag {
+    long: Option<String>,
+    short: Option<char>,
+    help: String,
+    takes_value: bool,
+    /// Whether the flag may be given more than once, such as `-vv`.
+    repeatable: bool,
+    /// The values to offer, if they are known.
+    values: Vec<String>,
+    path: bool,
+    /// Whether the value is a base station name, such as with `--exclude`.
+    names: bool,
+    /// Whether the flag is offered after any command, such as `--adapter`.
+    global: bool,
+}
+
+impl Flag {
+    fn new(arg: &Arg) -> Self {
+        let takes_value = arg.get_num_args().is_some_and(|range| range.takes_values());
+        let values = arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name().to_owned())
+            .collect();
+        Self {
+            long: arg.get_long().map(str::to_owned),
+            short: arg.get_short(),
+            help: summary(arg.get_help().map(ToString::to_string)),
+            takes_value,
+            repeatable: matches!(arg.get_action(), ArgAction::Count | ArgAction::Append),
+            values,
+            path: matches!(
+                arg.get_value_hint(),
+                ValueHint::FilePath | ValueHint::AnyPath | ValueHint::DirPath
+            ),
+            names: arg
+                .get_value_names()
+                .is_some_and(|names| names.iter().any(|name| name == "NAME")),
+            global: arg.is_global_set(),
+        }
+    }
+
+    /// Every spelling of the flag, such as `-v` and `--verbose`.
+    fn spellings(&self) -> Vec<String> {
+        let short = self.short.map(|short| format!("-{}", short));
+        let long = self.long.as_ref().map(|long| format!("--{}", long));
+        short.into_iter().chain(long).collect()
+    }
+}
+
+/// The first sentence of a help text, which fits on a completion menu line.
+fn summary(help: Option<String>) -> String {
+    let help = help.unwrap_or_default();
+    let line = help.lines().next().unwrap_or_default();
+    // Not at abbreviations such as `e.g.`, which are followed by lowercase.
+    let end = line
+        .match_indices(". ")
+        .find(|(at, _)| line[at + 2..].starts_with(char::is_uppercase))
+        .map_or(line.len(), |(at, _)| at + 1);
+    line[..end].trim_end_matches('.').to_owned()
+}
+
+/// A command of the command line as seen by the completion scripts, with its subcommands, such
+/// as `list` and `forget` of `cache`.
+struct Spec {
+    name: String,
+    help: String,
+    /// The flags of the command itself. The global flags, which every subcommand also has, are
+    /// only on the command line itself.
+    flags: Vec<Flag>,
+    /// Whether the command takes base station names, such as `on` but not `doctor`.
+    names: bool,
+    subcommands: Vec<Spec>,
+}
+
+impl Spec {
+    fn new(command: &Command) -> Self {
+        Self::of(command, true)
+    }
+
+    fn of(command: &Command, top: bool) -> Self {
+        let flags = command
+            .get_arguments()
+            .filter(|arg| !arg.is_hide_set() && !arg.is_positional())
+            .filter(|arg| top || !arg.is_global_set())
+            .map(Flag::new)
+            .collect();
+        let subcommands = command
+            .get_subcommands()
+            .filter(|subcommand| !subcommand.is_hide_set() && subcommand.get_name() != "help")
+            .map(|subcommand| Self::of(subcommand, false))
+            .collect();
+        Self {
+            name: command.get_name().to_owned(),
+            help: summary(command.get_about().map(ToString::to_string)),
+            flags,
+            names: command.get_positionals().any(|arg| arg.get_id() == "names"),
+            subcommands,
+        }
+    }
+
+    /// Every command with the words leading to it, such as `cache forget`, starting with the
+    /// command line itself, which none lead to.
+    fn commands(&self) -> Vec<(String, &Spec)> {
+        let mut commands = vec![(String::new(), self)];
+        let mut at = 0;
+        while at < commands.len() {
+            let (path, spec) = commands[at].clone();
+            for subcommand in &spec.subcommands {
+                let path = match path.as_str() {
+                    "" => subcommand.name.clone(),
+                    path => format!("{} {}", path, subcommand.name),
+                };
+                commands.push((path, subcommand));
+            }
+            at += 1;
+        }
+        commands
+    }
+
+    fn subcommand_names(&self) -> String {
+        let names: Vec<_> = self
+            .subcommands
+            .iter()
+            .map(|subcommand| subcommand.name.as_str())
+            .collect();
+        names.join(" ")
+    }
+
+    /// The flags offered after any command.
+    fn global_flags(&self) -> impl Iterator<Item = &Flag> {
+        self.flags.iter().filter(|flag| flag.global)
+    }
+
+    /// The flags of every command, each spelling once.
+    fn all_flags(&self) -> Vec<&Flag> {
+        let mut flags: Vec<&Flag> = Vec::new();
+        for (_, spec) in self.commands() {
+            for flag in &spec.flags {
+                if flags
+                    .iter()
+                    .all(|known| known.spellings() != flag.spellings())
+                {
+                    flags.push(flag);
+                }
+            }
+        }
+        flags
+    }
+
+    /// The commands that take base station names, by the words leading to them.
+    fn named_commands(&self) -> Vec<String> {
+        self.commands()
+            .into_iter()
+            .filter(|(_, spec)| spec.names)
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    /// The spellings of the flags taking a base station name.
+    fn name_flags(&self) -> Vec<String> {
+        self.all_flags()
+            .into_iter()
+            .filter(|flag| flag.names)
+            .flat_map(Flag::spellings)
+            .collect()
+    }
+
+    /// The spellings of the flags taking a value, which the value follows.
+    fn value_flags(&self) -> Vec<String> {
+        self.all_flags()
+            .into_iter()
+            .filter(|flag| flag.takes_value)
+            .flat_map(Flag::spellings)
+            .collect()
+    }
+}
+
+/// Writes the completion script for `shell` to stdout.
+pub fn print(shell: &str, mut command: Command) -> Result<()> {
+    // Adds `--help` and `--version`.
+    command.build();
+    let spec = Spec::new(&command);
+    let script = match shell {
+        "bash" => bash(&spec),
+        "zsh" => zsh(&spec),
+        "fish" => fish(&spec),
+        "powershell" => powershell(&spec),
+        _ => bail!("expected bash, zsh, fish or powershell, not `{}`", shell),
+    };
+    print!("{}", script);
+    Ok(())
+}
+
+/// `__complete-names`: prints the cached base station names and the `@group`s of the config, one
+/// per line. Nothing is printed if the cache cannot be read, which leaves the shell completing
+/// nothing.
+pub fn print_names<'a>(cache: Option<&Path>, groups: impl IntoIterator<Item = &'a String>) {
+    for name in cache.map(crate::cache::names).unwrap_or_default() {
+        println!("{}", name);
+    }
+    for group in groups {
+        println!("@{}", group);
+    }
+}
+
+fn single_quoted(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+fn bash(spec: &Spec) -> String {
+    let mut cases = String::new();
+    for flag in spec.all_flags().into_iter().filter(|flag| flag.takes_value) {
+        let reply = if !flag.values.is_empty() {
+            format!(
+                "COMPREPLY=($(compgen -W {} -- \"$cur\"))",
+                single_quoted(&flag.values.join(" "))
+            )
+        } else if flag.path {
+            "COMPREPLY=($(compgen -f -- \"$cur\"))".to_owned()
+        } else if flag.names {
+            format!("_{}_names", BIN)
+        } else {
+            ":".to_owned()
+        };
+        cases.push_str(&format!(
+            "        {})\n            {}\n            return\n            ;;\n",
+            flag.spellings().join("|"),
+            reply
+        ));
+    }
+    let mut subcommands = String::new();
+    let mut flag_cases = String::new();
+    for (path, command) in spec.commands() {
+        if !command.subcommands.is_empty() {
+            subcommands.push_str(&format!(
+                "        {}) echo {} ;;\n",
+                single_quoted(&path),
+                single_quoted(&command.subcommand_names())
+            ));
+        }
+        let flags: Vec<_> = command
+            .flags
+            .iter()
+            .filter(|flag| !flag.global)
+            .flat_map(Flag::spellings)
+            .collect();
+        if !flags.is_empty() {
+            flag_cases.push_str(&format!(
+                "            {}) flags+=\" {}\" ;;\n",
+                single_quoted(&path),
+                flags.join(" ")
+            ));
+        }
+    }
+    let global: Vec<_> = spec.global_flags().flat_map(Flag::spellings).collect();
+    let named: Vec<_> = spec
+        .named_commands()
+        .iter()
+        .map(|path| single_quoted(path))
+        .collect();
+    format!(
+        r#"_{bin}_names() {{
+    local IFS=$'\n'
+    COMPREPLY=($(compgen -W "$({bin} __complete-names 2>/dev/null)" -- "$cur"))
+}}
+
+# The subcommands of the command the words lead to, such as `cache`.
+_{bin}_subcommands() {{
+    case "$1" in
+{subcommands}    esac
+}}
+
+_{bin}() {{
+    COMPREPLY=()
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "$prev" in
+{cases}    esac
+    # The words that are neither flags nor their values lead to the command, up to the first
+    # that is not a subcommand.
+    local i word skip= command= subcommands="$(_{bin}_subcommands '')"
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${{COMP_WORDS[i]}}"
+        if [[ -n "$skip" ]]; then
+            skip=
+        elif [[ " "{value_flags}" " == *" $word "* ]]; then
+            skip=1
+        elif [[ "$word" != -* ]]; then
+            if [[ " $subcommands " != *" $word "* ]]; then
+                subcommands=
+                break
+            fi
+            command="${{command:+$command }}$word"
+            subcommands="$(_{bin}_subcommands "$command")"
+        fi
+    done
+    if [[ "$cur" == -* ]]; then
+        local flags={global}
+        case "$command" in
+{flag_cases}        esac
+        COMPREPLY=($(compgen -W "$flags" -- "$cur"))
+    elif [[ -n "$subcommands" ]]; then
+        COMPREPLY=($(compgen -W "$subcommands" -- "$cur"))
+    else
+        case "$command" in
+            {named}) _{bin}_names ;;
+        esac
+    fi
+}}
+complete -F _{bin} {bin}
+"#,
+        bin = BIN,
+        cases = cases,
+        subcommands = subcommands,
+        value_flags = single_quoted(&spec.value_flags().join(" ")),
+        global = single_quoted(&global.join(" ")),
+        flag_cases = flag_cases,
+        named = named.join("|"),
+    )
+}
+
+/// Escapes the brackets and colons that `_arguments` gives a meaning to.
+fn zsh_escape(text: &str) -> String {
+    text.replace('\\', r"\\")
+        .replace('\'', r"'\''")
+        .replace('[', r"\[")
+        .replace(']', r"\]")
+        .replace(':', r"\:")
+}
+
+/// The `_arguments` spec of a flag, such as `{-v,--verbose}'[Show more diagnostics]'`.
+fn zsh_flag(flag: &Flag) -> String {
+    let action = if !flag.takes_value {
+        String::new()
+    } else if !flag.values.is_empty() {
+        format!(":value:({})", flag.values.join(" "))
+    } else if flag.path {
+        ":path:_files".to_owned()
+    } else if flag.names {
+        format!(":name:_{}_names", BIN)
+    } else {
+        ":value:".to_owned()
+    };
+    let repeat = if flag.repeatable { "'*'" } else { "" };
+    let spellings = flag.spellings();
+    // Spelled both ways, the flag is given unquoted for the shell to expand, as in
+    // `{-v,--verbose}'[...]'`.
+    let names = match spellings.len() {
+        1 => format!("'{}'", spellings[0]),
+        _ => format!("{{{}}}", spellings.join(",")),
+    };
+    format!(
+        "{}{}'[{}]{}'",
+        repeat,
+        names,
+        zsh_escape(&flag.help),
+        action
+    )
+}
+
+/// The completion function of a command, which hands the words after a subcommand on to the
+/// function of the subcommand.
+fn zsh_function(path: &str, command: &Spec) -> String {
+    let function = |path: &str| match path {
+        "" => format!("_{}", BIN),
+        path => format!("_{}_{}", BIN, path.replace(' ', "_")),
+    };
+    let mut specs = vec!["$global".to_owned()];
+    specs.extend(
+        command
+            .flags
+            .iter()
+            .filter(|flag| !flag.global)
+            .map(zsh_flag),
+    );
+    if command.subcommands.is_empty() {
+        if command.names {
+            specs.push(format!("'*:name:_{}_names'", BIN));
+        }
+        return format!(
+            "{}() {{\n    local -a global\n    _{}_global\n    _arguments -s \\\n        {}\n}}\n",
+            function(path),
+            BIN,
+            specs.join(" \\\n        ")
+        );
+    }
+    specs.push("'1:command:->command'".to_owned());
+    specs.push("'*::argument:->argument'".to_owned());
+    let commands: Vec<_> = command
+        .subcommands
+        .iter()
+        // `_describe` only gives a meaning to colons.
+        .map(|subcommand| {
+            let help = subcommand.help.replace('\'', r"'\''").replace(':', r"\:");
+            format!("                '{}:{}'", subcommand.name, help)
+        })
+        .collect();
+    let cases: Vec<_> = command
+        .subcommands
+        .iter()
+        .map(|subcommand| {
+            let path = match path {
+                "" => subcommand.name.clone(),
+                path => format!("{} {}", path, subcommand.name),
+            };
+            format!(
+                "                ({}) {} ;;",
+                subcommand.name,
+                function(&path)
+            )
+        })
+        .collect();
+    format!(
+        r#"{function}() {{
+    local curcontext="$curcontext" state line
+    local -a global
+    _{bin}_global
+    _arguments -s -C \
+        {specs}
+    case $state in
+        (command)
+            local -a commands
+            commands=(
+{commands}
+            )
+            _describe -t commands command commands
+            ;;
+        (argument)
+            case $line[1] in
+{cases}
+            esac
+            ;;
+    esac
+}}
+"#,
+        function = function(path),
+        bin = BIN,
+        specs = specs.join(" \\\n        "),
+        commands = commands.join("\n"),
+        cases = cases.join("\n"),
+    )
+}
+
+fn zsh(spec: &Spec) -> String {
+    let global: Vec<_> = spec
+        .global_flags()
+        .map(|flag| format!("        {}", zsh_flag(flag)))
+        .collect();
+    let functions: Vec<_> = spec
+        .commands()
+        .into_iter()
+        .map(|(path, command)| zsh_function(&path, command))
+        .collect();
+    format!(
+        r#"#compdef {bin}
+
+# The flags offered after any command.
+_{bin}_global() {{
+    global=(
+{global}
+    )
+}}
+
+_{bin}_names() {{
+    local -a names
+    names=(${{(f)"$({bin} __complete-names 2>/dev/null)"}})
+    compadd -a names
+}}
+
+{functions}
+_{bin} "$@"
+"#,
+        bin = BIN,
+        global = global.join("\n"),
+        functions = functions.join("\n"),
+    )
+}
+
+fn fish(spec: &Spec) -> String {
+    let names_of = format!("({} __complete-names 2>/dev/null)", BIN);
+    let mut cases = String::new();
+    for (path, command) in spec.commands().into_iter().skip(1) {
+        if !command.subcommands.is_empty() {
+            cases.push_str(&format!(
+                "                case {}\n                    set subcommands {}\n",
+                single_quoted(&path),
+                command.subcommand_names()
+            ));
+        }
+    }
+    let mut lines = vec![
+        format!(
+            r#"# Whether the words typed so far lead to the command given, such as `cache forget`: those that
+# are neither flags nor their values, up to the first that is not a subcommand.
+function __{bin}_at
+    set -l words (commandline -opc)
+    set -e words[1]
+    set -l subcommands {subcommands}
+    set -l command
+    set -l skip
+    for word in $words
+        if set -q skip[1]
+            set -e skip
+        else if contains -- $word {value_flags}
+            set skip 1
+        else if not string match -q -- '-*' $word
+            contains -- $word $subcommands; or break
+            set command $command $word
+            switch "$command"
+{cases}                case '*'
+                    set subcommands
+            end
+        end
+    end
+    test "$command" = "$argv"
+end
+"#,
+            bin = BIN,
+            subcommands = spec.subcommand_names(),
+            value_flags = spec.value_flags().join(" "),
+            cases = cases,
+        ),
+        format!("complete -c {} -f", BIN),
+    ];
+    for (path, command) in spec.commands() {
+        let condition = format!("__{}_at {}", BIN, path);
+        let at = format!(" -n {}", single_quoted(condition.trim_end()));
+        for subcommand in &command.subcommands {
+            lines.push(format!(
+                "complete -c {}{} -a {} -d {}",
+                BIN,
+                at,
+                subcommand.name,
+                single_quoted(&subcommand.help)
+            ));
+        }
+        for flag in &command.flags {
+            let mut line = format!("complete -c {}", BIN);
+            if !flag.global {
+                line.push_str(&at);
+            }
+            if let Some(short) = flag.short {
+                line.push_str(&format!(" -s {}", short));
+            }
+            if let Some(long) = &flag.long {
+                line.push_str(&format!(" -l {}", long));
+            }
+            if flag.takes_value {
+                line.push_str(" -r");
+                if !flag.values.is_empty() {
+                    line.push_str(&format!(" -a {}", single_quoted(&flag.values.join(" "))));
+                } else if flag.path {
+                    line.push_str(" -F");
+                } else if flag.names {
+                    line.push_str(&format!(" -a {}", single_quoted(&names_of)));
+                }
+            }
+            line.push_str(&format!(" -d {}", single_quoted(&flag.help)));
+            lines.push(line);
+        }
+        if command.names {
+            lines.push(format!(
+                "complete -c {}{} -a {}",
+                BIN,
+                at,
+                single_quoted(&names_of)
+            ));
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+fn powershell(spec: &Spec) -> String {
+    let quoted = |text: &str| format!("'{}'", text.replace('\'', "''"));
+    let list = |items: Vec<String>| {
+        let items: Vec<_> = items.iter().map(|item| quoted(item)).collect();
+        items.join(", ")
+    };
+    // By the words leading to the command they are offered after, `*` for any command.
+    let mut completions = Vec::new();
+    for (path, command) in spec.commands() {
+        for subcommand in &command.subcommands {
+            let help = match subcommand.help.as_str() {
+                "" => &subcommand.name,
+                help => help,
+            };
+            completions.push(format!(
+                "        @({}, {}, {})",
+                quoted(&path),
+                quoted(&subcommand.name),
+                quoted(help)
+            ));
+        }
+        for flag in &command.flags {
+            let at = if flag.global { "*" } else { path.as_str() };
+            for spelling in flag.spellings() {
+                let help = if flag.help.is_empty() {
+                    spelling.clone()
+                } else {
+                    flag.help.clone()
+                };
+                completions.push(format!(
+                    "        @({}, {}, {})",
+                    quoted(at),
+                    quoted(&spelling),
+                    quoted(&help)
+                ));
+            }
+        }
+    }
+    let mut values = Vec::new();
+    for flag in spec
+        .all_flags()
+        .into_iter()
+        .filter(|flag| !flag.values.is_empty())
+    {
+        let list: Vec<_> = flag.values.iter().map(|value| quoted(value)).collect();
+        for spelling in flag.spellings() {
+            values.push(format!(
+                "        {} = @({})",
+                quoted(&spelling),
+                list.join(", ")
+            ));
+        }
+    }
+    let commands: Vec<_> = spec
+        .commands()
+        .into_iter()
+        .map(|(path, _)| path)
+        .filter(|path| !path.is_empty())
+        .collect();
+    format!(
+        r#"Register-ArgumentCompleter -Native -CommandName {bin} -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+    $values = @{{
+{values}
+    }}
+    $words = @($commandAst.CommandElements | ForEach-Object {{ $_.ToString() }})
+    $previous = if ($wordToComplete) {{ $words[-2] }} else {{ $words[-1] }}
+    if ($values.ContainsKey($previous)) {{
+        $values[$previous] | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+            [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+        }}
+        return
+    }}
+    # The words that are neither flags nor their values lead to the command, up to the first
+    # that is not a subcommand.
+    $commands = @({commands})
+    $valueFlags = @({value_flags})
+    $before = @($words | Select-Object -Skip 1 -First ($words.Count - 1 - [int][bool]$wordToComplete))
+    $command = ''
+    $skip = $false
+    foreach ($word in $before) {{
+        if ($skip) {{
+            $skip = $false
+        }} elseif ($valueFlags -contains $word) {{
+            $skip = $true
+        }} elseif (-not $word.StartsWith('-')) {{
+            $next = if ($command) {{ "$command $word" }} else {{ $word }}
+            if ($commands -notcontains $next) {{ break }}
+            $command = $next
+        }}
+    }}
+    # Base station names follow a command that takes them, or a flag such as `--exclude`.
+    $nameFlags = @({name_flags})
+    $named = @({named})
+    if (($nameFlags -contains $previous) -or (($named -contains $command) -and -not $wordToComplete.StartsWith('-'))) {{
+        & {bin} __complete-names 2>$null | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+            [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+        }}
+        return
+    }}
+    @(
+{completions}
+    ) | Where-Object {{ ($_[0] -eq $command -or $_[0] -eq '*') -and $_[1] -like "$wordToComplete*" }} | ForEach-Object {{
+        $type = if ($_[1].StartsWith('-')) {{ 'ParameterName' }} else {{ 'ParameterValue' }}
+        [System.Management.Automation.CompletionResult]::new($_[1], $_[1], $type, $_[2])
+    }}
+}}
+"#,
+        bin = BIN,
+        values = values.join("\n"),
+        commands = list(commands),
+        value_flags = list(spec.value_flags()),
+        name_flags = list(spec.name_flags()),
+        named = list(spec.named_commands()),
+        completions = completions.join(",\n"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn help_is_cut_to_the_first_sentence() {
+        let help = "Stop after this long. Base stations being handled are still finished.";
+        assert_eq!(summary(Some(help.to_owned())), "Stop after this long");
+        assert_eq!(
+            summary(Some("Defaults to 3.\nMore.".to_owned())),
+            "Defaults to 3"
+        );
+        assert_eq!(
+            summary(Some("Write a byte, e.g. `0x09`.".to_owned())),
+            "Write a byte, e.g. `0x09`"
+        );
+        assert_eq!(summary(None), "");
+    }
+
+    fn spec() -> Spec {
+        let mut command = <crate::Cli as clap::CommandFactory>::command();
+        command.build();
+        Spec::new(&command)
+    }
+
+    #[test]
+    fn names_are_completed_for_the_commands_taking_them() {
+        let named = spec().named_commands();
+        for path in ["on", "scan", "cache forget", "gatt read", "gatt write"] {
+            assert!(named.iter().any(|named| named == path), "{}", path);
+        }
+        for path in [
+            "",
+            "adapters",
+            "doctor",
+            "completions",
+            "cache",
+            "cache list",
+            "gatt",
+        ] {
+            assert!(named.iter().all(|named| named != path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn subcommands_are_completed_with_their_own_flags() {
+        let spec = spec();
+        let commands = spec.commands();
+        let flags = |path: &str| {
+            let (_, command) = commands.iter().find(|(at, _)| at == path).unwrap();
+            let longs = command.flags.iter().filter_map(|flag| flag.long.clone());
+            longs.collect::<Vec<_>>()
+        };
+        let (_, gatt) = commands.iter().find(|(at, _)| at == "gatt").unwrap();
+        assert_eq!(gatt.subcommand_names(), "read write");
+        assert!(flags("cache forget").contains(&"all".to_owned()));
+        assert!(!flags("").contains(&"all".to_owned()));
+        assert!(flags("").contains(&"adapter".to_owned()));
+        assert!(!flags("on").contains(&"adapter".to_owned()));
+        assert!(flags("bench").contains(&"write".to_owned()));
+    }
+}
@@ -0,0 +1,571 @@
+//! The config file, written in a subset of TOML: tables, comments, and keys with string,
+//! integer, boolean or array values.
+
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::parse_duration;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+/// A `key = value` line, remembering where it came from for error messages.
+#[derive(Debug)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+/// The entries of each table. The entries before any `[table]` header are in the `""` table.
+pub type Document = BTreeMap<String, Vec<Entry>>;
+
+#[derive(Default)]
+pub struct Config {
+    pub path: Option<PathBuf>,
+    pub names: Vec<String>,
+    pub adapter: Option<String>,
+    pub timeout: Option<String>,
+    pub connect_timeout: Option<String>,
+    pub io_timeout: Option<String>,
+    pub wait_timeout: Option<String>,
+    pub color: Option<String>,
+    pub off_means: Option<String>,
+    pub write_mode: Option<String>,
+    pub output: Option<String>,
+    pub format: Option<String>,
+    /// Named lists of base stations from the `[groups]` table, addressed as `@name`.
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// The unique IDs of V1 base stations from the `[v1_ids]` table, by lowercase name.
+    pub v1_ids: BTreeMap<String, u32>,
+    pub mqtt: Mqtt,
+}
+
+/// The `[mqtt]` table, for `mqtt` mode.
+#[derive(Default)]
+pub struct Mqtt {
+    /// `host`, `host:port` or `mqtt://host:port`.
+    pub broker: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: Option<String>,
+    /// Prepended to the topics of the base stations, `lighthousectl` by default.
+    pub prefix: Option<String>,
+    /// Where Home Assistant looks for discovery payloads, `homeassistant` by default.
+    pub discovery_prefix: Option<String>,
+}
+
+pub const OUTPUTS: [&str; 6] = ["plain", "json", "json-lines", "csv", "table", "porcelain"];
+
+impl Config {
+    /// Loads the config file at `path`, or at the default path if it exists.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, text) = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                (path.to_owned(), text)
+            }
+            None => {
+                let path = match default_path() {
+                    Some(path) => path,
+                    None => return Ok(Self::default()),
+                };
+                match fs::read_to_string(&path) {
+                    Ok(text) => (path, text),
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                        return Ok(Self::default())
+                    }
+                    Err(error) => {
+                        return Err(error)
+                            .with_context(|| format!("failed to read {}", path.display()))
+                    }
+                }
+            }
+        };
+        let document =
+            parse(&text).with_context(|| format!("failed to parse {}", path.display()))?;
+        let mut config = Self::from_document(&document)
+            .with_context(|| format!("invalid config in {}", path.display()))?;
+        config.path = Some(path);
+        Ok(config)
+    }
+
+    fn from_document(document: &Document) -> Result<Self> {
+        let mut config = Self::default();
+        for (table, entries) in document {
+            if table == "groups" {
+                for entry in entries {
+                    config
+                        .groups
+                        .insert(entry.key.clone(), string_array(entry)?);
+                }
+                continue;
+            }
+            if table == "v1_ids" {
+                for entry in entries {
+                    config
+                        .v1_ids
+                        .insert(entry.key.to_lowercase(), v1_id(entry)?);
+                }
+                continue;
+            }
+            if table == "mqtt" {
+                for entry in entries {
+                    let mqtt = &mut config.mqtt;
+                    let target = match entry.key.as_str() {
+                        "broker" => &mut mqtt.broker,
+                        "username" => &mut mqtt.username,
+                        "password" => &mut mqtt.password,
+                        "client_id" => &mut mqtt.client_id,
+                        "prefix" => &mut mqtt.prefix,
+                        "discovery_prefix" => &mut mqtt.discovery_prefix,
+                        key => bail!("line {}: unknown key `{}` in `[mqtt]`", entry.line, key),
+                    };
+                    match &entry.value {
+                        Value::String(value) => *target = Some(value.clone()),
+                        value => bail!(
+                            "line {}: `{}` must be a string, not {}",
+                            entry.line,
+                            entry.key,
+                            value.type_name()
+                        ),
+                    }
+                }
+                continue;
+            }
+            if !table.is_empty() {
+                bail!("unknown table `[{}]`", table);
+            }
+            for entry in entries {
+                let key = entry.key.as_str();
+                let target = match key {
+                    "names" => {
+                        config.names = string_array(entry)?;
+                        continue;
+                    }
+                    "adapter" => &mut config.adapter,
+                    "timeout" => &mut config.timeout,
+                    "connect_timeout" => &mut config.connect_timeout,
+                    "io_timeout" => &mut config.io_timeout,
+                    "wait_timeout" => &mut config.wait_timeout,
+                    "color" => &mut config.color,
+                    "off_means" => &mut config.off_means,
+                    "write_mode" => &mut config.write_mode,
+                    "output" => &mut config.output,
+                    "format" => &mut config.format,
+                    _ => bail!("line {}: unknown key `{}`", entry.line, key),
+                };
+                let value = match (key, &entry.value) {
+                    (_, Value::String(value)) => value.clone(),
+                    (
+                        "timeout" | "connect_timeout" | "io_timeout" | "wait_timeout",
+                        Value::Integer(secs),
+                    ) if *secs >= 0 => secs.to_string(),
+                    (_, value) => bail!(
+                        "line {}: `{}` must be a string, not {}",
+                        entry.line,
+                        key,
+                        value.type_name()
+                    ),
+                };
+                validate(key, &value).map_err(|error| {
+                    anyhow!("line {}: invalid `{}`: {}", entry.line, key, error)
+                })?;
+                *target = Some(value);
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn validate(key: &str, value: &str) -> Result<(), String> {
+    let allowed: &[&str] = match key {
+        "timeout" | "connect_timeout" | "io_timeout" | "wait_timeout" => {
+            return parse_duration(value).map(drop).map_err(|e| e.to_string())
+        }
+        "color" => &["auto", "always", "never"],
+        "off_means" => &["sleep", "standby"],
+        "write_mode" => &["with-response", "without-response"],
+        "output" => &OUTPUTS,
+        _ => return Ok(()),
+    };
+    if allowed.contains(&value) {
+        Ok(())
+    } else {
+        Err(format!("expected one of {}", allowed.join(", ")))
+    }
+}
+
+/// A V1 unique ID, as printed on the back of the base station, e.g. `"0x1A2B3C4D"`.
+fn v1_id(entry: &Entry) -> Result<u32> {
+    let id = match &entry.value {
+        Value::String(id) => {
+            let digits = id.strip_prefix("0x").unwrap_or(id);
+            u32::from_str_radix(digits, 16).ok()
+        }
+        Value::Integer(id) => u32::try_from(*id).ok(),
+        _ => None,
+    };
+    id.ok_or_else(|| {
+        anyhow!(
+            "line {}: `{}` must be a hexadecimal ID like \"1A2B3C4D\"",
+            entry.line,
+            entry.key
+        )
+    })
+}
+
+pub fn string_array(entry: &Entry) -> Result<Vec<String>> {
+    let error = || {
+        anyhow!(
+            "line {}: `{}` must be an array of strings",
+            entry.line,
+            entry.key
+        )
+    };
+    match &entry.value {
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(value) => Ok(value.clone()),
+                _ => Err(error()),
+            })
+            .collect(),
+        _ => Err(error()),
+    }
+}
+
+/// `$XDG_CONFIG_HOME/lighthousectl/config.toml`, falling back to `~/.config` or `%APPDATA%`.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join("lighthousectl").join("config.toml"))
+}
+
+pub fn parse(text: &str) -> Result<Document> {
+    let mut document = Document::new();
+    let mut table = String::new();
+    document.insert(table.clone(), Vec::new());
+    let mut lines = text.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let number = index + 1;
+        let mut line = strip_comment(line).trim().to_owned();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name
+                .strip_suffix(']')
+                .ok_or_else(|| anyhow!("line {}: unclosed table header", number))?;
+            table = parse_key(name.trim()).map_err(|e| anyhow!("line {}: {}", number, e))?;
+            if document.contains_key(&table) {
+                bail!("line {}: duplicate table `[{}]`", number, table);
+            }
+            document.insert(table.clone(), Vec::new());
+            continue;
+        }
+        // Arrays may span several lines.
+        while bracket_depth(&line) > 0 {
+            let (_, next) = lines
+                .next()
+                .ok_or_else(|| anyhow!("line {}: unclosed array", number))?;
+            line.push(' ');
+            line.push_str(strip_comment(next).trim());
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected `key = value`", number))?;
+        let key = parse_key(key.trim()).map_err(|e| anyhow!("line {}: {}", number, e))?;
+        let mut parser = ValueParser { rest: value.trim() };
+        let value = parser
+            .value()
+            .and_then(|value| {
+                if parser.rest.trim().is_empty() {
+                    Ok(value)
+                } else {
+                    Err(format!("unexpected `{}`", parser.rest.trim()))
+                }
+            })
+            .map_err(|e| anyhow!("line {}: invalid value for `{}`: {}", number, key, e))?;
+        let entries = document.get_mut(&table).unwrap();
+        if entries.iter().any(|entry| entry.key == key) {
+            bail!("line {}: duplicate key `{}`", number, key);
+        }
+        entries.push(Entry {
+            key,
+            value,
+            line: number,
+        });
+    }
+    Ok(document)
+}
+
+fn parse_key(key: &str) -> Result<String, String> {
+    if key.starts_with('"') {
+        let mut parser = ValueParser { rest: key };
+        return match parser.value()? {
+            Value::String(key) if parser.rest.is_empty() => Ok(key),
+            _ => Err(format!("invalid key `{}`", key)),
+        };
+    }
+    let bare = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if key.is_empty() || !key.chars().all(bare) {
+        return Err(format!("invalid key `{}`", key));
+    }
+    Ok(key.to_owned())
+}
+
+/// Removes a `#` comment, unless it is inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn bracket_depth(line: &str) -> i32 {
+    let value = line.split_once('=').map_or("", |(_, value)| value);
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in value.chars() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => {}
+        }
+        escaped = false;
+    }
+    depth
+}
+
+struct ValueParser<'a> {
+    rest: &'a str,
+}
+
+impl ValueParser<'_> {
+    fn value(&mut self) -> Result<Value, String> {
+        self.rest = self.rest.trim_start();
+        let mut chars = self.rest.chars();
+        match chars.next() {
+            Some('"') => self.basic_string(),
+            Some('\'') => {
+                let end = chars.as_str().find('\'').ok_or("unclosed string")?;
+                let value = chars.as_str()[..end].to_owned();
+                self.rest = &chars.as_str()[end + 1..];
+                Ok(Value::String(value))
+            }
+            Some('[') => {
+                self.rest = chars.as_str();
+                let mut values = Vec::new();
+                loop {
+                    self.rest = self.rest.trim_start();
+                    if let Some(rest) = self.rest.strip_prefix(']') {
+                        self.rest = rest;
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.rest = self.rest.trim_start();
+                    if let Some(rest) = self.rest.strip_prefix(',') {
+                        self.rest = rest;
+                    } else if !self.rest.starts_with(']') {
+                        return Err("expected `,` or `]` in array".into());
+                    }
+                }
+            }
+            Some(_) => {
+                let end = self
+                    .rest
+                    .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+                    .unwrap_or(self.rest.len());
+                let token = &self.rest[..end];
+                self.rest = &self.rest[end..];
+                match token {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    token => token
+                        .replace('_', "")
+                        .parse()
+                        .map(Value::Integer)
+                        .map_err(|_| format!("unexpected `{}`, strings must be quoted", token)),
+                }
+            }
+            None => Err("missing value".into()),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<Value, String> {
+        let mut value = String::new();
+        let mut chars = self.rest[1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 2..];
+                    return Ok(Value::String(value));
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some(c) => return Err(format!("unknown escape `\\{}`", c)),
+                    None => break,
+                },
+                c => value.push(c),
+            }
+        }
+        Err("unclosed string".into())
+    }
+}
+
+impl Config {
+    /// Makes the config values the defaults of the command line options,
+    /// so that they are overridden by both the options and their environment variables.
+    pub fn apply(&self, mut command: clap::Command) -> clap::Command {
+        let defaults = [
+            ("adapter", &self.adapter),
+            ("timeout", &self.timeout),
+            ("connect_timeout", &self.connect_timeout),
+            ("io_timeout", &self.io_timeout),
+            ("wait_timeout", &self.wait_timeout),
+            ("color", &self.color),
+            ("off_means", &self.off_means),
+            ("write_mode", &self.write_mode),
+        ];
+        for (id, value) in defaults {
+            if let Some(value) = value {
+                command = default_value(command, id, value);
+            }
+        }
+        command
+    }
+}
+
+/// Sets the default of the option, wherever it is defined: on the command itself for the global
+/// options, or on each of the subcommands taking it, such as `--wait-timeout`.
+fn default_value(mut command: clap::Command, id: &str, value: &str) -> clap::Command {
+    if command.get_arguments().any(|arg| arg.get_id() == id) {
+        command = command.mut_arg(id, |arg| arg.default_value(value.to_owned()));
+    }
+    let subcommands: Vec<_> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_owned())
+        .collect();
+    subcommands.into_iter().fold(command, |command, name| {
+        command.mut_subcommand(name, |subcommand| default_value(subcommand, id, value))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tables_and_values() {
+        let document = parse(
+            r#"
+            # defaults
+            names = [
+                "LHB-4A3C91F2", # left
+                'LHB-93D0B1E4',
+            ]
+            timeout = 30
+            adapter = "hci#1"
+
+            [groups]
+            "room-a" = ["LHB-4A3C91F2"]
+            "#,
+        )
+        .unwrap();
+        let root = &document[""];
+        assert_eq!(root[0].key, "names");
+        assert_eq!(
+            root[0].value,
+            Value::Array(vec![
+                Value::String("LHB-4A3C91F2".into()),
+                Value::String("LHB-93D0B1E4".into()),
+            ])
+        );
+        assert_eq!(root[1].value, Value::Integer(30));
+        assert_eq!(root[2].value, Value::String("hci#1".into()));
+        assert_eq!(document["groups"][0].key, "room-a");
+        assert_eq!(document["groups"][0].line, 11);
+    }
+
+    #[test]
+    fn reports_offending_line() {
+        let error = parse("names = []\ntimeout = 30s\n").unwrap_err();
+        assert!(error.to_string().starts_with("line 2:"), "{}", error);
+        assert!(parse("[groups\n").is_err());
+        assert!(parse("a = 1\na = 2\n").is_err());
+    }
+
+    #[test]
+    fn reports_offending_key() {
+        let document = parse("names = []\ncolour = \"never\"\n").unwrap();
+        let error = Config::from_document(&document).err().unwrap();
+        assert_eq!(error.to_string(), "line 2: unknown key `colour`");
+    }
+
+    #[test]
+    fn reads_groups() {
+        let document = parse(
+            "[groups]
+room-a = [\"LHB-4A3C91F2\", \"LHB-93D0B1E4\"]
+",
+        )
+        .unwrap();
+        let config = Config::from_document(&document).unwrap();
+        assert_eq!(config.groups["room-a"], ["LHB-4A3C91F2", "LHB-93D0B1E4"]);
+        let document = parse(
+            "[groups]
+room-a = \"LHB-4A3C91F2\"
+",
+        )
+        .unwrap();
+        assert!(Config::from_document(&document).is_err());
+    }
+}
@@ -0,0 +1,878 @@
+//! Handles the base stations that a command is run on, as `lighthousectl` does: finds them,
+//! reads their power states and carries out the [`Action`] on each, telling an [`Observer`]
+//! what happened so that it can be shown.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use btleplug::api::{BDAddr, Central, Peripheral};
+use futures::{
+    future::LocalBoxFuture,
+    stream::{BoxStream, FuturesUnordered},
+    FutureExt, StreamExt,
+};
+use tokio::time::{timeout, timeout_at};
+
+use crate::{
+    attempt, connect_cached, discover, fold, guard, hex, identify, read_channel, read_info,
+    release, retry, scoped, write_channel, write_raw, Discovery, Error, Filter, Lighthouse,
+    Operation, Options, PowerCommand, PowerState, Protocol, StationError,
+};
+
+/// How often the power state is read while waiting with `--wait`.
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What to do with each matched base station, resolved from the command and its argument.
+#[derive(Clone, Copy)]
+pub enum Action {
+    Show,
+    Power(PowerCommand),
+    Toggle,
+    Identify,
+    Channel,
+    SetChannel(u8),
+    Raw(u8),
+    Read,
+    Inspect,
+    Info,
+    /// Show the power state, and fail unless it is the expected one.
+    Check(PowerCommand),
+    /// Put to sleep, and turn back on after the delay.
+    PowerCycle(Duration),
+}
+
+/// The outcome for a single base station.
+#[derive(Clone)]
+pub struct Record {
+    pub name: String,
+    /// The address as shown, which on macOS is the UUID of the peripheral.
+    pub address: String,
+    pub rssi: Option<i16>,
+    /// The byte read from the power characteristic, `None` for V1 base stations,
+    /// whose power state cannot be read.
+    pub raw_state: Option<u8>,
+    /// The state written by power commands.
+    pub requested_state: Option<PowerCommand>,
+    /// Whether the requested state was left unwritten because of `--dry-run`.
+    pub dry_run: bool,
+    /// Whether the base station was excluded from power commands with `--exclude`.
+    pub excluded: bool,
+    /// Why a power command left the base station alone, e.g. `booting, skipped`.
+    pub skipped: Option<&'static str>,
+    /// Whether the base station was made to blink by `identify`.
+    pub identified: bool,
+    /// The RF channel read by `channel` and `set-channel`.
+    pub channel: Option<u8>,
+    /// The RF channel written by `set-channel`.
+    pub requested_channel: Option<u8>,
+    /// The byte written by `raw`, and the byte read back afterwards.
+    pub raw_write: Option<(u8, u8)>,
+    /// Every byte of the power characteristic, kept by `read`.
+    pub bytes: Option<Vec<u8>>,
+    /// The Device Information Service strings read by `info`, `None` for absent characteristics.
+    pub info: Option<Vec<(&'static str, Option<String>)>>,
+    /// The power states seen so far by `scan --interval`.
+    pub history: Option<History>,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+}
+
+impl Record {
+    pub fn new<P: Peripheral>(lh: &Lighthouse<P>) -> Self {
+        Self {
+            name: lh.name.clone(),
+            address: lh.shown_address(),
+            rssi: lh.rssi,
+            raw_state: None,
+            requested_state: None,
+            dry_run: false,
+            excluded: false,
+            skipped: None,
+            identified: false,
+            channel: None,
+            requested_channel: None,
+            raw_write: None,
+            bytes: None,
+            info: None,
+            history: None,
+            first_seen: lh.first_seen,
+            last_seen: lh.last_seen,
+        }
+    }
+
+    pub fn state(&self) -> Option<PowerState> {
+        self.raw_state.map(PowerState::from)
+    }
+
+    /// The state shown by the human-readable outputs, with the RF channel if it was read.
+    pub fn describe_state(&self, color: bool) -> String {
+        let state = match self.state() {
+            Some(state) => state.painted(color).to_string(),
+            None => "n/a".to_owned(),
+        };
+        match (self.channel, self.requested_channel) {
+            (Some(channel), Some(requested_channel)) => {
+                format!("channel {} -> {}", channel, requested_channel)
+            }
+            (Some(channel), None) => format!("{}, channel {}", state, channel),
+            _ => match self.raw_write {
+                Some((written, read)) => format!(
+                    "{} (0x{:02x}) -> wrote 0x{:02x}, read back {} (0x{:02x})",
+                    state,
+                    self.raw_state.unwrap_or_default(),
+                    written,
+                    PowerState::from(read).painted(color),
+                    read
+                ),
+                None => match &self.bytes {
+                    Some(bytes) => format!("{} [{}]", state, hex(bytes)),
+                    None => state,
+                },
+            },
+        }
+    }
+
+    /// A remark shown after the state in the human-readable outputs.
+    pub fn note(&self) -> Option<&'static str> {
+        if self.excluded {
+            Some("excluded")
+        } else if self.identified {
+            Some("identified")
+        } else {
+            self.skipped
+        }
+    }
+}
+
+/// The power states of a base station over a long-running session, to tell how long it has been
+/// in its state, such as stuck booting.
+#[derive(Clone, Copy)]
+pub struct History {
+    pub state: PowerState,
+    /// When the base station entered the state, or was first seen in it.
+    pub since: SystemTime,
+    /// How many times the state changed this session.
+    pub transitions: usize,
+    pub last_seen: SystemTime,
+}
+
+impl History {
+    pub fn new(state: PowerState) -> Self {
+        let now = SystemTime::now();
+        Self {
+            state,
+            since: now,
+            transitions: 0,
+            last_seen: now,
+        }
+    }
+
+    /// Records the state just read, returning how long the previous one lasted if it changed.
+    pub fn observe(&mut self, state: PowerState) -> Option<Duration> {
+        let now = SystemTime::now();
+        self.last_seen = now;
+        if state == self.state {
+            return None;
+        }
+        let lasted = now.duration_since(self.since).unwrap_or_default();
+        self.state = state;
+        self.since = now;
+        self.transitions += 1;
+        Some(lasted)
+    }
+
+    /// Records the state in the history of the base station at `address`, starting it if there
+    /// is none yet, and returns the history with how long the previous state lasted.
+    pub fn observe_in(
+        histories: &Mutex<BTreeMap<BDAddr, History>>,
+        address: BDAddr,
+        state: PowerState,
+    ) -> (History, Option<Duration>) {
+        let mut histories = histories.lock().unwrap();
+        let history = histories
+            .entry(address)
+            .or_insert_with(|| History::new(state));
+        let lasted = history.observe(state);
+        (*history, lasted)
+    }
+
+    /// How long the base station has been in its state, such as `for 4m 3s, 2 changes`.
+    pub fn describe(&self) -> String {
+        let elapsed = self
+            .last_seen
+            .duration_since(self.since)
+            .unwrap_or_default();
+        let elapsed = Duration::from_secs(elapsed.as_secs());
+        format!(
+            "for {}, {}",
+            humantime::format_duration(elapsed),
+            self.changes()
+        )
+    }
+
+    /// How many times the state changed, such as `2 changes`.
+    pub fn changes(&self) -> String {
+        match self.transitions {
+            1 => "1 change".to_owned(),
+            transitions => format!("{} changes", transitions),
+        }
+    }
+}
+
+/// Shows what happens to the base stations while they are handled, such as by printing it.
+/// Only the results and the failures have to be taken, the rest is shown if wanted.
+pub trait Observer {
+    /// How often [`Observer::progress`] is called while discovering, if progress is shown.
+    fn progress_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Shows how many base stations the filter still waits for.
+    fn progress(&self, _filter: &Filter, _options: &Options) {}
+
+    /// Clears the progress shown, before anything else is shown over it.
+    fn clear_progress(&self) {}
+
+    /// Called for every base station discovered, asked for or not.
+    fn discovered<P: Peripheral>(&mut self, _lh: &Lighthouse<P>) {}
+
+    /// Called instead of reading the power state with [`Action::Inspect`].
+    fn inspected<P: Peripheral>(&mut self, _lh: &Lighthouse<P>) {}
+
+    /// Called once the power state has been read.
+    /// For power commands, the record is reported again by [`Observer::written`].
+    fn read(&mut self, record: &Record);
+
+    /// Called once the requested state has been written, or left unwritten with `--dry-run`.
+    fn written(&mut self, record: Record);
+
+    /// Called for each state that a base station passes through with `--wait`.
+    fn changed<P: Peripheral>(
+        &mut self,
+        _lh: &Lighthouse<P>,
+        _previous: PowerState,
+        _state: PowerState,
+    ) {
+    }
+
+    /// Called for each base station asked for that failed.
+    fn failed(&mut self, error: StationError);
+
+    /// Called for a base station found once `--count` was reached, which is left alone.
+    fn ignored<P: Peripheral>(&mut self, _lh: &Lighthouse<P>) {}
+
+    /// Called by [`scan_planned`] with the base stations asked for that were not found, before
+    /// anything is handled.
+    fn not_found(&mut self, _names: &[String]) {}
+
+    /// Called by [`scan_planned`] when nothing is handled, since not every base station was
+    /// found with `require_all`.
+    fn held_back(&mut self) {}
+
+    /// Waits for `after` before [`scan_planned`] handles the `count` base stations found, which
+    /// may show the time left.
+    fn scheduled(
+        &self,
+        _command: &str,
+        _count: usize,
+        after: Duration,
+    ) -> LocalBoxFuture<'static, ()> {
+        tokio::time::sleep(after).boxed_local()
+    }
+}
+
+/// The observer of a scan, along with whether a base station failed, for `--fail-fast`.
+struct Handling<'a, O> {
+    observer: &'a RefCell<O>,
+    failed: Cell<bool>,
+}
+
+impl<'a, O: Observer> Handling<'a, O> {
+    fn new(observer: &'a RefCell<O>) -> Self {
+        Self {
+            observer,
+            failed: Cell::new(false),
+        }
+    }
+
+    fn failed(&self, error: StationError) {
+        self.failed.set(true);
+        self.observer.borrow_mut().failed(error);
+    }
+
+    /// Stops at the first failure with `--fail-fast`.
+    fn check_fail_fast(&self, options: &Options) -> Result<(), Error> {
+        match options.fail_fast && self.failed.get() {
+            true => Err(Error::FailFast),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Handles the base stations as they are discovered, trying the cached ones first, until the
+/// filter is completed or the deadline passes. Returns the names asked for that were not found.
+pub async fn scan<O: Observer>(
+    central: &impl Central,
+    action: Action,
+    filter: &mut Filter,
+    options: &Options,
+    observer: &RefCell<O>,
+) -> Result<Vec<String>, Error> {
+    let handling = Handling::new(observer);
+    let unnamed = filter.addresses();
+    let inspect = matches!(action, Action::Inspect);
+    let handled = Mutex::new(BTreeSet::new());
+    let mut result = Ok(());
+    if let Some(stream) = connect_cached(central, filter, options, &handled, inspect).await {
+        result = control_all(stream, action, filter, options, &handling).await;
+    }
+    if result.is_ok() && !filter.is_completed() {
+        let stream = discover(central, options, &unnamed, &handled, inspect).await?;
+        result = control_all(stream, action, filter, options, &handling).await;
+        if result.is_ok() {
+            if let Err(error) = central.stop_scan().await {
+                // BlueZ keeps discovering until told otherwise, even after the process exits.
+                log::warn!("failed to stop scanning: {}", error);
+            }
+        }
+    }
+    // Before the error, or what the retries and the summary print.
+    observer.borrow().clear_progress();
+    if result.is_err() {
+        // Base stations being handled were dropped while still connected.
+        release(central, options.io_timeout).await;
+    }
+    result?;
+    Ok(filter.remaining())
+}
+
+/// How the base stations are handled once all of them have been found, with `--after` or
+/// `--delay-between`.
+pub struct Plan<'a> {
+    /// The command, to tell what is scheduled.
+    pub command: &'a str,
+    pub after: Option<Duration>,
+    /// Whether the base stations are handled one at a time, in the order of their names.
+    pub one_by_one: bool,
+    /// Whether they are handled one at a time in the order given instead, with `--ordered`.
+    pub ordered: bool,
+    /// Whether nothing is handled unless every base station was found, with `--require-all`.
+    pub require_all: bool,
+}
+
+/// Finds the base stations first, so that the missing ones are reported before anything is
+/// written, then handles them as planned, connecting to each again. Returns the names asked for
+/// that were not found.
+pub async fn scan_planned<C: Central, O: Observer>(
+    central: &C,
+    action: Action,
+    filter: &mut Filter,
+    options: &mut Options,
+    observer: &RefCell<O>,
+    plan: Plan<'_>,
+) -> Result<Vec<String>, Error> {
+    let handling = Handling::new(observer);
+    let mut probe = filter.clone();
+    let handled = Mutex::new(BTreeSet::new());
+    let mut found = Vec::new();
+    if let Some(stream) = connect_cached(central, &probe, options, &handled, false).await {
+        gather(stream, &mut probe, options, &handling, &mut found).await?;
+    }
+    if !probe.is_completed() {
+        let unnamed = probe.addresses();
+        let stream = discover(central, options, &unnamed, &handled, false).await?;
+        let gathered = gather(stream, &mut probe, options, &handling, &mut found).await;
+        release(central, options.io_timeout).await;
+        gathered?;
+    }
+    observer.borrow().clear_progress();
+    let missing = probe.remaining();
+    if !missing.is_empty() {
+        observer.borrow_mut().not_found(&missing);
+    }
+    let incomplete = !missing.is_empty() || handling.failed.get();
+    if plan.require_all && incomplete {
+        observer.borrow_mut().held_back();
+        return Ok(missing);
+    }
+    if found.is_empty() {
+        return Ok(missing);
+    }
+    if let Some(after) = plan.after {
+        let waiting = observer
+            .borrow()
+            .scheduled(plan.command, found.len(), after);
+        waiting.await;
+    }
+    let concurrency = options.concurrency;
+    if plan.one_by_one || plan.ordered {
+        found.sort_by_key(|lh| {
+            let position = plan.ordered.then(|| filter.position(&lh.name, lh.address));
+            (position, fold(&lh.name))
+        });
+        options.concurrency = 1;
+    }
+    // Only the base stations found are handled, connecting to them directly.
+    let deadline = options.deadline.take();
+    let controlled = {
+        let options = &*options;
+        let reconnecting = futures::stream::iter(found).then(|mut lh| async move {
+            let connection = options.connections.clone().acquire_owned().await;
+            let connecting = || {
+                attempt(
+                    Operation::Connect,
+                    options.connect_timeout,
+                    lh.peripheral.connect(),
+                )
+            };
+            let connected = retry(&lh.name, options, connecting).await;
+            match connected {
+                Ok(()) => {
+                    lh.connection = connection.ok();
+                    Ok(lh)
+                }
+                Err(error) => Err(StationError::at(
+                    &lh.name,
+                    &lh.peripheral,
+                    lh.address,
+                    error,
+                )),
+            }
+        });
+        control_all(reconnecting.boxed(), action, filter, options, &handling).await
+    };
+    options.concurrency = concurrency;
+    options.deadline = deadline;
+    controlled?;
+    Ok(filter.remaining())
+}
+
+/// Collects the matched base stations of a stream until the filter is completed or the deadline
+/// passes, disconnecting from them. Discovery errors are reported right away.
+async fn gather<P: Peripheral, O: Observer>(
+    mut stream: BoxStream<'_, Discovery<P>>,
+    filter: &mut Filter,
+    options: &Options,
+    handling: &Handling<'_, O>,
+    found: &mut Vec<Lighthouse<P>>,
+) -> Result<(), Error> {
+    let observer = handling.observer;
+    let interval = observer.borrow().progress_interval();
+    // Never ticking without progress to show, whatever the period.
+    let mut ticks = tokio::time::interval(interval.unwrap_or(WAIT_INTERVAL));
+    while !filter.is_completed() {
+        let next = async {
+            match options.deadline {
+                Some(deadline) => timeout_at(deadline, stream.next()).await.ok().flatten(),
+                None => stream.next().await,
+            }
+        };
+        tokio::pin!(next);
+        let next = loop {
+            tokio::select! {
+                next = &mut next => break next,
+                _ = ticks.tick(), if interval.is_some() => observer.borrow().progress(filter, options),
+            }
+        };
+        match next {
+            Some(Ok(mut lh)) => {
+                lh.disconnect().await.ok();
+                observer.borrow_mut().discovered(&lh);
+                filter.check_ambiguity(&lh.name)?;
+                let known = found.iter().any(|other| other.address == lh.address);
+                if !known && filter.is_matched(&lh.name, lh.address) {
+                    filter.claim(&lh.name, lh.address)?;
+                }
+                if !known && filter.is_matched(&lh.name, lh.address) && filter.take(lh.address) {
+                    filter.complete(&lh.name, lh.address);
+                    found.push(lh);
+                }
+            }
+            Some(Err(error)) => {
+                let address = error.address.unwrap_or_default();
+                if filter.is_matched(&error.name, address) && filter.take(address) {
+                    filter.complete(&error.name, address);
+                    handling.failed(error);
+                }
+            }
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Handles the discovered base stations until all of them are found or the deadline passes.
+/// Up to `--concurrency` base stations are handled at once, while discovery goes on.
+async fn control_all<P: Peripheral, O: Observer>(
+    mut stream: BoxStream<'_, Discovery<P>>,
+    action: Action,
+    filter: &mut Filter,
+    options: &Options,
+    handling: &Handling<'_, O>,
+) -> Result<(), Error> {
+    let observer = handling.observer;
+    let inspect = matches!(action, Action::Inspect);
+    let writes = matches!(
+        action,
+        Action::Power(_) | Action::Toggle | Action::PowerCycle(_)
+    );
+    let mut controlling = FuturesUnordered::new();
+    let mut busy = BTreeSet::new();
+    let mut discovering = !filter.is_completed();
+    let interval = observer.borrow().progress_interval();
+    // Never ticking without progress to show, whatever the period.
+    let mut ticks = tokio::time::interval(interval.unwrap_or(WAIT_INTERVAL));
+    while discovering || !controlling.is_empty() {
+        handling.check_fail_fast(options)?;
+        let next = async {
+            match options.deadline {
+                Some(deadline) => timeout_at(deadline, stream.next()).await.ok().flatten(),
+                None => stream.next().await,
+            }
+        };
+        tokio::select! {
+            _ = ticks.tick(), if interval.is_some() && discovering => {
+                observer.borrow().progress(filter, options);
+            }
+            Some((lh, controlled)) = controlling.next() => {
+                let lh: Lighthouse<P> = lh;
+                if let Err(error) = controlled {
+                    handling.failed(StationError::at(&lh.name, &lh.peripheral, lh.address, error));
+                }
+                busy.remove(&lh.address);
+                // Failed base stations are done for this pass too, `--retries` tries them again.
+                filter.complete(&lh.name, lh.address);
+                discovering &= !filter.is_completed();
+            }
+            next = next, if discovering && controlling.len() < options.concurrency => {
+                let mut lh = match next {
+                    Some(Ok(lh)) => lh,
+                    Some(Err(error)) => {
+                        // Failures of base stations that were not asked for do not count.
+                        let address = error.address.unwrap_or_default();
+                        if filter.is_matched(&error.name, address) && filter.take(address) {
+                            filter.complete(&error.name, address);
+                            handling.failed(error);
+                        }
+                        discovering &= !filter.is_completed();
+                        continue;
+                    }
+                    None => {
+                        discovering = false;
+                        continue;
+                    }
+                };
+                observer.borrow_mut().discovered(&lh);
+                filter.check_ambiguity(&lh.name)?;
+                if !filter.is_matched(&lh.name, lh.address) || busy.contains(&lh.address) {
+                    lh.disconnect().await.ok();
+                    continue;
+                }
+                if let Err(error) = filter.claim(&lh.name, lh.address) {
+                    if writes {
+                        lh.disconnect().await.ok();
+                        return Err(error);
+                    }
+                    // Listed all the same, either one may be the one meant.
+                    log::warn!("{}", error);
+                }
+                if !filter.take(lh.address) {
+                    observer.borrow_mut().ignored(&lh);
+                    lh.disconnect().await.ok();
+                    continue;
+                }
+                let excluded = filter.is_excluded(&lh.name, lh.address);
+                busy.insert(lh.address);
+                controlling.push(async move {
+                    let (name, address) = (lh.name.clone(), lh.address);
+                    let controlling = async {
+                        let controlled = match lh.protocol {
+                            _ if inspect => {
+                                observer.borrow_mut().inspected(&lh);
+                                Ok(())
+                            }
+                            Protocol::V1 => control_v1(&lh, action, excluded, options, observer).await,
+                            Protocol::V2 => control(&lh, action, excluded, options, observer).await,
+                        };
+                        // Only now, since BlueZ does not reconnect for reads and writes by itself.
+                        if let Err(error) = lh.disconnect().await {
+                            log::warn!(error:? = error; "{}: disconnect failed: {}", lh.name, error);
+                        }
+                        controlled
+                    };
+                    let controlled = scoped(name, address, controlling).await;
+                    (lh, controlled)
+                });
+            }
+        }
+    }
+    handling.check_fail_fast(options)
+}
+
+/// Reads the power state of a V2 base station and carries out the action on it.
+async fn control<P: Peripheral, O: Observer>(
+    lh: &Lighthouse<P>,
+    action: Action,
+    excluded: bool,
+    options: &Options,
+    observer: &RefCell<O>,
+) -> Result<(), Error> {
+    let reading = || lh.read_power_bytes(Operation::Read, options.io_timeout);
+    let bytes = retry(&lh.name, options, reading).await?;
+    let raw_state = *bytes.first().ok_or(Error::NoData {
+        operation: Operation::Read,
+    })?;
+    PowerState::check(raw_state, &lh.name, options);
+    let mut skipped = None;
+    let requested_state = match action {
+        _ if excluded => None,
+        Action::Power(state) => Some(state),
+        Action::Toggle => match PowerState::from(raw_state) {
+            PowerState::Sleep | PowerState::Standby => Some(PowerCommand::On),
+            PowerState::On => Some(PowerCommand::Sleep),
+            PowerState::Booting => {
+                skipped = Some("booting, skipped");
+                None
+            }
+            PowerState::Stopping => {
+                skipped = Some("stopping, skipped");
+                None
+            }
+            PowerState::Unknown(_) => None,
+        },
+        Action::PowerCycle(_) => Some(PowerCommand::Sleep),
+        Action::Show
+        | Action::Identify
+        | Action::Channel
+        | Action::SetChannel(_)
+        | Action::Raw(_)
+        | Action::Read
+        | Action::Inspect
+        | Action::Info
+        | Action::Check(_) => None,
+    };
+    let mut record = Record {
+        raw_state: Some(raw_state),
+        requested_state,
+        dry_run: options.dry_run && requested_state.is_some(),
+        excluded,
+        skipped,
+        bytes: matches!(action, Action::Read).then_some(bytes),
+        ..Record::new(lh)
+    };
+    if let Action::Info = action {
+        record.info = Some(read_info(lh, options.io_timeout).await?);
+    }
+    if let Action::Identify = action {
+        identify(lh, options).await?;
+        record.identified = true;
+    }
+    if let Action::Channel | Action::SetChannel(_) = action {
+        let channel = read_channel(lh, options.io_timeout).await?;
+        options.cache.set_channel(&lh.name, channel);
+        record.channel = Some(channel);
+    }
+    if let Action::SetChannel(channel) = action {
+        write_channel(lh, channel, options.io_timeout).await?;
+        options.cache.set_channel(&lh.name, channel);
+        record.requested_channel = Some(channel);
+    }
+    if let Action::Raw(byte) = action {
+        let read = write_raw(lh, byte, options.io_timeout).await?;
+        record.raw_write = Some((byte, read));
+    }
+    observer.borrow_mut().read(&record);
+    if let (Action::Check(expected), false) = (action, excluded) {
+        let state = PowerState::from(raw_state);
+        let expected = PowerState::from(expected);
+        if state != expected {
+            return Err(Error::Unexpected { state, expected });
+        }
+    }
+    let mut next_state = guard!(requested_state, return Ok(()));
+    options.pace().await;
+    lh.set_power(next_state, options).await?;
+    observer.borrow_mut().written(record.clone());
+    let mut raw_state = raw_state;
+    if let Action::PowerCycle(delay) = action {
+        // Already asleep, the rotor has nothing to spin down.
+        if !options.dry_run && PowerState::from(raw_state) != PowerState::Sleep {
+            tokio::time::sleep(delay).await;
+        }
+        raw_state = next_state.into();
+        next_state = PowerCommand::On;
+        lh.set_power(PowerCommand::On, options)
+            .await
+            .map_err(|error| Error::NotTurnedBackOn(Box::new(error)))?;
+        observer.borrow_mut().written(Record {
+            raw_state: Some(raw_state),
+            requested_state: Some(next_state),
+            ..record
+        });
+    }
+    if let (Some(wait_timeout), false) = (options.wait_timeout, options.dry_run) {
+        let target = PowerState::from(next_state);
+        let last = Cell::new(None);
+        let waiting = wait_for(lh, raw_state, target, options.io_timeout, observer, &last);
+        timeout(wait_timeout, waiting)
+            .await
+            .map_err(|_| Error::NotReached {
+                state: target,
+                within: wait_timeout,
+                last: last.get(),
+            })?;
+    }
+    Ok(())
+}
+
+/// Polls the power state until the base station reports `target`, reporting each change and
+/// keeping the `last` state read. Failed reads are tried again, since a booting base station
+/// may not answer for a while.
+async fn wait_for<P: Peripheral, O: Observer>(
+    lh: &Lighthouse<P>,
+    mut raw_state: u8,
+    target: PowerState,
+    io_timeout: Duration,
+    observer: &RefCell<O>,
+    last: &Cell<Option<PowerState>>,
+) {
+    loop {
+        tokio::time::sleep(WAIT_INTERVAL).await;
+        let next = match lh.read_power(io_timeout).await {
+            Ok(next) => next,
+            Err(error) => {
+                log::info!("{}: {}, still waiting", lh.name, error);
+                continue;
+            }
+        };
+        last.set(Some(next.into()));
+        if next != raw_state {
+            observer
+                .borrow_mut()
+                .changed(lh, raw_state.into(), next.into());
+            raw_state = next;
+        }
+        if PowerState::from(raw_state) == target {
+            return;
+        }
+    }
+}
+
+/// V1 base stations only support showing and turning on or to sleep,
+/// and their power state cannot be read.
+async fn control_v1<P: Peripheral, O: Observer>(
+    lh: &Lighthouse<P>,
+    action: Action,
+    excluded: bool,
+    options: &Options,
+    observer: &RefCell<O>,
+) -> Result<(), Error> {
+    let requested_state = match action {
+        _ if excluded => None,
+        Action::Show => None,
+        Action::Power(command @ (PowerCommand::On | PowerCommand::Sleep)) => Some(command),
+        Action::Power(PowerCommand::Standby) => {
+            return Err(Error::Unsupported(
+                "standby is unsupported on V1, use sleep instead",
+            ));
+        }
+        _ => {
+            return Err(Error::Unsupported(
+                "only scan, on, sleep and off are supported on V1",
+            ))
+        }
+    };
+    let record = Record {
+        requested_state,
+        dry_run: options.dry_run && requested_state.is_some(),
+        excluded,
+        ..Record::new(lh)
+    };
+    observer.borrow_mut().read(&record);
+    if let Some(state) = requested_state {
+        options.pace().await;
+        lh.set_power(state, options).await?;
+        observer.borrow_mut().written(record);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockCentral, MockPeripheral};
+
+    /// Keeps what it is told, for the tests to look at.
+    #[derive(Default)]
+    struct Kept {
+        written: Vec<Record>,
+        failed: Vec<StationError>,
+    }
+
+    impl Observer for Kept {
+        fn read(&mut self, _record: &Record) {}
+
+        fn written(&mut self, record: Record) {
+            self.written.push(record);
+        }
+
+        fn failed(&mut self, error: StationError) {
+            self.failed.push(error);
+        }
+    }
+
+    #[test]
+    fn histories_count_only_the_changes() {
+        let mut history = History::new(PowerState::Booting);
+        assert_eq!(history.observe(PowerState::Booting), None);
+        assert!(history.observe(PowerState::On).is_some());
+        assert_eq!(history.state, PowerState::On);
+        assert_eq!(history.describe(), "for 0s, 1 change");
+    }
+
+    #[tokio::test]
+    async fn scans_end_once_the_named_base_stations_are_handled() {
+        let central = MockCentral::new(vec![
+            MockPeripheral::base_station(1, "LHB-4A3C91F2", 0x00),
+            MockPeripheral::base_station(2, "LHB-93D0B1E4", 0x00),
+        ])
+        .scanning_forever();
+        let mut filter = Filter::new(vec!["LHB-4A3C91F2".into()], false, Vec::new());
+        let options = Options::default();
+        let kept = RefCell::new(Kept::default());
+        let action = Action::Power(PowerCommand::On);
+        let scanning = scan(&central, action, &mut filter, &options, &kept);
+        let missing = timeout(Duration::from_secs(10), scanning)
+            .await
+            .expect("the scan ends")
+            .unwrap();
+        assert!(missing.is_empty());
+        assert_eq!(central.peripherals[0].writes(), [[0x01]]);
+        assert!(central.peripherals[1].writes().is_empty());
+        let kept = kept.into_inner();
+        assert_eq!(kept.written[0].name, "LHB-4A3C91F2");
+        assert!(kept.failed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fail_fast_stops_at_the_first_failure() {
+        let central = MockCentral::new(vec![MockPeripheral::base_station(1, "LHB-4A3C91F2", 0x00)]);
+        let mut filter = Filter::new(vec!["LHB-4A3C91F2".into()], false, Vec::new());
+        let options = Options {
+            fail_fast: true,
+            ..Options::default()
+        };
+        let kept = RefCell::new(Kept::default());
+        // The base station is asleep, so that checking for on fails.
+        let action = Action::Check(PowerCommand::On);
+        let error = scan(&central, action, &mut filter, &options, &kept)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::FailFast));
+        assert_eq!(kept.into_inner().failed[0].cause.kind(), "unexpected_state");
+    }
+}
@@ -0,0 +1,347 @@
+//! `daemon` mode: keeps track of the base stations and takes commands from `--daemon` clients
+//! over a Unix socket, which then skip scanning and service discovery.
+//!
+//! A client sends one line, `<command> [<name>...]`, where the command is `status`, `on`,
+//! `sleep` or `standby`. The daemon replies with a line per base station, `<name>\t<state>` or
+//! `<name>\terror\t<kind>\t<message>` with the kind of [`crate::Error::kind`], and closes the
+//! connection.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    env, fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Mutex,
+};
+
+use anyhow::{bail, Context, Result};
+use btleplug::{
+    api::{Central, Peripheral},
+    platform::PeripheralId,
+};
+use futures::{
+    future::{self, LocalBoxFuture},
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::oneshot,
+};
+
+use crate::{
+    discover, fold, release,
+    stations::{self, Station, Stations, REFRESH_INTERVAL},
+    supervisor::Supervisor,
+    Filter, Options, Outcome, PowerCommand, PowerState,
+};
+
+/// `$XDG_RUNTIME_DIR/lighthousectl.sock`, or a per-user socket in the temporary directory.
+pub fn default_socket() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("lighthousectl.sock"),
+        // SAFETY: `getuid` always succeeds.
+        None => env::temp_dir().join(format!("lighthousectl-{}.sock", unsafe { libc::getuid() })),
+    }
+}
+
+/// Runs until SIGTERM or Ctrl-C, then disconnects from every base station.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &Filter,
+    options: &Options,
+    path: &Path,
+    supervisor: &mut Supervisor,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let listener = bind(path).await?;
+    log::info!("listening on {}", path.display());
+    let stations: Stations<C::Peripheral> = RefCell::new(BTreeMap::new());
+    let handled = Mutex::new(BTreeSet::new());
+    let unnamed = filter.addresses();
+    let mut discoveries = discover(central, options, &unnamed, &handled, false).await?;
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+    let mut tasks: FuturesUnordered<LocalBoxFuture<()>> = FuturesUnordered::new();
+    let mut signals = signals;
+    let result = loop {
+        tokio::select! {
+            discovery = discoveries.next() => match discovery {
+                Some(Ok(lh)) => if let Some(station) = stations::track(lh, filter, "daemon").await {
+                    stations.borrow_mut().insert(fold(&station.lh.name), station.clone());
+                    supervisor.found(&station.lh.name, station.lh.address, stations.borrow().len());
+                    tasks.push(refresh_station(station, &stations, &handled, options).boxed_local());
+                },
+                Some(Err(error)) => log::warn!("{}: {}", error.name, error.cause),
+                None => break Err(anyhow::anyhow!("the adapter stopped reporting base stations")),
+            },
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => tasks.push(serve(stream, &stations, options).boxed_local()),
+                Err(error) => log::warn!("accepting a client failed: {}", error),
+            },
+            _ = refresh.tick() => {
+                for station in stations.borrow().values() {
+                    tasks.push(refresh_station(station.clone(), &stations, &handled, options).boxed_local());
+                }
+            }
+            _ = supervisor.due() => {
+                let count = stations.borrow().len();
+                supervisor.check(central, count, options.io_timeout).await;
+            }
+            Some(()) = tasks.next(), if !tasks.is_empty() => {}
+            Ok(signal) = &mut signals => {
+                log::info!("stopping on signal {}", signal);
+                break Ok(());
+            }
+        }
+    };
+    drop(tasks);
+    let tracked: Vec<_> = stations.borrow().values().cloned().collect();
+    supervisor
+        .stop(tracked.iter().map(|station| &station.lh), options)
+        .await;
+    release(central, options.io_timeout).await;
+    fs::remove_file(path).ok();
+    result
+}
+
+/// Binds the socket, replacing a stale one left by a daemon that did not exit cleanly.
+async fn bind(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            bail!("a daemon is already running at {}", path.display());
+        }
+        fs::remove_file(path)
+            .with_context(|| format!("failed to remove the stale socket {}", path.display()))?;
+    }
+    // Anyone who can connect can control the base stations, so the socket is created private
+    // rather than made so after it is bound.
+    // SAFETY: `umask` always succeeds.
+    let umask = unsafe { libc::umask(0o077) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.with_context(|| format!("failed to listen on {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+async fn refresh_station<P: Peripheral>(
+    station: Rc<Station<P>>,
+    stations: &Stations<P>,
+    handled: &Mutex<BTreeSet<PeripheralId>>,
+    options: &Options,
+) {
+    stations::refresh(&station, stations, handled, options).await;
+}
+
+async fn serve<P: Peripheral>(stream: UnixStream, stations: &Stations<P>, options: &Options) {
+    if let Err(error) = respond(stream, stations, options).await {
+        log::warn!("serving a client failed: {}", error);
+    }
+}
+
+async fn respond<P: Peripheral>(
+    stream: UnixStream,
+    stations: &Stations<P>,
+    options: &Options,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut request = String::new();
+    BufReader::new(reader).read_line(&mut request).await?;
+    let mut words = request.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let state = match command {
+        "status" => None,
+        "on" => Some(PowerCommand::On),
+        "sleep" => Some(PowerCommand::Sleep),
+        "standby" => Some(PowerCommand::Standby),
+        _ => {
+            let reply = format!("\terror\tunknown_command\tunknown command `{}`\n", command);
+            return writer.write_all(reply.as_bytes()).await;
+        }
+    };
+    let names: Vec<_> = words.collect();
+    let selected: Vec<_> = if names.is_empty() {
+        let all = stations.borrow().values().cloned().collect::<Vec<_>>();
+        all.into_iter()
+            .map(|station| (station.lh.name.clone(), Ok(station)))
+            .collect()
+    } else {
+        let find = |name: &str| stations::find(stations, name);
+        names
+            .iter()
+            .map(|name| (name.to_string(), find(name)))
+            .collect()
+    };
+    // Base stations are controlled at once, each in a session of its own, which `--concurrency`
+    // bounds.
+    let replies = future::join_all(selected.iter().map(|(name, station)| async move {
+        let station = match station {
+            Ok(station) => station,
+            Err(error) => return format!("{}\terror\t{}\t{}\n", name, error.kind(), error),
+        };
+        let result = match state {
+            Some(state) => station.write(state, options).await,
+            None => match station.raw_state.get() {
+                Some(_) => Ok(()),
+                None => station.read(options).await.map(drop),
+            },
+        };
+        match (result, station.raw_state.get()) {
+            (Ok(()), Some(raw_state)) => format!("{}\t{}\n", name, PowerState::from(raw_state)),
+            (Ok(()), None) => format!("{}\terror\tno_data\tpower state unknown\n", name),
+            (Err(error), _) => format!("{}\terror\t{}\t{}\n", name, error.kind(), error),
+        }
+    }))
+    .await;
+    for reply in replies {
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Sends a command to the daemon and prints its replies like `scan` does.
+pub async fn request(path: &Path, command: &str, names: &[String]) -> Result<()> {
+    let mut stream = UnixStream::connect(path).await.with_context(|| {
+        format!(
+            "cannot reach the daemon at {}, is `lighthousectl daemon` running?",
+            path.display()
+        )
+    })?;
+    let mut request = command.to_owned();
+    for name in names {
+        request.push(' ');
+        request.push_str(name);
+    }
+    request.push('\n');
+    stream.write_all(request.as_bytes()).await?;
+    let mut lines = BufReader::new(stream).lines();
+    let mut missing = Vec::new();
+    let mut failed = 0;
+    while let Some(line) = lines.next_line().await? {
+        let mut fields = line.splitn(4, '\t');
+        match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(""), Some("error"), Some(_), Some(message)) => bail!("daemon: {}", message),
+            // Listed by the outcome, as when scanning.
+            (Some(name), Some("error"), Some("not_found"), Some(_)) => {
+                missing.push(name.to_owned())
+            }
+            (Some(name), Some("error"), Some(_), Some(message)) => {
+                eprintln!("{}: {}", name, message);
+                failed += 1;
+            }
+            (Some(name), Some(state), None, None) => println!("{} {}", name, state),
+            _ => bail!("unexpected reply from the daemon: {}", line),
+        }
+    }
+    Outcome {
+        missing,
+        failed,
+        timed_out: 0,
+    }
+    .into_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::mock::{MockCentral, MockPeripheral};
+
+    #[tokio::test]
+    async fn replies_a_line_per_base_station() {
+        let central = MockCentral::new(vec![MockPeripheral::base_station(1, "LHB-4A3C91F2", 0x00)]);
+        let options = Options::default();
+        let unnamed = BTreeSet::new();
+        let handled = Mutex::new(BTreeSet::new());
+        let mut discoveries = discover(&central, &options, &unnamed, &handled, false)
+            .await
+            .unwrap();
+        let Some(Ok(lh)) = discoveries.next().await else {
+            panic!("the base station was not discovered");
+        };
+        let filter = Filter::new(Vec::new(), false, Vec::new());
+        let station = stations::track(lh, &filter, "daemon").await.unwrap();
+        let stations = RefCell::new(BTreeMap::from([(fold(&station.lh.name), station)]));
+        let exchange = |request: &'static str| {
+            let (stations, options) = (&stations, &options);
+            async move {
+                let (server, mut client) = UnixStream::pair().unwrap();
+                client.write_all(request.as_bytes()).await.unwrap();
+                let mut reply = String::new();
+                let reading = client.read_to_string(&mut reply);
+                let (responded, read) = tokio::join!(respond(server, stations, options), reading);
+                responded.unwrap();
+                read.unwrap();
+                reply
+            }
+        };
+
+        assert_eq!(
+            exchange("status lhb-4a3c91f2 LHB-93D0B1E4\n").await,
+            "lhb-4a3c91f2\tSLEEP\nLHB-93D0B1E4\terror\tnot_found\tLHB-93D0B1E4 was not found\n"
+        );
+        assert_eq!(exchange("on\n").await, "LHB-4A3C91F2\tBOOTING\n");
+        assert_eq!(central.peripherals[0].writes(), [vec![0x01]]);
+        assert_eq!(
+            exchange("off LHB-4A3C91F2\n").await,
+            "\terror\tunknown_command\tunknown command `off`\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_replies_by_their_outcome() {
+        let dir = env::temp_dir().join(format!("lighthousectl-daemon-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("socket");
+        let listener = UnixListener::bind(&path).unwrap();
+        let daemon = |reply: &'static str| {
+            let listener = &listener;
+            async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = String::new();
+                let (reader, mut writer) = stream.split();
+                BufReader::new(reader)
+                    .read_line(&mut request)
+                    .await
+                    .unwrap();
+                writer.write_all(reply.as_bytes()).await.unwrap();
+                request
+            }
+        };
+        let names = ["LHB-4A3C91F2".to_owned(), "LHB-93D0B1E4".to_owned()];
+
+        let reply = concat!(
+            "LHB-4A3C91F2\terror\tdisconnected\tdisconnected\n",
+            "LHB-93D0B1E4\terror\tnot_found\tLHB-93D0B1E4 was not found\n",
+        );
+        let (sent, result) = tokio::join!(daemon(reply), request(&path, "on", &names));
+        assert_eq!(sent, "on LHB-4A3C91F2 LHB-93D0B1E4\n");
+        let error = result.unwrap_err();
+        let outcome = error.downcast_ref::<Outcome>().unwrap();
+        assert_eq!(outcome.missing, ["LHB-93D0B1E4"]);
+        assert_eq!(outcome.failed, 1);
+
+        let reply = "LHB-4A3C91F2\tON\n";
+        let (_, result) = tokio::join!(daemon(reply), request(&path, "status", &names[..1]));
+        assert!(result.is_ok());
+
+        let reply = "\terror\tunknown_command\tunknown command `on`\n";
+        let (_, result) = tokio::join!(daemon(reply), request(&path, "on", &[]));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "daemon: unknown command `on`"
+        );
+
+        let (_, result) = tokio::join!(daemon("LHB-4A3C91F2\n"), request(&path, "status", &[]));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "unexpected reply from the daemon: LHB-4A3C91F2"
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! `doctor`: checks the Bluetooth setup step by step, from reaching the Bluetooth stack to seeing
+//! a base station advertise, with a hint at the fix for the first step that fails.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use btleplug::api::{Central, CentralEvent, Peripheral, ScanFilter};
+use futures::StreamExt;
+use tokio::time::{timeout_at, Instant};
+
+use lighthousectl::Error;
+
+use crate::{adapter_infos, adapters, adapters_powered, manager, radios, select_adapter};
+
+/// How long to wait for any advertisement once scanning.
+const EVENT_WINDOW: Duration = Duration::from_secs(5);
+/// How long to wait for a base station to advertise, from starting to scan.
+const BASE_STATION_WINDOW: Duration = Duration::from_secs(15);
+
+#[cfg(target_os = "linux")]
+const SCAN_HINT: &str = "make sure BlueZ allows scanning, such as by running as a user in the \
+    `bluetooth` group, and that no other program holds the adapter";
+#[cfg(target_os = "macos")]
+const SCAN_HINT: &str = "allow the terminal to use Bluetooth in System Settings > Privacy & \
+    Security > Bluetooth";
+#[cfg(windows)]
+const SCAN_HINT: &str = "turn on Bluetooth and allow apps to use the location in Settings > \
+    Privacy & security > Location, which Windows requires for scanning";
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+const SCAN_HINT: &str = "make sure the Bluetooth stack allows scanning";
+
+struct Checks {
+    failed: bool,
+}
+
+impl Checks {
+    fn pass(&self, check: &str) {
+        println!("\u{2705} {}", check);
+    }
+
+    fn fail(&mut self, check: &str, error: impl std::fmt::Display, hint: &str) {
+        println!("\u{274c} {}: {}", check, error);
+        println!("   hint: {}", hint);
+        self.failed = true;
+    }
+}
+
+/// Runs the checks in order, stopping at the first that fails, since the later ones depend on it.
+pub async fn run(selector: Option<&str>) -> Result<()> {
+    let mut checks = Checks { failed: false };
+    let result = diagnose(&mut checks, selector).await;
+    if checks.failed {
+        bail!("the Bluetooth setup is not working");
+    }
+    result
+}
+
+async fn diagnose(checks: &mut Checks, selector: Option<&str>) -> Result<()> {
+    let manager = match manager().await {
+        Ok(manager) => {
+            checks.pass("the Bluetooth stack is reachable");
+            manager
+        }
+        Err(error) => {
+            let hint = if cfg!(target_os = "macos") {
+                SCAN_HINT
+            } else {
+                "make sure Bluetooth is installed and its service is running"
+            };
+            checks.fail("the Bluetooth stack is reachable", error, hint);
+            return Ok(());
+        }
+    };
+    let found = match adapters(&manager).await {
+        Ok(adapters) => adapters,
+        Err(error) => {
+            let hint = "plug in a Bluetooth adapter, or enable it in the firmware settings";
+            checks.fail("a Bluetooth adapter exists", error, hint);
+            return Ok(());
+        }
+    };
+    let infos = match adapter_infos(&found).await {
+        Ok(infos) => infos,
+        Err(error) => {
+            let hint = "make sure the Bluetooth service is running and the adapter is not being \
+                        reset";
+            checks.fail("the Bluetooth adapters can be described", error, hint);
+            return Ok(());
+        }
+    };
+    checks.pass(&format!("Bluetooth adapters exist: {}", infos.join(", ")));
+    let powered = adapters_powered().await.unwrap_or_default();
+    if powered.contains(&Some(true)) || powered.is_empty() {
+        checks.pass("a Bluetooth adapter is powered");
+    } else {
+        let hint = if cfg!(target_os = "linux") {
+            "power it on with `bluetoothctl power on`, or unblock it with `rfkill unblock bluetooth`"
+        } else {
+            "turn Bluetooth on in the system settings"
+        };
+        checks.fail("a Bluetooth adapter is powered", "none is", hint);
+        return Ok(());
+    }
+    // Only Windows tells apart why an adapter cannot be used.
+    if cfg!(windows) {
+        match radios::check().await {
+            Ok(()) => checks.pass("the Bluetooth adapter can be used"),
+            Err(error) => {
+                if let Some(&Error::AdapterUnusable { problem, hint }) = error.downcast_ref() {
+                    checks.fail("the Bluetooth adapter can be used", problem, hint);
+                } else {
+                    let hint = "turn Bluetooth on in Settings > Bluetooth & devices";
+                    checks.fail("the Bluetooth adapter can be used", error, hint);
+                }
+                return Ok(());
+            }
+        }
+    }
+    let central = match select_adapter(&manager, selector).await {
+        Ok(central) => central,
+        Err(error) => {
+            let hint = "pick one of the adapters `lighthousectl adapters` lists with --adapter";
+            checks.fail("the Bluetooth adapter can be selected", error, hint);
+            return Ok(());
+        }
+    };
+    let events = central.events().await;
+    // Without a filter, for any advertisement at all to show that scanning works.
+    let started = match events {
+        Ok(events) => central
+            .start_scan(ScanFilter::default())
+            .await
+            .map(|()| events),
+        Err(error) => Err(error),
+    };
+    let mut events = match started {
+        Ok(events) => {
+            checks.pass("scanning can be started");
+            events
+        }
+        Err(error) => {
+            checks.fail("scanning can be started", error, SCAN_HINT);
+            return Ok(());
+        }
+    };
+    let started = Instant::now();
+    let mut seen = 0;
+    let mut base_station = None;
+    while base_station.is_none() {
+        let window = match seen {
+            0 => EVENT_WINDOW,
+            _ => BASE_STATION_WINDOW,
+        };
+        let event = match timeout_at(started + window, events.next()).await {
+            Ok(Some(event)) => event,
+            Ok(None) | Err(_) => break,
+        };
+        let id = match event {
+            CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) => id,
+            CentralEvent::ManufacturerDataAdvertisement { id, .. } => id,
+            _ => continue,
+        };
+        seen += 1;
+        let Ok(p) = central.peripheral(&id).await else {
+            continue;
+        };
+        let Ok(Some(props)) = p.properties().await else {
+            continue;
+        };
+        if props.manufacturer_data.contains_key(&0x055d) {
+            let name = props
+                .local_name
+                .unwrap_or_else(|| props.address.to_string());
+            base_station = Some(name);
+        }
+    }
+    if let Err(error) = central.stop_scan().await {
+        log::warn!("failed to stop scanning: {}", error);
+    }
+    if seen == 0 {
+        let check = "advertisements are received";
+        let error = format!("none in {}", humantime::format_duration(EVENT_WINDOW));
+        checks.fail(check, error, SCAN_HINT);
+        return Ok(());
+    }
+    checks.pass(&format!("advertisements are received, {} events", seen));
+    match base_station {
+        Some(name) => checks.pass(&format!("a base station advertises: {}", name)),
+        None => {
+            let error = format!(
+                "none in {}",
+                humantime::format_duration(BASE_STATION_WINDOW)
+            );
+            let hint = "make sure a base station is plugged in and within range; they advertise \
+                        even while asleep";
+            checks.fail("a base station advertises", error, hint);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,273 @@
+//! `ensure`: keeps the base stations in a power state, turning them back whenever they drift.
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use btleplug::api::{Central, Peripheral};
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::{
+    discover, release, supervisor::Supervisor, Error, Filter, Lighthouse, Options, Outcome,
+    PowerCommand, PowerState, Protocol,
+};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+/// The longest a base station that keeps failing is left alone.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// The error of `ensure --once` when some base stations had drifted, which exits with its own
+/// status.
+#[derive(Debug)]
+pub struct Corrected(pub usize);
+
+impl fmt::Display for Corrected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "corrected {} base stations", self.0)
+    }
+}
+
+impl std::error::Error for Corrected {}
+
+struct Station<P> {
+    lh: Lighthouse<P>,
+    /// Failed checks in a row, or corrections right after another.
+    strikes: u32,
+    due: Instant,
+}
+
+/// Whether the base station is in `target`, or on its way there.
+fn complies(state: PowerState, target: PowerCommand) -> bool {
+    state == target.into() || (target == PowerCommand::On && state == PowerState::Booting)
+}
+
+/// Turns the base station to `target` unless it is already there.
+/// Returns the state it was in if it had to be corrected.
+async fn reconcile<P: Peripheral>(
+    lh: &Lighthouse<P>,
+    target: PowerCommand,
+    options: &Options,
+) -> Result<Option<PowerState>, Error> {
+    let state = lh.power_state(options).await?;
+    if complies(state, target) {
+        return Ok(None);
+    }
+    lh.set_power(target, options).await?;
+    Ok(Some(state))
+}
+
+struct Outcomes {
+    target: PowerCommand,
+    interval: Duration,
+    once: bool,
+    color: bool,
+    corrected: usize,
+    failed: usize,
+    timed_out: usize,
+}
+
+/// A base station handed back from a check, along with the result.
+type Checked<P> = (Station<P>, Result<Option<PowerState>, Error>);
+
+/// Checks a base station kept from discovery.
+async fn check<P: Peripheral>(
+    station: Station<P>,
+    target: PowerCommand,
+    options: &Options,
+) -> Checked<P> {
+    let lh = &station.lh;
+    let result = lh.session(options, reconcile(lh, target, options)).await;
+    (station, result)
+}
+
+impl Outcomes {
+    /// Reports the result of a check, and schedules the next one.
+    fn record<P: Peripheral>(
+        &mut self,
+        station: &mut Station<P>,
+        result: Result<Option<PowerState>, Error>,
+    ) {
+        let lh = &station.lh;
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+        let complied = match result {
+            Ok(None) => true,
+            Ok(Some(previous)) => {
+                self.corrected += 1;
+                println!(
+                    "{} {} [{}]: {} -> {}",
+                    timestamp,
+                    lh.name,
+                    lh.shown_address(),
+                    previous.painted(self.color),
+                    self.target.painted(self.color)
+                );
+                // Drifting again right after a correction counts as refusing.
+                station.strikes == 0
+            }
+            Err(error) => {
+                if error.is_timeout() {
+                    self.timed_out += 1;
+                } else {
+                    self.failed += 1;
+                }
+                eprintln!("{} {}: {}", timestamp, lh.name, error);
+                false
+            }
+        };
+        if complied {
+            station.strikes = 0;
+            station.due = Instant::now() + self.interval;
+            return;
+        }
+        station.strikes += 1;
+        if self.once {
+            return;
+        }
+        let backoff = self
+            .interval
+            .saturating_mul(1 << station.strikes.min(6))
+            .min(MAX_BACKOFF);
+        log::warn!(
+            "{} does not comply, checking again in {}",
+            lh.name,
+            humantime::format_duration(backoff)
+        );
+        station.due = Instant::now() + backoff;
+    }
+}
+
+pub struct Goal {
+    pub target: PowerCommand,
+    /// How often each base station is checked.
+    pub interval: Duration,
+    /// Check each base station once and exit, `--once`.
+    pub once: bool,
+}
+
+/// Runs until Ctrl-C or SIGTERM, or with `once` until every base station has been checked once.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &mut Filter,
+    options: &Options,
+    goal: &Goal,
+    color: bool,
+    supervisor: &mut Supervisor,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let once = goal.once;
+    let handled = Mutex::new(BTreeSet::new());
+    let unnamed = filter.addresses();
+    let mut discoveries = discover(central, options, &unnamed, &handled, false).await?;
+    let mut stations: Vec<Station<C::Peripheral>> = Vec::new();
+    // The base stations being checked, which are out of `stations` meanwhile.
+    let mut checks: FuturesUnordered<LocalBoxFuture<Checked<C::Peripheral>>> =
+        FuturesUnordered::new();
+    let mut outcomes = Outcomes {
+        target: goal.target,
+        interval: goal.interval,
+        once,
+        color,
+        corrected: 0,
+        failed: 0,
+        timed_out: 0,
+    };
+    let deadline = options.deadline.filter(|_| once);
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    let mut signals = signals;
+    while !(once && filter.is_completed()) {
+        tokio::select! {
+            discovery = discoveries.next() => match discovery {
+                Some(Ok(mut lh)) => {
+                    let matched = filter.is_matched(&lh.name, lh.address)
+                        && !filter.is_excluded(&lh.name, lh.address);
+                    if !matched || matches!(lh.protocol, Protocol::V1) {
+                        if matched {
+                            eprintln!("{}: V1 base stations are not supported by ensure", lh.name);
+                        }
+                        lh.disconnect().await.ok();
+                        continue;
+                    }
+                    if let Err(error) = filter.claim(&lh.name, lh.address) {
+                        eprintln!("{}, ignored", error);
+                        lh.disconnect().await.ok();
+                        continue;
+                    }
+                    // Still connected from discovery, so it is checked right away.
+                    let result = reconcile(&lh, goal.target, options).await;
+                    lh.disconnect().await.ok();
+                    let mut station = Station { lh, strikes: 0, due: Instant::now() };
+                    outcomes.record(&mut station, result);
+                    if once {
+                        filter.complete(&station.lh.name, station.lh.address);
+                    } else {
+                        let count = stations.len() + checks.len() + 1;
+                        supervisor.found(&station.lh.name, station.lh.address, count);
+                        stations.push(station);
+                    }
+                }
+                Some(Err(error)) => {
+                    if filter.is_matched(&error.name, error.address.unwrap_or_default()) {
+                        eprintln!("{}: {}", error.name, error.cause);
+                        if error.timed_out() {
+                            outcomes.timed_out += 1;
+                        } else {
+                            outcomes.failed += 1;
+                        }
+                        if once {
+                            filter.complete(&error.name, error.address.unwrap_or_default());
+                        }
+                    }
+                }
+                None => bail!("the adapter stopped reporting base stations"),
+            },
+            _ = ticks.tick(), if !once => {
+                let now = Instant::now();
+                let (due, waiting) = std::mem::take(&mut stations)
+                    .into_iter()
+                    .partition(|station| station.due <= now);
+                stations = waiting;
+                for station in due {
+                    checks.push(check(station, goal.target, options).boxed_local());
+                }
+            }
+            Some((mut station, result)) = checks.next(), if !checks.is_empty() => {
+                outcomes.record(&mut station, result);
+                stations.push(station);
+            }
+            _ = supervisor.due(), if !once => {
+                let count = stations.len() + checks.len();
+                supervisor.check(central, count, options.io_timeout).await;
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => break,
+            Ok(_) = &mut signals => break,
+        }
+    }
+    if !once {
+        if supervisor.sleeps_on_exit() {
+            while let Some((station, _)) = checks.next().await {
+                stations.push(station);
+            }
+        }
+        drop(checks);
+        let tracked = stations.iter().map(|station| &station.lh);
+        supervisor.stop(tracked, options).await;
+        release(central, options.io_timeout).await;
+        return Ok(());
+    }
+    release(central, options.io_timeout).await;
+    let outcome = Outcome {
+        missing: filter.remaining(),
+        failed: outcomes.failed,
+        timed_out: outcomes.timed_out,
+    };
+    outcome.into_result()?;
+    if outcomes.corrected > 0 {
+        return Err(Corrected(outcomes.corrected).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,420 @@
+//! What went wrong handling base stations, with what failed and on which base station, so that
+//! callers can tell timeouts from refusals and report each base station without parsing messages.
+
+use std::{
+    fmt::{self, Display},
+    time::Duration,
+};
+
+use btleplug::api::BDAddr;
+use uuid::Uuid;
+
+use crate::{PowerCommand, PowerState};
+
+/// What was being done with the adapter or a base station when an error happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Subscribing to the events of the adapter, before scanning.
+    Events,
+    StartScan,
+    /// Listing the peripherals that the adapter already knows, for `--passive`.
+    KnownPeripherals,
+    Lookup,
+    Properties,
+    Connect,
+    CheckConnection,
+    Read,
+    /// Reading the power state back after writing it.
+    ReadBack,
+    Write,
+    /// Subscribing to the notifications of the power characteristic.
+    Subscribe,
+    Notification,
+    Identify,
+    ChannelRead,
+    ChannelWrite,
+    DeviceNameRead,
+    /// Reading a Device Information Service string, such as the firmware revision.
+    InfoRead(&'static str),
+    GattRead,
+}
+
+/// What an operation did, to name its errors by.
+enum Doing {
+    Scan,
+    Connect,
+    Read,
+    Write,
+}
+
+impl Operation {
+    fn doing(self) -> Doing {
+        match self {
+            Operation::Events
+            | Operation::StartScan
+            | Operation::KnownPeripherals
+            | Operation::Lookup => Doing::Scan,
+            Operation::Connect | Operation::CheckConnection => Doing::Connect,
+            Operation::Properties
+            | Operation::Read
+            | Operation::ReadBack
+            | Operation::Subscribe
+            | Operation::Notification
+            | Operation::ChannelRead
+            | Operation::DeviceNameRead
+            | Operation::InfoRead(_)
+            | Operation::GattRead => Doing::Read,
+            Operation::Write | Operation::Identify | Operation::ChannelWrite => Doing::Write,
+        }
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Events => write!(f, "subscribing"),
+            Operation::StartScan => write!(f, "starting to scan"),
+            Operation::KnownPeripherals => write!(f, "listing the known peripherals"),
+            Operation::Lookup => write!(f, "lookup"),
+            Operation::Properties => write!(f, "reading properties"),
+            Operation::Connect => write!(f, "connect"),
+            Operation::CheckConnection => write!(f, "checking the connection"),
+            Operation::Read => write!(f, "read"),
+            Operation::ReadBack => write!(f, "read back"),
+            Operation::Write => write!(f, "write"),
+            Operation::Subscribe => write!(f, "subscribe"),
+            Operation::Notification => write!(f, "notification"),
+            Operation::Identify => write!(f, "identify"),
+            Operation::ChannelRead => write!(f, "channel read"),
+            Operation::ChannelWrite => write!(f, "channel write"),
+            Operation::DeviceNameRead => write!(f, "device name read"),
+            Operation::InfoRead(key) => write!(f, "{} read", key),
+            Operation::GattRead => write!(f, "gatt read"),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// There is no usable Bluetooth adapter, with a hint at the cause where it can be detected.
+    #[error(
+        "no Bluetooth adapters found; is the adapter enabled and is the Bluetooth service running?{}",
+        hint.map(|hint| format!("\nhint: {}", hint)).unwrap_or_default()
+    )]
+    NoAdapter { hint: Option<&'static str> },
+    /// The adapter is there but cannot be used for base stations, such as its radio being off.
+    #[error("the Bluetooth adapter {problem}\nhint: {hint}")]
+    AdapterUnusable {
+        problem: &'static str,
+        hint: &'static str,
+    },
+    /// A base station asked for by name or address that was not found, or is not tracked.
+    #[error("{name} was not found")]
+    NotFound { name: String },
+    #[error("suffix `{suffix}` is ambiguous: it matches both {first} and {second}")]
+    Ambiguous {
+        suffix: String,
+        first: String,
+        second: String,
+    },
+    /// A name advertised by more than one base station, which has to be told apart by address.
+    #[error(
+        "{name} is advertised by {} base stations, give the address of the one meant instead: {}",
+        addresses.len(),
+        addresses.iter().map(BDAddr::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    Namesakes {
+        name: String,
+        addresses: Vec<BDAddr>,
+    },
+    /// The firmware of the base station lacks the characteristic needed for `operation`.
+    #[error("{operation} is not supported")]
+    CharacteristicMissing {
+        operation: Operation,
+        address: BDAddr,
+    },
+    /// None of the services has the characteristic asked for by UUID, with those they have.
+    #[error(
+        "no characteristic {uuid}, the base station has {}",
+        available.iter().map(Uuid::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    NoSuchCharacteristic { uuid: Uuid, available: Vec<Uuid> },
+    #[error("{operation} failed: {source}")]
+    Ble {
+        operation: Operation,
+        source: btleplug::Error,
+    },
+    /// The operation did not finish within `--io-timeout` or `--connect-timeout`.
+    #[error("{operation} timed out")]
+    Timeout { operation: Operation },
+    /// The platform refused the operation until the base station is paired, as Windows does on
+    /// some machines.
+    #[error(
+        "{operation} was denied until the base station is paired: pair it in the Bluetooth \
+         settings, or on Windows pass --pair"
+    )]
+    PairingRequired { operation: Operation },
+    #[error("pairing failed: {0}")]
+    PairingFailed(String),
+    #[error("{operation} returned no data")]
+    NoData { operation: Operation },
+    /// A state that the base station reports, but that cannot be written.
+    #[error("{state} cannot be written, it is only ever read")]
+    ReadOnly { state: PowerState },
+    #[error("state is still {read} after writing {written}")]
+    NotTaken {
+        written: PowerCommand,
+        read: PowerState,
+    },
+    /// The last failure of an operation given up on after `attempts`.
+    #[error("{last} ({attempts} attempts)")]
+    GaveUp { last: Box<Error>, attempts: usize },
+    #[error("channel read back as {read} instead of {written}")]
+    ChannelNotTaken { written: u8, read: u8 },
+    /// `check` found the base station in another state than expected.
+    #[error("is {state}, expected {expected}")]
+    Unexpected {
+        state: PowerState,
+        expected: PowerState,
+    },
+    /// `--wait` ran out before the base station reported the state, with the last state it did
+    /// report while waiting, if it answered at all.
+    #[error(
+        "did not reach {state} within {}, {}",
+        humantime::format_duration(*within),
+        match last {
+            Some(last) => format!("still {}", last),
+            None => "no answer while waiting".to_owned(),
+        }
+    )]
+    NotReached {
+        state: PowerState,
+        within: Duration,
+        last: Option<PowerState>,
+    },
+    #[error("put to sleep, but not turned back on: {0}")]
+    NotTurnedBackOn(Box<Error>),
+    /// The base station cannot carry out the action at all, such as standby on V1.
+    #[error("{0}")]
+    Unsupported(&'static str),
+    /// The base station went away while being followed.
+    #[error("{0}")]
+    Lost(&'static str),
+    #[error("stopped at the first failure because of --fail-fast")]
+    FailFast,
+}
+
+impl Error {
+    /// Whether the base station did not answer in time, rather than refusing.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Timeout { .. } | Error::NotReached { .. } => true,
+            Error::Ble { source, .. } => matches!(source, btleplug::Error::TimedOut(_)),
+            Error::GaveUp { last: error, .. } | Error::NotTurnedBackOn(error) => error.is_timeout(),
+            _ => false,
+        }
+    }
+
+    /// What failed, as a stable name for the structured outputs, such as `connect_timeout` or
+    /// `write_failed`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::NoAdapter { .. } => "no_adapter",
+            Error::AdapterUnusable { .. } => "adapter_unusable",
+            Error::NotFound { .. } => "not_found",
+            Error::Ambiguous { .. } => "ambiguous",
+            Error::Namesakes { .. } => "namesakes",
+            Error::CharacteristicMissing { .. } | Error::NoSuchCharacteristic { .. } => {
+                "characteristic_missing"
+            }
+            Error::Ble { operation, .. } => match operation.doing() {
+                Doing::Scan => "scan_failed",
+                Doing::Connect => "connect_failed",
+                Doing::Read => "read_failed",
+                Doing::Write => "write_failed",
+            },
+            Error::Timeout { operation } => match operation.doing() {
+                Doing::Scan => "scan_timeout",
+                Doing::Connect => "connect_timeout",
+                Doing::Read => "read_timeout",
+                Doing::Write => "write_timeout",
+            },
+            Error::PairingRequired { .. } => "pairing_required",
+            Error::PairingFailed(_) => "pairing_failed",
+            Error::NoData { .. } => "no_data",
+            Error::ReadOnly { .. } => "read_only",
+            Error::NotTaken { .. } | Error::ChannelNotTaken { .. } => "not_taken",
+            Error::GaveUp { last: error, .. } | Error::NotTurnedBackOn(error) => error.kind(),
+            Error::Unexpected { .. } => "unexpected_state",
+            Error::NotReached { last: None, .. } => "no_response",
+            Error::NotReached {
+                last: Some(PowerState::Booting),
+                ..
+            } => "stuck_booting",
+            Error::NotReached { .. } => "wait_timeout",
+            Error::Unsupported(_) => "unsupported",
+            Error::Lost(_) => "lost",
+            Error::FailFast => "fail_fast",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_are_told_from_refusals() {
+        let operation = Operation::Write;
+        let timed_out = Error::Timeout { operation };
+        assert_eq!(timed_out.to_string(), "write timed out");
+        assert_eq!(timed_out.kind(), "write_timeout");
+        let last = Box::new(timed_out);
+        assert!(Error::GaveUp { last, attempts: 3 }.is_timeout());
+        let not_taken = Error::NotTaken {
+            written: PowerCommand::On,
+            read: PowerState::Sleep,
+        };
+        assert!(!not_taken.is_timeout());
+        let last = Box::new(not_taken);
+        let gave_up = Error::GaveUp { last, attempts: 3 };
+        assert_eq!(gave_up.kind(), "not_taken");
+        let stuck = Error::NotReached {
+            state: PowerState::On,
+            within: Duration::from_secs(60),
+            last: Some(PowerState::Booting),
+        };
+        assert_eq!(
+            stuck.to_string(),
+            "did not reach ON within 1m, still BOOTING"
+        );
+        assert_eq!(stuck.kind(), "stuck_booting");
+        assert_eq!(
+            gave_up.to_string(),
+            "state is still SLEEP after writing ON (3 attempts)"
+        );
+    }
+
+    #[test]
+    fn failures_are_named_by_what_the_operation_did() {
+        let failed = |operation| Error::Ble {
+            operation,
+            source: btleplug::Error::NotConnected,
+        };
+        assert_eq!(failed(Operation::StartScan).kind(), "scan_failed");
+        assert_eq!(failed(Operation::Connect).kind(), "connect_failed");
+        assert_eq!(failed(Operation::CheckConnection).kind(), "connect_failed");
+        assert_eq!(failed(Operation::Subscribe).kind(), "read_failed");
+        assert_eq!(
+            failed(Operation::InfoRead("firmware")).kind(),
+            "read_failed"
+        );
+        assert_eq!(failed(Operation::Identify).kind(), "write_failed");
+        let timed_out = |operation| Error::Timeout { operation };
+        assert_eq!(timed_out(Operation::Lookup).kind(), "scan_timeout");
+        assert_eq!(timed_out(Operation::Connect).kind(), "connect_timeout");
+        assert_eq!(timed_out(Operation::ReadBack).kind(), "read_timeout");
+        assert_eq!(timed_out(Operation::ChannelWrite).kind(), "write_timeout");
+        assert_eq!(
+            timed_out(Operation::InfoRead("firmware")).to_string(),
+            "firmware read timed out"
+        );
+    }
+
+    #[test]
+    fn every_other_error_has_its_kind() {
+        let operation = Operation::Read;
+        let address = BDAddr::default();
+        let state = PowerState::On;
+        let within = Duration::from_secs(60);
+        let kinds = [
+            (Error::NoAdapter { hint: None }, "no_adapter"),
+            (
+                Error::AdapterUnusable {
+                    problem: "is off",
+                    hint: "turn it on",
+                },
+                "adapter_unusable",
+            ),
+            (
+                Error::NotFound {
+                    name: "LHB-4A3C91F2".to_owned(),
+                },
+                "not_found",
+            ),
+            (
+                Error::Ambiguous {
+                    suffix: "b1e4".to_owned(),
+                    first: "LHB-93D0B1E4".to_owned(),
+                    second: "LHB-4A3CB1E4".to_owned(),
+                },
+                "ambiguous",
+            ),
+            (
+                Error::Namesakes {
+                    name: "LHB-4A3C91F2".to_owned(),
+                    addresses: vec![address],
+                },
+                "namesakes",
+            ),
+            (
+                Error::CharacteristicMissing { operation, address },
+                "characteristic_missing",
+            ),
+            (
+                Error::NoSuchCharacteristic {
+                    uuid: Uuid::nil(),
+                    available: Vec::new(),
+                },
+                "characteristic_missing",
+            ),
+            (Error::PairingRequired { operation }, "pairing_required"),
+            (Error::PairingFailed("refused".to_owned()), "pairing_failed"),
+            (Error::NoData { operation }, "no_data"),
+            (Error::ReadOnly { state }, "read_only"),
+            (
+                Error::ChannelNotTaken {
+                    written: 3,
+                    read: 1,
+                },
+                "not_taken",
+            ),
+            (
+                Error::Unexpected {
+                    state,
+                    expected: PowerState::Sleep,
+                },
+                "unexpected_state",
+            ),
+            (
+                Error::NotReached {
+                    state,
+                    within,
+                    last: None,
+                },
+                "no_response",
+            ),
+            (
+                Error::NotReached {
+                    state,
+                    within,
+                    last: Some(PowerState::Standby),
+                },
+                "wait_timeout",
+            ),
+            (
+                Error::NotTurnedBackOn(Box::new(Error::Timeout { operation })),
+                "read_timeout",
+            ),
+            (
+                Error::Unsupported("standby is unsupported on V1"),
+                "unsupported",
+            ),
+            (Error::Lost("disconnected"), "lost"),
+            (Error::FailFast, "fail_fast"),
+        ];
+        for (error, kind) in kinds {
+            assert_eq!(error.kind(), kind, "{}", error);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! `exporter`: polls the base stations on a schedule and serves the results as Prometheus metrics
+//! at `/metrics`.
+//!
+//! Scrapes are answered from the last poll, so they never wait for Bluetooth. A base station that
+//! cannot be reached is kept with `lighthousectl_up` at 0 and its last known values.
+
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    rc::Rc,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use btleplug::{
+    api::{Central, Peripheral},
+    platform::PeripheralId,
+};
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+    time::MissedTickBehavior,
+};
+
+use crate::{
+    discover, fold, http, release, supervisor::Supervisor, Filter, Lighthouse, Options, PowerState,
+    Protocol,
+};
+
+pub const DEFAULT_LISTEN: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9785));
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Every state is exported for each base station, with 1 for the current one.
+const STATES: [&str; 6] = ["sleep", "standby", "booting", "on", "stopping", "unknown"];
+
+struct Station<P> {
+    lh: Lighthouse<P>,
+    raw_state: Cell<Option<u8>>,
+    rssi: Cell<Option<i16>>,
+    last_success: Cell<Option<SystemTime>>,
+    /// Whether the last read succeeded.
+    up: Cell<bool>,
+    errors: Cell<u64>,
+    /// Set while a read is in flight, so that a slow base station is not polled twice at once.
+    polling: Cell<bool>,
+}
+
+type Stations<P> = RefCell<BTreeMap<String, Rc<Station<P>>>>;
+
+/// Runs until Ctrl-C or SIGTERM, then disconnects from every base station.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &Filter,
+    options: &Options,
+    listen: SocketAddr,
+    interval: Duration,
+    supervisor: &mut Supervisor,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("failed to listen on {}", listen))?;
+    log::info!("serving metrics on http://{}/metrics", listen);
+    let stations: Stations<C::Peripheral> = RefCell::new(BTreeMap::new());
+    let discovery_errors = Cell::new(0);
+    let handled = Mutex::new(BTreeSet::new());
+    let unnamed = filter.addresses();
+    let mut discoveries = discover(central, options, &unnamed, &handled, false).await?;
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+    let mut tasks: FuturesUnordered<LocalBoxFuture<()>> = FuturesUnordered::new();
+    let mut signals = signals;
+    let result = loop {
+        tokio::select! {
+            discovery = discoveries.next() => match discovery {
+                Some(Ok(lh)) => if let Some(station) = track(lh, filter, &stations).await {
+                    supervisor.found(&station.lh.name, station.lh.address, stations.borrow().len());
+                    tasks.push(poll(station, &handled, options).boxed_local());
+                },
+                Some(Err(error)) => {
+                    if filter.is_matched(&error.name, error.address.unwrap_or_default()) {
+                        log::warn!("{}: {}", error.name, error.cause);
+                        discovery_errors.set(discovery_errors.get() + 1);
+                    }
+                }
+                None => break Err(anyhow::anyhow!("the adapter stopped reporting base stations")),
+            },
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tasks.push(serve(stream, &stations, &discovery_errors).boxed_local());
+                }
+                Err(error) => log::warn!("accepting a connection failed: {}", error),
+            },
+            _ = ticks.tick() => {
+                for station in stations.borrow().values() {
+                    if !station.polling.get() {
+                        tasks.push(poll(station.clone(), &handled, options).boxed_local());
+                    }
+                }
+            }
+            _ = supervisor.due() => {
+                let count = stations.borrow().len();
+                supervisor.check(central, count, options.io_timeout).await;
+            }
+            Some(()) = tasks.next(), if !tasks.is_empty() => {}
+            Ok(signal) = &mut signals => {
+                log::info!("stopping on signal {}", signal);
+                break Ok(());
+            }
+        }
+    };
+    drop(tasks);
+    let tracked: Vec<_> = stations.borrow().values().cloned().collect();
+    supervisor
+        .stop(tracked.iter().map(|station| &station.lh), options)
+        .await;
+    release(central, options.io_timeout).await;
+    result
+}
+
+/// Disconnects from a newly discovered base station and starts exporting it if it is to be
+/// polled. The counters of a base station discovered again carry over.
+async fn track<P: Peripheral>(
+    mut lh: Lighthouse<P>,
+    filter: &Filter,
+    stations: &Stations<P>,
+) -> Option<Rc<Station<P>>> {
+    lh.disconnect().await.ok();
+    if !filter.is_matched(&lh.name, lh.address) || filter.is_excluded(&lh.name, lh.address) {
+        return None;
+    }
+    if let Protocol::V1 = lh.protocol {
+        log::warn!(
+            "{}: V1 base stations are not supported by the exporter",
+            lh.name
+        );
+        return None;
+    }
+    let key = fold(&lh.name);
+    let (raw_state, last_success, errors) = match stations.borrow_mut().remove(&key) {
+        Some(previous) => (
+            previous.raw_state.get(),
+            previous.last_success.get(),
+            previous.errors.get(),
+        ),
+        None => (None, None, 0),
+    };
+    let station = Rc::new(Station {
+        raw_state: Cell::new(raw_state),
+        rssi: Cell::new(lh.rssi),
+        last_success: Cell::new(last_success),
+        up: Cell::new(false),
+        errors: Cell::new(errors),
+        polling: Cell::new(false),
+        lh,
+    });
+    stations.borrow_mut().insert(key, station.clone());
+    Some(station)
+}
+
+async fn poll<P: Peripheral>(
+    station: Rc<Station<P>>,
+    handled: &Mutex<BTreeSet<PeripheralId>>,
+    options: &Options,
+) {
+    station.polling.set(true);
+    let lh = &station.lh;
+    let result = lh.session(options, lh.read_power(options.io_timeout)).await;
+    station.polling.set(false);
+    match result {
+        Ok(raw_state) => {
+            station.raw_state.set(Some(raw_state));
+            station.last_success.set(Some(SystemTime::now()));
+            station.up.set(true);
+            if let Ok(Some(props)) = lh.peripheral.properties().await {
+                station.rssi.set(props.rssi.or(station.rssi.get()));
+            }
+        }
+        Err(message) => {
+            if station.up.replace(false) {
+                log::warn!("{}: {}", lh.name, message);
+            } else {
+                log::debug!("{}: {}", lh.name, message);
+            }
+            station.errors.set(station.errors.get() + 1);
+            // Still polled, but also discovered again once it advertises.
+            handled.lock().unwrap().remove(&lh.peripheral.id());
+        }
+    }
+}
+
+async fn serve<P: Peripheral>(
+    mut stream: TcpStream,
+    stations: &Stations<P>,
+    discovery_errors: &Cell<u64>,
+) {
+    let served = async {
+        let request = http::read_request(&mut stream).await?;
+        let (status, content_type, body) = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => (
+                "200 OK",
+                "text/plain; version=0.0.4",
+                metrics(stations, discovery_errors.get()),
+            ),
+            ("GET", "/") => (
+                "200 OK",
+                "text/html",
+                "<a href=\"/metrics\">metrics</a>\n".to_owned(),
+            ),
+            _ => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+        };
+        http::respond(&mut stream, status, content_type, &body).await
+    };
+    if let Err(error) = served.await {
+        log::debug!("serving a scrape failed: {}", error);
+    }
+}
+
+fn metrics<P: Peripheral>(stations: &Stations<P>, discovery_errors: u64) -> String {
+    let stations = stations.borrow();
+    let mut out = String::new();
+    let mut family =
+        |name: &str, kind: &str, help: &str, sample: &dyn Fn(&Station<P>, &str, &mut String)| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for station in stations.values() {
+                let labels = format!(
+                    "name=\"{}\",address=\"{}\"",
+                    escape(&station.lh.name),
+                    station.lh.shown_address()
+                );
+                sample(station, &labels, &mut out);
+            }
+        };
+    family(
+        "lighthousectl_up",
+        "gauge",
+        "Whether the last read of the base station succeeded.",
+        &|station, labels, out| {
+            let up = u8::from(station.up.get());
+            let _ = writeln!(out, "lighthousectl_up{{{}}} {}", labels, up);
+        },
+    );
+    family(
+        "lighthousectl_power_state",
+        "gauge",
+        "The last known power state of the base station, 1 for the state in the label.",
+        &|station, labels, out| {
+            let current = station
+                .raw_state
+                .get()
+                .map(|raw| PowerState::from(raw).porcelain());
+            for state in STATES {
+                let value = u8::from(current == Some(state));
+                let _ = writeln!(
+                    out,
+                    "lighthousectl_power_state{{{},state=\"{}\"}} {}",
+                    labels, state, value
+                );
+            }
+        },
+    );
+    family(
+        "lighthousectl_power_state_raw",
+        "gauge",
+        "The last known raw power state byte of the base station.",
+        &|station, labels, out| {
+            if let Some(raw_state) = station.raw_state.get() {
+                let _ = writeln!(
+                    out,
+                    "lighthousectl_power_state_raw{{{}}} {}",
+                    labels, raw_state
+                );
+            }
+        },
+    );
+    family(
+        "lighthousectl_rssi_dbm",
+        "gauge",
+        "The last known signal strength of the base station.",
+        &|station, labels, out| {
+            if let Some(rssi) = station.rssi.get() {
+                let _ = writeln!(out, "lighthousectl_rssi_dbm{{{}}} {}", labels, rssi);
+            }
+        },
+    );
+    family(
+        "lighthousectl_last_success_timestamp_seconds",
+        "gauge",
+        "When the power state of the base station was last read successfully.",
+        &|station, labels, out| {
+            let since_epoch = station
+                .last_success
+                .get()
+                .and_then(|at| at.duration_since(UNIX_EPOCH).ok());
+            if let Some(since_epoch) = since_epoch {
+                let _ = writeln!(
+                    out,
+                    "lighthousectl_last_success_timestamp_seconds{{{}}} {:.3}",
+                    labels,
+                    since_epoch.as_secs_f64()
+                );
+            }
+        },
+    );
+    family(
+        "lighthousectl_ble_errors_total",
+        "counter",
+        "Failed reads of the base station.",
+        &|station, labels, out| {
+            let errors = station.errors.get();
+            let _ = writeln!(
+                out,
+                "lighthousectl_ble_errors_total{{{}}} {}",
+                labels, errors
+            );
+        },
+    );
+    let _ = writeln!(
+        out,
+        "# HELP lighthousectl_discovery_errors_total Base stations that failed while being discovered."
+    );
+    let _ = writeln!(out, "# TYPE lighthousectl_discovery_errors_total counter");
+    let _ = writeln!(
+        out,
+        "lighthousectl_discovery_errors_total {}",
+        discovery_errors
+    );
+    out
+}
+
+/// Escapes a label value in the Prometheus text format.
+fn escape(value: &str) -> Cow<'_, str> {
+    if !value.contains(['\\', '"', '\n']) {
+        return Cow::Borrowed(value);
+    }
+    Cow::Owned(
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockCentral, MockPeripheral};
+
+    #[test]
+    fn every_byte_has_a_state_label() {
+        for byte in 0..=u8::MAX {
+            assert!(STATES.contains(&PowerState::from(byte).porcelain()));
+        }
+    }
+
+    #[tokio::test]
+    async fn stopping_base_stations_are_exported_as_stopping() {
+        let central = MockCentral::new(vec![MockPeripheral::base_station(1, "LHB-4A3C91F2", 0x0a)]);
+        let options = Options::default();
+        let unnamed = BTreeSet::new();
+        let handled = Mutex::new(BTreeSet::new());
+        let mut discoveries = discover(&central, &options, &unnamed, &handled, false)
+            .await
+            .unwrap();
+        let Some(Ok(lh)) = discoveries.next().await else {
+            panic!("the base station was not discovered");
+        };
+        let filter = Filter::new(Vec::new(), false, Vec::new());
+        let stations = RefCell::new(BTreeMap::new());
+        let station = track(lh, &filter, &stations).await.unwrap();
+        poll(station, &handled, &options).await;
+        let metrics = metrics(&stations, 0);
+        let labels = "name=\"LHB-4A3C91F2\",address=\"00:00:00:00:00:01\"";
+        for state in STATES {
+            let value = u8::from(state == "stopping");
+            let sample = format!(
+                "lighthousectl_power_state{{{},state=\"{}\"}} {}",
+                labels, state, value
+            );
+            assert!(metrics.contains(&sample), "{} not in {}", sample, metrics);
+        }
+        assert!(metrics.contains(&format!("lighthousectl_power_state_raw{{{}}} 10", labels)));
+    }
+}
@@ -0,0 +1,140 @@
+//! Per-record fields shared by `--format` templates and the CSV output.
+
+use std::{str::FromStr, time::SystemTime};
+
+use crate::{hex, Record};
+
+#[derive(Clone, Copy)]
+pub enum Field {
+    Name,
+    Address,
+    State,
+    RawState,
+    RequestedState,
+    Rssi,
+    Channel,
+    Bytes,
+    Timestamp,
+}
+
+impl Field {
+    const ALL: [(&'static str, Field); 9] = [
+        ("name", Field::Name),
+        ("address", Field::Address),
+        ("state", Field::State),
+        ("raw_state", Field::RawState),
+        ("requested_state", Field::RequestedState),
+        ("rssi", Field::Rssi),
+        ("channel", Field::Channel),
+        ("bytes", Field::Bytes),
+        ("timestamp", Field::Timestamp),
+    ];
+
+    /// Formats the field of the record. Missing values are empty.
+    pub fn value(self, record: &Record) -> String {
+        match self {
+            Field::Name => record.name.clone(),
+            Field::Address => record.address.clone(),
+            Field::State => record
+                .state()
+                .map(|state| state.to_string())
+                .unwrap_or_default(),
+            Field::RawState => record
+                .raw_state
+                .map(|raw_state| raw_state.to_string())
+                .unwrap_or_default(),
+            Field::RequestedState => record
+                .requested_state
+                .map(|state| state.to_string())
+                .unwrap_or_default(),
+            Field::Rssi => record.rssi.map(|rssi| rssi.to_string()).unwrap_or_default(),
+            Field::Channel => record
+                .channel
+                .map(|channel| channel.to_string())
+                .unwrap_or_default(),
+            Field::Bytes => record.bytes.as_deref().map(hex).unwrap_or_default(),
+            Field::Timestamp => humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Field::ALL
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, field)| *field)
+            .ok_or_else(|| {
+                let names: Vec<_> = Field::ALL.iter().map(|(name, _)| *name).collect();
+                format!(
+                    "unknown placeholder `{{{}}}`, expected one of: {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+#[derive(Clone)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// A template like `{name} {state}`. Use `{{` and `}}` for literal braces.
+#[derive(Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn render(&self, record: &Record) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::Field(field) => field.value(record),
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or("unclosed `{` in format")?;
+                    let field = rest[..end].parse()?;
+                    chars = rest[end + 1..].chars();
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Field(field));
+                }
+                '}' => return Err("unmatched `}` in format, use `}}` for a literal brace".into()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self { segments })
+    }
+}
@@ -0,0 +1,131 @@
+//! `gatt`: reads or writes any characteristic by its UUID, for exploring the protocol beyond the
+//! power, channel and identify characteristics that the other commands know of.
+
+use std::{collections::BTreeSet, sync::Mutex};
+
+use anyhow::Result;
+use btleplug::api::{Central, Peripheral};
+use futures::StreamExt;
+use tokio::{sync::oneshot, time::timeout_at};
+use uuid::Uuid;
+
+use crate::{discover, hex, release, Error, Filter, Lighthouse, Options, Outcome};
+
+pub enum Operation {
+    Read(Uuid),
+    Write { uuid: Uuid, bytes: Vec<u8> },
+}
+
+/// Carries out the operation on the characteristic, wherever the service it is in, and prints
+/// the bytes read or written.
+async fn carry_out<P: Peripheral>(
+    lh: &Lighthouse<P>,
+    operation: &Operation,
+    options: &Options,
+) -> Result<(), Error> {
+    match operation {
+        Operation::Read(uuid) => {
+            let characteristic = lh.any_characteristic(*uuid)?;
+            let bytes = lh
+                .read_bytes(
+                    characteristic,
+                    lighthousectl::Operation::GattRead,
+                    options.io_timeout,
+                )
+                .await?;
+            println!("{} [{}]: {}", lh.name, lh.shown_address(), hex(&bytes));
+        }
+        Operation::Write { uuid, bytes } => {
+            let characteristic = lh.any_characteristic(*uuid)?;
+            lh.write_bytes(
+                characteristic,
+                bytes,
+                options.write_type,
+                options.io_timeout,
+            )
+            .await?;
+            println!("{} [{}]: wrote {}", lh.name, lh.shown_address(), hex(bytes));
+        }
+    }
+    Ok(())
+}
+
+/// Carries out the operation on each matched base station, until all of them are found or the
+/// timeout passes. Base stations lacking the power characteristic are included, since the
+/// characteristic asked for may be all there is to them.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &mut Filter,
+    options: &Options,
+    operation: &Operation,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let handled = Mutex::new(BTreeSet::new());
+    let unnamed = filter.addresses();
+    let mut discoveries = discover(central, options, &unnamed, &handled, true).await?;
+    let mut failed = 0;
+    let mut timed_out = 0;
+    let mut signals = signals;
+    while !filter.is_completed() {
+        let next = async {
+            match options.deadline {
+                Some(deadline) => timeout_at(deadline, discoveries.next())
+                    .await
+                    .ok()
+                    .flatten(),
+                None => discoveries.next().await,
+            }
+        };
+        let discovery = tokio::select! {
+            discovery = next => discovery,
+            Ok(_) = &mut signals => break,
+        };
+        let (name, address, result) = match discovery {
+            Some(Ok(mut lh)) => {
+                let matched = filter.is_matched(&lh.name, lh.address)
+                    && !filter.is_excluded(&lh.name, lh.address);
+                if !matched {
+                    lh.disconnect().await.ok();
+                    continue;
+                }
+                if let Err(error) = filter.claim(&lh.name, lh.address) {
+                    eprintln!("{}, ignored", error);
+                    lh.disconnect().await.ok();
+                    continue;
+                }
+                if !filter.take(lh.address) {
+                    lh.disconnect().await.ok();
+                    continue;
+                }
+                let result = carry_out(&lh, operation, options).await;
+                lh.disconnect().await.ok();
+                (lh.name, lh.address, result)
+            }
+            Some(Err(error)) => {
+                let address = error.address.unwrap_or_default();
+                if !filter.is_matched(&error.name, address) || !filter.take(address) {
+                    continue;
+                }
+                (error.name, address, Err(error.cause))
+            }
+            None => break,
+        };
+        filter.complete(&name, address);
+        if let Err(error) = result {
+            eprintln!("{}: {}", name, error);
+            if error.is_timeout() {
+                timed_out += 1;
+            } else {
+                failed += 1;
+            }
+        }
+    }
+    drop(discoveries);
+    release(central, options.io_timeout).await;
+    let outcome = Outcome {
+        missing: filter.remaining(),
+        failed,
+        timed_out,
+    };
+    outcome.into_result()
+}
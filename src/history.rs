@@ -0,0 +1,141 @@
+//! `--history-file`: appends a JSON line per power state transition seen by `watch` or
+//! `scan --interval`, such as to find out whether base stations wake up at night. Each line is
+//! written and synced on its own, so that a crash loses nothing seen before it.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{json::Object, PowerState};
+
+/// The size past which the file is rotated to `<path>.1`, replacing the one rotated before.
+const MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How a transition came to be seen.
+#[derive(Clone, Copy)]
+pub enum Source {
+    Notification,
+    Poll,
+}
+
+impl Source {
+    fn name(self) -> &'static str {
+        match self {
+            Source::Notification => "notification",
+            Source::Poll => "poll",
+        }
+    }
+}
+
+pub struct HistoryFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl HistoryFile {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let file = append(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends the transition. Failures are only logged, since they should not stop watching.
+    pub fn transition(
+        &self,
+        name: &str,
+        address: &str,
+        previous: PowerState,
+        state: PowerState,
+        source: Source,
+    ) {
+        let mut out = String::new();
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        Object::new(&mut out)
+            .field("timestamp", &timestamp)
+            .field("name", name)
+            .field("address", address)
+            .field("previous_state", &previous)
+            .field("state", &state)
+            .field("source", source.name())
+            .finish();
+        out.push('\n');
+        if let Err(error) = self.write(out.as_bytes()) {
+            log::warn!("failed to write to {}: {}", self.path.display(), error);
+        }
+    }
+
+    fn write(&self, line: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        if file.metadata()?.len() + line.len() as u64 > MAX_SIZE {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, rotated)?;
+            *file = append(&self.path)?;
+        }
+        file.write_all(line)?;
+        file.sync_data()
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn rotates_past_the_size() {
+        let dir = env::temp_dir().join(format!("lighthousectl-history-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history");
+        let rotated = dir.join("history.1");
+        fs::write(&rotated, "from before\n").unwrap();
+        let history = HistoryFile::open(path.clone()).unwrap();
+        let transition = |source| {
+            let address = "AA:BB:CC:DD:EE:01";
+            history.transition(
+                "LHB-4A3C91F2",
+                address,
+                PowerState::Sleep,
+                PowerState::On,
+                source,
+            )
+        };
+        transition(Source::Notification);
+        let first = fs::read_to_string(&path).unwrap();
+        assert!(first.starts_with(r#"{"timestamp":""#), "{}", first);
+        assert!(
+            first.ends_with(r#""name":"LHB-4A3C91F2","address":"AA:BB:CC:DD:EE:01","previous_state":"sleep","state":"on","source":"notification"}
+"#),
+            "{}",
+            first
+        );
+        // Just short of the size, which the next line pushes past.
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(MAX_SIZE - 1)
+            .unwrap();
+        transition(Source::Poll);
+        let current = fs::read_to_string(&path).unwrap();
+        let previous = fs::metadata(&rotated).unwrap().len();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(current.lines().count(), 1);
+        assert!(current.ends_with(
+            r#""source":"poll"}
+"#
+        ));
+        assert_eq!(previous, MAX_SIZE - 1);
+    }
+}
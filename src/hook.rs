@@ -0,0 +1,232 @@
+//! `--on-change`: runs a shell command whenever a base station changes its power state.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{fold, PowerState};
+
+/// A change waiting for the debounce window to pass.
+struct Pending {
+    name: String,
+    address: String,
+    /// The power state before the first change in the window.
+    old: PowerState,
+    new: PowerState,
+    due: Instant,
+}
+
+pub struct Hook {
+    command: String,
+    debounce: Duration,
+    /// By folded name.
+    pending: RefCell<BTreeMap<String, Pending>>,
+    added: Notify,
+}
+
+impl Hook {
+    pub fn new(command: String, debounce: Duration) -> Self {
+        Self {
+            command,
+            debounce,
+            pending: RefCell::new(BTreeMap::new()),
+            added: Notify::new(),
+        }
+    }
+
+    /// Runs the command, or once the base station has kept the new state for the debounce
+    /// window. Returns right away either way.
+    pub fn changed(&self, name: &str, address: &str, old: PowerState, new: PowerState) {
+        if self.debounce.is_zero() {
+            self.spawn(name, address, old, new);
+            return;
+        }
+        let due = Instant::now() + self.debounce;
+        let mut pending = self.pending.borrow_mut();
+        let entry = pending.entry(fold(name)).or_insert_with(|| Pending {
+            name: name.to_owned(),
+            address: address.to_owned(),
+            old,
+            new,
+            due,
+        });
+        entry.new = new;
+        entry.due = due;
+        self.added.notify_one();
+    }
+
+    /// Runs the debounced commands as they come due. Never returns, so it is meant to be polled
+    /// alongside the loop that reports the changes.
+    pub async fn run(&self) {
+        loop {
+            let due = self
+                .pending
+                .borrow()
+                .values()
+                .map(|pending| pending.due)
+                .min();
+            let due = match due {
+                Some(due) => due,
+                None => {
+                    self.added.notified().await;
+                    continue;
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep_until(due) => {}
+                // The window of a base station may have been pushed back.
+                _ = self.added.notified() => continue,
+            }
+            let now = Instant::now();
+            let mut fired = Vec::new();
+            self.pending.borrow_mut().retain(|_, pending| {
+                let waiting = pending.due > now;
+                if !waiting {
+                    fired.push((
+                        pending.name.clone(),
+                        pending.address.clone(),
+                        pending.old,
+                        pending.new,
+                    ));
+                }
+                waiting
+            });
+            for (name, address, old, new) in fired {
+                // Flapping back within the window is no change.
+                if old != new {
+                    self.spawn(&name, &address, old, new);
+                }
+            }
+        }
+    }
+
+    fn spawn(&self, name: &str, address: &str, old: PowerState, new: PowerState) {
+        let mut command = shell(&self.command);
+        command
+            .env("LH_NAME", name)
+            .env("LH_ADDRESS", address)
+            .env("LH_OLD", old.porcelain())
+            .env("LH_NEW", new.porcelain())
+            .stdin(Stdio::null());
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(error) => {
+                log::warn!("{}: failed to run --on-change: {}", name, error);
+                return;
+            }
+        };
+        log::info!("{}: ran --on-change for {} -> {}", name, old, new);
+        // Waited for on a thread, so that a slow command does not hold up Bluetooth.
+        let name = name.to_owned();
+        thread::spawn(move || match child.wait() {
+            Ok(status) if !status.success() => {
+                log::warn!("{}: --on-change exited with {}", name, status)
+            }
+            Ok(_) => {}
+            Err(error) => log::warn!("{}: failed to wait for --on-change: {}", name, error),
+        });
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    use super::*;
+
+    fn hook(path: &Path, debounce: Duration) -> Hook {
+        let command = format!(
+            r#"echo "$LH_NAME $LH_ADDRESS $LH_OLD $LH_NEW" >> '{}'"#,
+            path.display()
+        );
+        Hook::new(command, debounce)
+    }
+
+    /// The lines written by the commands once there are `count` of them, which run on their own.
+    fn lines(path: &Path, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let lines: Vec<_> = fs::read_to_string(path)
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_owned)
+                .collect();
+            if lines.len() >= count {
+                // Give a command that should not have run the time to show up.
+                thread::sleep(Duration::from_millis(100));
+                return fs::read_to_string(path)
+                    .unwrap()
+                    .lines()
+                    .map(str::to_owned)
+                    .collect();
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("--on-change did not run");
+    }
+
+    #[test]
+    fn runs_with_the_change_in_the_environment() {
+        let path = env::temp_dir().join(format!("lighthousectl-hook-{}", std::process::id()));
+        let hook = hook(&path, Duration::ZERO);
+        hook.changed(
+            "LHB-4A3C91F2",
+            "AA:BB:CC:DD:EE:01",
+            PowerState::Sleep,
+            PowerState::On,
+        );
+        let lines = lines(&path, 1);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(lines, ["LHB-4A3C91F2 AA:BB:CC:DD:EE:01 sleep on"]);
+    }
+
+    #[tokio::test]
+    async fn debounces_changes() {
+        let path = env::temp_dir().join(format!(
+            "lighthousectl-hook-{}-debounced",
+            std::process::id()
+        ));
+        let hook = hook(&path, Duration::from_millis(500));
+        let address = "AA:BB:CC:DD:EE:01";
+        hook.changed(
+            "LHB-4A3C91F2",
+            address,
+            PowerState::Sleep,
+            PowerState::Booting,
+        );
+        hook.changed("LHB-93D0B1E4", address, PowerState::Sleep, PowerState::On);
+        tokio::select! {
+            _ = hook.run() => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_millis(200)) => {}
+        }
+        // Settling within the window of the first change, and flapping back.
+        hook.changed("lhb-4a3c91f2", address, PowerState::Booting, PowerState::On);
+        hook.changed("LHB-93D0B1E4", address, PowerState::On, PowerState::Sleep);
+        tokio::select! {
+            _ = hook.run() => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_millis(1000)) => {}
+        }
+        let lines = lines(&path, 1);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(lines, [format!("LHB-4A3C91F2 {} sleep on", address)]);
+    }
+}
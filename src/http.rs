@@ -0,0 +1,192 @@
+//! Just enough HTTP/1.1 for `exporter` and `serve`: one request per connection.
+
+use std::{io, time::Duration};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// Requests with larger bodies are refused, since none of the endpoints need them.
+const MAX_BODY: usize = 4096;
+/// Longer request and header lines are refused, so that a client cannot fill the memory.
+const MAX_LINE: usize = 8192;
+/// How long a client has to send its request, so that one that never does is not served forever.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Reads a line of the request head, refusing lines longer than `MAX_LINE`.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+) -> io::Result<usize> {
+    line.clear();
+    let read = (&mut *reader).take(MAX_LINE as u64).read_line(line).await?;
+    if read == MAX_LINE && !line.ends_with('\n') {
+        return Err(invalid("request line too long"));
+    }
+    Ok(read)
+}
+
+pub async fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
+    match timeout(REQUEST_TIMEOUT, read(stream)).await {
+        Ok(request) => request,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out")),
+    }
+}
+
+async fn read(stream: impl AsyncRead + Unpin) -> io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    read_line(&mut reader, &mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Err(invalid("malformed request")),
+    };
+    let mut length = 0;
+    loop {
+        if read_line(&mut reader, &mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("malformed Content-Length"))?;
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(invalid("request body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Request {
+        method,
+        path,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+/// Decodes the `%XX` escapes of a path segment, such as `%3A` for the colons of an address.
+/// Malformed escapes are kept as they are, and bytes that are not UTF-8 replaced.
+pub fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut at = 0;
+    while at < bytes.len() {
+        let escaped = bytes
+            .get(at + 1..at + 3)
+            .filter(|hex| bytes[at] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                at += 3;
+            }
+            None => {
+                decoded.push(bytes[at]);
+                at += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Writes the response and closes the connection.
+pub async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Starts a response whose body is written as it comes, until the connection is closed.
+pub async fn respond_streaming(stream: &mut TcpStream, content_type: &str) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        content_type
+    );
+    stream.write_all(head.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(request: &str) -> io::Result<Request> {
+        read(request.as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn reads_requests() {
+        let request = parse("POST /stations/LHB-4A3C91F2/power HTTP/1.1\r\nHost: lh\r\ncontent-length: 2\r\n\r\nonTRAILING")
+            .await
+            .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/stations/LHB-4A3C91F2/power");
+        assert_eq!(request.body, "on");
+        // Without Content-Length, as from a client closing early.
+        let request = parse("GET /metrics HTTP/1.1\n").await.unwrap();
+        assert_eq!(
+            (request.path.as_str(), request.body.as_str()),
+            ("/metrics", "")
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_requests() {
+        let error = |request: String| async move { parse(&request).await.err().unwrap() };
+        assert_eq!(
+            error("GET\r\n\r\n".to_owned()).await.to_string(),
+            "malformed request"
+        );
+        assert_eq!(
+            error("POST / HTTP/1.1\r\nContent-Length: two\r\n\r\n".to_owned())
+                .await
+                .to_string(),
+            "malformed Content-Length"
+        );
+        let large = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert_eq!(error(large).await.to_string(), "request body too large");
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "x".repeat(MAX_LINE));
+        assert_eq!(error(long).await.to_string(), "request line too long");
+        let short = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\non".to_owned();
+        assert_eq!(error(short).await.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("00%3A00%3a01"), "00:00:01");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%+1"), "%+1");
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+    }
+}
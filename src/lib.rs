@@ -0,0 +1,1940 @@
+//! Controls the power state of Valve Base Stations over Bluetooth LE, as the `lighthousectl`
+//! command does.
+//!
+//! [`discover`] scans for base stations and yields each one connected, as a [`Lighthouse`]
+//! whose power state can then be read and written. A [`Filter`] tells which of them were asked
+//! for by name, address, glob pattern or suffix, and when all of them have been found.
+//!
+//! ```no_run
+//! use std::{collections::BTreeSet, sync::Mutex};
+//!
+//! use btleplug::{api::Manager as _, platform::Manager};
+//! use futures::StreamExt;
+//! use lighthousectl::{discover, Filter, Options, PowerCommand, PowerState};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let manager = Manager::new().await?;
+//! let central = manager.adapters().await?.remove(0);
+//! let options = Options::default();
+//! let mut filter = Filter::new(vec!["LHB-4A3C91F2".into()], false, Vec::new());
+//! let unnamed = filter.addresses();
+//! let handled = Mutex::new(BTreeSet::new());
+//! let mut stations = discover(&central, &options, &unnamed, &handled, false).await?;
+//! while let Some(discovery) = stations.next().await {
+//!     let Ok(mut lh) = discovery else { continue };
+//!     if filter.is_matched(&lh.name, lh.address) && lh.power_state(&options).await? != PowerState::On {
+//!         lh.set_power(PowerCommand::On, &options).await?;
+//!         filter.complete(&lh.name, lh.address);
+//!     }
+//!     lh.disconnect().await.ok();
+//!     if filter.is_completed() {
+//!         break;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The commands themselves handle the base stations through [`scan`], which carries out an
+//! [`Action`] on each one found and tells an [`Observer`] about every [`Record`].
+
+pub mod address;
+pub mod cache;
+mod control;
+mod error;
+#[cfg(test)]
+mod mock;
+pub mod multi;
+mod pairing;
+
+pub use control::{scan, scan_planned, Action, History, Observer, Plan, Record};
+pub use error::{Error, Operation};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{self, AtomicUsize},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use btleplug::{
+    api::{
+        bleuuid::uuid_from_u16, BDAddr, Central, CentralEvent, Characteristic, Peripheral,
+        PeripheralProperties, ScanFilter, Service, WriteType,
+    },
+    platform::PeripheralId,
+};
+use futures::{future, stream::BoxStream, StreamExt};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{timeout, Instant},
+};
+use uuid::{uuid, Uuid};
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! guard {
+    ($ex:expr, $else:expr) => {
+        if let Some(x) = $ex {
+            x
+        } else {
+            $else
+        }
+    };
+}
+
+/// The control service holding the characteristics below.
+pub const SERVICE_UUID: Uuid = uuid!("00001523-1212-efde-1523-785feabcd124");
+pub const CHARACTERISTIC_UUID: Uuid = uuid!("00001525-1212-efde-1523-785feabcd124");
+/// The service and command characteristic of V1 base stations.
+pub const V1_SERVICE_UUID: Uuid = uuid!("0000cb00-0000-1000-8000-00805f9b34fb");
+pub const V1_CHARACTERISTIC_UUID: Uuid = uuid!("0000cb01-0000-1000-8000-00805f9b34fb");
+/// Addresses V1 base stations whose unique ID is not in the config.
+pub const V1_BROADCAST_ID: u32 = 0xffff_ffff;
+pub const CHANNEL_CHARACTERISTIC_UUID: Uuid = uuid!("00001524-1212-efde-1523-785feabcd124");
+pub const IDENTIFY_CHARACTERISTIC_UUID: Uuid = uuid!("00008421-1212-efde-1523-785feabcd124");
+const IDENTIFY_VALUE: [u8; 1] = [0x00];
+/// The strings of the Device Information Service shown by `info`.
+const DEVICE_INFORMATION: [(&str, Uuid); 3] = [
+    ("model", uuid_from_u16(0x2a24)),
+    ("firmware", uuid_from_u16(0x2a26)),
+    ("hardware", uuid_from_u16(0x2a27)),
+];
+/// The GAP Device Name, read when the advertisements do not carry a local name.
+const DEVICE_NAME_UUID: Uuid = uuid_from_u16(0x2a00);
+/// How long to stay connected after identifying, since the base station only starts blinking
+/// after the write has been acknowledged.
+const IDENTIFY_DURATION: Duration = Duration::from_secs(2);
+pub const DEFAULT_CONNECT_TIMEOUT: &str = "10s";
+pub const DEFAULT_IO_TIMEOUT: &str = "5s";
+pub const DEFAULT_CONNECT_RETRIES: u32 = 2;
+pub const DEFAULT_CONNECT_BACKOFF: &str = "500ms";
+pub const DEFAULT_CONCURRENCY: usize = 3;
+/// How many times a power write is tried until it succeeds and reading back shows that it took
+/// effect.
+const WRITE_ATTEMPTS: usize = 3;
+/// How long to give the base station to act on a power write before reading it back.
+const VERIFY_DELAY: Duration = Duration::from_millis(500);
+/// How long a connection to a cached address may take before scanning for the base station.
+const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The power state of a V2 base station, as read from its power characteristic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerState {
+    Sleep,
+    Booting,
+    Standby,
+    On,
+    /// Spinning down, on the way to sleep or standby. Only ever read.
+    Stopping,
+    Unknown(u8),
+}
+
+impl From<u8> for PowerState {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0x00 => PowerState::Sleep,
+            0x01 | 0x08 | 0x09 => PowerState::Booting,
+            0x02 => PowerState::Standby,
+            0x0a => PowerState::Stopping,
+            0x0b => PowerState::On,
+            byte => PowerState::Unknown(byte),
+        }
+    }
+}
+
+/// A power state that can be written, unlike the ones that base stations only report on the
+/// way, so that nothing else can reach the power characteristic but a raw write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerCommand {
+    Sleep,
+    Standby,
+    On,
+}
+
+/// The byte written to the power characteristic.
+impl From<PowerCommand> for u8 {
+    fn from(command: PowerCommand) -> Self {
+        match command {
+            PowerCommand::Sleep => 0x00,
+            PowerCommand::Standby => 0x02,
+            PowerCommand::On => 0x01,
+        }
+    }
+}
+
+/// The state that the base station ends up in.
+impl From<PowerCommand> for PowerState {
+    fn from(command: PowerCommand) -> Self {
+        match command {
+            PowerCommand::Sleep => PowerState::Sleep,
+            PowerCommand::Standby => PowerState::Standby,
+            PowerCommand::On => PowerState::On,
+        }
+    }
+}
+
+/// Refuses the states that are only ever read, such as booting.
+impl TryFrom<PowerState> for PowerCommand {
+    type Error = Error;
+
+    fn try_from(state: PowerState) -> Result<Self, Error> {
+        match state {
+            PowerState::Sleep => Ok(PowerCommand::Sleep),
+            PowerState::Standby => Ok(PowerCommand::Standby),
+            PowerState::On => Ok(PowerCommand::On),
+            state => Err(Error::ReadOnly { state }),
+        }
+    }
+}
+
+impl Display for PowerCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        PowerState::from(*self).fmt(f)
+    }
+}
+
+impl PowerCommand {
+    /// The stable name used by `--porcelain`.
+    pub fn porcelain(self) -> &'static str {
+        PowerState::from(self).porcelain()
+    }
+
+    pub fn painted(self, color: bool) -> Painted {
+        PowerState::from(self).painted(color)
+    }
+}
+
+impl Display for PowerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PowerState::Sleep => write!(f, "SLEEP"),
+            PowerState::Standby => write!(f, "STANDBY"),
+            PowerState::Booting => write!(f, "BOOTING"),
+            PowerState::On => write!(f, "ON"),
+            PowerState::Stopping => write!(f, "STOPPING"),
+            PowerState::Unknown(byte) => write!(f, "UNKNOWN(0x{:02x})", byte),
+        }
+    }
+}
+
+impl PowerState {
+    /// The stable name used by `--porcelain`.
+    pub fn porcelain(self) -> &'static str {
+        match self {
+            PowerState::Sleep => "sleep",
+            PowerState::Booting => "booting",
+            PowerState::Standby => "standby",
+            PowerState::On => "on",
+            PowerState::Stopping => "stopping",
+            PowerState::Unknown(_) => "unknown",
+        }
+    }
+
+    /// With `--strict`, warns about a byte that no state is known for, so that it gets added.
+    pub fn check(raw_state: u8, name: &str, options: &Options) {
+        if let (true, PowerState::Unknown(byte)) = (options.strict, PowerState::from(raw_state)) {
+            log::warn!(
+                "{}: unknown power state 0x{:02x}; please open an issue at {}/issues with the \
+                 byte and what the base station was doing",
+                name,
+                byte,
+                env!("CARGO_PKG_REPOSITORY")
+            );
+        }
+    }
+
+    pub fn painted(self, color: bool) -> Painted {
+        Painted { state: self, color }
+    }
+}
+
+/// Displays a power state, colored with ANSI escape codes if enabled.
+pub struct Painted {
+    state: PowerState,
+    color: bool,
+}
+
+impl Display for Painted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.color {
+            return write!(f, "{}", self.state);
+        }
+        let code = match self.state {
+            PowerState::On => "32",
+            PowerState::Sleep | PowerState::Standby => "2",
+            PowerState::Booting | PowerState::Stopping => "33",
+            PowerState::Unknown(_) => "31",
+        };
+        write!(f, "\x1b[{}m{}\x1b[0m", code, self.state)
+    }
+}
+
+/// Stops scanning and disconnects every connected base station, so that others such as
+/// SteamVR can reach them. Other peripherals are left alone. Errors are ignored, since this is
+/// only cleaning up.
+pub async fn release(central: &impl Central, io_timeout: Duration) {
+    let _ = timeout(io_timeout, central.stop_scan()).await;
+    let peripherals = match timeout(io_timeout, central.peripherals()).await {
+        Ok(Ok(peripherals)) => peripherals,
+        _ => return,
+    };
+    for peripheral in peripherals {
+        let props = match timeout(io_timeout, peripheral.properties()).await {
+            Ok(Ok(Some(props))) => props,
+            _ => continue,
+        };
+        if Protocol::detect(&props).is_none() {
+            continue;
+        }
+        if let Ok(Ok(true)) = timeout(io_timeout, peripheral.is_connected()).await {
+            let _ = timeout(io_timeout, peripheral.disconnect()).await;
+        }
+    }
+}
+
+/// A failure that only affects a single base station.
+/// The address is unknown if the peripheral could not even be looked up.
+pub struct StationError {
+    pub name: String,
+    pub address: Option<BDAddr>,
+    /// The address as shown, which on macOS is the UUID of the peripheral.
+    pub shown_address: Option<String>,
+    pub cause: Error,
+}
+
+impl StationError {
+    /// A failure before the peripheral is known by an address.
+    pub fn new(name: &str, cause: Error) -> Self {
+        Self {
+            name: name.to_owned(),
+            address: None,
+            shown_address: None,
+            cause,
+        }
+    }
+
+    /// A failure of the peripheral known by the address.
+    pub fn at<P: Peripheral>(name: &str, p: &P, address: BDAddr, cause: Error) -> Self {
+        Self {
+            name: name.to_owned(),
+            address: Some(address),
+            shown_address: Some(address::shown(p, address)),
+            cause,
+        }
+    }
+
+    /// The base station did not answer in time, rather than refusing.
+    pub fn timed_out(&self) -> bool {
+        self.cause.is_timeout()
+    }
+}
+
+/// How base stations are connected to and written to, shared by everything handling them.
+pub struct Options {
+    /// When to stop discovering base stations. Base stations being handled are still finished.
+    pub deadline: Option<Instant>,
+    pub connect_timeout: Duration,
+    pub connect_retries: u32,
+    /// The wait before the first connect retry, doubling on each retry.
+    pub connect_backoff: Duration,
+    pub io_timeout: Duration,
+    /// How long to wait for the requested state with `--wait`.
+    pub wait_timeout: Option<Duration>,
+    pub write_type: WriteType,
+    /// Whether power writes are read back and retried, unless `--no-verify`.
+    pub verify: bool,
+    /// Whether power writes are skipped, with `--dry-run`.
+    pub dry_run: bool,
+    /// The pause between writing to one base station and the next, with `--delay-between`.
+    pub delay_between: Option<Duration>,
+    /// When the last base station was written to, held while waiting for the pause to pass.
+    pub last_write: tokio::sync::Mutex<Option<Instant>>,
+    /// Whether to stop at the first failing base station.
+    pub fail_fast: bool,
+    /// Whether base stations are handled each time they advertise, instead of once.
+    pub repeat: bool,
+    /// Whether scanning on several adapters, with `--adapter all`, which may each see the same
+    /// base station.
+    pub multi_adapter: bool,
+    /// Whether the adapter only reports peripherals advertising the control service, unless
+    /// `--no-scan-filter`.
+    pub scan_filter: bool,
+    /// Whether no scan is started, with `--passive`, taking in only what the adapter receives
+    /// while others scan and the peripherals it already knows of.
+    pub passive: bool,
+    /// Whether power state bytes that no state is known for are warned about, with `--strict`.
+    pub strict: bool,
+    /// How many base stations are connected to and handled at once.
+    pub concurrency: usize,
+    /// Held while connected to a base station, to bound the simultaneous connections.
+    pub connections: Arc<Semaphore>,
+    /// The unique IDs of V1 base stations, by case-folded name.
+    pub v1_ids: BTreeMap<String, u32>,
+    /// The base stations found before, updated as they are discovered.
+    pub cache: cache::Cache,
+    /// How many peripherals [`discover`] has looked at, each counted once, for showing progress.
+    pub considered: Arc<AtomicUsize>,
+    /// Whether base stations whose power state cannot be read until they are paired are paired
+    /// on connecting, with `--pair`.
+    pub pair: bool,
+}
+
+impl Default for Options {
+    /// The defaults of the command line, without a deadline or the station cache.
+    fn default() -> Self {
+        let duration = |default| humantime::parse_duration(default).expect("a valid default");
+        Self {
+            deadline: None,
+            connect_timeout: duration(DEFAULT_CONNECT_TIMEOUT),
+            connect_retries: DEFAULT_CONNECT_RETRIES,
+            connect_backoff: duration(DEFAULT_CONNECT_BACKOFF),
+            io_timeout: duration(DEFAULT_IO_TIMEOUT),
+            wait_timeout: None,
+            write_type: WriteType::WithoutResponse,
+            verify: true,
+            dry_run: false,
+            delay_between: None,
+            last_write: tokio::sync::Mutex::new(None),
+            fail_fast: false,
+            repeat: false,
+            multi_adapter: false,
+            scan_filter: true,
+            passive: false,
+            strict: false,
+            concurrency: DEFAULT_CONCURRENCY,
+            connections: Arc::new(Semaphore::new(DEFAULT_CONCURRENCY)),
+            v1_ids: BTreeMap::new(),
+            cache: cache::Cache::open(None, String::new()),
+            considered: Arc::default(),
+            pair: false,
+        }
+    }
+}
+
+impl Options {
+    /// Waits out `--delay-between` since the last base station was written to, before writing to
+    /// another.
+    pub async fn pace(&self) {
+        let Some(delay) = self.delay_between.filter(|_| !self.dry_run) else {
+            return;
+        };
+        let mut last_write = self.last_write.lock().await;
+        if let Some(last_write) = *last_write {
+            tokio::time::sleep_until(last_write + delay).await;
+        }
+        *last_write = Some(Instant::now());
+    }
+}
+
+/// A base station specified on the command line.
+/// Names are stored case-folded, since stations advertise their serials in uppercase.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Target {
+    Name(String),
+    Address(BDAddr),
+}
+
+impl Target {
+    pub fn parse(arg: &str) -> Self {
+        match address::parse(arg) {
+            Some(address) => Target::Address(address),
+            None => Target::Name(fold(arg)),
+        }
+    }
+}
+
+/// Case-folds a base station name, for comparing names regardless of case.
+pub fn fold(name: &str) -> String {
+    name.to_lowercase()
+}
+
+/// Formats bytes as space-separated hexadecimal pairs, like `0b 00`.
+pub fn hex(bytes: &[u8]) -> String {
+    let pairs: Vec<_> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    pairs.join(" ")
+}
+
+/// A glob pattern specified on the command line, such as `LHB-4A*`.
+#[derive(Clone)]
+struct Pattern {
+    glob: String,
+    matched: bool,
+}
+
+impl Pattern {
+    fn is_matched(&self, name: &str) -> bool {
+        glob_match(&fold(&self.glob), &fold(name))
+    }
+}
+
+/// Matches `text` against a glob, where `*` matches any sequence and `?` matches any character.
+fn glob_match(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut g, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, t));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    g = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+/// A name suffix specified with `--suffix`, such as `91F2`.
+#[derive(Clone)]
+struct Suffix {
+    suffix: String,
+    /// The first base station that matched, to detect ambiguous suffixes.
+    matched: Option<String>,
+}
+
+impl Suffix {
+    fn is_matched(&self, name: &str) -> bool {
+        fold(name).ends_with(&fold(&self.suffix))
+    }
+}
+
+/// A name given on the command line, as it is matched.
+#[derive(Clone)]
+enum Given {
+    Target(Target),
+    Pattern(Pattern),
+    Suffix(Suffix),
+}
+
+impl Given {
+    fn is_matched(&self, name: &str, address: BDAddr) -> bool {
+        match self {
+            Given::Target(Target::Name(target)) => *target == fold(name),
+            Given::Target(Target::Address(target)) => *target == address,
+            Given::Pattern(pattern) => pattern.is_matched(name),
+            Given::Suffix(suffix) => suffix.is_matched(name),
+        }
+    }
+}
+
+/// The base stations asked for, by name, address, glob pattern or suffix, keeping track of which
+/// have been found. Without names, every base station is matched.
+#[derive(Clone)]
+pub struct Filter {
+    /// `None` if nothing is specified, which matches every base station.
+    /// Each target maps to the form typed by the user, for reporting.
+    targets: Option<BTreeMap<Target, String>>,
+    /// Patterns stay active until the scan times out, since they may match many base stations.
+    patterns: Vec<Pattern>,
+    suffixes: Vec<Suffix>,
+    /// Base stations that are listed but never written to.
+    excluded: BTreeSet<Target>,
+    /// How many base stations to handle at most, with `--count`.
+    pub count: Option<usize>,
+    /// The base stations taken towards the count, and those of them that have been handled.
+    taken: BTreeSet<BDAddr>,
+    done: BTreeSet<BDAddr>,
+    /// The names in the order given, which the sets above do not keep, for `--ordered`.
+    order: Vec<Given>,
+    /// The addresses of the base stations matched by each name given in full, folded, which
+    /// tells when two of them advertise the same name.
+    claimed: BTreeMap<String, BTreeSet<BDAddr>>,
+}
+
+impl Filter {
+    /// With `suffix`, names are matched against the end of the base station names.
+    pub fn new(names: Vec<String>, suffix: bool, excluded: Vec<String>) -> Self {
+        let excluded = excluded.iter().map(|name| Target::parse(name)).collect();
+        if names.is_empty() {
+            return Self {
+                targets: None,
+                patterns: Vec::new(),
+                suffixes: Vec::new(),
+                excluded,
+                count: None,
+                taken: BTreeSet::new(),
+                done: BTreeSet::new(),
+                order: Vec::new(),
+                claimed: BTreeMap::new(),
+            };
+        }
+        let mut targets = BTreeMap::new();
+        let mut patterns = Vec::new();
+        let mut suffixes = Vec::new();
+        let mut order = Vec::new();
+        for name in names {
+            let target = Target::parse(&name);
+            if name.contains(['*', '?']) {
+                let pattern = Pattern {
+                    glob: name,
+                    matched: false,
+                };
+                order.push(Given::Pattern(pattern.clone()));
+                patterns.push(pattern);
+            } else if suffix && matches!(target, Target::Name(_)) {
+                let suffix = Suffix {
+                    suffix: name,
+                    matched: None,
+                };
+                order.push(Given::Suffix(suffix.clone()));
+                suffixes.push(suffix);
+            } else {
+                order.push(Given::Target(target.clone()));
+                targets.entry(target).or_insert(name);
+            }
+        }
+        Self {
+            targets: Some(targets),
+            patterns,
+            suffixes,
+            excluded,
+            count: None,
+            taken: BTreeSet::new(),
+            done: BTreeSet::new(),
+            order,
+            claimed: BTreeMap::new(),
+        }
+    }
+
+    /// Where the base station comes in the names given: the position of the first one that
+    /// matches it, or after all of them.
+    pub fn position(&self, name: &str, address: BDAddr) -> usize {
+        let position = self
+            .order
+            .iter()
+            .position(|given| given.is_matched(name, address));
+        position.unwrap_or(self.order.len())
+    }
+
+    pub fn is_completed(&self) -> bool {
+        if self.count.is_some_and(|count| self.done.len() >= count) {
+            return true;
+        }
+        if let Some(map) = self.targets.as_ref() {
+            map.is_empty()
+                && self.patterns.is_empty()
+                && self.suffixes.iter().all(|suffix| suffix.matched.is_some())
+        } else {
+            false
+        }
+    }
+
+    pub fn remaining(&self) -> Vec<String> {
+        let targets = self.targets.iter().flat_map(|map| map.values().cloned());
+        let patterns = self
+            .patterns
+            .iter()
+            .filter(|pattern| !pattern.matched)
+            .map(|pattern| pattern.glob.clone());
+        let suffixes = self
+            .suffixes
+            .iter()
+            .filter(|suffix| suffix.matched.is_none())
+            .map(|suffix| suffix.suffix.clone());
+        let mut remaining: Vec<_> = targets.chain(patterns).chain(suffixes).collect();
+        if let Some(count) = self.count.filter(|&count| self.done.len() < count) {
+            remaining.push(format!(
+                "{} more for --count {}",
+                count - self.done.len(),
+                count
+            ));
+        }
+        remaining
+    }
+
+    /// Counts the base station towards `--count`, unless the count has already been reached by
+    /// the others being handled or handled already.
+    pub fn take(&mut self, address: BDAddr) -> bool {
+        match self.count {
+            Some(count) if self.taken.len() >= count => self.taken.contains(&address),
+            Some(_) => {
+                self.taken.insert(address);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Fails if a suffix matches another base station than the one it matched before.
+    pub fn check_ambiguity(&self, name: &str) -> Result<(), Error> {
+        for suffix in &self.suffixes {
+            if let Some(matched) = suffix.matched.as_deref() {
+                if matched != name && suffix.is_matched(name) {
+                    return Err(Error::Ambiguous {
+                        suffix: suffix.suffix.clone(),
+                        first: matched.to_owned(),
+                        second: name.to_owned(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the base station as matched by its name, if that was given in full. Fails with
+    /// the addresses of all of them once another base station has matched the same name.
+    pub fn claim(&mut self, name: &str, address: BDAddr) -> Result<(), Error> {
+        let folded = fold(name);
+        let given = self.targets.as_ref().is_some_and(|map| {
+            map.contains_key(&Target::Name(folded.clone()))
+                && !map.contains_key(&Target::Address(address))
+        });
+        if !given && !self.claimed.contains_key(&folded) {
+            return Ok(());
+        }
+        let addresses = self.claimed.entry(folded).or_default();
+        addresses.insert(address);
+        if addresses.len() > 1 {
+            return Err(Error::Namesakes {
+                name: name.to_owned(),
+                addresses: addresses.iter().copied().collect(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The folded names given in full, which the station cache may know the addresses of.
+    pub fn names(&self) -> Vec<String> {
+        self.targets
+            .iter()
+            .flat_map(|map| map.keys())
+            .filter_map(|target| match target {
+                Target::Name(name) => Some(name.clone()),
+                Target::Address(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn addresses(&self) -> BTreeSet<BDAddr> {
+        self.targets
+            .iter()
+            .flat_map(|map| map.keys())
+            .filter_map(|target| match target {
+                Target::Address(address) => Some(*address),
+                Target::Name(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn is_matched(&self, name: &str, address: BDAddr) -> bool {
+        if let Some(map) = self.targets.as_ref() {
+            // Still matched once handled, so that another base station of the same name is noticed.
+            map.contains_key(&Target::Name(fold(name)))
+                || self.claimed.contains_key(&fold(name))
+                || map.contains_key(&Target::Address(address))
+                || self.patterns.iter().any(|pattern| pattern.is_matched(name))
+                || self
+                    .suffixes
+                    .iter()
+                    .any(|suffix| suffix.matched.is_none() && suffix.is_matched(name))
+        } else {
+            true
+        }
+    }
+
+    pub fn is_excluded(&self, name: &str, address: BDAddr) -> bool {
+        self.excluded.contains(&Target::Name(fold(name)))
+            || self.excluded.contains(&Target::Address(address))
+    }
+
+    pub fn complete(&mut self, name: &str, address: BDAddr) {
+        if self.taken.contains(&address) {
+            self.done.insert(address);
+        }
+        if let Some(map) = self.targets.as_mut() {
+            map.remove(&Target::Name(fold(name)));
+            map.remove(&Target::Address(address));
+        }
+        for pattern in &mut self.patterns {
+            if pattern.is_matched(name) {
+                pattern.matched = true;
+            }
+        }
+        for suffix in &mut self.suffixes {
+            if suffix.matched.is_none() && suffix.is_matched(name) {
+                suffix.matched = Some(name.to_owned());
+            }
+        }
+    }
+}
+
+/// Writes the power state, then reads it back and writes again until it has taken effect.
+pub async fn write_power<P: Peripheral>(
+    lh: &Lighthouse<P>,
+    command: PowerCommand,
+    options: &Options,
+) -> Result<(), Error> {
+    if options.dry_run {
+        return Ok(());
+    }
+    let bytes = [command.into()];
+    let mut failure = None;
+    for _ in 0..WRITE_ATTEMPTS {
+        let writing = lh.write_power_bytes(&bytes, options.write_type, options.io_timeout);
+        match writing.await {
+            Ok(()) => {}
+            // Writing again is no use until the base station is paired.
+            Err(error @ Error::PairingRequired { .. }) => return Err(error),
+            Err(error) => {
+                failure = Some(error);
+                tokio::time::sleep(VERIFY_DELAY).await;
+                continue;
+            }
+        }
+        if !options.verify {
+            return Ok(());
+        }
+        tokio::time::sleep(VERIFY_DELAY).await;
+        let read = lh
+            .read_power_bytes(Operation::ReadBack, options.io_timeout)
+            .await?;
+        let read = PowerState::from(*read.first().ok_or_else(|| no_data(Operation::ReadBack))?);
+        // Turning on goes through booting, which `--wait` can follow.
+        if read == command.into() || (command == PowerCommand::On && read == PowerState::Booting) {
+            return Ok(());
+        }
+        failure = Some(Error::NotTaken {
+            written: command,
+            read,
+        });
+    }
+    Err(Error::GaveUp {
+        last: Box::new(failure.expect("written at least once")),
+        attempts: WRITE_ATTEMPTS,
+    })
+}
+
+/// The 20-byte command packet of V1 base stations, addressed by their unique ID.
+pub fn v1_packet(command: PowerCommand, id: u32) -> [u8; 20] {
+    let mut packet = [0; 20];
+    let bytes: [u8; 4] = match command {
+        PowerCommand::Sleep => [0x12, 0x02, 0x00, 0x01],
+        _ => [0x12, 0x00, 0x00, 0x00],
+    };
+    packet[..4].copy_from_slice(&bytes);
+    packet[4..8].copy_from_slice(&id.to_le_bytes());
+    packet
+}
+
+/// Retries an operation with exponential backoff, since BlueZ often aborts the first connection.
+pub async fn retry<T, Fut: Future<Output = Result<T, Error>>>(
+    name: &str,
+    options: &Options,
+    mut operation: impl FnMut() -> Fut,
+) -> Result<T, Error> {
+    let mut backoff = options.connect_backoff;
+    for _ in 0..options.connect_retries {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            // Trying again is no use until the base station is paired.
+            Err(error @ Error::PairingRequired { .. }) => return Err(error),
+            Err(message) => {
+                log::info!("{}: {}, retrying in {:?}", name, message, backoff);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+    operation().await
+}
+
+/// Runs a BLE operation, naming it in its failure for per-station reporting.
+pub async fn attempt<T>(
+    operation: Operation,
+    duration: Duration,
+    future: impl Future<Output = btleplug::Result<T>>,
+) -> Result<T, Error> {
+    match timeout(duration, future).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(error)) => {
+            log::warn!(error:? = error; "{} failed: {}", operation, error);
+            if pairing::is_required(&error) {
+                return Err(Error::PairingRequired { operation });
+            }
+            Err(Error::Ble {
+                operation,
+                source: error,
+            })
+        }
+        Err(_) => Err(Error::Timeout { operation }),
+    }
+}
+
+/// The failure of a read that returned nothing to take the first byte of.
+fn no_data(operation: Operation) -> Error {
+    Error::NoData { operation }
+}
+
+/// Makes the base station blink, staying connected long enough for it to start.
+pub async fn identify<P: Peripheral>(lh: &Lighthouse<P>, options: &Options) -> Result<(), Error> {
+    let characteristic = lh.characteristic(IDENTIFY_CHARACTERISTIC_UUID, Operation::Identify)?;
+    let writing = lh
+        .peripheral
+        .write(characteristic, &IDENTIFY_VALUE, WriteType::WithResponse);
+    let result = attempt(Operation::Identify, options.io_timeout, writing).await;
+    if result.is_ok() {
+        tokio::time::sleep(IDENTIFY_DURATION).await;
+    }
+    result
+}
+
+/// Reads the RF channel, from 1 to 16.
+pub async fn read_channel<P: Peripheral>(
+    lh: &Lighthouse<P>,
+    io_timeout: Duration,
+) -> Result<u8, Error> {
+    let operation = Operation::ChannelRead;
+    let characteristic = lh.characteristic(CHANNEL_CHARACTERISTIC_UUID, operation)?;
+    let bytes = attempt(operation, io_timeout, lh.peripheral.read(characteristic)).await?;
+    bytes.first().copied().ok_or_else(|| no_data(operation))
+}
+
+/// Reads the Device Information Service strings that the base station has.
+pub async fn read_info<P: Peripheral>(
+    lh: &Lighthouse<P>,
+    io_timeout: Duration,
+) -> Result<Vec<(&'static str, Option<String>)>, Error> {
+    let mut info = Vec::new();
+    for (key, uuid) in DEVICE_INFORMATION {
+        let characteristic = guard!(lh.find_characteristic(uuid), {
+            info.push((key, None));
+            continue;
+        });
+        let operation = Operation::InfoRead(key);
+        let bytes = attempt(operation, io_timeout, lh.peripheral.read(characteristic)).await?;
+        let value = String::from_utf8_lossy(&bytes);
+        info.push((key, Some(value.trim_end_matches('\0').to_owned())));
+    }
+    Ok(info)
+}
+
+/// Writes a byte to the power characteristic with a response, and returns the byte read back.
+pub async fn write_raw<P: Peripheral>(
+    lh: &Lighthouse<P>,
+    byte: u8,
+    io_timeout: Duration,
+) -> Result<u8, Error> {
+    lh.write_power_bytes(&[byte], WriteType::WithResponse, io_timeout)
+        .await?;
+    let bytes = lh.read_power_bytes(Operation::ReadBack, io_timeout).await?;
+    bytes
+        .first()
+        .copied()
+        .ok_or_else(|| no_data(Operation::ReadBack))
+}
+
+/// Writes the RF channel and reads it back to confirm that the base station took it.
+pub async fn write_channel<P: Peripheral>(
+    lh: &Lighthouse<P>,
+    channel: u8,
+    io_timeout: Duration,
+) -> Result<(), Error> {
+    let characteristic = lh.characteristic(CHANNEL_CHARACTERISTIC_UUID, Operation::ChannelWrite)?;
+    let bytes = [channel];
+    let writing = lh
+        .peripheral
+        .write(characteristic, &bytes, WriteType::WithResponse);
+    attempt(Operation::ChannelWrite, io_timeout, writing).await?;
+    match read_channel(lh, io_timeout).await? {
+        read if read == channel => Ok(()),
+        read => Err(Error::ChannelNotTaken {
+            written: channel,
+            read,
+        }),
+    }
+}
+
+/// The generation of a base station, which decides how it is controlled.
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    /// Valve base stations 2.0, advertising the manufacturer ID 0x055d.
+    V2,
+    /// HTC base stations 1.0, named like `HTC BS 4A3C91`.
+    V1,
+}
+
+impl Protocol {
+    /// Tells base stations apart from other peripherals by their advertisement.
+    pub fn detect(props: &PeripheralProperties) -> Option<Self> {
+        let v1 = props
+            .local_name
+            .as_deref()
+            .is_some_and(|name| name.starts_with("HTC BS "));
+        if props.manufacturer_data.contains_key(&0x055d) {
+            Some(Protocol::V2)
+        } else if v1 {
+            Some(Protocol::V1)
+        } else {
+            None
+        }
+    }
+
+    /// The service holding the characteristics used to control the base station.
+    pub fn service(self) -> Uuid {
+        match self {
+            Protocol::V2 => SERVICE_UUID,
+            Protocol::V1 => V1_SERVICE_UUID,
+        }
+    }
+
+    /// The characteristic that the power state, or for V1 the command packet, is written to.
+    pub fn power_characteristic(self) -> Uuid {
+        match self {
+            Protocol::V2 => CHARACTERISTIC_UUID,
+            Protocol::V1 => V1_CHARACTERISTIC_UUID,
+        }
+    }
+}
+
+/// How long reaching a base station took, step by step, for `bench` and shown with `-v`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timings {
+    /// From starting to scan to the base station being looked at for its first advertisement
+    /// with the Valve manufacturer data. Unknown when connected to from the station cache.
+    pub advertised: Option<Duration>,
+    pub connected: Duration,
+    pub services_discovered: Duration,
+}
+
+/// A base station connected to, with the characteristics of its control service.
+pub struct Lighthouse<P> {
+    pub name: String,
+    pub protocol: Protocol,
+    pub address: BDAddr,
+    pub rssi: Option<i16>,
+    pub peripheral: P,
+    /// The characteristics of the control service, which always include the power characteristic
+    /// unless discovered for `inspect`.
+    pub characteristics: BTreeMap<Uuid, Characteristic>,
+    pub services: BTreeSet<Service>,
+    /// When the base station was first discovered, according to the station cache, and when
+    /// it was last discovered, which is during this run.
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    /// The base station stays connected until it has been handled, and this is held meanwhile.
+    pub connection: Option<OwnedSemaphorePermit>,
+    /// How long it took to be discovered and connected to.
+    pub timings: Timings,
+}
+
+impl<P> Lighthouse<P> {
+    pub fn power_characteristic(&self) -> &Characteristic {
+        &self.characteristics[&self.protocol.power_characteristic()]
+    }
+
+    /// Looks up a characteristic in any service.
+    pub fn find_characteristic(&self, uuid: Uuid) -> Option<&Characteristic> {
+        self.services
+            .iter()
+            .flat_map(|service| &service.characteristics)
+            .find(|characteristic| characteristic.uuid == uuid)
+    }
+
+    /// Looks up a characteristic in any service, failing with the UUIDs of those there are.
+    pub fn any_characteristic(&self, uuid: Uuid) -> Result<&Characteristic, Error> {
+        self.find_characteristic(uuid)
+            .ok_or_else(|| Error::NoSuchCharacteristic {
+                uuid,
+                available: self
+                    .services
+                    .iter()
+                    .flat_map(|service| &service.characteristics)
+                    .map(|characteristic| characteristic.uuid)
+                    .collect(),
+            })
+    }
+
+    /// Looks up a characteristic needed for `operation`, which some firmwares may lack.
+    pub fn characteristic(
+        &self,
+        uuid: Uuid,
+        operation: Operation,
+    ) -> Result<&Characteristic, Error> {
+        self.characteristics
+            .get(&uuid)
+            .ok_or_else(|| Error::CharacteristicMissing {
+                operation,
+                address: self.address,
+            })
+    }
+}
+
+impl<P: Peripheral> Lighthouse<P> {
+    /// Disconnects, letting another base station be connected to.
+    pub async fn disconnect(&mut self) -> btleplug::Result<()> {
+        let result = self.peripheral.disconnect().await;
+        self.connection = None;
+        result
+    }
+
+    /// Connects again for the duration of `operation` only, for base stations kept after being
+    /// handled. The services are already known, so this is much faster than discovery.
+    pub async fn session<T>(
+        &self,
+        options: &Options,
+        operation: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let _connection = options.connections.acquire().await;
+        let session = async {
+            let connecting = || {
+                let connecting = self.peripheral.connect();
+                attempt(Operation::Connect, options.connect_timeout, connecting)
+            };
+            let started = Instant::now();
+            retry(&self.name, options, connecting).await?;
+            log::debug!("{}: connected in {:?}", self.name, started.elapsed());
+            let result = operation.await;
+            self.peripheral.disconnect().await.ok();
+            result
+        };
+        scoped(self.name.clone(), self.address, session).await
+    }
+
+    /// Connects again unless still connected, as base stations may drop the connection once
+    /// asleep.
+    pub async fn reconnect(&self, options: &Options) -> Result<(), Error> {
+        let checking = self.peripheral.is_connected();
+        if attempt(Operation::CheckConnection, options.io_timeout, checking).await? {
+            return Ok(());
+        }
+        log::info!("{}: disconnected, connecting again", self.name);
+        let connecting = || {
+            attempt(
+                Operation::Connect,
+                options.connect_timeout,
+                self.peripheral.connect(),
+            )
+        };
+        retry(&self.name, options, connecting).await
+    }
+
+    /// Reads the power state, connecting again first if needed. V1 base stations cannot tell.
+    pub async fn power_state(&self, options: &Options) -> Result<PowerState, Error> {
+        if let Protocol::V1 = self.protocol {
+            return Err(Error::Unsupported(
+                "the power state of V1 base stations cannot be read",
+            ));
+        }
+        self.reconnect(options).await?;
+        let raw_state = self.read_power(options.io_timeout).await?;
+        PowerState::check(raw_state, &self.name, options);
+        Ok(raw_state.into())
+    }
+
+    /// Writes the power state, connecting again first if needed. V2 base stations are read back
+    /// with `--verify`, while V1 base stations get the command packet for their ID, and only go
+    /// on or to sleep.
+    pub async fn set_power(&self, command: PowerCommand, options: &Options) -> Result<(), Error> {
+        if let (Protocol::V1, PowerCommand::Standby) = (self.protocol, command) {
+            return Err(Error::Unsupported(
+                "standby is unsupported on V1, use sleep instead",
+            ));
+        }
+        if options.dry_run {
+            return Ok(());
+        }
+        self.reconnect(options).await?;
+        if let Protocol::V2 = self.protocol {
+            return write_power(self, command, options).await;
+        }
+        let id = options
+            .v1_ids
+            .get(&fold(&self.name))
+            .copied()
+            .unwrap_or(V1_BROADCAST_ID);
+        let packet = v1_packet(command, id);
+        self.write_power_bytes(&packet, WriteType::WithResponse, options.io_timeout)
+            .await
+    }
+
+    /// Reads the raw power state once connected.
+    pub async fn read_power(&self, io_timeout: Duration) -> Result<u8, Error> {
+        let bytes = self.read_power_bytes(Operation::Read, io_timeout).await?;
+        Ok(*bytes.first().ok_or_else(|| no_data(Operation::Read))?)
+    }
+
+    /// The address to show for the base station, which on macOS is the UUID of the peripheral,
+    /// since the address is hidden there.
+    pub fn shown_address(&self) -> String {
+        address::shown(&self.peripheral, self.address)
+    }
+
+    /// Reads the power characteristic, showing how long it took with `-v` and the bytes with
+    /// `-vv`.
+    pub async fn read_power_bytes(
+        &self,
+        operation: Operation,
+        io_timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        self.read_bytes(self.power_characteristic(), operation, io_timeout)
+            .await
+    }
+
+    /// Reads a characteristic, showing how long it took with `-v` and the bytes with `-vv`.
+    pub async fn read_bytes(
+        &self,
+        characteristic: &Characteristic,
+        operation: Operation,
+        io_timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let reading = self.peripheral.read(characteristic);
+        let started = Instant::now();
+        let bytes = attempt(operation, io_timeout, reading).await?;
+        log::debug!("{}: {} in {:?}", self.name, operation, started.elapsed());
+        log::trace!("{}: {} {:02x?}", self.name, operation, bytes);
+        Ok(bytes)
+    }
+
+    /// Pairs with the base station if reading its power state is denied until then, with
+    /// `--pair`. Other failures are left to the reads that follow.
+    async fn pair_if_denied(&self, io_timeout: Duration) -> Result<(), Error> {
+        match self.read_power_bytes(Operation::Read, io_timeout).await {
+            Err(Error::PairingRequired { .. }) => {
+                log::info!("{}: reading was denied, pairing", self.name);
+                pairing::pair(self.address).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the power characteristic, showing how long it took with `-v` and the bytes with
+    /// `-vv`.
+    pub async fn write_power_bytes(
+        &self,
+        bytes: &[u8],
+        write_type: WriteType,
+        io_timeout: Duration,
+    ) -> Result<(), Error> {
+        self.write_bytes(self.power_characteristic(), bytes, write_type, io_timeout)
+            .await
+    }
+
+    /// Writes a characteristic, showing how long it took with `-v` and the bytes with `-vv`.
+    pub async fn write_bytes(
+        &self,
+        characteristic: &Characteristic,
+        bytes: &[u8],
+        write_type: WriteType,
+        io_timeout: Duration,
+    ) -> Result<(), Error> {
+        log::trace!("{}: write {:02x?}", self.name, bytes);
+        let writing = self.peripheral.write(characteristic, bytes, write_type);
+        let started = Instant::now();
+        attempt(Operation::Write, io_timeout, writing).await?;
+        log::debug!("{}: written in {:?}", self.name, started.elapsed());
+        Ok(())
+    }
+}
+
+/// A discovered base station, or why it could not be used.
+pub type Discovery<P> = std::result::Result<Lighthouse<P>, StationError>;
+
+/// Connects to a base station and gathers its characteristics. `direct` connections to cached
+/// addresses get a single short try, since scanning is the fallback. Returns `None` if the
+/// peripheral turns out not to be a usable base station.
+async fn open<P: Peripheral>(
+    p: P,
+    name: Option<String>,
+    protocol: Protocol,
+    props: &PeripheralProperties,
+    options: &Options,
+    direct: bool,
+    keep_unusable: bool,
+) -> Option<Discovery<P>> {
+    let connection = options.connections.clone().acquire_owned().await.ok()?;
+    let peripheral = &p;
+    // Until connected, when the device name can be read.
+    let who = name.clone().unwrap_or_else(|| props.address.to_string());
+    let local_name = who.as_str();
+    let connect_timeout = if direct {
+        DIRECT_CONNECT_TIMEOUT
+    } else {
+        options.connect_timeout
+    };
+    let connecting = || async move {
+        let connecting = async {
+            let started = Instant::now();
+            peripheral.connect().await?;
+            let connected = started.elapsed();
+            peripheral.discover_services().await?;
+            Ok(Timings {
+                advertised: None,
+                connected,
+                services_discovered: started.elapsed() - connected,
+            })
+        };
+        let result = attempt(Operation::Connect, connect_timeout, connecting).await;
+        match &result {
+            Ok(timings) => log::debug!(
+                "{}: connected in {:?}, services discovered in {:?}",
+                local_name,
+                timings.connected,
+                timings.services_discovered
+            ),
+            Err(_) => {
+                peripheral.disconnect().await.ok();
+            }
+        }
+        result
+    };
+    let connected = async {
+        match direct {
+            true => connecting().await,
+            false => retry(&who, options, connecting).await,
+        }
+    };
+    let connected = scoped(who.clone(), props.address, connected);
+    let timings = match connected.await {
+        Ok(timings) => timings,
+        Err(error) => return Some(Err(StationError::at(&who, &p, props.address, error))),
+    };
+    let name = match name {
+        Some(name) => name,
+        None => match read_device_name(&p, props.address, options.io_timeout).await {
+            Ok(name) => name,
+            Err(error) => {
+                log::debug!(
+                    "{}: no local name, and the device name cannot be read either, {}; \
+                     target it by address instead",
+                    props.address,
+                    error
+                );
+                p.disconnect().await.ok();
+                return None;
+            }
+        },
+    };
+    let seen = options.cache.seen(&name, props.address);
+    let characteristics: BTreeMap<_, _> = p
+        .characteristics()
+        .into_iter()
+        .filter(|ch| ch.service_uuid == protocol.service())
+        .map(|ch| (ch.uuid, ch))
+        .collect();
+    let power_characteristic = protocol.power_characteristic();
+    if !keep_unusable && !characteristics.contains_key(&power_characteristic) {
+        log::debug!(
+            "{}: not a base station, missing the power characteristic",
+            name
+        );
+        p.disconnect().await.ok();
+        return None;
+    }
+    let pairable =
+        matches!(protocol, Protocol::V2) && characteristics.contains_key(&power_characteristic);
+    let lh = Lighthouse {
+        name,
+        protocol,
+        address: props.address,
+        rssi: props.rssi,
+        services: p.services(),
+        first_seen: seen.first_seen,
+        last_seen: seen.last_seen,
+        peripheral: p,
+        characteristics,
+        connection: Some(connection),
+        timings,
+    };
+    // V1 base stations have nothing to read, and so nothing to be denied.
+    if options.pair && pairable {
+        if let Err(error) = lh.pair_if_denied(options.io_timeout).await {
+            lh.peripheral.disconnect().await.ok();
+            return Some(Err(StationError::at(
+                &lh.name,
+                &lh.peripheral,
+                lh.address,
+                error,
+            )));
+        }
+    }
+    Some(Ok(lh))
+}
+
+/// Reads the GAP Device Name, for base stations whose advertisements lack the local name, as on
+/// Windows for the first few of them.
+async fn read_device_name<P: Peripheral>(
+    p: &P,
+    address: BDAddr,
+    io_timeout: Duration,
+) -> Result<String, Error> {
+    let characteristic = p
+        .characteristics()
+        .into_iter()
+        .find(|ch| ch.uuid == DEVICE_NAME_UUID)
+        .ok_or_else(|| Error::CharacteristicMissing {
+            operation: Operation::DeviceNameRead,
+            address,
+        })?;
+    let bytes = attempt(
+        Operation::DeviceNameRead,
+        io_timeout,
+        p.read(&characteristic),
+    )
+    .await?;
+    let name = String::from_utf8_lossy(&bytes);
+    let name = name.trim_end_matches('\0').trim();
+    if name.is_empty() {
+        return Err(no_data(Operation::DeviceNameRead));
+    }
+    Ok(name.to_owned())
+}
+
+/// The discovery events processed by [`discover`], reported with `-v` once it is done, to tell
+/// how much the scan filter spares.
+struct EventCount {
+    count: AtomicUsize,
+    started: Instant,
+    filtered: bool,
+}
+
+impl Drop for EventCount {
+    fn drop(&mut self) {
+        log::debug!(
+            "processed {} discovery events in {:.1?}, {}",
+            self.count.load(atomic::Ordering::Relaxed),
+            self.started.elapsed(),
+            match self.filtered {
+                true => "filtered by the control service",
+                false => "without a scan filter",
+            }
+        );
+    }
+}
+
+/// Connects straight to the named base stations that the adapter still knows at their cached
+/// addresses, without waiting for them to advertise. BlueZ keeps the devices it has seen, while
+/// other platforms only know what has been scanned in this run. The base stations that do not
+/// answer quickly are dropped from the cache and left to [`discover`].
+pub async fn connect_cached<'a, C: Central>(
+    central: &'a C,
+    filter: &Filter,
+    options: &'a Options,
+    handled: &'a Mutex<BTreeSet<PeripheralId>>,
+    keep_unusable: bool,
+) -> Option<BoxStream<'a, Discovery<C::Peripheral>>> {
+    if options.repeat {
+        return None;
+    }
+    let cached: BTreeMap<_, _> = filter
+        .names()
+        .iter()
+        .filter_map(|name| options.cache.get(name))
+        .map(|entry| (entry.address, entry))
+        .collect();
+    if cached.is_empty() {
+        return None;
+    }
+    let peripherals = match central.peripherals().await {
+        Ok(peripherals) => peripherals,
+        Err(error) => {
+            log::debug!(
+                "cannot list the known peripherals, scanning instead: {}",
+                error
+            );
+            return None;
+        }
+    };
+    let mut known = Vec::new();
+    for p in peripherals {
+        let props = guard!(address::properties(&p).await.ok().flatten(), continue);
+        if let Some(entry) = cached.get(&props.address) {
+            known.push((p, props, entry.name.clone()));
+        }
+    }
+    if known.is_empty() {
+        return None;
+    }
+    log::debug!(
+        "connecting to {} cached base stations directly",
+        known.len()
+    );
+    let stream = futures::stream::iter(known)
+        .map(move |(p, props, name)| async move {
+            let id = p.id();
+            handled.lock().unwrap().insert(id.clone());
+            // The advertisement may be long gone, but only V1 base stations have to be told apart.
+            let protocol = Protocol::detect(&props).unwrap_or(Protocol::V2);
+            let local_name = props.local_name.clone().unwrap_or_else(|| name.clone());
+            match open(
+                p,
+                Some(local_name),
+                protocol,
+                &props,
+                options,
+                true,
+                keep_unusable,
+            )
+            .await
+            {
+                Some(Ok(lh)) => Some(Ok(lh)),
+                opened => {
+                    if let Some(Err(error)) = opened {
+                        log::info!(
+                            "{}: {} at the cached address, scanning instead",
+                            name,
+                            error.cause
+                        );
+                    }
+                    options.cache.forget(&name);
+                    handled.lock().unwrap().remove(&id);
+                    None
+                }
+            }
+        })
+        .buffer_unordered(options.concurrency)
+        .filter_map(future::ready)
+        .boxed();
+    Some(stream)
+}
+
+/// Base stations without a local name are only accepted if their address is in `unnamed`,
+/// and they are named after their address.
+/// With `keep_unusable`, base stations lacking the power characteristic are kept too.
+pub async fn discover<'a, C: Central>(
+    central: &'a C,
+    options: &'a Options,
+    unnamed: &'a BTreeSet<BDAddr>,
+    handled: &'a Mutex<BTreeSet<PeripheralId>>,
+    keep_unusable: bool,
+) -> Result<BoxStream<'a, Discovery<C::Peripheral>>, Error> {
+    let failed = |operation| move |source| Error::Ble { operation, source };
+    // Subscribe first, so that no event is missed and scanning is not started on failure.
+    let events = central.events().await.map_err(failed(Operation::Events))?;
+    let scan_filter = match options.scan_filter {
+        true => ScanFilter {
+            services: vec![SERVICE_UUID, V1_SERVICE_UUID],
+        },
+        false => ScanFilter::default(),
+    };
+    let started = Instant::now();
+    if !options.passive {
+        central
+            .start_scan(scan_filter)
+            .await
+            .map_err(failed(Operation::StartScan))?;
+    }
+    // BlueZ only reports a peripheral it already knows of again once its properties change,
+    // which may never happen without scanning, so those are looked at from the properties it
+    // keeps instead.
+    let known = match options.passive {
+        true => central
+            .peripherals()
+            .await
+            .map_err(failed(Operation::KnownPeripherals))?
+            .iter()
+            .map(|p| CentralEvent::DeviceDiscovered(p.id()))
+            .collect(),
+        false => Vec::new(),
+    };
+    let events_seen = Arc::new(EventCount {
+        count: AtomicUsize::new(0),
+        started: Instant::now(),
+        filtered: options.scan_filter,
+    });
+    // Peripherals keep advertising, so each reason is only shown once per peripheral with `-v`.
+    let rejected = Arc::new(Mutex::new(BTreeSet::new()));
+    let unreadable = Arc::new(Mutex::new(BTreeSet::new()));
+    // The peripherals whose manufacturer data, from the events of backends that report it, is
+    // from another company than Valve.
+    let others = Arc::new(Mutex::new(BTreeSet::new()));
+    const NOT_VALVE: &str = "no 0x055d manufacturer data";
+    let filtered_others = others.clone();
+    let considered = Arc::new(Mutex::new(BTreeSet::new()));
+    let advertised = Arc::new(Mutex::new(BTreeMap::new()));
+    Ok(futures::stream::iter(known)
+        .chain(events)
+        .filter_map(move |ev| {
+            let events_seen = events_seen.clone();
+            let others = filtered_others.clone();
+            async move {
+                match ev {
+                    CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) => {
+                        events_seen.count.fetch_add(1, atomic::Ordering::Relaxed);
+                        Some(id)
+                    }
+                    // Often the first event of a base station, so it is looked at right away.
+                    CentralEvent::ManufacturerDataAdvertisement {
+                        id,
+                        manufacturer_data,
+                    } => {
+                        events_seen.count.fetch_add(1, atomic::Ordering::Relaxed);
+                        if manufacturer_data.contains_key(&0x055d) {
+                            others.lock().unwrap().remove(&id);
+                            Some(id)
+                        } else {
+                            others.lock().unwrap().insert(id);
+                            None
+                        }
+                    }
+                    _ => None,
+                }
+            }
+        })
+        .map(move |id| {
+            let rejected = rejected.clone();
+            let unreadable = unreadable.clone();
+            let others = others.clone();
+            let advertised = advertised.clone();
+            if considered.lock().unwrap().insert(id.clone()) {
+                options.considered.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+            async move {
+                let reject = |who: &str, reason: &'static str| {
+                    if rejected.lock().unwrap().insert((id.clone(), reason)) {
+                        log::debug!("{}: not a base station, {}", who, reason);
+                    }
+                };
+                // Looked at once still, since V1 base stations are told by their names.
+                let other = others.lock().unwrap().contains(&id);
+                if other && rejected.lock().unwrap().contains(&(id.clone(), NOT_VALVE)) {
+                    return None;
+                }
+                let p = match central.peripheral(&id).await {
+                    Ok(p) => p,
+                    Err(source) => {
+                        let operation = Operation::Lookup;
+                        let error = Error::Ble { operation, source };
+                        return Some(Err(StationError::new(&format!("{:?}", id), error)));
+                    }
+                };
+                let props = match address::properties(&p).await {
+                    Ok(Some(props)) => props,
+                    Ok(None) => {
+                        reject(&format!("{:?}", id), "no properties");
+                        return None;
+                    }
+                    Err(source) => {
+                        let operation = Operation::Properties;
+                        let error = Error::Ble { operation, source };
+                        return Some(Err(StationError::new(&format!("{:?}", id), error)));
+                    }
+                };
+                let who = props
+                    .local_name
+                    .clone()
+                    .unwrap_or_else(|| props.address.to_string());
+                log::trace!(
+                    "{}: manufacturer data {:02x?}, services {:?}, service data {:02x?}, RSSI {:?}",
+                    who,
+                    props.manufacturer_data,
+                    props.services,
+                    props.service_data,
+                    props.rssi
+                );
+                let protocol = guard!(Protocol::detect(&props), {
+                    reject(&who, NOT_VALVE);
+                    return None;
+                });
+                let first_advertised = *advertised
+                    .lock()
+                    .unwrap()
+                    .entry(id.clone())
+                    .or_insert_with(|| {
+                        let elapsed = started.elapsed();
+                        log::debug!("{}: advertised {:?} after starting to scan", who, elapsed);
+                        elapsed
+                    });
+                let local_name = match props.local_name.clone() {
+                    Some(local_name) => Some(local_name),
+                    None if unnamed.contains(&props.address) => {
+                        Some(address::shown(&p, props.address))
+                    }
+                    // Read from the device name once connected, once per peripheral.
+                    None if unreadable.lock().unwrap().contains(&id) => {
+                        reject(&who, "no local name");
+                        return None;
+                    }
+                    None => None,
+                };
+                // Base stations keep advertising, so updates would connect to them over and over.
+                if !options.repeat && handled.lock().unwrap().contains(&id) {
+                    return None;
+                }
+                let (p, props) = match options.multi_adapter {
+                    true => multi::strongest(central, p, props).await,
+                    false => (p, props),
+                };
+                // Seen by several adapters under their own IDs, but handled through one of them.
+                if !options.repeat && !handled.lock().unwrap().insert(p.id()) {
+                    return None;
+                }
+                handled.lock().unwrap().insert(id.clone());
+                let opened_id = p.id();
+                let nameless = local_name.is_none();
+                let mut opened = open(
+                    p,
+                    local_name,
+                    protocol,
+                    &props,
+                    options,
+                    false,
+                    keep_unusable,
+                )
+                .await;
+                if let Some(Ok(lh)) = &mut opened {
+                    lh.timings.advertised = Some(first_advertised);
+                }
+                if nameless && opened.is_none() {
+                    // Handled again once the advertisements carry the local name.
+                    unreadable.lock().unwrap().insert(id.clone());
+                    handled.lock().unwrap().remove(&id);
+                    handled.lock().unwrap().remove(&opened_id);
+                }
+                opened
+            }
+        })
+        // Connecting takes seconds, so other base stations are connected to meanwhile.
+        .buffer_unordered(options.concurrency)
+        .filter_map(future::ready)
+        .boxed())
+}
+
+tokio::task_local! {
+    /// The base station being talked to.
+    static STATION: (String, BDAddr);
+}
+
+/// Runs `future` with the records it logs attributed to the base station.
+pub async fn scoped<F: Future>(name: String, address: BDAddr, future: F) -> F::Output {
+    STATION.scope((name, address), future).await
+}
+
+/// The base station that the records being logged are about, within [`scoped`].
+pub fn current_station() -> Option<(String, BDAddr)> {
+    STATION.try_with(Clone::clone).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(names: &[&str]) -> Filter {
+        Filter::new(
+            names.iter().map(|name| name.to_string()).collect(),
+            false,
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn names_match_case_insensitively() {
+        let mut filter = filter(&["lhb-4a3c91f2"]);
+        assert!(filter.is_matched("LHB-4A3C91F2", BDAddr::default()));
+        assert!(!filter.is_matched("LHB-93D0B1E4", BDAddr::default()));
+        filter.complete("LHB-4A3C91F2", BDAddr::default());
+        assert!(filter.is_completed());
+    }
+
+    #[test]
+    fn remaining_keeps_typed_form() {
+        let filter = filter(&["Lhb-4A3c91F2"]);
+        assert_eq!(filter.remaining(), ["Lhb-4A3c91F2"]);
+    }
+
+    #[test]
+    fn duplicates_differing_by_case_are_merged() {
+        let mut filter = filter(&["lhb-4a3c91f2", "LHB-4A3C91F2"]);
+        assert_eq!(filter.remaining(), ["lhb-4a3c91f2"]);
+        filter.complete("LHB-4A3C91F2", BDAddr::default());
+        assert!(filter.is_completed());
+    }
+
+    #[test]
+    fn v1_packets_carry_the_id() {
+        let packet = v1_packet(PowerCommand::Sleep, 0x1a4a3c91);
+        assert_eq!(
+            packet[..8],
+            [0x12, 0x02, 0x00, 0x01, 0x91, 0x3c, 0x4a, 0x1a]
+        );
+        assert!(packet[8..].iter().all(|&byte| byte == 0));
+        assert_eq!(
+            v1_packet(PowerCommand::On, V1_BROADCAST_ID)[..4],
+            [0x12, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn patterns_and_suffixes_match_case_insensitively() {
+        let filter = Filter::new(vec!["lhb-4a*".into(), "b1e4".into()], true, Vec::new());
+        assert!(filter.is_matched("LHB-4A3C91F2", BDAddr::default()));
+        assert!(filter.is_matched("LHB-93D0B1E4", BDAddr::default()));
+        assert!(!filter.is_matched("LHB-93D0B1E5", BDAddr::default()));
+    }
+
+    #[test]
+    fn peripheral_uuids_match_like_addresses() {
+        let filter = filter(&["1B4E28BA-2FA1-11D2-883F-0016D3CCA427"]);
+        let address = BDAddr::from([0x00, 0x16, 0xd3, 0xcc, 0xa4, 0x27]);
+        assert!(filter.is_matched("LHB-4A3C91F2", address));
+        assert!(!filter.is_matched("LHB-4A3C91F2", BDAddr::default()));
+        // Without hyphens, it is taken for a name.
+        let filter = self::filter(&["1b4e28ba2fa111d2883f0016d3cca427"]);
+        assert!(!filter.is_matched("LHB-4A3C91F2", address));
+    }
+
+    #[test]
+    fn names_advertised_twice_are_refused() {
+        let addresses: Vec<BDAddr> = (1..=2).map(|i| [0, 0, 0, 0, 0, i].into()).collect();
+        let mut filter = filter(&["LHB-4A3C91F2"]);
+        assert!(filter.claim("LHB-4A3C91F2", addresses[0]).is_ok());
+        filter.complete("LHB-4A3C91F2", addresses[0]);
+        assert!(filter.is_completed());
+        assert!(filter.claim("LHB-4A3C91F2", addresses[0]).is_ok());
+        assert!(filter.is_matched("lhb-4a3c91f2", addresses[1]));
+        match filter.claim("lhb-4a3c91f2", addresses[1]) {
+            Err(Error::Namesakes {
+                addresses: found, ..
+            }) => assert_eq!(found, addresses),
+            _ => panic!("the second base station was not refused"),
+        }
+        assert!(filter.claim("LHB-93D0B1E4", addresses[1]).is_ok());
+    }
+
+    #[test]
+    fn positions_follow_the_names_given() {
+        let address: BDAddr = [0, 0, 0, 0, 0, 1].into();
+        let filter = Filter::new(
+            vec!["b1e4".into(), "lhb-4a*".into(), address.to_string()],
+            true,
+            Vec::new(),
+        );
+        assert_eq!(filter.position("LHB-93D0B1E4", BDAddr::default()), 0);
+        assert_eq!(filter.position("LHB-4A3C91F2", BDAddr::default()), 1);
+        assert_eq!(filter.position("LHB-1D0E77B5", address), 2);
+        assert_eq!(filter.position("LHB-1D0E77B5", BDAddr::default()), 3);
+    }
+
+    #[test]
+    fn the_count_completes_once_handled() {
+        let mut filter = Filter::new(Vec::new(), false, Vec::new());
+        filter.count = Some(2);
+        let addresses: Vec<BDAddr> = (1..=3).map(|i| [0, 0, 0, 0, 0, i].into()).collect();
+        assert!(filter.take(addresses[0]));
+        assert!(filter.take(addresses[1]));
+        assert!(!filter.take(addresses[2]));
+        filter.complete("LHB-4A3C91F2", addresses[0]);
+        assert!(!filter.is_completed());
+        assert_eq!(filter.remaining(), ["1 more for --count 2"]);
+        filter.complete("LHB-1D0E77B5", addresses[1]);
+        assert!(filter.is_completed());
+    }
+
+    #[test]
+    fn read_only_states_are_never_written() {
+        assert_eq!(PowerState::from(0x0a), PowerState::Stopping);
+        assert_eq!(PowerState::Stopping.to_string(), "STOPPING");
+        assert!(PowerCommand::try_from(PowerState::Stopping).is_err());
+        assert!(PowerCommand::try_from(PowerState::Booting).is_err());
+        assert!(PowerCommand::try_from(PowerState::from(0x0c)).is_err());
+        let on = PowerCommand::try_from(PowerState::On).unwrap();
+        assert_eq!(u8::from(on), 0x01);
+    }
+
+    async fn discovered(
+        central: &mock::MockCentral,
+        options: &Options,
+    ) -> Vec<Lighthouse<mock::MockPeripheral>> {
+        let unnamed = BTreeSet::new();
+        let handled = Mutex::new(BTreeSet::new());
+        let stream = discover(central, options, &unnamed, &handled, false)
+            .await
+            .unwrap();
+        stream
+            .filter_map(|found| future::ready(found.ok()))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn only_base_stations_with_the_power_characteristic_are_discovered() {
+        let central = mock::MockCentral::new(vec![
+            mock::MockPeripheral::other(1, "Keyboard"),
+            mock::MockPeripheral::base_station(2, "LHB-4A3C91F2", 0x00)
+                .without_power_characteristic(),
+            mock::MockPeripheral::base_station(3, "LHB-93D0B1E4", 0x00),
+        ]);
+        let found = discovered(&central, &Options::default()).await;
+        let names: Vec<_> = found.iter().map(|lh| lh.name.as_str()).collect();
+        assert_eq!(names, ["LHB-93D0B1E4"]);
+    }
+
+    #[tokio::test]
+    async fn passive_discovery_finds_the_known_base_stations() {
+        let central = mock::MockCentral::new(vec![mock::MockPeripheral::base_station(
+            1,
+            "LHB-4A3C91F2",
+            0x00,
+        )])
+        .without_advertisements();
+        assert!(discovered(&central, &Options::default()).await.is_empty());
+        let options = Options {
+            passive: true,
+            ..Options::default()
+        };
+        let found = discovered(&central, &options).await;
+        assert_eq!(found[0].name, "LHB-4A3C91F2");
+    }
+
+    #[tokio::test]
+    async fn turning_a_sleeping_base_station_on_writes_0x01() {
+        let central = mock::MockCentral::new(vec![mock::MockPeripheral::base_station(
+            1,
+            "LHB-4A3C91F2",
+            0x00,
+        )]);
+        let options = Options::default();
+        let lh = discovered(&central, &options).await.pop().unwrap();
+        assert_eq!(lh.power_state(&options).await.unwrap(), PowerState::Sleep);
+        lh.set_power(PowerCommand::On, &options).await.unwrap();
+        assert_eq!(central.peripherals[0].writes(), [[0x01]]);
+        assert_eq!(lh.power_state(&options).await.unwrap(), PowerState::Booting);
+    }
+
+    #[tokio::test]
+    async fn denied_writes_ask_for_pairing_without_writing_again() {
+        let central = mock::MockCentral::new(vec![mock::MockPeripheral::base_station(
+            1,
+            "LHB-4A3C91F2",
+            0x00,
+        )]);
+        let options = Options::default();
+        let lh = discovered(&central, &options).await.pop().unwrap();
+        central.peripherals[0].log.lock().unwrap().denying = true;
+        let error = write_power(&lh, PowerCommand::On, &options)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), "pairing_required");
+        assert_eq!(central.peripherals[0].log.lock().unwrap().denied, 1);
+    }
+
+    #[tokio::test]
+    async fn denied_reads_ask_for_pairing_without_retrying() {
+        let options = Options::default();
+        let tries = AtomicUsize::new(0);
+        let reading = || async {
+            tries.fetch_add(1, atomic::Ordering::Relaxed);
+            let denied = async { Err::<Vec<u8>, _>(btleplug::Error::PermissionDenied) };
+            attempt(Operation::Read, options.io_timeout, denied).await
+        };
+        let error = retry("LHB-4A3C91F2", &options, reading).await.unwrap_err();
+        assert_eq!(error.kind(), "pairing_required");
+        assert_eq!(tries.into_inner(), 1);
+    }
+}
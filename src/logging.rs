@@ -0,0 +1,249 @@
+//! Where diagnostics go, `--log-target`: stderr as before, or journald or syslog when running as a
+//! service. Results are printed to stdout either way.
+//!
+//! Records about a base station carry its name and address as the `name` and `address` fields,
+//! either given to the log macro or taken from [`scoped`]. journald gets the fields as `LH_NAME`
+//! and `LH_ADDRESS`, so that `journalctl LH_NAME=LHB-4A3C91F2` shows one base station.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    Level, LevelFilter, Log, Metadata, Record,
+};
+
+const IDENTIFIER: &str = "lighthousectl";
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogTarget {
+    Stderr,
+    /// The native protocol of the systemd journal, with structured fields.
+    Journald,
+    /// The local syslog socket, with the fields appended to the message.
+    Syslog,
+}
+
+/// Sets up logging. `RUST_LOG` filters the records as usual, and defaults to `info` for journald
+/// and syslog, since they keep the records out of the way. `verbosity`, from `-v` and `-q`,
+/// overrides it for this crate.
+pub fn init(target: LogTarget, verbosity: Option<LevelFilter>) -> Result<()> {
+    let default = match target {
+        LogTarget::Stderr => "error",
+        LogTarget::Journald | LogTarget::Syslog => "info",
+    };
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default));
+    if let Some(level) = verbosity {
+        builder.filter_module(env!("CARGO_CRATE_NAME"), level);
+    }
+    let sink = match target {
+        LogTarget::Stderr => {
+            builder.init();
+            return Ok(());
+        }
+        LogTarget::Journald => Sink::journald()?,
+        LogTarget::Syslog => Sink::syslog()?,
+    };
+    let filter = builder.build();
+    log::set_max_level(filter.filter());
+    log::set_boxed_logger(Box::new(Logger { filter, sink }))?;
+    Ok(())
+}
+
+struct Logger {
+    /// Only used for its filter.
+    filter: env_logger::Logger,
+    sink: Sink,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        let mut fields = fields.0;
+        if let Some((name, address)) = lighthousectl::current_station() {
+            if !fields.iter().any(|(key, _)| key == "name") {
+                fields.push(("name".to_owned(), name));
+            }
+            if !fields.iter().any(|(key, _)| key == "address") {
+                fields.push(("address".to_owned(), address.to_string()));
+            }
+        }
+        // There is nowhere left to report failing to log.
+        let _ = self.sink.send(record, &fields);
+    }
+
+    fn flush(&self) {}
+}
+
+/// The key-value pairs of a record, formatted.
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.as_str().to_owned(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// The syslog severity, which journald takes as the priority too.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// The journal field for a record field, such as `LH_NAME` for `name`.
+fn journal_key(key: &str) -> String {
+    let key: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    format!("LH_{}", key)
+}
+
+/// Appends a field in the journal's native protocol, where values spanning lines are prefixed
+/// with their length.
+fn journal_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+fn journal_entry(record: &Record, fields: &[(String, String)]) -> Vec<u8> {
+    let mut entry = Vec::new();
+    journal_field(&mut entry, "MESSAGE", &record.args().to_string());
+    journal_field(
+        &mut entry,
+        "PRIORITY",
+        &severity(record.level()).to_string(),
+    );
+    journal_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    journal_field(&mut entry, "TARGET", record.target());
+    for (key, value) in fields {
+        journal_field(&mut entry, &journal_key(key), value);
+    }
+    entry
+}
+
+/// A line for the local syslog daemon, in the daemon facility.
+fn syslog_line(record: &Record, fields: &[(String, String)]) -> String {
+    const DAEMON: u8 = 3;
+    let mut line = format!(
+        "<{}>{}[{}]: {}",
+        DAEMON * 8 + severity(record.level()),
+        IDENTIFIER,
+        std::process::id(),
+        record.args()
+    );
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={:?}", key, value));
+    }
+    line
+}
+
+#[cfg(unix)]
+enum Sink {
+    Journald(std::os::unix::net::UnixDatagram),
+    Syslog(std::os::unix::net::UnixDatagram),
+}
+
+#[cfg(unix)]
+impl Sink {
+    fn journald() -> Result<Self> {
+        let socket = connect(&["/run/systemd/journal/socket"], "journald")?;
+        Ok(Sink::Journald(socket))
+    }
+
+    fn syslog() -> Result<Self> {
+        let socket = connect(&["/dev/log", "/var/run/syslog", "/var/run/log"], "syslog")?;
+        Ok(Sink::Syslog(socket))
+    }
+
+    fn send(&self, record: &Record, fields: &[(String, String)]) -> std::io::Result<()> {
+        match self {
+            Sink::Journald(socket) => socket.send(&journal_entry(record, fields)).map(drop),
+            Sink::Syslog(socket) => socket
+                .send(syslog_line(record, fields).as_bytes())
+                .map(drop),
+        }
+    }
+}
+
+/// Connects to the first of the sockets that exists.
+#[cfg(unix)]
+fn connect(paths: &[&str], daemon: &str) -> Result<std::os::unix::net::UnixDatagram> {
+    use anyhow::{anyhow, Context};
+
+    let path = paths
+        .iter()
+        .find(|path| std::path::Path::new(path).exists())
+        .ok_or_else(|| anyhow!("cannot log to {}, {} does not exist", daemon, paths[0]))?;
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket
+        .connect(path)
+        .with_context(|| format!("cannot log to {} at {}", daemon, path))?;
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+enum Sink {}
+
+#[cfg(not(unix))]
+impl Sink {
+    fn journald() -> Result<Self> {
+        anyhow::bail!("journald is only supported on Linux")
+    }
+
+    fn syslog() -> Result<Self> {
+        anyhow::bail!("syslog is only supported on Unix")
+    }
+
+    fn send(&self, _: &Record, _: &[(String, String)]) -> std::io::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_entries() {
+        let fields = [
+            ("name".to_owned(), "LHB-4A3C91F2".to_owned()),
+            ("error".to_owned(), "a\nb".to_owned()),
+        ];
+        let args = format_args!("connect failed");
+        let record = Record::builder()
+            .args(args)
+            .level(Level::Warn)
+            .target("lighthousectl")
+            .build();
+        let mut expected = b"MESSAGE=connect failed\nPRIORITY=4\nSYSLOG_IDENTIFIER=lighthousectl\n\
+            TARGET=lighthousectl\nLH_NAME=LHB-4A3C91F2\nLH_ERROR\n"
+            .to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(journal_entry(&record, &fields), expected);
+    }
+}
@@ -1,197 +1,2831 @@
-use std::{collections::BTreeSet, fmt::Display};
+mod bench;
+mod completions;
+mod config;
+#[cfg(unix)]
+mod daemon;
+mod doctor;
+mod ensure;
+mod exporter;
+mod format;
+mod gatt;
+mod history;
+mod hook;
+mod http;
+mod json;
+mod logging;
+#[cfg(test)]
+#[path = "mock.rs"]
+mod mock;
+mod monitor;
+mod mqtt;
+mod notify;
+mod progress;
+mod radios;
+mod serve;
+mod signal;
+mod stations;
+mod steamvr;
+mod supervisor;
+#[cfg(unix)]
+mod tui;
+mod watch;
 
-use anyhow::Result;
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::Display,
+    io::{self, BufRead, IsTerminal},
+    net::SocketAddr,
+    path::PathBuf,
+    process::{self, ExitCode},
+    str::FromStr,
+    sync::{atomic, Arc},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
 use btleplug::{
-    api::{Central, CentralEvent, Characteristic, Manager as _, Peripheral, ScanFilter, WriteType},
-    platform::Manager,
+    api::{Central, CharPropFlags, Manager as _, Peripheral, WriteType},
+    platform::{Adapter, Manager},
 };
-use clap::{Parser, ValueEnum};
-use futures::{stream::BoxStream, StreamExt, TryStreamExt};
-use uuid::{uuid, Uuid};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use config::Config;
+use format::{Field, Template};
+use futures::{future::LocalBoxFuture, FutureExt};
+use json::{Object, ToJson};
+use lighthousectl::{
+    attempt, cache, discover, fold, guard, hex, identify, multi, read_channel, release, scan,
+    scan_planned, write_power, Action, Error, Filter, History, Lighthouse, Observer, Operation,
+    Options, Plan, PowerCommand, PowerState, Protocol, Record, StationError,
+    CHANNEL_CHARACTERISTIC_UUID, CHARACTERISTIC_UUID, DEFAULT_CONCURRENCY, DEFAULT_CONNECT_BACKOFF,
+    DEFAULT_CONNECT_RETRIES, DEFAULT_CONNECT_TIMEOUT, DEFAULT_IO_TIMEOUT,
+    IDENTIFY_CHARACTERISTIC_UUID, V1_CHARACTERISTIC_UUID,
+};
+use tokio::{sync::Semaphore, time::Instant};
+use uuid::Uuid;
 
-const CHARACTERISTIC_UUID: Uuid = uuid!("00001525-1212-efde-1523-785feabcd124");
-
-macro_rules! guard {
-    ($ex:expr, $else:expr) => {
-        if let Some(x) = $ex {
-            x
-        } else {
-            $else
-        }
-    };
-}
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_WAIT_TIMEOUT: &str = "60s";
+/// How long to wait before another pass over the base stations that failed, with `--retries`.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// How long `power-cycle` leaves the base stations asleep, enough for the rotors to spin down.
+const DEFAULT_CYCLE_DELAY: Duration = Duration::from_secs(5);
+/// Some of the named base stations were never found.
+const EXIT_NOT_FOUND: u8 = 2;
+/// Some base stations were found but failed.
+const EXIT_FAILED: u8 = 3;
+/// There is no usable Bluetooth adapter, so that scripts can tell a broken Bluetooth stack apart
+/// from base stations that were not found.
+const EXIT_NO_ADAPTER: u8 = 4;
+/// Some base stations were found but did not answer in time.
+const EXIT_TIMED_OUT: u8 = 5;
+/// `ensure --once` had to correct some base stations.
+const EXIT_CORRECTED: u8 = 6;
 
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Every V2 command connects to each base station and first reads its power \
+                  characteristic, 00001525-1212-efde-1523-785feabcd124, for the power state it \
+                  shows. V1 base stations have no power state to read, and only take the power \
+                  commands. See `lighthousectl <COMMAND> --help` for what else a command reads \
+                  and writes."
+)]
 struct Cli {
-    #[clap(value_enum)]
+    /// Config file providing default names and options.
+    /// Defaults to `~/.config/lighthousectl/config.toml`.
+    #[clap(long, global = true, env = "LIGHTHOUSECTL_CONFIG", value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+    config: Option<PathBuf>,
+    #[clap(subcommand)]
     command: Command,
-    /// Base station names to control or show.
+    /// Stop once this many base stations have been handled, instead of scanning endlessly.
+    /// Base stations found after that are ignored.
+    #[clap(
+        long,
+        global = true,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    count: Option<usize>,
+    /// Stop scanning after this duration (e.g. `30`, `30s`, `2m`).
+    /// Defaults to 30 seconds when names are specified, otherwise it scans endlessly.
+    /// Base stations that are already being controlled are finished first.
+    #[clap(long, global = true, env = "LIGHTHOUSECTL_TIMEOUT", value_parser = parse_duration)]
+    timeout: Option<Duration>,
+    /// Give up connecting to a base station after this duration.
+    #[clap(
+        long,
+        global = true,
+        env = "LIGHTHOUSECTL_CONNECT_TIMEOUT",
+        value_parser = parse_duration,
+        default_value = DEFAULT_CONNECT_TIMEOUT
+    )]
+    connect_timeout: Duration,
+    /// How many times to retry connecting to a base station and reading its power state.
+    #[clap(
+        long,
+        global = true,
+        env = "LIGHTHOUSECTL_CONNECT_RETRIES",
+        default_value_t = DEFAULT_CONNECT_RETRIES
+    )]
+    connect_retries: u32,
+    /// How long to wait before the first connect retry. The wait doubles on each retry.
+    #[clap(
+        long,
+        global = true,
+        env = "LIGHTHOUSECTL_CONNECT_BACKOFF",
+        value_parser = parse_duration,
+        default_value = DEFAULT_CONNECT_BACKOFF
+    )]
+    connect_backoff: Duration,
+    /// How many base stations to connect to at once. Some adapters fail with too many
+    /// simultaneous connections. Use 1 to handle base stations one by one.
+    #[clap(
+        long,
+        global = true,
+        value_name = "N",
+        env = "LIGHTHOUSECTL_CONCURRENCY",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        default_value_t = DEFAULT_CONCURRENCY
+    )]
+    concurrency: usize,
+    /// Give up reading or writing the power state after this duration.
+    #[clap(
+        long,
+        global = true,
+        env = "LIGHTHOUSECTL_IO_TIMEOUT",
+        value_parser = parse_duration,
+        default_value = DEFAULT_IO_TIMEOUT
+    )]
+    io_timeout: Duration,
+    /// After all base stations have been handled, try the failed ones again up to this many
+    /// times. Each pass scans again for up to `--timeout`.
+    #[clap(
+        long,
+        global = true,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with = "fail_fast"
+    )]
+    retries: u32,
+    /// Stop at the first base station that fails, instead of continuing with the others.
+    #[clap(long, global = true)]
+    fail_fast: bool,
+    /// Handle base stations again each time they advertise, instead of once per run.
+    /// Without names, this keeps reading their states.
+    #[clap(long, global = true)]
+    repeat: bool,
+    /// How power states are written. Writing with response lets the base station acknowledge it,
+    /// which may be more reliable over long range.
+    #[clap(
+        long,
+        global = true,
+        env = "LIGHTHOUSECTL_WRITE_MODE",
+        value_enum,
+        default_value_t = WriteMode::WithoutResponse
+    )]
+    write_mode: WriteMode,
+    /// Do not read the power state back after writing it, nor retry writes that did not take effect.
+    #[clap(long, global = true)]
+    no_verify: bool,
+    /// Do not read or update the station cache of the base stations found before.
+    #[clap(long, global = true, env = "LIGHTHOUSECTL_NO_CACHE")]
+    no_cache: bool,
+    /// Scan for every peripheral, instead of only those advertising the control service. For
+    /// adapters and backends that leave the service out of the advertisements and find nothing.
+    #[clap(long, global = true, env = "LIGHTHOUSECTL_NO_SCAN_FILTER")]
+    no_scan_filter: bool,
+    /// Do not scan, only take in the advertisements BlueZ receives while other programs scan and
+    /// the base stations it already knows of, so as not to disturb other tools on the adapter.
+    /// Finding base stations may take longer, or not happen while nothing else scans. Only works
+    /// on Linux.
+    #[clap(long, global = true, env = "LIGHTHOUSECTL_PASSIVE")]
+    passive: bool,
+    /// Warn about power state bytes that no state is known for, such as from newer firmware,
+    /// with how to report them.
+    #[clap(long, global = true, env = "LIGHTHOUSECTL_STRICT")]
+    strict: bool,
+    /// Pair with base stations whose power state cannot be read until they are paired, which
+    /// may show a system dialog. Those already paired are left alone. Only works on Windows.
+    #[clap(long, global = true, env = "LIGHTHOUSECTL_PAIR")]
+    pair: bool,
+    /// The command `off` stands for.
+    #[clap(
+        long,
+        global = true,
+        env = "LIGHTHOUSECTL_OFF_MEANS",
+        value_enum,
+        default_value_t = OffMeans::Sleep
+    )]
+    off_means: OffMeans,
+    /// Bluetooth adapter to use, either an index or a part of its name.
+    /// If nothing is specified, the first adapter is used. `all` scans on every adapter at once,
+    /// connecting to each base station through the adapter that receives it the strongest.
+    #[clap(long, global = true, env = "LIGHTHOUSECTL_ADAPTER")]
+    adapter: Option<String>,
+    /// Whether to color the power states.
+    #[clap(
+        long,
+        global = true,
+        env = "LIGHTHOUSECTL_COLOR",
+        value_enum,
+        default_value_t = ColorChoice::Auto
+    )]
+    color: ColorChoice,
+    /// Where diagnostics go. Results are still printed to stdout.
+    #[clap(
+        long,
+        global = true,
+        env = "LIGHTHOUSECTL_LOG_TARGET",
+        value_enum,
+        default_value_t = logging::LogTarget::Stderr
+    )]
+    log_target: logging::LogTarget,
+    /// Show more diagnostics: `-v` shows why peripherals are not taken for base stations and how
+    /// long each step took, `-vv` also shows their advertisements and the bytes read and written.
+    #[clap(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Show no diagnostics, only the results and errors.
+    #[clap(short, long, global = true)]
+    quiet: bool,
+    /// Send `scan`, `on`, `sleep`, `standby` and `off` to a running `lighthousectl daemon`
+    /// instead of scanning, which is much faster.
+    #[clap(long, global = true, env = "LIGHTHOUSECTL_DAEMON")]
+    daemon: bool,
+    /// The socket of the daemon. Defaults to `$XDG_RUNTIME_DIR/lighthousectl.sock`.
+    #[clap(long, global = true, value_name = "PATH", env = "LIGHTHOUSECTL_SOCKET", value_hint = clap::ValueHint::FilePath)]
+    socket: Option<PathBuf>,
+    /// Print the results as a JSON object once the run has finished, with counts of the base
+    /// stations that succeeded, failed or were missing.
+    #[clap(long, global = true, group = "output")]
+    json: bool,
+    /// Print a JSON object per line for each event as it happens.
+    #[clap(long, global = true, group = "output")]
+    json_lines: bool,
+    /// Print the results as CSV rows with a header line.
+    #[clap(long, global = true, group = "output")]
+    csv: bool,
+    /// Print the results as an aligned table once the run has finished.
+    #[clap(long, global = true, group = "output")]
+    table: bool,
+    /// Print a stable, tab-separated record per base station: `name`, `state`, `raw_byte`,
+    /// `result` (`ok`, `set:<state>`, `error:<reason>`, ...) and `address`.
+    #[clap(long, global = true, group = "output")]
+    porcelain: bool,
+    /// Print each base station with a template such as `"{name} {address} {state}"`.
+    /// Placeholders: name, address, state, raw_state, requested_state, rssi, channel, bytes,
+    /// timestamp.
+    #[clap(long, global = true, group = "output", value_parser = Template::from_str)]
+    format: Option<Template>,
+    /// Prefix each line of the plain output with an RFC 3339 timestamp, as `scan --interval`
+    /// always does.
+    #[clap(long, global = true)]
+    timestamps: bool,
+    /// Hold the results until the scan has finished, then print them in the order of their
+    /// names, ending with a summary line of the counts.
+    #[clap(long, global = true, conflicts_with = "json_lines")]
+    sorted: bool,
+}
+
+/// The base stations a command handles.
+#[derive(Args, Clone, Default)]
+struct Targets {
+    /// Base station names, glob patterns (`LHB-4A*`) or Bluetooth addresses (`AA:BB:CC:DD:EE:FF`,
+    /// or on macOS the peripheral UUIDs shown instead) to control or show. `-` reads them from stdin and `@group` expands to a group in the config.
     /// If nothing is specified, it will scan endlessly and show or control all discovered base stations.
+    ///
+    /// [env: LIGHTHOUSECTL_NAMES, separated by whitespace]
     names: Vec<String>,
+    /// Match names against the end of the base station names, e.g. `91F2` for `LHB-4A3C91F2`.
+    /// It is an error if a suffix matches more than one base station.
+    #[clap(long)]
+    suffix: bool,
+    /// Allow `-` to read no names from stdin, which shows or controls all base stations.
+    #[clap(long)]
+    allow_empty_stdin: bool,
+    /// Base station name or address to leave untouched when no names are specified.
+    /// Excluded base stations are still shown. Can be repeated.
+    #[clap(long, value_name = "NAME", conflicts_with = "names")]
+    exclude: Vec<String>,
+}
+
+/// The options of the commands that write power states.
+#[derive(Args, Clone, Default)]
+struct PowerArgs {
+    /// Only print the power states that would be written, after discovering and reading the
+    /// base stations as usual.
+    #[clap(long)]
+    dry_run: bool,
+    /// Wait until the base stations report the requested state, printing the states they pass
+    /// through.
+    #[clap(long, visible_alias = "progress")]
+    wait: bool,
+    /// Give up waiting for a base station to reach the requested state after this duration.
+    #[clap(
+        long,
+        env = "LIGHTHOUSECTL_WAIT_TIMEOUT",
+        value_parser = parse_duration,
+        default_value = DEFAULT_WAIT_TIMEOUT
+    )]
+    wait_timeout: Duration,
+    /// Find the base stations right away, reporting the missing ones, but power them only once
+    /// this duration has passed, such as `10m`. Ctrl-C meanwhile cancels without writing.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    after: Option<Duration>,
+    /// Pause this long between writing to one base station and the next, such as `2s` so that
+    /// they do not all draw their inrush current at once. The base stations are found first,
+    /// then written to one at a time, in the order of their names or with `--ordered` as given.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    delay_between: Option<Duration>,
+    /// Find every base station first, within `--timeout`, then handle them one at a time in the
+    /// order the names were given. The missing ones are reported before anything is written.
+    #[clap(long)]
+    ordered: bool,
+    /// Find every named base station first, within `--timeout`, and handle none of them unless
+    /// all were found, exiting with status 2 and listing the missing ones otherwise.
+    #[clap(long)]
+    require_all: bool,
+}
+
+/// The options of the commands that follow the power states as they change.
+#[derive(Args, Clone, Default)]
+struct ChangeArgs {
+    /// Run this shell command whenever a base station changes its power state, with `LH_NAME`,
+    /// `LH_ADDRESS`, `LH_OLD` and `LH_NEW` in its environment.
+    #[clap(long, value_name = "COMMAND")]
+    on_change: Option<String>,
+    /// With `--on-change`, wait until a base station has kept its power state this long before
+    /// running the command, so that flapping runs it once or not at all.
+    #[clap(long, value_parser = parse_duration, requires = "on_change")]
+    debounce: Option<Duration>,
+    /// Append a JSON line to this file for each power state transition. It is rotated to
+    /// `<PATH>.1` past 10 MiB.
+    #[clap(long, value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+    history_file: Option<PathBuf>,
+}
+
+fn parse_duration(s: &str) -> Result<Duration, humantime::DurationError> {
+    if let Ok(secs) = s.parse() {
+        return Ok(Duration::from_secs(secs));
+    }
+    humantime::parse_duration(s)
 }
 
-#[derive(Clone, ValueEnum)]
+/// The commands, each with the options that only make sense for it.
+#[derive(Subcommand)]
 enum Command {
-    On,
-    Sleep,
-    Standby,
-    Scan,
+    /// Turn the base stations on.
+    ///
+    /// Writes 0x01 to the power characteristic, then reads it back until it has left sleep or
+    /// standby. V1 base stations get the wake-up packet for their ID instead.
+    On {
+        #[clap(flatten)]
+        targets: Targets,
+        #[clap(flatten)]
+        power: PowerArgs,
+        /// Show a desktop notification once the base stations are on, or listing those that are
+        /// not once `--wait-timeout` passes. Implies `--wait`. Without a notification service,
+        /// rings the terminal bell and prints the message instead.
+        #[clap(long, conflicts_with = "dry_run")]
+        notify: bool,
+    },
+    /// Put the base stations to sleep, which turns the motors off.
+    ///
+    /// Writes 0x00 to the power characteristic, then reads it back. V1 base stations get the
+    /// sleep packet for their ID instead.
+    Sleep {
+        #[clap(flatten)]
+        targets: Targets,
+        #[clap(flatten)]
+        power: PowerArgs,
+    },
+    /// Put the base stations in standby, which keeps the motors spinning and wakes up faster.
+    ///
+    /// Writes 0x02 to the power characteristic, then reads it back. Unsupported on V1.
+    Standby {
+        #[clap(flatten)]
+        targets: Targets,
+        #[clap(flatten)]
+        power: PowerArgs,
+    },
+    /// Same as `sleep`, or `standby` with `--off-means standby`.
+    Off {
+        #[clap(flatten)]
+        targets: Targets,
+        #[clap(flatten)]
+        power: PowerArgs,
+    },
+    /// Show the power states of the base stations.
+    ///
+    /// Only reads the power characteristic, and writes nothing.
+    Scan {
+        #[clap(flatten)]
+        targets: Targets,
+        /// Read the power states again at this interval until Ctrl-C, printing only the changes.
+        /// The base stations found are reused instead of scanning again.
+        #[clap(long, value_parser = parse_duration)]
+        interval: Option<Duration>,
+        /// Print every sample instead of only the changes.
+        #[clap(long, requires = "interval")]
+        always_print: bool,
+        #[clap(flatten)]
+        changes: ChangeArgs,
+        /// With `--interval`, turn the base stations off before exiting on Ctrl-C or SIGTERM.
+        #[clap(long, requires = "interval")]
+        sleep_on_exit: bool,
+    },
+    /// Turn sleeping or standing-by base stations on, and turn base stations that are on to sleep.
+    ///
+    /// Writes 0x01 or 0x00 to the power characteristic depending on the state read, and leaves
+    /// booting or stopping base stations alone.
+    Toggle {
+        #[clap(flatten)]
+        targets: Targets,
+        #[clap(flatten)]
+        power: PowerArgs,
+    },
+    /// Put the base stations to sleep, then turn them back on after `--cycle-delay`, such as to
+    /// get one out of a stuck tracking state.
+    ///
+    /// Writes 0x00 to the power characteristic, then 0x01, reading each back.
+    PowerCycle {
+        #[clap(flatten)]
+        targets: Targets,
+        #[clap(flatten)]
+        power: PowerArgs,
+        /// How long the base stations are left asleep before being turned back on, 5 seconds by
+        /// default.
+        #[clap(long, value_parser = parse_duration)]
+        cycle_delay: Option<Duration>,
+    },
+    /// Make the specified base stations blink, to tell which one is which.
+    ///
+    /// Writes 0x00 to the identify characteristic, `00008421-1212-efde-1523-785feabcd124`.
+    Identify {
+        #[clap(flatten)]
+        targets: Targets,
+    },
+    /// Show the RF channel of each base station along with its power state.
+    ///
+    /// Also reads the channel characteristic, `00001524-1212-efde-1523-785feabcd124`, and writes
+    /// nothing.
+    Channel {
+        #[clap(flatten)]
+        targets: Targets,
+    },
+    /// Change the RF channel of the specified base stations.
+    ///
+    /// Reads the channel characteristic, `00001524-1212-efde-1523-785feabcd124`, then writes the
+    /// channel to it and reads it back.
+    SetChannel {
+        /// The channel, from 1 to 16.
+        #[clap(value_parser = parse_channel)]
+        channel: u8,
+        #[clap(flatten)]
+        targets: Targets,
+    },
+    /// Write an arbitrary byte to the power characteristic, e.g. `raw 0x09`.
+    /// Asks for confirmation unless `--yes` is given.
+    ///
+    /// Writes the byte as given, then reads the power characteristic back and shows what it
+    /// holds, without verifying anything.
+    Raw {
+        /// The byte, in hex with or without `0x`.
+        #[clap(value_parser = parse_byte)]
+        byte: u8,
+        #[clap(flatten)]
+        targets: Targets,
+        /// Do not ask for confirmation before writing.
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Show every byte of the power characteristic in hex, along with the decoded power state.
+    ///
+    /// Only reads the power characteristic, and writes nothing.
+    Read {
+        #[clap(flatten)]
+        targets: Targets,
+    },
+    /// List every GATT service and characteristic of the base stations, even those lacking the
+    /// power characteristic. Always prints text.
+    ///
+    /// Only discovers the services, and neither reads nor writes any characteristic.
+    Inspect {
+        #[clap(flatten)]
+        targets: Targets,
+    },
+    /// Show the model number, firmware revision and hardware revision of each base station.
+    ///
+    /// Also reads the Device Information characteristics 0x2a24, 0x2a26 and 0x2a27, and writes
+    /// nothing.
+    Info {
+        #[clap(flatten)]
+        targets: Targets,
+    },
+    /// List Bluetooth adapters without scanning.
+    Adapters,
+    /// Check the Bluetooth setup step by step, up to a base station advertising, with hints at
+    /// what to fix. Exits with an error if any check fails.
+    ///
+    /// Only scans, and connects to nothing.
+    Doctor,
+    /// Stay connected to the base stations and show each power state change as it happens,
+    /// until Ctrl-C or SIGTERM.
+    ///
+    /// Subscribes to the power characteristic, reading it instead where notifications are not
+    /// supported, and writes nothing.
+    Watch {
+        #[clap(flatten)]
+        targets: Targets,
+        #[clap(flatten)]
+        changes: ChangeArgs,
+        /// Turn the base stations off before exiting on Ctrl-C or SIGTERM.
+        #[clap(long)]
+        sleep_on_exit: bool,
+    },
+    /// Keep running, tracking the base stations and taking commands from `--daemon` clients.
+    /// Stops on SIGTERM or Ctrl-C.
+    ///
+    /// Reads the power characteristic of the base stations found in the background, and writes
+    /// to it only for the commands taken.
+    Daemon {
+        #[clap(flatten)]
+        targets: Targets,
+        /// Turn the base stations off before exiting on Ctrl-C or SIGTERM.
+        #[clap(long)]
+        sleep_on_exit: bool,
+    },
+    /// Poll the base stations and serve Prometheus metrics at `--listen`, until Ctrl-C or SIGTERM.
+    ///
+    /// Reads the power characteristic at each `--interval`, and writes nothing.
+    Exporter {
+        #[clap(flatten)]
+        targets: Targets,
+        /// How often to poll the base stations, 30 seconds by default.
+        #[clap(long, value_parser = parse_duration)]
+        interval: Option<Duration>,
+        /// The address to listen on, `127.0.0.1:9785` by default.
+        #[clap(long, value_name = "ADDR", env = "LIGHTHOUSECTL_LISTEN")]
+        listen: Option<SocketAddr>,
+        /// Turn the base stations off before exiting on Ctrl-C or SIGTERM.
+        #[clap(long)]
+        sleep_on_exit: bool,
+    },
+    /// Bridge the base stations to the MQTT broker in the `[mqtt]` table of the config, with
+    /// Home Assistant discovery, until Ctrl-C or SIGTERM.
+    ///
+    /// Reads the power characteristic to publish the states, and writes to it only for the
+    /// commands received.
+    Mqtt {
+        #[clap(flatten)]
+        targets: Targets,
+        /// Turn the base stations off before exiting on Ctrl-C or SIGTERM.
+        #[clap(long)]
+        sleep_on_exit: bool,
+    },
+    /// Serve an HTTP API for showing and controlling the base stations at `--listen`, until
+    /// Ctrl-C or SIGTERM.
+    ///
+    /// Reads the power characteristic of the base stations found in the background, and writes
+    /// to it only for the power requests.
+    Serve {
+        #[clap(flatten)]
+        targets: Targets,
+        /// The address to listen on, `127.0.0.1:8090` by default.
+        #[clap(long, value_name = "ADDR", env = "LIGHTHOUSECTL_LISTEN")]
+        listen: Option<SocketAddr>,
+        /// Turn the base stations off before exiting on Ctrl-C or SIGTERM.
+        #[clap(long)]
+        sleep_on_exit: bool,
+    },
+    /// Keep the base stations in a power state, turning them back whenever they drift.
+    /// Runs until Ctrl-C or SIGTERM unless `--once` is given.
+    ///
+    /// Reads the power characteristic at each `--interval`, and writes the state to it only
+    /// where it differs.
+    Ensure {
+        /// The power state to keep: `on`, `sleep`, `standby` or `off`.
+        state: String,
+        #[clap(flatten)]
+        targets: Targets,
+        /// How often to check the base stations, 60 seconds by default.
+        #[clap(long, value_parser = parse_duration)]
+        interval: Option<Duration>,
+        /// Check every base station once and exit, with status 6 if some had to be corrected.
+        #[clap(long)]
+        once: bool,
+        /// Turn the base stations off before exiting on Ctrl-C or SIGTERM.
+        #[clap(long, conflicts_with = "once")]
+        sleep_on_exit: bool,
+    },
+    /// Turn the base stations on while SteamVR is running, and off once it has been gone for
+    /// `--idle-delay`, until Ctrl-C or SIGTERM.
+    ///
+    /// Writes 0x01 or the state of `--off-means` to the power characteristic when SteamVR starts
+    /// or stops, and nothing in between.
+    SteamvrWatch {
+        #[clap(flatten)]
+        targets: Targets,
+        /// How long SteamVR has to be gone before the base stations are turned off, 5 minutes by
+        /// default. Restarting SteamVR within it keeps them on.
+        #[clap(long, value_parser = parse_duration)]
+        idle_delay: Option<Duration>,
+        /// Only print the power states that would be written.
+        #[clap(long)]
+        dry_run: bool,
+        /// Turn the base stations off before exiting on Ctrl-C or SIGTERM.
+        #[clap(long)]
+        sleep_on_exit: bool,
+    },
+    /// Check that the specified base stations are in the `--expect` power state, without
+    /// writing anything. Exits with status 3 if some are not.
+    ///
+    /// Only reads the power characteristic.
+    Check {
+        #[clap(flatten)]
+        targets: Targets,
+        /// The power state the base stations must be in: `on`, `sleep`, `standby` or `off`.
+        #[clap(long, value_name = "STATE")]
+        expect: String,
+    },
+    /// Show the station cache, or drop base stations from it. Works without an adapter.
+    Cache {
+        #[clap(subcommand)]
+        operation: CacheCommand,
+    },
+    /// Print the completion script for a shell.
+    Completions {
+        #[clap(value_parser = ["bash", "zsh", "fish", "powershell"])]
+        shell: String,
+    },
+    /// Open a terminal UI listing the base stations with their power state, RSSI and channel,
+    /// where the selected one can be turned on, to sleep or to standby, or identified.
+    ///
+    /// Reads the power and channel characteristics, and writes to the power or identify
+    /// characteristic only when asked to from the UI.
+    Tui {
+        #[clap(flatten)]
+        targets: Targets,
+    },
+    /// Time each step of reaching the base stations, from starting to scan to their first
+    /// advertisement, connecting, discovering services, reading and writing, and print the
+    /// latencies by base station.
+    ///
+    /// Reads the power characteristic, and with `--write` also writes the state read back to
+    /// it, leaving the base stations as they were.
+    Bench {
+        #[clap(flatten)]
+        targets: Targets,
+        /// Repeat this many times, scanning anew each time, and show the minimum, median and
+        /// maximum of each step.
+        #[clap(
+            long,
+            value_name = "N",
+            default_value_t = 1,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        iterations: usize,
+        /// Also time writing, by writing the power state read back to each base station. This
+        /// writes to the base stations, though they are left in the state they were in.
+        #[clap(long)]
+        write: bool,
+    },
+    /// Read or write any characteristic by its UUID, in whichever service it is, printing the
+    /// bytes in hexadecimal. For exploring the protocol.
+    Gatt {
+        #[clap(subcommand)]
+        operation: GattCommand,
+    },
+    /// Print the base station names known from the station cache, for the completion scripts.
+    #[clap(name = "__complete-names", hide = true)]
+    CompleteNames,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// List the base stations in the station cache.
+    List,
+    /// Drop base stations from the station cache, so that they are looked for again.
+    #[clap(group(clap::ArgGroup::new("forgotten").args(["names", "all"]).required(true)))]
+    Forget {
+        names: Vec<String>,
+        /// Forget every cached base station.
+        #[clap(long)]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum GattCommand {
+    /// Read the characteristic of the base stations.
+    Read {
+        /// The UUID of the characteristic, e.g. `00001525-1212-efde-1523-785feabcd124`.
+        characteristic: Uuid,
+        #[clap(flatten)]
+        targets: Targets,
+    },
+    /// Write the characteristic of the named base stations, after asking for confirmation.
+    /// `--write-mode` picks whether the base stations respond.
+    Write {
+        /// The UUID of the characteristic, e.g. `00001525-1212-efde-1523-785feabcd124`.
+        characteristic: Uuid,
+        /// The bytes to write in hexadecimal, e.g. `0b00`.
+        #[clap(value_parser = parse_bytes)]
+        bytes: HexBytes,
+        #[clap(flatten)]
+        targets: Targets,
+        /// Write without asking for confirmation.
+        #[clap(long)]
+        yes: bool,
+    },
+}
+
+impl GattCommand {
+    fn targets(&self) -> &Targets {
+        match self {
+            GattCommand::Read { targets, .. } | GattCommand::Write { targets, .. } => targets,
+        }
+    }
+}
+
+impl Command {
+    /// The base stations to handle, for the commands that take them.
+    fn targets(&self) -> Option<&Targets> {
+        match self {
+            Command::On { targets, .. }
+            | Command::Sleep { targets, .. }
+            | Command::Standby { targets, .. }
+            | Command::Off { targets, .. }
+            | Command::Scan { targets, .. }
+            | Command::Toggle { targets, .. }
+            | Command::PowerCycle { targets, .. }
+            | Command::Identify { targets }
+            | Command::Channel { targets }
+            | Command::SetChannel { targets, .. }
+            | Command::Raw { targets, .. }
+            | Command::Read { targets }
+            | Command::Inspect { targets }
+            | Command::Info { targets }
+            | Command::Watch { targets, .. }
+            | Command::Daemon { targets, .. }
+            | Command::Exporter { targets, .. }
+            | Command::Mqtt { targets, .. }
+            | Command::Serve { targets, .. }
+            | Command::Ensure { targets, .. }
+            | Command::SteamvrWatch { targets, .. }
+            | Command::Check { targets, .. }
+            | Command::Tui { targets }
+            | Command::Bench { targets, .. } => Some(targets),
+            Command::Gatt { operation } => Some(operation.targets()),
+            Command::Adapters
+            | Command::Doctor
+            | Command::Cache { .. }
+            | Command::Completions { .. }
+            | Command::CompleteNames => None,
+        }
+    }
+
+    /// The options of the power commands.
+    fn power(&self) -> Option<&PowerArgs> {
+        match self {
+            Command::On { power, .. }
+            | Command::Sleep { power, .. }
+            | Command::Standby { power, .. }
+            | Command::Off { power, .. }
+            | Command::Toggle { power, .. }
+            | Command::PowerCycle { power, .. } => Some(power),
+            _ => None,
+        }
+    }
+
+    /// The options of the commands that follow the power states.
+    fn changes(&self) -> Option<&ChangeArgs> {
+        match self {
+            Command::Scan { changes, .. } | Command::Watch { changes, .. } => Some(changes),
+            _ => None,
+        }
+    }
+
+    /// `--sleep-on-exit` of the commands that keep running.
+    fn sleep_on_exit(&self) -> bool {
+        match self {
+            Command::Daemon { sleep_on_exit, .. }
+            | Command::Exporter { sleep_on_exit, .. }
+            | Command::Mqtt { sleep_on_exit, .. }
+            | Command::Serve { sleep_on_exit, .. }
+            | Command::SteamvrWatch { sleep_on_exit, .. }
+            | Command::Watch { sleep_on_exit, .. }
+            | Command::Ensure { sleep_on_exit, .. }
+            | Command::Scan { sleep_on_exit, .. } => *sleep_on_exit,
+            _ => false,
+        }
+    }
 }
 
-enum PowerState {
+#[derive(Clone, Copy, ValueEnum)]
+enum OffMeans {
     Sleep,
-    Booting,
+    /// Standby wakes up faster than sleep.
     Standby,
-    On,
-    Unknown(u8),
 }
 
-impl From<u8> for PowerState {
-    fn from(byte: u8) -> Self {
-        match byte {
-            0x00 => PowerState::Sleep,
-            0x01 | 0x08 | 0x09 => PowerState::Booting,
-            0x02 => PowerState::Standby,
-            0x0b => PowerState::On,
-            byte => PowerState::Unknown(byte),
+impl From<OffMeans> for PowerCommand {
+    fn from(off_means: OffMeans) -> Self {
+        match off_means {
+            OffMeans::Sleep => PowerCommand::Sleep,
+            OffMeans::Standby => PowerCommand::Standby,
         }
     }
 }
 
-impl From<PowerState> for u8 {
-    fn from(state: PowerState) -> Self {
-        match state {
-            PowerState::Sleep => 0x00,
-            PowerState::Booting => 0x01,
-            PowerState::Standby => 0x02,
-            PowerState::On => 0x01,
-            PowerState::Unknown(byte) => byte,
+/// Parses the power state taken by `ensure` and `check --expect`.
+fn parse_state(state: &str, off_means: OffMeans) -> Result<PowerCommand> {
+    Ok(match state.to_lowercase().as_str() {
+        "on" => PowerCommand::On,
+        "sleep" => PowerCommand::Sleep,
+        "standby" => PowerCommand::Standby,
+        "off" => off_means.into(),
+        _ => bail!("expected on, sleep, standby or off, not `{}`", state),
+    })
+}
+
+/// The RF channels of V2 base stations.
+const CHANNELS: std::ops::RangeInclusive<u8> = 1..=16;
+
+/// Bytes given in hexadecimal, kept whole rather than taken for several arguments.
+#[derive(Clone)]
+struct HexBytes(Vec<u8>);
+
+/// Parses hexadecimal bytes, with or without `0x`, such as `0b00`.
+fn parse_bytes(s: &str) -> Result<HexBytes> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    let invalid = || {
+        anyhow!(
+            "invalid bytes `{}`, expected hexadecimal bytes like 0b00",
+            s
+        )
+    };
+    if digits.is_empty() || !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return Err(invalid());
+    }
+    let bytes = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| invalid()))
+        .collect::<Result<_>>()?;
+    Ok(HexBytes(bytes))
+}
+
+/// Parses a hexadecimal byte, with or without `0x`.
+fn parse_byte(s: &str) -> Result<u8> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u8::from_str_radix(digits, 16).map_err(|_| {
+        anyhow!(
+            "invalid byte `{}`, expected a hexadecimal byte like 0x09",
+            s
+        )
+    })
+}
+
+/// Asks a yes/no question on stderr, defaulting to no.
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn parse_channel(s: &str) -> Result<u8> {
+    match s.parse() {
+        Ok(channel) if CHANNELS.contains(&channel) => Ok(channel),
+        _ => bail!(
+            "invalid channel `{}`, expected {} to {}",
+            s,
+            CHANNELS.start(),
+            CHANNELS.end()
+        ),
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum WriteMode {
+    WithResponse,
+    WithoutResponse,
+}
+
+impl From<WriteMode> for WriteType {
+    fn from(mode: WriteMode) -> Self {
+        match mode {
+            WriteMode::WithResponse => WriteType::WithResponse,
+            WriteMode::WithoutResponse => WriteType::WithoutResponse,
         }
     }
 }
 
-impl Display for PowerState {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+#[derive(Clone, Copy, ValueEnum)]
+enum ColorChoice {
+    /// Color when stdout is a terminal and `NO_COLOR` is not set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
         match self {
-            PowerState::Sleep => write!(f, "SLEEP"),
-            PowerState::Standby => write!(f, "STANDBY"),
-            PowerState::Booting => write!(f, "BOOTING"),
-            PowerState::On => write!(f, "ON"),
-            PowerState::Unknown(byte) => write!(f, "UNKNOWN(0x{:02x})", byte),
+            ColorChoice::Auto => {
+                let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+                !no_color && io::stdout().is_terminal()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
         }
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let manager = Manager::new().await?;
-    let adapters = manager.adapters().await?;
-    let central = adapters.into_iter().next().unwrap();
-    let filter = Filter::new(cli.names);
-    scan(&central, cli.command, filter).await?;
-    Ok(())
+/// What failed and why, as `{"kind":"connect_timeout","message":"connect timed out"}`.
+impl ToJson for Error {
+    fn write_json(&self, out: &mut String) {
+        Object::new(out)
+            .field("kind", self.kind())
+            .field("message", &self.to_string())
+            .finish();
+    }
 }
 
-struct Filter {
-    names: Option<BTreeSet<String>>,
+impl ToJson for PowerCommand {
+    fn write_json(&self, out: &mut String) {
+        PowerState::from(*self).write_json(out)
+    }
 }
 
-impl Filter {
-    fn new(names: Vec<String>) -> Self {
-        Self {
-            names: if names.is_empty() {
-                None
+impl ToJson for PowerState {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            PowerState::Unknown(byte) => Object::new(out).field("unknown", byte).finish(),
+            state => state.to_string().to_lowercase().write_json(out),
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) if error.is::<ensure::Corrected>() => {
+            log::info!("{}", error);
+            ExitCode::from(EXIT_CORRECTED)
+        }
+        Err(error) => {
+            eprintln!("Error: {:?}", error);
+            if let Some(outcome) = error.downcast_ref::<Outcome>() {
+                ExitCode::from(outcome.exit_status())
+            } else if let Some(Error::NotFound { .. }) = error.downcast_ref() {
+                ExitCode::from(EXIT_NOT_FOUND)
+            } else if let Some(Error::NoAdapter { .. } | Error::AdapterUnusable { .. }) =
+                error.downcast_ref()
+            {
+                ExitCode::from(EXIT_NO_ADAPTER)
             } else {
-                Some(BTreeSet::from_iter(names.into_iter()))
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
+
+async fn run() -> Result<()> {
+    let config = Config::load(config_path().as_deref())?;
+    let matches = config.apply(Cli::command()).get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    let verbosity = match (cli.quiet, cli.verbose) {
+        (true, _) => Some(log::LevelFilter::Off),
+        (false, 0) => None,
+        (false, 1) => Some(log::LevelFilter::Debug),
+        (false, _) => Some(log::LevelFilter::Trace),
+    };
+    logging::init(cli.log_target, verbosity)?;
+    let action = match &cli.command {
+        Command::On { .. } => Action::Power(PowerCommand::On),
+        Command::Sleep { .. } => Action::Power(PowerCommand::Sleep),
+        Command::Standby { .. } => Action::Power(PowerCommand::Standby),
+        Command::Off { .. } => Action::Power(cli.off_means.into()),
+        Command::Scan { .. } => Action::Show,
+        Command::Toggle { .. } => Action::Toggle,
+        Command::PowerCycle { cycle_delay, .. } => {
+            Action::PowerCycle(cycle_delay.unwrap_or(DEFAULT_CYCLE_DELAY))
+        }
+        Command::Identify { .. } => Action::Identify,
+        Command::Channel { .. } => Action::Channel,
+        Command::Read { .. } => Action::Read,
+        Command::Inspect { .. } => Action::Inspect,
+        Command::Info { .. } => Action::Info,
+        Command::SetChannel { channel, targets } => {
+            if targets.names.is_empty() {
+                bail!("set-channel requires base station names or addresses");
+            }
+            Action::SetChannel(*channel)
+        }
+        Command::Raw { byte, targets, yes } => {
+            if targets.names.is_empty() {
+                bail!("raw requires base station names or addresses");
+            }
+            if !yes {
+                if !io::stdin().is_terminal() {
+                    bail!("raw writes need confirmation, pass --yes to write without asking");
+                }
+                let question = format!("Write 0x{:02x} to {}?", byte, targets.names.join(", "));
+                if !confirm(&question)? {
+                    bail!("aborted");
+                }
+            }
+            Action::Raw(*byte)
+        }
+        Command::Ensure { state, .. } => Action::Power(parse_state(state, cli.off_means)?),
+        Command::Check { expect, .. } => Action::Check(parse_state(expect, cli.off_means)?),
+        Command::Adapters => return list_adapters(&manager().await?).await,
+        Command::Doctor => return doctor::run(cli.adapter.as_deref()).await,
+        Command::Completions { shell } => return completions::print(shell, Cli::command()),
+        Command::CompleteNames => {
+            let cache_path = (!cli.no_cache).then(cache::default_path).flatten();
+            completions::print_names(cache_path.as_deref(), config.groups.keys());
+            return Ok(());
+        }
+        Command::Cache { operation } => {
+            let path = guard!(
+                cache::default_path(),
+                bail!("cannot tell where the station cache is, set XDG_CACHE_HOME")
+            );
+            return match operation {
+                CacheCommand::List => cache::list(&path),
+                CacheCommand::Forget { names, all } => cache::forget(&path, names, *all),
+            };
+        }
+        // Handled once connected to the adapter.
+        Command::Watch { .. }
+        | Command::Daemon { .. }
+        | Command::Exporter { .. }
+        | Command::Mqtt { .. }
+        | Command::Serve { .. }
+        | Command::SteamvrWatch { .. }
+        | Command::Tui { .. }
+        | Command::Bench { .. } => Action::Show,
+        Command::Gatt { operation } => {
+            if let GattCommand::Write {
+                characteristic,
+                bytes,
+                targets,
+                yes,
+            } = operation
+            {
+                if targets.names.is_empty() {
+                    bail!("gatt write requires base station names or addresses");
+                }
+                if targets.suffix || targets.names.iter().any(|name| name.contains(['*', '?'])) {
+                    bail!("gatt write requires whole names or addresses, not patterns or --suffix");
+                }
+                if !yes {
+                    if !io::stdin().is_terminal() {
+                        bail!("gatt writes need confirmation, pass --yes to write without asking");
+                    }
+                    let question = format!(
+                        "Write {} to {} of {}?",
+                        hex(&bytes.0),
+                        characteristic,
+                        targets.names.join(", ")
+                    );
+                    if !confirm(&question)? {
+                        bail!("aborted");
+                    }
+                }
+            }
+            Action::Show
+        }
+    };
+    let mut targets = cli.command.targets().cloned().unwrap_or_default();
+    let power = cli.command.power().cloned().unwrap_or_default();
+    let changes = cli.command.changes().cloned().unwrap_or_default();
+    if targets.names.is_empty() && targets.exclude.is_empty() {
+        if let Some(names) = env::var_os("LIGHTHOUSECTL_NAMES") {
+            targets.names = read_names(names.to_string_lossy().as_bytes())?;
+        } else {
+            targets.names = config.names.clone();
+        }
+    }
+    if targets.names.iter().any(|name| name == "-") {
+        targets.names = expand_stdin(targets.names, targets.allow_empty_stdin)?;
+    }
+    targets.names = expand_groups(targets.names, &config)?;
+    targets.exclude = expand_groups(targets.exclude, &config)?;
+    if matches!(action, Action::Identify) && targets.names.is_empty() {
+        bail!("identify requires at least one base station name or address");
+    }
+    if matches!(cli.command, Command::Ensure { .. }) && targets.names.is_empty() {
+        bail!("ensure requires base station names or addresses");
+    }
+    if matches!(cli.command, Command::Check { .. }) && targets.names.is_empty() {
+        bail!("check requires base station names or addresses");
+    }
+    let terminal = io::stdin().is_terminal() && io::stdout().is_terminal();
+    if matches!(cli.command, Command::Tui { .. }) && !terminal {
+        bail!("tui needs a terminal");
+    }
+    let notify = matches!(cli.command, Command::On { notify: true, .. });
+    let dry_run =
+        power.dry_run || matches!(cli.command, Command::SteamvrWatch { dry_run: true, .. });
+    let planned = [
+        ("--after", power.after.is_some()),
+        ("--delay-between", power.delay_between.is_some()),
+        ("--ordered", power.ordered),
+        ("--require-all", power.require_all),
+    ];
+    if power.ordered && targets.names.is_empty() {
+        bail!("--ordered requires base station names");
+    }
+    if power.require_all && targets.names.is_empty() {
+        bail!("--require-all requires base station names");
+    }
+    for (flag, _) in planned.into_iter().filter(|(_, given)| *given) {
+        if cli.daemon {
+            bail!("{} cannot be used with --daemon", flag);
+        }
+        if targets.names.is_empty() && cli.count.is_none() && cli.timeout.is_none() {
+            bail!(
+                "{} requires base station names, --count or --timeout to stop scanning",
+                flag
+            );
+        }
+    }
+    if matches!(cli.command, Command::Bench { .. })
+        && targets.names.is_empty()
+        && cli.count.is_none()
+        && cli.timeout.is_none()
+    {
+        bail!("bench requires base station names, --count or --timeout to stop scanning");
+    }
+    if cli.passive && !cfg!(target_os = "linux") {
+        bail!(
+            "--passive is only supported on Linux, since btleplug always scans actively on the \
+             other platforms"
+        );
+    }
+    if cli.sorted
+        && matches!(
+            cli.command,
+            Command::Scan {
+                interval: Some(_),
+                ..
+            }
+        )
+    {
+        bail!("--sorted cannot be used with scan --interval");
+    }
+    if cli.daemon && !matches!(cli.command, Command::Daemon { .. }) {
+        let command = match action {
+            _ if matches!(cli.command, Command::Bench { .. } | Command::Gatt { .. }) => {
+                bail!("only scan, on, sleep, standby and off can be sent to the daemon")
+            }
+            Action::Show => "status",
+            Action::Power(state) => state.porcelain(),
+            _ => bail!("only scan, on, sleep, standby and off can be sent to the daemon"),
+        };
+        if targets.suffix
+            || !targets.exclude.is_empty()
+            || targets.names.iter().any(|name| name.contains(['*', '?']))
+        {
+            bail!("patterns, --suffix and --exclude cannot be used with --daemon");
+        }
+        // The daemon replies with names and states only, printed as they come.
+        let output = [
+            (cli.json, "--json"),
+            (cli.json_lines, "--json-lines"),
+            (cli.csv, "--csv"),
+            (cli.table, "--table"),
+            (cli.porcelain, "--porcelain"),
+            (cli.format.is_some(), "--format"),
+        ];
+        if let Some((_, flag)) = output.iter().find(|(given, _)| *given) {
+            bail!(
+                "{} cannot be used with --daemon, which only has the plain output",
+                flag
+            );
+        }
+        if !matches!(config_output_mode(&config)?, OutputMode::Plain) {
+            bail!(
+                "the `output` or `format` of {} cannot be used with --daemon, which only has the \
+                 plain output",
+                config.path.as_ref().unwrap().display()
+            );
+        }
+        #[cfg(unix)]
+        return daemon::request(&socket(cli.socket), command, &targets.names).await;
+        #[cfg(not(unix))]
+        bail!("the daemon is only supported on Unix");
+    }
+    let signals = signal::listen()?;
+    let manager = manager().await?;
+    let central = select_adapter(&manager, cli.adapter.as_deref()).await?;
+    let timeout = cli
+        .timeout
+        .or_else(|| (!targets.names.is_empty()).then_some(DEFAULT_TIMEOUT));
+    let mut filter = Filter::new(targets.names, targets.suffix, targets.exclude);
+    let cache_path = (!cli.no_cache).then(cache::default_path).flatten();
+    let once = !matches!(
+        cli.command,
+        Command::Watch { .. }
+            | Command::Daemon { .. }
+            | Command::Exporter { .. }
+            | Command::Mqtt { .. }
+            | Command::Serve { .. }
+            | Command::Ensure { .. }
+            | Command::SteamvrWatch { .. }
+            | Command::Tui { .. }
+            | Command::Scan {
+                interval: Some(_),
+                ..
+            }
+    );
+    if cli.count.is_some() && !once {
+        bail!("--count only works with the commands that handle each base station once");
+    }
+    filter.count = cli.count;
+    let mut options = Options {
+        deadline: timeout.map(|timeout| Instant::now() + timeout),
+        connect_timeout: cli.connect_timeout,
+        connect_retries: cli.connect_retries,
+        connect_backoff: cli.connect_backoff,
+        io_timeout: cli.io_timeout,
+        wait_timeout: (power.wait || notify).then_some(power.wait_timeout),
+        write_type: cli.write_mode.into(),
+        verify: !cli.no_verify,
+        dry_run,
+        delay_between: power.delay_between,
+        last_write: tokio::sync::Mutex::new(None),
+        fail_fast: cli.fail_fast,
+        repeat: cli.repeat,
+        multi_adapter: central.count() > 1,
+        scan_filter: !cli.no_scan_filter,
+        passive: cli.passive,
+        strict: cli.strict,
+        concurrency: cli.concurrency,
+        connections: Arc::new(Semaphore::new(cli.concurrency)),
+        v1_ids: config.v1_ids.clone(),
+        cache: cache::Cache::open(cache_path, central.adapter_info().await?),
+        considered: Arc::default(),
+        pair: cli.pair,
+    };
+    let mode = if cli.json {
+        OutputMode::Json
+    } else if cli.json_lines {
+        OutputMode::JsonLines
+    } else if cli.csv {
+        OutputMode::Csv
+    } else if cli.table {
+        OutputMode::Table
+    } else if cli.porcelain {
+        OutputMode::Porcelain
+    } else if let Some(template) = cli.format {
+        OutputMode::Template(template)
+    } else {
+        config_output_mode(&config)?
+    };
+    let sleep_on_exit = cli.command.sleep_on_exit().then(|| cli.off_means.into());
+    let mut supervisor = supervisor::Supervisor::new(&filter, sleep_on_exit);
+    if let Command::Daemon { .. } = cli.command {
+        #[cfg(unix)]
+        return daemon::run(
+            &central,
+            &filter,
+            &options,
+            &socket(cli.socket),
+            &mut supervisor,
+            signals,
+        )
+        .await;
+        #[cfg(not(unix))]
+        bail!("the daemon is only supported on Unix");
+    }
+    if let Command::Mqtt { .. } = cli.command {
+        let settings = mqtt::Settings::new(&config.mqtt, cli.off_means.into())?;
+        return mqtt::run(
+            &central,
+            &filter,
+            &options,
+            &settings,
+            &mut supervisor,
+            signals,
+        )
+        .await;
+    }
+    if let Command::Exporter {
+        interval, listen, ..
+    } = cli.command
+    {
+        let interval = interval.unwrap_or(exporter::DEFAULT_INTERVAL);
+        let listen = listen.unwrap_or(exporter::DEFAULT_LISTEN);
+        return exporter::run(
+            &central,
+            &filter,
+            &options,
+            listen,
+            interval,
+            &mut supervisor,
+            signals,
+        )
+        .await;
+    }
+    if let Command::Serve { listen, .. } = cli.command {
+        let listen = listen.unwrap_or(serve::DEFAULT_LISTEN);
+        return serve::run(
+            &central,
+            &filter,
+            &options,
+            listen,
+            &mut supervisor,
+            signals,
+        )
+        .await;
+    }
+    let color = !matches!(mode, OutputMode::Porcelain) && cli.color.enabled();
+    if let (Command::Ensure { interval, once, .. }, Action::Power(target)) = (&cli.command, action)
+    {
+        let goal = ensure::Goal {
+            target,
+            interval: interval.unwrap_or(ensure::DEFAULT_INTERVAL),
+            once: *once,
+        };
+        return ensure::run(
+            &central,
+            &mut filter,
+            &options,
+            &goal,
+            color,
+            &mut supervisor,
+            signals,
+        )
+        .await;
+    }
+    if let Command::SteamvrWatch { idle_delay, .. } = cli.command {
+        let policy = steamvr::Policy {
+            idle: cli.off_means.into(),
+            idle_delay: idle_delay.unwrap_or(steamvr::DEFAULT_IDLE_DELAY),
+            dry_run,
+            color,
+        };
+        return steamvr::run(
+            &central,
+            &filter,
+            &options,
+            &policy,
+            &mut supervisor,
+            signals,
+        )
+        .await;
+    }
+    if let Command::Gatt { operation } = cli.command {
+        let operation = match operation {
+            GattCommand::Read { characteristic, .. } => gatt::Operation::Read(characteristic),
+            GattCommand::Write {
+                characteristic,
+                bytes,
+                ..
+            } => gatt::Operation::Write {
+                uuid: characteristic,
+                bytes: bytes.0,
             },
+        };
+        return gatt::run(&central, &mut filter, &options, &operation, signals).await;
+    }
+    if let Command::Bench {
+        iterations, write, ..
+    } = cli.command
+    {
+        return bench::run(
+            &central, &filter, &options, iterations, write, timeout, signals,
+        )
+        .await;
+    }
+    let following = match cli.command {
+        Command::Watch { .. } => true,
+        Command::Scan { interval, .. } => interval.is_some(),
+        _ => false,
+    };
+    if changes.on_change.is_some() && !following {
+        bail!("--on-change only works with watch and scan --interval");
+    }
+    if changes.history_file.is_some() && !following {
+        bail!("--history-file only works with watch and scan --interval");
+    }
+    let history_file = match changes.history_file {
+        Some(path) => {
+            let opened = history::HistoryFile::open(path.clone());
+            Some(opened.with_context(|| format!("failed to open {}", path.display()))?)
+        }
+        None => None,
+    };
+    let debounce = changes.debounce.unwrap_or_default();
+    let hook = changes
+        .on_change
+        .map(|command| hook::Hook::new(command, debounce));
+    if let Command::Scan {
+        interval: Some(interval),
+        always_print,
+        ..
+    } = cli.command
+    {
+        let mut report = Report::new(mode, color);
+        report.timestamps = true;
+        report.history_file = history_file;
+        let sampling = monitor::Sampling {
+            interval,
+            always_print,
+            hook: hook.as_ref(),
+        };
+        return monitor::run(
+            &central,
+            &filter,
+            &options,
+            &mut report,
+            sampling,
+            &mut supervisor,
+            signals,
+        )
+        .await;
+    }
+    if let Command::Tui { .. } = cli.command {
+        #[cfg(unix)]
+        return tui::run(&central, &filter, &options, color, signals).await;
+        #[cfg(not(unix))]
+        bail!("the TUI is only supported on Unix");
+    }
+    if let Command::Watch { .. } = cli.command {
+        let mut report = Report::new(mode, color);
+        report.history_file = history_file;
+        return watch::run(
+            &central,
+            &filter,
+            &options,
+            &report,
+            hook.as_ref(),
+            &mut supervisor,
+            signals,
+        )
+        .await;
+    }
+    let mut report = Report::new(mode, color);
+    report.timestamps = cli.timestamps;
+    report.sorted = cli.sorted;
+    // Only where it cannot end up among the results or the diagnostics.
+    let terminal = io::stdout().is_terminal() && io::stderr().is_terminal();
+    let porcelain = matches!(report.mode, OutputMode::Porcelain);
+    if terminal && !porcelain && !cli.quiet && cli.verbose == 0 {
+        report.status = Some(progress::StatusLine::new());
+    }
+    let report = RefCell::new(report);
+    let mut retried = BTreeSet::new();
+    let passes = async {
+        let mut outcome = match power.after {
+            None if power.delay_between.is_none() && !power.ordered && !power.require_all => {
+                let missing = scan(&central, action, &mut filter, &options, &report).await?;
+                Outcome::new(missing, &report.borrow().failures)
+            }
+            after => {
+                let plan = Plan {
+                    command: matches.subcommand_name().expect("a command is required"),
+                    after,
+                    one_by_one: power.delay_between.is_some(),
+                    ordered: power.ordered,
+                    require_all: power.require_all,
+                };
+                let missing =
+                    scan_planned(&central, action, &mut filter, &mut options, &report, plan)
+                        .await?;
+                Outcome::new(missing, &report.borrow().failures)
+            }
+        };
+        for _ in 0..cli.retries {
+            if report.borrow().failures.is_empty() {
+                break;
+            }
+            let failed: Vec<_> = report.borrow().failures.keys().cloned().collect();
+            log::info!("retrying {}", failed.join(", "));
+            tokio::time::sleep(RETRY_DELAY).await;
+            retried.extend(failed.iter().cloned());
+            let mut retrying = Filter::new(failed, false, Vec::new());
+            options.deadline = timeout.map(|timeout| Instant::now() + timeout);
+            scan(&central, action, &mut retrying, &options, &report).await?;
+            // The failures add up over the passes, while the missing ones are not looked for again.
+            outcome = Outcome::new(outcome.missing, &report.borrow().failures);
+        }
+        anyhow::Ok(outcome)
+    };
+    // Dropping the passes on a signal cancels the discovery and whatever a base station was doing.
+    let (outcome, interrupted) = tokio::select! {
+        result = passes => (Some(result?), None),
+        Ok(signal) = signals => (None, Some(signal)),
+    };
+    let mut report = report.into_inner();
+    report.status = None;
+    if let Some(signal) = interrupted {
+        eprintln!("interrupted, stopping");
+        release(&central, cli.io_timeout).await;
+        let missing = filter.remaining();
+        if !missing.is_empty() {
+            eprintln!("not found: {}", missing.join(", "));
+        }
+        report.finish(&missing);
+        summarize(&report, &retried, cli.retries, &missing);
+        process::exit(128 + signal);
+    }
+    let missing: Vec<_> = outcome
+        .iter()
+        .flat_map(|outcome| outcome.missing.clone())
+        .collect();
+    report.finish(&missing);
+    summarize(&report, &retried, cli.retries, &missing);
+    if notify {
+        // Those that timed out waiting were written to, but failed.
+        let reached: Vec<_> = report
+            .written
+            .iter()
+            .filter(|name| !report.failures.contains_key(*name))
+            .map(String::as_str)
+            .collect();
+        let missing = outcome.iter().flat_map(|outcome| &outcome.missing);
+        let stragglers: Vec<_> = report
+            .failures
+            .keys()
+            .chain(missing)
+            .map(String::as_str)
+            .collect();
+        notify::powered_on(&reached, &stragglers);
+    }
+    outcome.map_or(Ok(()), Outcome::into_result)
+}
+
+#[cfg(unix)]
+fn socket(socket: Option<PathBuf>) -> PathBuf {
+    socket.unwrap_or_else(daemon::default_socket)
+}
+
+/// Lists the failed base stations, and those that only succeeded on a retry pass.
+fn summarize(report: &Report, retried: &BTreeSet<String>, retries: u32, missing: &[String]) {
+    if !report.failures.is_empty() {
+        eprintln!("failed base stations:");
+        for (name, failure) in &report.failures {
+            if retried.contains(name) {
+                eprintln!("  {}: {} (after {} retries)", name, failure.cause, retries);
+            } else {
+                eprintln!("  {}: {}", name, failure.cause);
+            }
+        }
+    }
+    let recovered: Vec<_> = retried
+        .iter()
+        .filter(|name| !report.failures.contains_key(*name))
+        .map(String::as_str)
+        .collect();
+    if !recovered.is_empty() {
+        eprintln!("succeeded on retry: {}", recovered.join(", "));
+    }
+    // The missing ones are listed by the error, or when interrupted.
+    for name in missing {
+        if let Some(nearest) = report.nearest_unrequested(name) {
+            eprintln!("{} was not found, did you mean {}?", name, nearest);
+        }
+    }
+}
+
+/// How many single-character insertions, deletions or substitutions turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &b) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(a != b);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Finds `--config` before the command line is parsed, since the config provides its defaults.
+fn config_path() -> Option<PathBuf> {
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    env::var_os("LIGHTHOUSECTL_CONFIG").map(PathBuf::from)
+}
+
+fn config_output_mode(config: &Config) -> Result<OutputMode> {
+    if let Some(format) = config.format.as_deref() {
+        let template = Template::from_str(format).map_err(|error| {
+            anyhow!(
+                "invalid `format` in {}: {}",
+                config.path.as_ref().unwrap().display(),
+                error
+            )
+        })?;
+        return Ok(OutputMode::Template(template));
+    }
+    Ok(match config.output.as_deref() {
+        Some("json") => OutputMode::Json,
+        Some("json-lines") => OutputMode::JsonLines,
+        Some("csv") => OutputMode::Csv,
+        Some("table") => OutputMode::Table,
+        Some("porcelain") => OutputMode::Porcelain,
+        _ => OutputMode::Plain,
+    })
+}
+
+/// Replaces `-` in the names with the names read from stdin.
+fn expand_stdin(names: Vec<String>, allow_empty: bool) -> Result<Vec<String>> {
+    let stdin_names = read_names(io::stdin().lock())?;
+    if stdin_names.is_empty() && !allow_empty {
+        bail!("no base station names were read from stdin, pass --allow-empty-stdin to allow it");
+    }
+    let mut expanded = Vec::new();
+    for name in names {
+        if name == "-" {
+            expanded.extend(stdin_names.iter().cloned());
+        } else {
+            expanded.push(name);
+        }
+    }
+    Ok(expanded)
+}
+
+/// Replaces `@group` in the names with the members of the group in the config.
+fn expand_groups(names: Vec<String>, config: &Config) -> Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    for name in names {
+        let group = guard!(name.strip_prefix('@'), {
+            expanded.push(name);
+            continue;
+        });
+        match config.groups.get(group) {
+            Some(members) => expanded.extend(members.iter().cloned()),
+            None if config.groups.is_empty() => {
+                bail!(
+                    "unknown group `{}`, no groups are defined in the config",
+                    name
+                )
+            }
+            None => {
+                let groups: Vec<_> = config
+                    .groups
+                    .keys()
+                    .map(|group| format!("@{}", group))
+                    .collect();
+                bail!(
+                    "unknown group `{}`, expected one of: {}",
+                    name,
+                    groups.join(", ")
+                )
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// Reads whitespace separated names, ignoring `#` comments.
+fn read_names(reader: impl BufRead) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.split('#').next().unwrap_or_default();
+        names.extend(line.split_whitespace().map(str::to_owned));
+    }
+    Ok(names)
+}
+
+/// How the base stations that were asked for fared, which decides the exit status.
+#[derive(Debug)]
+struct Outcome {
+    /// The named base stations that were never found.
+    missing: Vec<String>,
+    /// The base stations that failed, other than by timing out.
+    failed: usize,
+    timed_out: usize,
+}
+
+impl Outcome {
+    fn new(missing: Vec<String>, failures: &BTreeMap<String, StationError>) -> Self {
+        let timed_out = failures
+            .values()
+            .filter(|failure| failure.timed_out())
+            .count();
+        Self {
+            missing,
+            failed: failures.len() - timed_out,
+            timed_out,
         }
     }
 
-    fn is_completed(&self) -> bool {
-        if let Some(set) = self.names.as_ref() {
-            set.is_empty()
+    /// Failing is the most telling, then timing out, then not being found.
+    fn exit_status(&self) -> u8 {
+        if self.failed > 0 {
+            EXIT_FAILED
+        } else if self.timed_out > 0 {
+            EXIT_TIMED_OUT
         } else {
-            false
+            EXIT_NOT_FOUND
         }
     }
 
-    fn is_matched(&mut self, name: &str) -> bool {
-        if let Some(set) = self.names.as_mut() {
-            set.remove(name)
+    fn into_result(self) -> Result<()> {
+        if self.missing.is_empty() && self.failed == 0 && self.timed_out == 0 {
+            Ok(())
         } else {
-            true
+            Err(self.into())
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if !self.missing.is_empty() {
+            parts.push(format!(
+                "base stations not found: {}",
+                self.missing.join(", ")
+            ));
+        }
+        if self.failed > 0 {
+            parts.push(format!("{} base stations failed", self.failed));
+        }
+        if self.timed_out > 0 {
+            parts.push(format!("{} base stations timed out", self.timed_out));
         }
+        write!(f, "{}", parts.join("; "))
     }
 }
 
-async fn scan(central: &impl Central, command: Command, mut filter: Filter) -> Result<()> {
-    let mut stream = discover(central).await?;
-    while !filter.is_completed() {
-        let lh = guard!(stream.try_next().await?, break);
-        if filter.is_matched(&lh.name) {
-            let bytes = lh.peripheral.read(&lh.characteristic).await?;
-            let current_state: PowerState = (*guard!(bytes.get(0), continue)).into();
-            let next_state = match command {
-                Command::Scan => {
-                    println!("{}: {}", lh.name, current_state);
-                    continue;
+impl std::error::Error for Outcome {}
+
+async fn manager() -> Result<Manager> {
+    match Manager::new().await {
+        Ok(manager) => Ok(manager),
+        // BlueZ is reached over the system bus, so without it there is no adapter either.
+        Err(_) if cfg!(target_os = "linux") => {
+            let hint = Some("cannot connect to the system D-Bus, is it running?");
+            Err(Error::NoAdapter { hint }.into())
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+async fn adapters(manager: &Manager) -> Result<Vec<Adapter>> {
+    let adapters = match manager.adapters().await {
+        Ok(adapters) => adapters,
+        Err(error) if cfg!(target_os = "linux") && error.to_string().contains("org.bluez") => {
+            let hint = "BlueZ is not running, start it with `sudo systemctl start bluetooth`";
+            return Err(Error::NoAdapter { hint: Some(hint) }.into());
+        }
+        Err(error) => return Err(error.into()),
+    };
+    if adapters.is_empty() {
+        let hint = no_adapter_hint();
+        return Err(Error::NoAdapter { hint }.into());
+    }
+    Ok(adapters)
+}
+
+/// Looks for a blocked or missing Bluetooth device in rfkill.
+#[cfg(target_os = "linux")]
+fn no_adapter_hint() -> Option<&'static str> {
+    let entries = std::fs::read_dir("/sys/class/rfkill").ok()?;
+    let mut found = false;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let read = |file| std::fs::read_to_string(path.join(file)).unwrap_or_default();
+        if read("type").trim() != "bluetooth" {
+            continue;
+        }
+        if read("hard").trim() == "1" {
+            return Some("the adapter is blocked by a hardware switch or the firmware settings");
+        }
+        if read("soft").trim() == "1" {
+            return Some("the adapter is soft-blocked, unblock it with `rfkill unblock bluetooth`");
+        }
+        found = true;
+    }
+    (!found).then_some("no Bluetooth hardware was detected, check that it is enabled in the BIOS")
+}
+
+#[cfg(target_os = "macos")]
+fn no_adapter_hint() -> Option<&'static str> {
+    Some("check that Bluetooth is turned on and that the terminal may use it in System Settings > Privacy & Security > Bluetooth")
+}
+
+#[cfg(target_os = "windows")]
+fn no_adapter_hint() -> Option<&'static str> {
+    Some("check that Bluetooth is turned on in Settings > Bluetooth & devices")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn no_adapter_hint() -> Option<&'static str> {
+    None
+}
+
+async fn select_adapter(
+    manager: &Manager,
+    selector: Option<&str>,
+) -> Result<multi::Adapters<Adapter>> {
+    radios::check().await?;
+    let mut adapters = adapters(manager).await?;
+    if selector == Some("all") {
+        // Every adapter btleplug gives on Windows scans on the default one.
+        if cfg!(windows) {
+            adapters.truncate(1);
+        }
+        return Ok(multi::Adapters::new(adapters));
+    }
+    let infos = adapter_infos(&adapters).await?;
+    let position = match selector {
+        None => Some(0),
+        Some(selector) => match selector.parse::<usize>() {
+            Ok(index) => (index < infos.len()).then_some(index),
+            Err(_) => infos.iter().position(|info| info.contains(selector)),
+        },
+    };
+    if let Some(position) = position {
+        if cfg!(windows) && position > 0 {
+            bail!(
+                "Windows only scans on the default Bluetooth adapter, {}; to use {}, disable the \
+                 other adapters in the Device Manager",
+                infos[0],
+                infos[position]
+            );
+        }
+        return Ok(multi::Adapters::new(vec![adapters.swap_remove(position)]));
+    }
+    let available: Vec<_> = infos
+        .iter()
+        .enumerate()
+        .map(|(index, info)| format!("  {}: {}", index, info))
+        .collect();
+    bail!(
+        "no adapter matches `{}`, available adapters:\n{}",
+        selector.unwrap_or_default(),
+        available.join("\n")
+    );
+}
+
+#[cfg(not(windows))]
+async fn adapter_infos(adapters: &[Adapter]) -> Result<Vec<String>> {
+    let mut infos = Vec::with_capacity(adapters.len());
+    for adapter in adapters {
+        infos.push(adapter.adapter_info().await?);
+    }
+    Ok(infos)
+}
+
+/// The names Windows gives the adapters, since btleplug names each of them `WinRT`.
+#[cfg(windows)]
+async fn adapter_infos(_: &[Adapter]) -> Result<Vec<String>> {
+    let radios = radios::list().await?;
+    Ok(radios.into_iter().map(|radio| radio.name).collect())
+}
+
+async fn list_adapters(manager: &Manager) -> Result<()> {
+    let adapters = adapters(manager).await?;
+    let infos = adapter_infos(&adapters).await?;
+    let powered = adapters_powered().await?;
+    for (index, info) in infos.iter().enumerate() {
+        let powered = match powered.get(index).copied().flatten() {
+            Some(true) => "powered",
+            Some(false) => "not powered",
+            None => "unknown",
+        };
+        println!("{}: {} [{}]", index, info, powered);
+    }
+    Ok(())
+}
+
+/// Returns whether each adapter is powered, in the same order as `Manager::adapters()`.
+#[cfg(target_os = "linux")]
+async fn adapters_powered() -> Result<Vec<Option<bool>>> {
+    let (_, session) = bluez_async::BluetoothSession::new().await?;
+    let adapters = session.get_adapters().await?;
+    Ok(adapters
+        .into_iter()
+        .map(|adapter| Some(adapter.powered))
+        .collect())
+}
+
+/// In the same order as `adapter_infos()`, the default adapter first.
+#[cfg(windows)]
+async fn adapters_powered() -> Result<Vec<Option<bool>>> {
+    let radios = radios::list().await?;
+    Ok(radios.into_iter().map(|radio| radio.powered).collect())
+}
+
+/// btleplug does not expose the power state of adapters on this platform.
+#[cfg(not(any(target_os = "linux", windows)))]
+async fn adapters_powered() -> Result<Vec<Option<bool>>> {
+    Ok(Vec::new())
+}
+
+/// The fields of a record in the JSON outputs, shared by the results and the events.
+trait WriteFields {
+    fn write_fields(&self, object: &mut Object);
+}
+
+impl WriteFields for Record {
+    fn write_fields(&self, object: &mut Object) {
+        let state = self.state();
+        object
+            .field("name", &self.name)
+            .field("address", &self.address)
+            .field("rssi", &self.rssi)
+            .field("excluded", &self.excluded)
+            .field("skipped", &self.skipped)
+            .field("identified", &self.identified)
+            .field("channel", &self.channel)
+            .field("requested_channel", &self.requested_channel)
+            .field(
+                "written_raw_state",
+                &self.raw_write.map(|(written, _)| written),
+            )
+            .field("read_back_raw_state", &self.raw_write.map(|(_, read)| read))
+            .field("bytes", &self.bytes.as_deref().map(hex))
+            .field("first_seen", &timestamp(self.first_seen))
+            .field("last_seen", &timestamp(self.last_seen));
+        if let Some(info) = &self.info {
+            let mut out = String::new();
+            let mut fields = Object::new(&mut out);
+            for (key, value) in info {
+                fields.field(key, value);
+            }
+            fields.finish();
+            object.field("info", &json::Raw(out));
+        }
+        if let Some(history) = &self.history {
+            history.write_fields(object);
+        }
+        match self.requested_state {
+            Some(requested_state) => object
+                .field("previous_state", &state)
+                .field("raw_state", &self.raw_state)
+                .field("requested_state", &requested_state)
+                .field("dry_run", &self.dry_run),
+            None => object
+                .field("state", &state)
+                .field("raw_state", &self.raw_state),
+        };
+    }
+}
+
+impl ToJson for Record {
+    fn write_json(&self, out: &mut String) {
+        let mut object = Object::new(out);
+        self.write_fields(&mut object);
+        object.finish();
+    }
+}
+
+impl WriteFields for History {
+    fn write_fields(&self, object: &mut Object) {
+        object
+            .field("state_since", &timestamp(self.since))
+            .field("transitions", &self.transitions);
+    }
+}
+
+fn properties(flags: CharPropFlags) -> String {
+    let names = [
+        (CharPropFlags::BROADCAST, "broadcast"),
+        (CharPropFlags::READ, "read"),
+        (
+            CharPropFlags::WRITE_WITHOUT_RESPONSE,
+            "write-without-response",
+        ),
+        (CharPropFlags::WRITE, "write"),
+        (CharPropFlags::NOTIFY, "notify"),
+        (CharPropFlags::INDICATE, "indicate"),
+        (
+            CharPropFlags::AUTHENTICATED_SIGNED_WRITES,
+            "authenticated-signed-writes",
+        ),
+        (CharPropFlags::EXTENDED_PROPERTIES, "extended-properties"),
+    ];
+    let names: Vec<_> = names
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect();
+    names.join(", ")
+}
+
+fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Keeps a porcelain field on a single line without breaking the columns.
+fn porcelain_field(field: &str) -> String {
+    field
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+enum OutputMode {
+    Plain,
+    Json,
+    JsonLines,
+    Csv,
+    Table,
+    Porcelain,
+    Template(Template),
+}
+
+/// Writes everything that goes to stdout according to the output mode.
+struct Report {
+    mode: OutputMode,
+    color: bool,
+    records: Vec<Record>,
+    /// The last error of each base station that has not succeeded since.
+    failures: BTreeMap<String, StationError>,
+    /// The names of the base stations that a power state was written to.
+    written: BTreeSet<String>,
+    /// Whether each line of the plain output starts with a timestamp, with `--timestamps`.
+    timestamps: bool,
+    /// Whether the results are held until the run has finished and printed in the order of
+    /// their names, with `--sorted`.
+    sorted: bool,
+    /// The status line shown while scanning, on a terminal.
+    status: Option<progress::StatusLine>,
+    /// Where the power state transitions are logged, with `--history-file`.
+    history_file: Option<history::HistoryFile>,
+    /// The names of every base station discovered, asked for or not.
+    seen: BTreeSet<String>,
+}
+
+impl Report {
+    fn new(mode: OutputMode, color: bool) -> Self {
+        if matches!(mode, OutputMode::Csv) {
+            println!("timestamp,name,address,state,rssi");
+        }
+        Self {
+            mode,
+            color,
+            records: Vec::new(),
+            failures: BTreeMap::new(),
+            written: BTreeSet::new(),
+            timestamps: false,
+            sorted: false,
+            status: None,
+            history_file: None,
+            seen: BTreeSet::new(),
+        }
+    }
+
+    /// Draws the status line, if shown, with how many base stations the filter still waits for.
+    fn draw_status(&self, filter: &Filter, options: &Options) {
+        if let Some(status) = &self.status {
+            let considered = options.considered.load(atomic::Ordering::Relaxed);
+            status.draw(considered, filter.remaining().len());
+        }
+    }
+
+    /// The base station seen but not asked for whose name is the closest to `name`, if it is off
+    /// by no more than a couple of characters, such as a mistyped serial.
+    fn nearest_unrequested(&self, name: &str) -> Option<&str> {
+        let handled: BTreeSet<_> = self
+            .records
+            .iter()
+            .map(|record| &record.name)
+            .chain(self.failures.keys())
+            .collect();
+        let folded = fold(name);
+        self.seen
+            .iter()
+            .filter(|seen| !handled.contains(seen))
+            .map(|seen| (edit_distance(&folded, &fold(seen)), seen))
+            .filter(|&(distance, _)| distance <= 2)
+            .min()
+            .map(|(_, seen)| seen.as_str())
+    }
+
+    /// Logs a power state transition with `--history-file`.
+    fn transition<P: Peripheral>(
+        &self,
+        lh: &Lighthouse<P>,
+        previous: PowerState,
+        state: PowerState,
+        source: history::Source,
+    ) {
+        if let Some(history_file) = &self.history_file {
+            history_file.transition(&lh.name, &lh.shown_address(), previous, state, source);
+        }
+    }
+
+    /// Clears the status line, before anything else is printed over it.
+    fn clear_status(&self) {
+        if let Some(status) = &self.status {
+            status.clear();
+        }
+    }
+
+    /// The timestamp that starts a line of the plain output, if any.
+    fn stamp(&self) -> String {
+        match self.timestamps {
+            true => format!("{} ", humantime::format_rfc3339_millis(SystemTime::now())),
+            false => String::new(),
+        }
+    }
+
+    fn discovered<P: Peripheral>(&mut self, lh: &Lighthouse<P>) {
+        self.seen.insert(lh.name.clone());
+        if matches!(self.mode, OutputMode::JsonLines) {
+            self.event("discovered", |object| {
+                object
+                    .field("name", &lh.name)
+                    .field("address", &lh.shown_address());
+            });
+        }
+    }
+
+    /// Called once the power state has been read.
+    /// For power commands, the record is reported again by `state_written`.
+    fn state_read(&mut self, record: &Record) {
+        if record.requested_state.is_none() {
+            self.failures.remove(&record.name);
+        }
+        if matches!(self.mode, OutputMode::JsonLines) {
+            let record = Record {
+                requested_state: None,
+                ..record.clone()
+            };
+            self.event("state_read", |object| record.write_fields(object));
+        } else if record.requested_state.is_none() {
+            self.result(record.clone());
+        }
+    }
+
+    fn state_written(&mut self, record: Record) {
+        self.failures.remove(&record.name);
+        self.written.insert(record.name.clone());
+        if matches!(self.mode, OutputMode::JsonLines) {
+            self.event("state_written", |object| record.write_fields(object));
+        } else {
+            self.result(record);
+        }
+    }
+
+    /// Called for each state that a base station passes through with `--wait`.
+    fn state_changed<P: Peripheral>(
+        &self,
+        lh: &Lighthouse<P>,
+        previous: PowerState,
+        state: PowerState,
+    ) {
+        self.clear_status();
+        match self.mode {
+            OutputMode::Plain => println!(
+                "{}{} [{}]: {} -> {}",
+                self.stamp(),
+                lh.name,
+                lh.shown_address(),
+                previous.painted(self.color),
+                state.painted(self.color)
+            ),
+            OutputMode::JsonLines => self.event("state_changed", |object| {
+                object
+                    .field("name", &lh.name)
+                    .field("address", &lh.shown_address())
+                    .field("previous_state", &previous)
+                    .field("state", &state);
+            }),
+            _ => {}
+        }
+    }
+
+    fn error(&mut self, error: StationError) {
+        self.clear_status();
+        let address = error.shown_address.clone();
+        if matches!(self.mode, OutputMode::JsonLines) {
+            self.event("error", |object| {
+                object
+                    .field("name", &error.name)
+                    .field("address", &address)
+                    .field("error", &error.cause);
+            });
+        } else if matches!(self.mode, OutputMode::Porcelain) {
+            let result = format!("error:{}", error.cause);
+            println!(
+                "{}\t\t\t{}\t{}",
+                porcelain_field(&error.name),
+                porcelain_field(&result),
+                address.unwrap_or_default(),
+            );
+        } else if let Some(address) = address {
+            eprintln!(
+                "{}{} [{}]: {}",
+                self.stamp(),
+                error.name,
+                address,
+                error.cause
+            );
+        } else {
+            eprintln!("{}{}: {}", self.stamp(), error.name, error.cause);
+        }
+        self.failures.insert(error.name.clone(), error);
+    }
+
+    fn result(&mut self, record: Record) {
+        self.clear_status();
+        match &self.mode {
+            OutputMode::Json | OutputMode::Table => self.records.push(record),
+            _ if self.sorted => self.records.push(record),
+            _ => self.print(&record),
+        }
+    }
+
+    /// Prints the result of a base station as it comes, in the modes that do not wait for the
+    /// run to finish.
+    fn print(&self, record: &Record) {
+        match &self.mode {
+            OutputMode::Json | OutputMode::Table => {}
+            OutputMode::Csv => {
+                let fields = [
+                    Field::Timestamp,
+                    Field::Name,
+                    Field::Address,
+                    Field::State,
+                    Field::Rssi,
+                ];
+                let row: Vec<_> = fields
+                    .iter()
+                    .map(|field| csv_field(&field.value(record)).into_owned())
+                    .collect();
+                println!("{}", row.join(","));
+            }
+            OutputMode::Template(template) => println!("{}", template.render(record)),
+            OutputMode::Porcelain => {
+                let state = record.state().map_or("", PowerState::porcelain);
+                let raw_state = record
+                    .raw_state
+                    .map(|raw_state| raw_state.to_string())
+                    .unwrap_or_default();
+                let result = match record.requested_state {
+                    Some(requested_state) if record.dry_run => {
+                        format!("dry-run:{}", requested_state.porcelain())
+                    }
+                    Some(requested_state) => format!("set:{}", requested_state.porcelain()),
+                    None if record.excluded => "excluded".to_owned(),
+                    None if record.skipped.is_some() => "skipped".to_owned(),
+                    None if record.identified => "identified".to_owned(),
+                    None => match (record.requested_channel, record.raw_write) {
+                        (Some(channel), _) => format!("set:channel={}", channel),
+                        (_, Some((written, _))) => format!("set:raw={}", written),
+                        _ => "ok".to_owned(),
+                    },
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    porcelain_field(&record.name),
+                    state,
+                    raw_state,
+                    result,
+                    record.address,
+                );
+            }
+            _ => {
+                let station = format!("{}{} [{}]", self.stamp(), record.name, record.address);
+                let state = record.describe_state(self.color);
+                let mut note = match record.note() {
+                    Some(note) => format!(" ({})", note),
+                    None => String::new(),
+                };
+                if let Some(history) = &record.history {
+                    note.push_str(&format!(" ({})", history.describe()));
                 }
-                Command::On => PowerState::On,
-                Command::Standby => PowerState::Standby,
-                Command::Sleep => PowerState::Sleep,
+                match record.requested_state {
+                    Some(requested_state) => {
+                        let requested_state = requested_state.painted(self.color);
+                        let dry_run = if record.dry_run { " (dry run)" } else { "" };
+                        println!("{}: {} -> {}{}", station, state, requested_state, dry_run);
+                    }
+                    None => match record.rssi {
+                        Some(rssi) => {
+                            println!("{}: {} ({} dBm){}", station, state, rssi, note)
+                        }
+                        None => println!("{}: {} (n/a){}", station, state, note),
+                    },
+                }
+                for (key, value) in record.info.iter().flatten() {
+                    println!("  {}: {}", key, value.as_deref().unwrap_or("n/a"));
+                }
+            }
+        }
+    }
+
+    /// Prints the GATT services of a base station, marking the characteristics in use.
+    fn inspected<P: Peripheral>(&self, lh: &Lighthouse<P>) {
+        self.clear_status();
+        println!("{} [{}]:", lh.name, lh.shown_address());
+        for service in &lh.services {
+            let primary = if service.primary { " (primary)" } else { "" };
+            println!("  service {}{}", service.uuid, primary);
+            for characteristic in &service.characteristics {
+                let used = match characteristic.uuid {
+                    _ if characteristic.service_uuid != lh.protocol.service() => "",
+                    V1_CHARACTERISTIC_UUID => " <- V1 command",
+                    CHARACTERISTIC_UUID => " <- power state",
+                    CHANNEL_CHARACTERISTIC_UUID => " <- channel",
+                    IDENTIFY_CHARACTERISTIC_UUID => " <- identify",
+                    _ => "",
+                };
+                println!(
+                    "    characteristic {} [{}]{}",
+                    characteristic.uuid,
+                    properties(characteristic.properties),
+                    used
+                );
+            }
+        }
+        let power_characteristic = lh.protocol.power_characteristic();
+        if !lh.characteristics.contains_key(&power_characteristic) {
+            println!("  no power characteristic {} found", power_characteristic);
+        }
+    }
+
+    fn event(&self, kind: &str, fields: impl FnOnce(&mut Object)) {
+        self.clear_status();
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        let mut out = String::new();
+        let mut object = Object::new(&mut out);
+        object.field("event", kind).field("timestamp", &timestamp);
+        fields(&mut object);
+        object.finish();
+        println!("{}", out);
+    }
+
+    /// Prints what is only known once the run has finished, given the named base stations that
+    /// were never found.
+    fn finish(&mut self, missing: &[String]) {
+        self.clear_status();
+        if self.sorted {
+            self.records.sort_by_key(|record| fold(&record.name));
+            for record in &self.records {
+                self.print(record);
+            }
+        }
+        match self.mode {
+            OutputMode::Json => {
+                let mut out = String::new();
+                let mut object = Object::new(&mut out);
+                object.field("stations", &json::Raw(self.stations(missing)));
+                self.write_counts(&mut object, missing);
+                object.finish();
+                println!("{}", out);
+            }
+            OutputMode::JsonLines => {
+                self.event("summary", |object| self.write_counts(object, missing))
+            }
+            OutputMode::Table => self.print_table(),
+            _ => {}
+        }
+        if self.sorted && matches!(self.mode, OutputMode::Plain | OutputMode::Table) {
+            println!("{}", self.summary(missing));
+        }
+    }
+
+    /// The counts ending `--sorted`, such as `4 found, 3 on, 1 sleep, 0 failed, 1 requested name
+    /// not found`.
+    fn summary(&self, missing: &[String]) -> String {
+        let mut states = BTreeMap::new();
+        for record in &self.records {
+            // The state a power command left it in, rather than the one it was found in.
+            let state: Option<PowerState> = match record.requested_state {
+                Some(requested_state) if !record.dry_run => Some(requested_state.into()),
+                _ => record.state(),
             };
-            println!("{}: {} -> {}", lh.name, current_state, next_state);
-            lh.peripheral
-                .write(
-                    &lh.characteristic,
-                    &[next_state.into()],
-                    WriteType::WithoutResponse,
-                )
-                .await?;
+            if let Some(state) = state {
+                *states.entry(state.porcelain()).or_insert(0) += 1;
+            }
+        }
+        let found: BTreeSet<_> = self
+            .records
+            .iter()
+            .map(|record| &record.name)
+            .chain(self.failures.keys())
+            .collect();
+        let mut parts = vec![format!("{} found", found.len())];
+        parts.extend(
+            states
+                .iter()
+                .map(|(state, count)| format!("{} {}", count, state)),
+        );
+        parts.push(format!("{} failed", self.failures.len()));
+        match missing.len() {
+            0 => {}
+            1 => parts.push("1 requested name not found".to_owned()),
+            count => parts.push(format!("{} requested names not found", count)),
+        }
+        parts.join(", ")
+    }
+
+    /// Every base station as a JSON array, those that failed or were not found with an `error`
+    /// object and the others with a `null` one.
+    fn stations(&self, missing: &[String]) -> String {
+        let mut stations = Vec::new();
+        for record in &self.records {
+            let mut out = String::new();
+            let mut object = Object::new(&mut out);
+            record.write_fields(&mut object);
+            let failure = self.failures.get(&record.name);
+            object.field("error", &failure.map(|failure| &failure.cause));
+            object.finish();
+            stations.push(json::Raw(out));
+        }
+        let recorded: BTreeSet<_> = self.records.iter().map(|record| &record.name).collect();
+        for failure in self.failures.values() {
+            if recorded.contains(&failure.name) {
+                continue;
+            }
+            let mut out = String::new();
+            Object::new(&mut out)
+                .field("name", &failure.name)
+                .field("address", &failure.shown_address)
+                .field("error", &failure.cause)
+                .finish();
+            stations.push(json::Raw(out));
+        }
+        for name in missing {
+            let mut out = String::new();
+            let error = Error::NotFound { name: name.clone() };
+            Object::new(&mut out)
+                .field("name", name)
+                .field("address", &None::<String>)
+                .field("error", &error)
+                .finish();
+            stations.push(json::Raw(out));
+        }
+        stations.to_json()
+    }
+
+    /// How many base stations succeeded, failed or were not found, to alert on partial failures.
+    fn write_counts(&self, object: &mut Object, missing: &[String]) {
+        let succeeded = self
+            .records
+            .iter()
+            .filter(|record| !self.failures.contains_key(&record.name))
+            .count();
+        object
+            .field("succeeded", &succeeded)
+            .field("failed", &self.failures.len())
+            .field("missing", &missing.len());
+    }
+
+    fn print_table(&self) {
+        let mut rows = vec![[
+            "NAME".to_owned(),
+            "ADDRESS".to_owned(),
+            "STATE".to_owned(),
+            "RSSI".to_owned(),
+        ]];
+        rows.extend(self.records.iter().map(|record| {
+            let state = record.describe_state(false);
+            [
+                record.name.clone(),
+                record.address.clone(),
+                match record.requested_state {
+                    Some(requested_state) if record.dry_run => {
+                        format!("{} -> {} (dry run)", state, requested_state)
+                    }
+                    Some(requested_state) => format!("{} -> {}", state, requested_state),
+                    None => match record.note() {
+                        Some(note) => format!("{} ({})", state, note),
+                        None => state.to_string(),
+                    },
+                },
+                record
+                    .rssi
+                    .map(|rssi| rssi.to_string())
+                    .unwrap_or_else(|| "n/a".to_owned()),
+            ]
+        }));
+        let mut widths = [0; 4];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for row in &rows {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            println!("{}", line.trim_end());
         }
     }
-    Ok(())
 }
 
-struct Lighthouse<P> {
-    name: String,
-    peripheral: P,
-    characteristic: Characteristic,
-}
-
-async fn discover<C: Central>(central: &C) -> Result<BoxStream<Result<Lighthouse<C::Peripheral>>>> {
-    central.start_scan(ScanFilter::default()).await?;
-    let events = central.events().await?;
-    Ok(events
-        .filter_map(|ev| async {
-            match ev {
-                CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) => Some(id),
-                _ => None,
-            }
-        })
-        .map(anyhow::Ok)
-        .and_then(move |id| async move { Ok(central.peripheral(&id).await?) })
-        .try_filter_map(move |p| async move {
-            let props = guard!(p.properties().await?, return Ok(None));
-            if !props.manufacturer_data.contains_key(&0x055d) {
-                return Ok(None);
-            }
-            let local_name = guard!(props.local_name, return Ok(None));
-            p.connect().await?;
-            p.discover_services().await?;
-            p.disconnect().await?;
-            let characteristic = p
-                .characteristics()
-                .into_iter()
-                .find(|ch| ch.uuid == CHARACTERISTIC_UUID);
-            let characteristic = guard!(characteristic, return Ok(None));
-            Ok(Some(Lighthouse {
-                name: local_name,
-                peripheral: p,
-                characteristic,
-            }))
-        })
-        .boxed())
+impl Observer for Report {
+    fn progress_interval(&self) -> Option<Duration> {
+        self.status.as_ref().map(|_| progress::INTERVAL)
+    }
+
+    fn progress(&self, filter: &Filter, options: &Options) {
+        self.draw_status(filter, options);
+    }
+
+    fn clear_progress(&self) {
+        self.clear_status();
+    }
+
+    fn discovered<P: Peripheral>(&mut self, lh: &Lighthouse<P>) {
+        Report::discovered(self, lh);
+    }
+
+    fn inspected<P: Peripheral>(&mut self, lh: &Lighthouse<P>) {
+        Report::inspected(self, lh);
+    }
+
+    fn read(&mut self, record: &Record) {
+        self.state_read(record);
+    }
+
+    fn written(&mut self, record: Record) {
+        self.state_written(record);
+    }
+
+    fn changed<P: Peripheral>(
+        &mut self,
+        lh: &Lighthouse<P>,
+        previous: PowerState,
+        state: PowerState,
+    ) {
+        self.state_changed(lh, previous, state);
+    }
+
+    fn failed(&mut self, error: StationError) {
+        self.error(error);
+    }
+
+    fn ignored<P: Peripheral>(&mut self, lh: &Lighthouse<P>) {
+        self.clear_status();
+        eprintln!(
+            "{} [{}]: ignored, --count already reached",
+            lh.name, lh.address
+        );
+    }
+
+    fn not_found(&mut self, names: &[String]) {
+        eprintln!("not found: {}", names.join(", "));
+    }
+
+    fn held_back(&mut self) {
+        eprintln!("nothing handled, since not every base station was found");
+    }
+
+    fn scheduled(
+        &self,
+        command: &str,
+        count: usize,
+        after: Duration,
+    ) -> LocalBoxFuture<'static, ()> {
+        let at = SystemTime::now() + after;
+        eprintln!(
+            "{} for {} base stations at {}, in {}",
+            command,
+            count,
+            humantime::format_rfc3339_seconds(at),
+            humantime::format_duration(after)
+        );
+        countdown(after).boxed_local()
+    }
+}
+
+/// Waits for `--after`, showing the time left on a terminal.
+async fn countdown(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    if !io::stderr().is_terminal() {
+        tokio::time::sleep_until(deadline).await;
+        return;
+    }
+    /// Clears the line when done, or when cancelled by a signal.
+    struct Line;
+    impl Drop for Line {
+        fn drop(&mut self) {
+            eprint!("\r\x1b[K");
+        }
+    }
+    let _line = Line;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        let seconds = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+        let shown = humantime::format_duration(Duration::from_secs(seconds));
+        eprint!("\r\x1b[K{} left", shown);
+        // Until the next whole second.
+        tokio::time::sleep(left - Duration::from_secs(seconds - 1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{MockCentral, MockPeripheral};
+
+    #[test]
+    fn names_are_separated_by_whitespace() {
+        let names = read_names("LHB-4A3C91F2  LHB-93D0B1E4\tLHB-01234567\n".as_bytes()).unwrap();
+        assert_eq!(names, ["LHB-4A3C91F2", "LHB-93D0B1E4", "LHB-01234567"]);
+    }
+
+    #[test]
+    fn names_ignore_blank_lines_and_comments() {
+        let input = "# room A\nLHB-4A3C91F2 # left\n\n   \nLHB-93D0B1E4\n";
+        let names = read_names(input.as_bytes()).unwrap();
+        assert_eq!(names, ["LHB-4A3C91F2", "LHB-93D0B1E4"]);
+    }
+
+    #[test]
+    fn empty_names() {
+        assert!(read_names("".as_bytes()).unwrap().is_empty());
+        assert!(read_names("  ".as_bytes()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn groups_are_expanded() {
+        let mut config = Config::default();
+        config.groups.insert(
+            "room-a".into(),
+            vec!["LHB-4A3C91F2".into(), "LHB-93D0B1E4".into()],
+        );
+        config
+            .groups
+            .insert("left".into(), vec!["LHB-4A3C91F2".into()]);
+        let names = vec!["@room-a".into(), "@left".into(), "LHB-01234567".into()];
+        let names = expand_groups(names, &config).unwrap();
+        assert_eq!(
+            names,
+            [
+                "LHB-4A3C91F2",
+                "LHB-93D0B1E4",
+                "LHB-4A3C91F2",
+                "LHB-01234567"
+            ]
+        );
+        assert!(expand_groups(vec!["@room-b".into()], &config).is_err());
+    }
+
+    #[test]
+    fn shared_options_go_before_or_after_the_command() {
+        Cli::command().debug_assert();
+        let args = [
+            "lighthousectl",
+            "--adapter",
+            "hci1",
+            "on",
+            "--wait",
+            "LHB-4A3C91F2",
+        ];
+        let cli = Cli::try_parse_from(args.into_iter().chain(["--timeout", "10"])).unwrap();
+        assert_eq!(cli.adapter.as_deref(), Some("hci1"));
+        assert_eq!(cli.timeout, Some(Duration::from_secs(10)));
+        let Command::On { targets, power, .. } = cli.command else {
+            panic!("parsed as another command");
+        };
+        assert_eq!(targets.names, ["LHB-4A3C91F2"]);
+        assert!(power.wait);
+        // The options of a command are not taken by the others.
+        assert!(Cli::try_parse_from(["lighthousectl", "scan", "--wait"]).is_err());
+        let cli = Cli::try_parse_from(["lighthousectl", "set-channel", "3", "LHB-4A3C91F2"]);
+        assert!(matches!(
+            cli.unwrap().command,
+            Command::SetChannel { channel: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn command_arguments_are_validated() {
+        assert_eq!(parse_byte("0x09").unwrap(), 0x09);
+        assert_eq!(parse_byte("ff").unwrap(), 0xff);
+        assert!(parse_byte("0x100").is_err());
+        assert_eq!(parse_bytes("0x0b00").unwrap().0, [0x0b, 0x00]);
+        assert!(parse_bytes("0b0").is_err());
+        assert!(parse_bytes("").is_err());
+        assert_eq!(parse_channel("16").unwrap(), 16);
+        assert!(parse_channel("0").is_err());
+        assert!(parse_channel("17").is_err());
+    }
+
+    #[test]
+    fn failures_outweigh_timeouts_and_missing_base_stations() {
+        let mut failures = BTreeMap::new();
+        let operation = Operation::Connect;
+        let timed_out = StationError::new("LHB-4A3C91F2", Error::Timeout { operation });
+        failures.insert(timed_out.name.clone(), timed_out);
+        let missing = vec!["LHB-1D0E77B5".to_owned()];
+        assert_eq!(
+            Outcome::new(missing.clone(), &BTreeMap::new()).exit_status(),
+            EXIT_NOT_FOUND
+        );
+        assert_eq!(
+            Outcome::new(missing.clone(), &failures).exit_status(),
+            EXIT_TIMED_OUT
+        );
+        let unsupported = Error::Unsupported("standby is unsupported on V1, use sleep instead");
+        let failed = StationError::new("LHB-93D0B1E4", unsupported);
+        failures.insert(failed.name.clone(), failed);
+        assert_eq!(Outcome::new(missing, &failures).exit_status(), EXIT_FAILED);
+        assert!(Outcome::new(Vec::new(), &BTreeMap::new())
+            .into_result()
+            .is_ok());
+    }
+
+    #[test]
+    fn mistyped_serials_are_matched_to_the_base_stations_seen() {
+        assert_eq!(edit_distance("lhb-93d0b1e5", "lhb-93d0b1e4"), 1);
+        assert_eq!(edit_distance("lhb-93d0b1e", "lhb-93d0b1e4"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        let mut report = Report::new(OutputMode::Plain, false);
+        report.seen.insert("LHB-93D0B1E4".to_owned());
+        report.seen.insert("LHB-4A3C91F2".to_owned());
+        assert_eq!(
+            report.nearest_unrequested("LHB-93D0B1E5"),
+            Some("LHB-93D0B1E4")
+        );
+        assert_eq!(report.nearest_unrequested("LHB-1D0E77B5"), None);
+    }
+
+    #[test]
+    fn failed_and_missing_base_stations_are_in_the_json() {
+        let mut report = Report::new(OutputMode::Json, false);
+        let operation = Operation::Connect;
+        let timed_out = StationError::new("LHB-4A3C91F2", Error::Timeout { operation });
+        report.error(timed_out);
+        let missing = ["LHB-1D0E77B5".to_owned()];
+        assert_eq!(
+            report.stations(&missing),
+            concat!(
+                r#"[{"name":"LHB-4A3C91F2","address":null,"#,
+                r#""error":{"kind":"connect_timeout","message":"connect timed out"}},"#,
+                r#"{"name":"LHB-1D0E77B5","address":null,"#,
+                r#""error":{"kind":"not_found","message":"LHB-1D0E77B5 was not found"}}]"#
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn sorted_results_end_with_the_counts() {
+        let central = MockCentral::new(vec![
+            MockPeripheral::base_station(1, "LHB-93D0B1E4", 0x0b),
+            MockPeripheral::base_station(2, "LHB-4A3C91F2", 0x00),
+        ]);
+        let mut filter = Filter::new(Vec::new(), false, Vec::new());
+        let options = Options::default();
+        let mut report = Report::new(OutputMode::Plain, false);
+        report.sorted = true;
+        let report = RefCell::new(report);
+        scan(&central, Action::Show, &mut filter, &options, &report)
+            .await
+            .unwrap();
+        let mut report = report.into_inner();
+        report.finish(&["LHB-1D0E77B5".to_owned()]);
+        let names: Vec<_> = report.records.iter().map(|record| &record.name).collect();
+        assert_eq!(names, ["LHB-4A3C91F2", "LHB-93D0B1E4"]);
+        assert_eq!(
+            report.summary(&["LHB-1D0E77B5".to_owned()]),
+            "2 found, 1 on, 1 sleep, 0 failed, 1 requested name not found"
+        );
+    }
 }
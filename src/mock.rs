@@ -0,0 +1,294 @@
+//! Scripted stand-ins for an adapter and the peripherals it sees, for testing discovery and
+//! control without any Bluetooth hardware.
+
+// Shared by the tests of the library and of the command, which each use only some of it.
+#![allow(dead_code)]
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use btleplug::{
+    api::{
+        BDAddr, CentralEvent, CharPropFlags, Characteristic, PeripheralProperties, ScanFilter,
+        Service, ValueNotification, WriteType,
+    },
+    platform::PeripheralId,
+};
+use futures::{stream, Stream, StreamExt};
+use uuid::Uuid;
+
+// Spelled out rather than imported, since the library and the command name them differently.
+const SERVICE_UUID: Uuid = uuid::uuid!("00001523-1212-efde-1523-785feabcd124");
+const CHARACTERISTIC_UUID: Uuid = uuid::uuid!("00001525-1212-efde-1523-785feabcd124");
+
+/// A peripheral ID for the address, which each platform makes differently.
+#[cfg(target_os = "linux")]
+fn peripheral_id(address: BDAddr) -> PeripheralId {
+    use serde::{
+        de::value::{Error, MapDeserializer},
+        Deserialize,
+    };
+    let path = format!(
+        "/org/bluez/hci0/dev_{}",
+        address.to_string().replace(':', "_")
+    );
+    let fields = MapDeserializer::<_, Error>::new([("object_path", path)].into_iter());
+    bluez_async::DeviceId::deserialize(fields)
+        .expect("a valid D-Bus path")
+        .into()
+}
+
+#[cfg(target_os = "macos")]
+fn peripheral_id(address: BDAddr) -> PeripheralId {
+    Uuid::from_u64_pair(
+        0,
+        address
+            .into_inner()
+            .iter()
+            .fold(0, |id, &b| id << 8 | u64::from(b)),
+    )
+    .into()
+}
+
+#[cfg(windows)]
+fn peripheral_id(address: BDAddr) -> PeripheralId {
+    address.into()
+}
+
+/// What a peripheral has been through, shared by its clones.
+#[derive(Debug, Default)]
+pub struct Log {
+    pub connected: bool,
+    /// The value of the power characteristic.
+    pub power: u8,
+    /// Everything written to the peripheral, in order.
+    pub writes: Vec<Vec<u8>>,
+    /// Whether writes are denied, as some platforms do until the peripheral is paired.
+    pub denying: bool,
+    /// How many writes were denied.
+    pub denied: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct MockPeripheral {
+    id: PeripheralId,
+    props: PeripheralProperties,
+    services: BTreeSet<Service>,
+    pub log: Arc<Mutex<Log>>,
+}
+
+impl MockPeripheral {
+    /// A V2 base station advertising the Valve manufacturer data, with the power characteristic
+    /// reading `power`.
+    pub fn base_station(last_byte: u8, name: &str, power: u8) -> Self {
+        let power_characteristic = Characteristic {
+            uuid: CHARACTERISTIC_UUID,
+            service_uuid: SERVICE_UUID,
+            properties: CharPropFlags::READ | CharPropFlags::WRITE,
+        };
+        let service = Service {
+            uuid: SERVICE_UUID,
+            primary: true,
+            characteristics: [power_characteristic].into(),
+        };
+        let mut peripheral = Self::other(last_byte, name);
+        peripheral.props.manufacturer_data = HashMap::from([(0x055d, vec![0x02])]);
+        peripheral.services = [service].into();
+        peripheral.log.lock().unwrap().power = power;
+        peripheral
+    }
+
+    /// A peripheral of another company, advertising nothing of a base station.
+    pub fn other(last_byte: u8, name: &str) -> Self {
+        let address = BDAddr::from([0, 0, 0, 0, 0, last_byte]);
+        let props = PeripheralProperties {
+            address,
+            local_name: Some(name.to_owned()),
+            rssi: Some(-60),
+            ..PeripheralProperties::default()
+        };
+        Self {
+            id: peripheral_id(address),
+            props,
+            services: BTreeSet::new(),
+            log: Arc::default(),
+        }
+    }
+
+    /// Drops the power characteristic, as a firmware that lacks it would.
+    pub fn without_power_characteristic(mut self) -> Self {
+        for mut service in std::mem::take(&mut self.services) {
+            service
+                .characteristics
+                .retain(|ch| ch.uuid != CHARACTERISTIC_UUID);
+            self.services.insert(service);
+        }
+        self
+    }
+
+    pub fn writes(&self) -> Vec<Vec<u8>> {
+        self.log.lock().unwrap().writes.clone()
+    }
+}
+
+#[async_trait]
+impl btleplug::api::Peripheral for MockPeripheral {
+    fn id(&self) -> PeripheralId {
+        self.id.clone()
+    }
+
+    fn address(&self) -> BDAddr {
+        self.props.address
+    }
+
+    async fn properties(&self) -> btleplug::Result<Option<PeripheralProperties>> {
+        Ok(Some(self.props.clone()))
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        self.services.clone()
+    }
+
+    async fn is_connected(&self) -> btleplug::Result<bool> {
+        Ok(self.log.lock().unwrap().connected)
+    }
+
+    async fn connect(&self) -> btleplug::Result<()> {
+        self.log.lock().unwrap().connected = true;
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> btleplug::Result<()> {
+        self.log.lock().unwrap().connected = false;
+        Ok(())
+    }
+
+    async fn discover_services(&self) -> btleplug::Result<()> {
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        _: WriteType,
+    ) -> btleplug::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        if !log.connected {
+            return Err(btleplug::Error::NotConnected);
+        }
+        if log.denying {
+            log.denied += 1;
+            return Err(btleplug::Error::PermissionDenied);
+        }
+        log.writes.push(data.to_vec());
+        if characteristic.uuid == CHARACTERISTIC_UUID {
+            log.power = data[0];
+        }
+        Ok(())
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> btleplug::Result<Vec<u8>> {
+        let log = self.log.lock().unwrap();
+        match (log.connected, characteristic.uuid) {
+            (false, _) => Err(btleplug::Error::NotConnected),
+            (true, CHARACTERISTIC_UUID) => Ok(vec![log.power]),
+            (true, _) => Err(btleplug::Error::NotSupported("read".to_owned())),
+        }
+    }
+
+    async fn subscribe(&self, _: &Characteristic) -> btleplug::Result<()> {
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, _: &Characteristic) -> btleplug::Result<()> {
+        Ok(())
+    }
+
+    async fn notifications(
+        &self,
+    ) -> btleplug::Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>> {
+        Ok(Box::pin(stream::empty()))
+    }
+}
+
+/// An adapter that reports each of its peripherals as discovered once scanning, and then either
+/// ends the events, as if it went away, or goes quiet.
+#[derive(Clone)]
+pub struct MockCentral {
+    pub peripherals: Vec<MockPeripheral>,
+    forever: bool,
+    advertising: bool,
+}
+
+impl MockCentral {
+    pub fn new(peripherals: Vec<MockPeripheral>) -> Self {
+        Self {
+            peripherals,
+            forever: false,
+            advertising: true,
+        }
+    }
+
+    /// Reports no discoveries, as BlueZ does for peripherals it already knows of and that have
+    /// not changed, while still listing them as known.
+    pub fn without_advertisements(mut self) -> Self {
+        self.advertising = false;
+        self
+    }
+
+    /// Keeps the events going after the discoveries, so that only being done can end a scan.
+    pub fn scanning_forever(mut self) -> Self {
+        self.forever = true;
+        self
+    }
+}
+
+#[async_trait]
+impl btleplug::api::Central for MockCentral {
+    type Peripheral = MockPeripheral;
+
+    async fn events(&self) -> btleplug::Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
+        let ids: Vec<_> = match self.advertising {
+            true => self.peripherals.iter().map(|p| p.id.clone()).collect(),
+            false => Vec::new(),
+        };
+        let discovered = stream::iter(ids).map(CentralEvent::DeviceDiscovered);
+        Ok(match self.forever {
+            true => Box::pin(discovered.chain(stream::pending())),
+            false => Box::pin(discovered),
+        })
+    }
+
+    async fn start_scan(&self, _: ScanFilter) -> btleplug::Result<()> {
+        Ok(())
+    }
+
+    async fn stop_scan(&self) -> btleplug::Result<()> {
+        Ok(())
+    }
+
+    async fn peripherals(&self) -> btleplug::Result<Vec<MockPeripheral>> {
+        Ok(self.peripherals.clone())
+    }
+
+    async fn peripheral(&self, id: &PeripheralId) -> btleplug::Result<MockPeripheral> {
+        self.peripherals
+            .iter()
+            .find(|p| &p.id == id)
+            .cloned()
+            .ok_or(btleplug::Error::DeviceNotFound)
+    }
+
+    async fn add_peripheral(&self, id: &PeripheralId) -> btleplug::Result<MockPeripheral> {
+        self.peripheral(id).await
+    }
+
+    async fn adapter_info(&self) -> btleplug::Result<String> {
+        Ok("mock".to_owned())
+    }
+}
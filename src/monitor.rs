@@ -0,0 +1,202 @@
+//! `scan --interval`: reads the power states again on a schedule, reusing the discovered base
+//! stations instead of discovering them again.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use btleplug::{
+    api::{BDAddr, Central, Peripheral},
+    platform::PeripheralId,
+};
+use futures::{
+    future::{self, LocalBoxFuture},
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use tokio::{sync::oneshot, time::MissedTickBehavior};
+
+use crate::{
+    discover, history, hook::Hook, release, supervisor::Supervisor, Error, Filter, History,
+    Lighthouse, Options, PowerState, Protocol, Record, Report, StationError,
+};
+
+struct Station<P> {
+    lh: Lighthouse<P>,
+    /// The last known power state, to tell whether it changed. Carried on from the history when
+    /// a lost base station is found again.
+    state: Option<PowerState>,
+}
+
+/// A base station handed back from a reading, along with the result.
+type Read<P> = (Station<P>, Result<u8, Error>);
+
+/// Reads the power state of a base station kept from discovery.
+async fn read<P: Peripheral>(station: Station<P>, options: &Options) -> Read<P> {
+    let lh = &station.lh;
+    let result = lh.session(options, lh.read_power(options.io_timeout)).await;
+    (station, result)
+}
+
+/// `--interval`, `--always-print` and `--on-change`.
+pub struct Sampling<'a> {
+    pub interval: Duration,
+    /// Report every sample instead of only the changes.
+    pub always_print: bool,
+    pub hook: Option<&'a Hook>,
+}
+
+/// Runs until Ctrl-C or SIGTERM.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &Filter,
+    options: &Options,
+    report: &mut Report,
+    sampling: Sampling<'_>,
+    supervisor: &mut Supervisor,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let hook = sampling.hook;
+    let hooks = async {
+        match hook {
+            Some(hook) => hook.run().await,
+            None => future::pending().await,
+        }
+    };
+    tokio::pin!(hooks);
+    let handled = Mutex::new(BTreeSet::new());
+    let unnamed = filter.addresses();
+    let mut discoveries = discover(central, options, &unnamed, &handled, false).await?;
+    let mut stations = Vec::new();
+    // The base stations being read, which are out of `stations` meanwhile.
+    let mut readings: FuturesUnordered<LocalBoxFuture<Read<C::Peripheral>>> =
+        FuturesUnordered::new();
+    // Kept by address, so that base stations lost and found again carry on their history.
+    let histories: Mutex<BTreeMap<BDAddr, History>> = Mutex::new(BTreeMap::new());
+    let mut ticks = tokio::time::interval(sampling.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+    let mut signals = signals;
+    loop {
+        tokio::select! {
+            discovery = discoveries.next() => match discovery {
+                Some(Ok(mut lh)) => {
+                    let matched = filter.is_matched(&lh.name, lh.address)
+                        && !filter.is_excluded(&lh.name, lh.address);
+                    if !matched || matches!(lh.protocol, Protocol::V1) {
+                        lh.disconnect().await.ok();
+                        continue;
+                    }
+                    // Still connected from discovery, so the first sample is taken right away.
+                    let sampled = lh.read_power(options.io_timeout).await;
+                    lh.disconnect().await.ok();
+                    let seen = histories.lock().unwrap().get(&lh.address).map(|seen| seen.state);
+                    let mut station = Station { lh, state: seen };
+                    match sampled {
+                        Ok(raw_state) => {
+                            sample(report, hook, &mut station, raw_state, true, options, &histories);
+                            let count = stations.len() + readings.len() + 1;
+                            supervisor.found(&station.lh.name, station.lh.address, count);
+                            stations.push(station);
+                        }
+                        Err(error) => lose(report, &handled, &station.lh, error),
+                    }
+                }
+                Some(Err(error)) => {
+                    if filter.is_matched(&error.name, error.address.unwrap_or_default()) {
+                        report.error(error);
+                    }
+                }
+                None => bail!("the adapter stopped reporting base stations"),
+            },
+            _ = ticks.tick() => {
+                for station in stations.drain(..) {
+                    readings.push(read(station, options).boxed_local());
+                }
+            }
+            Some((mut station, result)) = readings.next(), if !readings.is_empty() => {
+                match result {
+                    Ok(raw_state) => {
+                        let always_print = sampling.always_print;
+                        sample(report, hook, &mut station, raw_state, always_print, options, &histories);
+                        stations.push(station);
+                    }
+                    Err(error) => lose(report, &handled, &station.lh, error),
+                }
+            }
+            _ = supervisor.due() => {
+                let count = stations.len() + readings.len();
+                supervisor.check(central, count, options.io_timeout).await;
+            }
+            _ = &mut hooks => {}
+            Ok(_) = &mut signals => break,
+        }
+    }
+    if supervisor.sleeps_on_exit() {
+        while let Some((station, _)) = readings.next().await {
+            stations.push(station);
+        }
+    }
+    drop(readings);
+    let tracked = stations.iter().map(|station| &station.lh);
+    supervisor.stop(tracked, options).await;
+    release(central, options.io_timeout).await;
+    report.finish(&[]);
+    Ok(())
+}
+
+/// Reports the failure and lets the base station be discovered again once it advertises, to be
+/// sampled from then on.
+fn lose<P: Peripheral>(
+    report: &mut Report,
+    handled: &Mutex<BTreeSet<PeripheralId>>,
+    lh: &Lighthouse<P>,
+    error: Error,
+) {
+    report.error(StationError::at(
+        &lh.name,
+        &lh.peripheral,
+        lh.address,
+        error,
+    ));
+    handled.lock().unwrap().remove(&lh.peripheral.id());
+}
+
+fn sample<P: Peripheral>(
+    report: &mut Report,
+    hook: Option<&Hook>,
+    station: &mut Station<P>,
+    raw_state: u8,
+    always_print: bool,
+    options: &Options,
+    histories: &Mutex<BTreeMap<BDAddr, History>>,
+) {
+    let state = PowerState::from(raw_state);
+    let changed = station.state != Some(state);
+    if changed {
+        PowerState::check(raw_state, &station.lh.name, options);
+    }
+    if let (Some(previous), true) = (station.state, changed) {
+        let source = history::Source::Poll;
+        report.transition(&station.lh, previous, state, source);
+    }
+    if let (Some(hook), Some(previous), true) = (hook, station.state, changed) {
+        let lh = &station.lh;
+        let address = lh.shown_address();
+        hook.changed(&lh.name, &address, previous, state);
+    }
+    station.state = Some(state);
+    let (history, _) = History::observe_in(histories, station.lh.address, state);
+    if changed || always_print {
+        let record = Record {
+            raw_state: Some(raw_state),
+            history: Some(history),
+            last_seen: history.last_seen,
+            ..Record::new(&station.lh)
+        };
+        report.state_read(&record);
+    }
+}
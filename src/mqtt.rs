@@ -0,0 +1,735 @@
+//! `mqtt`: bridges the base stations to an MQTT broker, speaking just enough MQTT 3.1.1 to
+//! publish and subscribe at QoS 0.
+//!
+//! Under the prefix, `lighthousectl` by default:
+//!
+//! - `<prefix>/status` is `online`, or `offline` once the bridge goes away.
+//! - `<prefix>/<name>/state` is the power state: `on`, `sleep`, `standby`, `booting`, `stopping`
+//!   or `unknown`.
+//! - `<prefix>/<name>/availability` is `online`, or `offline` once the base station is forgotten.
+//! - `<prefix>/<name>/set` takes `on`, `sleep`, `standby` or `off`.
+//!
+//! A Home Assistant switch is announced for each base station under the discovery prefix.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    io, process,
+    rc::Rc,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use btleplug::{
+    api::{Central, Peripheral},
+    platform::PeripheralId,
+};
+use futures::{
+    future::LocalBoxFuture,
+    stream::{self, FuturesUnordered, LocalBoxStream},
+    FutureExt, StreamExt,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::oneshot,
+    time::{timeout, Instant},
+};
+
+use crate::{
+    config, discover, fold,
+    json::{Object, Raw},
+    release,
+    stations::{self, Station, Stations, REFRESH_INTERVAL},
+    supervisor::Supervisor,
+    Filter, Options, PowerCommand, PowerState,
+};
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_PREFIX: &str = "lighthousectl";
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+/// How long the broker may take to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The broker drops the connection after one and a half times this without a packet.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Larger packets are refused, since the bridge only receives short commands.
+const MAX_PACKET: usize = 64 * 1024;
+
+/// The broker and topics, from the `[mqtt]` table of the config.
+pub struct Settings {
+    broker: String,
+    username: Option<String>,
+    password: Option<String>,
+    client_id: String,
+    prefix: String,
+    discovery_prefix: String,
+    /// What `off` means, from `--off-means`.
+    off: PowerCommand,
+}
+
+impl Settings {
+    pub fn new(config: &config::Mqtt, off: PowerCommand) -> Result<Self> {
+        let broker = match config.broker.as_deref() {
+            Some(broker) => broker.trim_end_matches('/'),
+            None => bail!("mqtt requires `broker` in the `[mqtt]` table of the config"),
+        };
+        if broker.starts_with("mqtts://") || broker.starts_with("ssl://") {
+            bail!("TLS connections to the broker are not supported");
+        }
+        let broker = broker
+            .strip_prefix("mqtt://")
+            .or_else(|| broker.strip_prefix("tcp://"))
+            .unwrap_or(broker);
+        let has_port = match broker.rsplit_once(':') {
+            Some((host, port)) => !host.ends_with(':') && port.parse::<u16>().is_ok(),
+            None => false,
+        };
+        // MQTT 3.1.1 only allows a password along with a username.
+        if config.password.is_some() && config.username.is_none() {
+            bail!("`password` in the `[mqtt]` table of the config requires `username`");
+        }
+        let broker = if has_port {
+            broker.to_owned()
+        } else {
+            format!("{}:{}", broker, DEFAULT_PORT)
+        };
+        Ok(Self {
+            broker,
+            username: config.username.clone(),
+            password: config.password.clone(),
+            client_id: config
+                .client_id
+                .clone()
+                .unwrap_or_else(|| format!("lighthousectl-{}", process::id())),
+            prefix: config
+                .prefix
+                .as_deref()
+                .unwrap_or(DEFAULT_PREFIX)
+                .to_owned(),
+            discovery_prefix: config
+                .discovery_prefix
+                .as_deref()
+                .unwrap_or(DEFAULT_DISCOVERY_PREFIX)
+                .to_owned(),
+            off,
+        })
+    }
+
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    fn topic(&self, name: &str, leaf: &str) -> String {
+        format!("{}/{}/{}", self.prefix, name, leaf)
+    }
+
+    /// The base station and power state of a `<prefix>/<name>/set` message.
+    fn command<'a>(&self, topic: &'a str, payload: &[u8]) -> Option<(&'a str, PowerCommand)> {
+        let name = topic
+            .strip_prefix(&self.prefix)?
+            .strip_prefix('/')?
+            .strip_suffix("/set")?;
+        let state = match String::from_utf8_lossy(payload)
+            .trim()
+            .to_lowercase()
+            .as_str()
+        {
+            "on" => PowerCommand::On,
+            "sleep" => PowerCommand::Sleep,
+            "standby" => PowerCommand::Standby,
+            "off" => self.off,
+            payload => {
+                log::warn!("{}: unknown command `{}`", name, payload);
+                return None;
+            }
+        };
+        Some((name, state))
+    }
+}
+
+enum Packet {
+    /// The return code, 0 when accepted.
+    ConnAck(u8),
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        retain: bool,
+    },
+    Other,
+}
+
+/// Appends a string or binary field, prefixed by its length.
+fn put(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u16).to_be_bytes());
+    out.extend(bytes);
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    out.extend(body);
+    out
+}
+
+fn connect_packet(settings: &Settings) -> Vec<u8> {
+    let mut body = Vec::new();
+    put(&mut body, b"MQTT");
+    body.push(4);
+    // Clean session, and a retained will, so that the status turns `offline` on a crash.
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if settings.username.is_some() {
+        flags |= 0x80;
+    }
+    if settings.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend((KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    put(&mut body, settings.client_id.as_bytes());
+    put(&mut body, settings.status_topic().as_bytes());
+    put(&mut body, b"offline");
+    for field in [&settings.username, &settings.password]
+        .into_iter()
+        .flatten()
+    {
+        put(&mut body, field.as_bytes());
+    }
+    packet(0x10, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put(&mut body, topic.as_bytes());
+    body.extend(payload);
+    packet(0x30 | u8::from(retain), &body)
+}
+
+fn subscribe_packet(id: u16, filter: &str) -> Vec<u8> {
+    let mut body = id.to_be_bytes().to_vec();
+    put(&mut body, filter.as_bytes());
+    body.push(0);
+    packet(0x82, &body)
+}
+
+const PINGREQ: [u8; 2] = [0xc0, 0];
+const DISCONNECT: [u8; 2] = [0xe0, 0];
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Packet> {
+    let header = reader.read_u8().await?;
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = reader.read_u8().await?;
+        length |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        // The remaining length takes at most four bytes.
+        if shift > 21 {
+            return Err(invalid("malformed remaining length"));
+        }
+    }
+    if length > MAX_PACKET {
+        return Err(invalid("packet too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(match header >> 4 {
+        2 => Packet::ConnAck(*body.get(1).ok_or_else(|| invalid("short CONNACK"))?),
+        3 => {
+            let (length, rest) = match body.as_slice() {
+                [high, low, rest @ ..] => (usize::from(u16::from_be_bytes([*high, *low])), rest),
+                _ => return Err(invalid("short PUBLISH")),
+            };
+            // A packet identifier follows the topic above QoS 0.
+            let skip = if header & 0x06 != 0 { 2 } else { 0 };
+            if rest.len() < length + skip {
+                return Err(invalid("short PUBLISH"));
+            }
+            Packet::Publish {
+                topic: String::from_utf8_lossy(&rest[..length]).into_owned(),
+                payload: rest[length + skip..].to_vec(),
+                retain: header & 0x01 != 0,
+            }
+        }
+        _ => Packet::Other,
+    })
+}
+
+struct Broker {
+    writer: OwnedWriteHalf,
+    packets: LocalBoxStream<'static, io::Result<Packet>>,
+}
+
+impl Broker {
+    async fn connect(settings: &Settings) -> io::Result<Self> {
+        let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        let connecting = TcpStream::connect(&settings.broker);
+        let stream = timeout(CONNECT_TIMEOUT, connecting)
+            .await
+            .map_err(|_| timed_out())??;
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(&connect_packet(settings)).await?;
+        let mut packets = stream::unfold(BufReader::new(reader), |mut reader| async move {
+            let packet = read_packet(&mut reader).await;
+            Some((packet, reader))
+        })
+        .boxed_local();
+        let code = match timeout(CONNECT_TIMEOUT, packets.next()).await {
+            Ok(Some(Ok(Packet::ConnAck(code)))) => code,
+            Ok(Some(Ok(_))) => return Err(invalid("expected CONNACK")),
+            Ok(Some(Err(error))) => return Err(error),
+            Ok(None) => unreachable!(),
+            Err(_) => return Err(timed_out()),
+        };
+        let refused = match code {
+            0 => None,
+            1 => Some("unacceptable protocol version"),
+            2 => Some("client identifier rejected"),
+            3 => Some("server unavailable"),
+            4 => Some("bad username or password"),
+            5 => Some("not authorized"),
+            _ => Some("refused"),
+        };
+        if let Some(reason) = refused {
+            let message = format!("connection refused: {}", reason);
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, message));
+        }
+        let filter = format!("{}/+/set", settings.prefix);
+        writer.write_all(&subscribe_packet(1, &filter)).await?;
+        Ok(Self { writer, packets })
+    }
+
+    async fn publish(&mut self, topic: &str, payload: &str, retain: bool) -> io::Result<()> {
+        let packet = publish_packet(topic, payload.as_bytes(), retain);
+        self.writer.write_all(&packet).await
+    }
+
+    /// Announces the bridge and every known base station.
+    async fn announce<P>(&mut self, settings: &Settings, stations: &Stations<P>) -> io::Result<()> {
+        self.publish(&settings.status_topic(), "online", true)
+            .await?;
+        let all: Vec<_> = stations.borrow().values().cloned().collect();
+        for station in all {
+            self.announce_station(settings, &station).await?;
+        }
+        Ok(())
+    }
+
+    async fn announce_station<P>(
+        &mut self,
+        settings: &Settings,
+        station: &Station<P>,
+    ) -> io::Result<()> {
+        let lh = &station.lh;
+        let id = format!("lighthousectl_{}", lh.address.to_string_no_delim());
+        let topic = format!("{}/switch/{}/config", settings.discovery_prefix, id);
+        self.publish(&topic, &discovery_payload(settings, station, &id), true)
+            .await?;
+        self.update(settings, station, true).await
+    }
+
+    async fn update<P>(
+        &mut self,
+        settings: &Settings,
+        station: &Station<P>,
+        available: bool,
+    ) -> io::Result<()> {
+        let name = &station.lh.name;
+        let availability = if available { "online" } else { "offline" };
+        self.publish(&settings.topic(name, "availability"), availability, true)
+            .await?;
+        if let Some(raw_state) = station.raw_state.get() {
+            let state = PowerState::from(raw_state).porcelain();
+            self.publish(&settings.topic(name, "state"), state, true)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+fn discovery_payload<P>(settings: &Settings, station: &Station<P>, id: &str) -> String {
+    let name = &station.lh.name;
+    let mut device = String::new();
+    Object::new(&mut device)
+        .field("identifiers", &[id][..])
+        .field("name", name)
+        .field("manufacturer", "Valve")
+        .field("model", "Base Station 2.0")
+        .finish();
+    let mut availability = String::from("[");
+    for (i, topic) in [
+        settings.status_topic(),
+        settings.topic(name, "availability"),
+    ]
+    .iter()
+    .enumerate()
+    {
+        if i > 0 {
+            availability.push(',');
+        }
+        Object::new(&mut availability)
+            .field("topic", topic)
+            .finish();
+    }
+    availability.push(']');
+    let mut out = String::new();
+    Object::new(&mut out)
+        // Named after the device.
+        .field("name", &None::<String>)
+        .field("unique_id", id)
+        .field("state_topic", &settings.topic(name, "state"))
+        .field("command_topic", &settings.topic(name, "set"))
+        .field(
+            "value_template",
+            "{{ 'ON' if value in ('on', 'booting') else 'OFF' }}",
+        )
+        .field("state_on", "ON")
+        .field("state_off", "OFF")
+        .field("payload_on", "on")
+        .field("payload_off", settings.off.porcelain())
+        .field("availability", &Raw(availability))
+        .field("availability_mode", "all")
+        .field("device", &Raw(device))
+        .finish();
+    out
+}
+
+/// The connection to the broker, which is made again whenever it is lost.
+struct Link {
+    broker: Option<Broker>,
+    retry_at: Instant,
+    delay: Duration,
+}
+
+impl Link {
+    async fn next_packet(&mut self) -> Option<io::Result<Packet>> {
+        match &mut self.broker {
+            Some(broker) => broker.packets.next().await,
+            None => None,
+        }
+    }
+
+    async fn connect<P>(&mut self, settings: &Settings, stations: &Stations<P>) {
+        let connected = async {
+            let mut broker = Broker::connect(settings).await?;
+            broker.announce(settings, stations).await?;
+            io::Result::Ok(broker)
+        };
+        match connected.await {
+            Ok(broker) => {
+                log::info!("connected to {}", settings.broker);
+                self.broker = Some(broker);
+                self.delay = Duration::from_secs(1);
+            }
+            Err(error) => {
+                log::warn!(
+                    "connecting to {} failed: {}, retrying in {}",
+                    settings.broker,
+                    error,
+                    humantime::format_duration(self.delay)
+                );
+                self.retry_at = Instant::now() + self.delay;
+                self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+
+    /// Forgets the broker unless `result` succeeded, to connect again right away.
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(error) = result {
+            log::warn!("lost the connection to the broker: {}", error);
+            self.broker = None;
+            self.retry_at = Instant::now();
+        }
+    }
+}
+
+/// Passes on a base station to publish, and whether it is still known.
+type Task<'a, P> = LocalBoxFuture<'a, (Rc<Station<P>>, bool)>;
+
+/// Runs until Ctrl-C or SIGTERM, then disconnects from the broker and every base station.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &Filter,
+    options: &Options,
+    settings: &Settings,
+    supervisor: &mut Supervisor,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let stations: Stations<C::Peripheral> = RefCell::new(BTreeMap::new());
+    let handled = Mutex::new(BTreeSet::new());
+    let unnamed = filter.addresses();
+    let mut discoveries = discover(central, options, &unnamed, &handled, false).await?;
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+    let mut pings = tokio::time::interval(PING_INTERVAL);
+    let mut tasks: FuturesUnordered<Task<C::Peripheral>> = FuturesUnordered::new();
+    let mut link = Link {
+        broker: None,
+        retry_at: Instant::now(),
+        delay: Duration::from_secs(1),
+    };
+    let mut signals = signals;
+    let result = loop {
+        tokio::select! {
+            discovery = discoveries.next() => match discovery {
+                Some(Ok(lh)) => if let Some(station) = stations::track(lh, filter, "MQTT bridge").await {
+                    stations.borrow_mut().insert(fold(&station.lh.name), station.clone());
+                    supervisor.found(&station.lh.name, station.lh.address, stations.borrow().len());
+                    if let Some(broker) = &mut link.broker {
+                        let announced = broker.announce_station(settings, &station).await;
+                        link.check(announced);
+                    }
+                    tasks.push(refresh_station(station, &stations, &handled, options).boxed_local());
+                },
+                Some(Err(error)) => log::warn!("{}: {}", error.name, error.cause),
+                None => break Err(anyhow::anyhow!("the adapter stopped reporting base stations")),
+            },
+            _ = tokio::time::sleep_until(link.retry_at), if link.broker.is_none() => {
+                link.connect(settings, &stations).await;
+            }
+            packet = link.next_packet(), if link.broker.is_some() => match packet {
+                Some(Ok(Packet::Publish { topic, payload, retain })) => {
+                    // Retained commands would be carried out again on every reconnect.
+                    if retain {
+                        continue;
+                    }
+                    if let Some((name, state)) = settings.command(&topic, &payload) {
+                        match stations::find(&stations, name) {
+                            Ok(station) => tasks.push(set(station, state, options).boxed_local()),
+                            Err(error) => log::warn!("{}", error),
+                        }
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(error)) => link.check(Err(error)),
+                None => link.check(Err(io::ErrorKind::UnexpectedEof.into())),
+            },
+            _ = pings.tick(), if link.broker.is_some() => {
+                if let Some(broker) = &mut link.broker {
+                    let pinged = broker.writer.write_all(&PINGREQ).await;
+                    link.check(pinged);
+                }
+            }
+            _ = refresh.tick() => {
+                for station in stations.borrow().values() {
+                    tasks.push(refresh_station(station.clone(), &stations, &handled, options).boxed_local());
+                }
+            }
+            _ = supervisor.due() => {
+                let count = stations.borrow().len();
+                supervisor.check(central, count, options.io_timeout).await;
+            }
+            Some((station, available)) = tasks.next(), if !tasks.is_empty() => {
+                if let Some(broker) = &mut link.broker {
+                    let updated = broker.update(settings, &station, available).await;
+                    link.check(updated);
+                }
+            }
+            Ok(signal) = &mut signals => {
+                log::info!("stopping on signal {}", signal);
+                break Ok(());
+            }
+        }
+    };
+    drop(tasks);
+    let tracked: Vec<_> = stations.borrow().values().cloned().collect();
+    supervisor
+        .stop(tracked.iter().map(|station| &station.lh), options)
+        .await;
+    if let Some(mut broker) = link.broker.take() {
+        // A clean disconnect does not trigger the will.
+        let _ = broker
+            .publish(&settings.status_topic(), "offline", true)
+            .await;
+        let _ = broker.writer.write_all(&DISCONNECT).await;
+    }
+    release(central, options.io_timeout).await;
+    result
+}
+
+async fn refresh_station<P: Peripheral>(
+    station: Rc<Station<P>>,
+    stations: &Stations<P>,
+    handled: &Mutex<BTreeSet<PeripheralId>>,
+    options: &Options,
+) -> (Rc<Station<P>>, bool) {
+    let known = stations::refresh(&station, stations, handled, options).await;
+    (station, known)
+}
+
+/// Publishes the power state even on failure, so that the switch turns back.
+async fn set<P: Peripheral>(
+    station: Rc<Station<P>>,
+    state: PowerCommand,
+    options: &Options,
+) -> (Rc<Station<P>>, bool) {
+    match station.write(state, options).await {
+        Ok(()) => log::info!("{}: {}", station.lh.name, state),
+        Err(message) => log::warn!("{}: {}", station.lh.name, message),
+    }
+    (station, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(broker: &str) -> Result<Settings> {
+        let config = config::Mqtt {
+            broker: Some(broker.to_owned()),
+            ..Default::default()
+        };
+        Settings::new(&config, PowerCommand::Sleep)
+    }
+
+    #[test]
+    fn parses_broker() {
+        assert_eq!(
+            settings("mqtt://broker.lan").unwrap().broker,
+            "broker.lan:1883"
+        );
+        assert_eq!(
+            settings("broker.lan:8883/").unwrap().broker,
+            "broker.lan:8883"
+        );
+        assert_eq!(settings("[::1]").unwrap().broker, "[::1]:1883");
+        assert!(settings("mqtts://broker.lan").is_err());
+        let config = config::Mqtt {
+            broker: Some("broker.lan".to_owned()),
+            password: Some("secret".to_owned()),
+            ..Default::default()
+        };
+        assert!(Settings::new(&config, PowerCommand::Sleep).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_published_packet() {
+        let payload = [b'x'; 200];
+        let packet = publish_packet("lighthousectl/LHB-4A3C91F2/set", &payload, true);
+        // The remaining length takes two bytes above 127.
+        assert_eq!(packet[1..3], [0xe8, 0x01]);
+        match read_packet(&mut packet.as_slice()).await.unwrap() {
+            Packet::Publish {
+                topic,
+                payload: read,
+                retain,
+            } => {
+                assert_eq!(topic, "lighthousectl/LHB-4A3C91F2/set");
+                assert_eq!(read, payload);
+                assert!(retain);
+            }
+            _ => panic!("expected PUBLISH"),
+        }
+    }
+
+    #[test]
+    fn frames_remaining_lengths() {
+        assert_eq!(packet(0xc0, &[]), PINGREQ);
+        assert_eq!(packet(0x30, &[0; 127])[1], 0x7f);
+        assert_eq!(packet(0x30, &[0; 128])[1..3], [0x80, 0x01]);
+        assert_eq!(packet(0x30, &[0; 16383])[1..3], [0xff, 0x7f]);
+        assert_eq!(packet(0x30, &[0; 16384])[1..4], [0x80, 0x80, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn reads_packets_of_every_length() {
+        for length in [0, 127, 128, 16383, 16384] {
+            let payload = vec![b'x'; length];
+            let packet = publish_packet("t", &payload, false);
+            match read_packet(&mut packet.as_slice()).await.unwrap() {
+                Packet::Publish { payload: read, .. } => assert_eq!(read.len(), length),
+                _ => panic!("expected PUBLISH"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_connack_and_acknowledged_publish() {
+        let connack: &[u8] = &[0x20, 0x02, 0x00, 0x05];
+        assert!(matches!(
+            read_packet(&mut &connack[..]).await.unwrap(),
+            Packet::ConnAck(5)
+        ));
+        // QoS 1, whose packet identifier 0x0102 is not part of the payload.
+        let publish: &[u8] = &[0x32, 0x07, 0x00, 0x01, b't', 0x01, 0x02, b'o', b'n'];
+        match read_packet(&mut &publish[..]).await.unwrap() {
+            Packet::Publish {
+                topic,
+                payload,
+                retain,
+            } => {
+                assert_eq!(topic, "t");
+                assert_eq!(payload, b"on");
+                assert!(!retain);
+            }
+            _ => panic!("expected PUBLISH"),
+        }
+        let suback: &[u8] = &[0x90, 0x03, 0x00, 0x01, 0x00];
+        assert!(matches!(
+            read_packet(&mut &suback[..]).await.unwrap(),
+            Packet::Other
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_packets() {
+        let error = |packet: &'static [u8]| async move {
+            read_packet(&mut &packet[..]).await.err().unwrap()
+        };
+        // A fifth byte of remaining length.
+        let malformed = error(&[0x30, 0x80, 0x80, 0x80, 0x80, 0x01]).await;
+        assert_eq!(malformed.to_string(), "malformed remaining length");
+        // 2 MiB, which takes four bytes.
+        let large = error(&[0x30, 0x80, 0x80, 0x80, 0x01]).await;
+        assert_eq!(large.to_string(), "packet too large");
+        assert_eq!(
+            error(&[0x20, 0x01, 0x00]).await.to_string(),
+            "short CONNACK"
+        );
+        assert_eq!(
+            error(&[0x30, 0x03, 0x00, 0x05, b't']).await.to_string(),
+            "short PUBLISH"
+        );
+        assert_eq!(
+            error(&[0x30, 0x01]).await.kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            error(&[0x30, 0x05, 0x00]).await.kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn parses_commands() {
+        let settings = settings("broker.lan").unwrap();
+        let command = settings.command("lighthousectl/LHB-4A3C91F2/set", b"OFF\n");
+        assert_eq!(
+            command.map(|(name, state)| (name, state.porcelain())),
+            Some(("LHB-4A3C91F2", "sleep"))
+        );
+        assert!(settings
+            .command("lighthousectl/LHB-4A3C91F2/state", b"on")
+            .is_none());
+    }
+}
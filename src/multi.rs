@@ -0,0 +1,160 @@
+//! `--adapter all`: scans on every adapter at once, for play spaces too large for one adapter to
+//! reach all the base stations. Their events are merged, and an adapter failing, such as a dongle
+//! resetting, leaves the others scanning.
+
+use std::pin::Pin;
+
+use async_trait::async_trait;
+use btleplug::{
+    api::{Central, CentralEvent, Peripheral, PeripheralProperties, ScanFilter},
+    platform::PeripheralId,
+};
+use futures::{stream, Stream};
+
+#[derive(Clone)]
+pub struct Adapters<C> {
+    centrals: Vec<C>,
+}
+
+impl<C: Central> Adapters<C> {
+    pub fn new(centrals: Vec<C>) -> Self {
+        Self { centrals }
+    }
+
+    /// How many adapters are scanned on.
+    pub fn count(&self) -> usize {
+        self.centrals.len()
+    }
+
+    /// Runs `operation` on every adapter, which succeeds if it does on any of them. The failures
+    /// are only logged, unless all of them fail.
+    async fn on_each<T, F>(&self, operation: &str, f: impl Fn(C) -> F) -> btleplug::Result<Vec<T>>
+    where
+        F: std::future::Future<Output = btleplug::Result<T>>,
+    {
+        let mut results = Vec::new();
+        let mut failure = None;
+        for (index, central) in self.centrals.iter().enumerate() {
+            match f(central.clone()).await {
+                Ok(result) => results.push(result),
+                Err(error) => {
+                    log::warn!("adapter {}: {} failed: {}", index, operation, error);
+                    failure = Some(error);
+                }
+            }
+        }
+        match failure {
+            Some(error) if results.is_empty() => Err(error),
+            _ => Ok(results),
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Central + 'static> Central for Adapters<C> {
+    type Peripheral = C::Peripheral;
+
+    async fn events(&self) -> btleplug::Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
+        let events = self
+            .on_each(
+                "subscribing",
+                |central| async move { central.events().await },
+            )
+            .await?;
+        // An adapter that goes away ends its own stream only.
+        Ok(Box::pin(stream::select_all(events)))
+    }
+
+    async fn start_scan(&self, filter: ScanFilter) -> btleplug::Result<()> {
+        let filter = &filter;
+        self.on_each("starting to scan", |central| async move {
+            central.start_scan(filter.clone()).await
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn stop_scan(&self) -> btleplug::Result<()> {
+        self.on_each("stopping the scan", |central| async move {
+            central.stop_scan().await
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn peripherals(&self) -> btleplug::Result<Vec<Self::Peripheral>> {
+        let peripherals = self
+            .on_each("listing peripherals", |central| async move {
+                central.peripherals().await
+            })
+            .await?;
+        Ok(peripherals.into_iter().flatten().collect())
+    }
+
+    /// The peripheral from the adapter that has seen it, since the IDs differ between adapters.
+    async fn peripheral(&self, id: &PeripheralId) -> btleplug::Result<Self::Peripheral> {
+        let mut failure = btleplug::Error::DeviceNotFound;
+        for central in &self.centrals {
+            match central.peripheral(id).await {
+                Ok(p) => return Ok(p),
+                Err(error) => failure = error,
+            }
+        }
+        Err(failure)
+    }
+
+    async fn add_peripheral(&self, id: &PeripheralId) -> btleplug::Result<Self::Peripheral> {
+        let mut failure = btleplug::Error::DeviceNotFound;
+        for central in &self.centrals {
+            match central.add_peripheral(id).await {
+                Ok(p) => return Ok(p),
+                Err(error) => failure = error,
+            }
+        }
+        Err(failure)
+    }
+
+    async fn adapter_info(&self) -> btleplug::Result<String> {
+        let infos = self
+            .on_each("reading the adapter info", |central| async move {
+                central.adapter_info().await
+            })
+            .await?;
+        Ok(infos.join(", "))
+    }
+}
+
+/// The base station as seen by the adapter that receives it the strongest, out of those that have
+/// seen it, to connect through that one.
+pub async fn strongest<C: Central>(
+    central: &C,
+    p: C::Peripheral,
+    props: PeripheralProperties,
+) -> (C::Peripheral, PeripheralProperties) {
+    let peripherals = match central.peripherals().await {
+        Ok(peripherals) => peripherals,
+        Err(error) => {
+            log::debug!("{}: cannot compare the adapters: {}", props.address, error);
+            return (p, props);
+        }
+    };
+    let mut best = (p, props);
+    for other in peripherals {
+        if other.id() == best.0.id() {
+            continue;
+        }
+        let Ok(Some(other_props)) = crate::address::properties(&other).await else {
+            continue;
+        };
+        // No RSSI, as for peripherals only known from before, is the weakest.
+        if other_props.address == best.1.address && other_props.rssi > best.1.rssi {
+            log::debug!(
+                "{}: received stronger by another adapter, at {} dBm",
+                other_props.address,
+                other_props.rssi.unwrap_or_default()
+            );
+            best = (other, other_props);
+        }
+    }
+    best
+}
@@ -0,0 +1,147 @@
+//! `--notify`: a desktop notification once `on --wait` is done, through `notify-send` on Linux
+//! and the BSDs, `osascript` on macOS, or a balloon tip from PowerShell on Windows. Without a
+//! notification service, such as over SSH, the terminal bell rings and the message is printed
+//! instead.
+
+use std::{
+    io::{self, IsTerminal},
+    process::{Command, Stdio},
+};
+
+const APP_NAME: &str = "lighthousectl";
+
+/// The summary and body of the notification, and whether it is a warning.
+fn message(reached: &[&str], stragglers: &[&str]) -> (String, String, bool) {
+    let total = reached.len() + stragglers.len();
+    match (reached, stragglers) {
+        ([], []) => (
+            "No base stations were found".to_owned(),
+            String::new(),
+            true,
+        ),
+        ([name], []) => (format!("{} is ON", name), String::new(), false),
+        (_, []) => (
+            format!("All {} base stations are ON", total),
+            String::new(),
+            false,
+        ),
+        _ => (
+            format!("{} of {} base stations are ON", reached.len(), total),
+            format!("Not ON: {}", stragglers.join(", ")),
+            true,
+        ),
+    }
+}
+
+/// Notifies whether the base stations are on. `reached` are the names of those that are, and
+/// `stragglers` those that failed, timed out or were not found.
+pub fn powered_on(reached: &[&str], stragglers: &[&str]) {
+    let (summary, body, warning) = message(reached, stragglers);
+    match send(&summary, &body, warning) {
+        Ok(true) => return,
+        Ok(false) => log::debug!("no notification service, ringing the bell instead"),
+        Err(error) => log::warn!("failed to send a notification: {}", error),
+    }
+    let bell = if io::stderr().is_terminal() {
+        "\x07"
+    } else {
+        ""
+    };
+    match body.as_str() {
+        "" => eprintln!("{}{}", bell, summary),
+        body => eprintln!("{}{}: {}", bell, summary, body),
+    }
+}
+
+/// Runs the notifier, returning whether the notification was shown.
+#[cfg(not(windows))]
+fn run(command: &mut Command) -> io::Result<bool> {
+    let output = command.stdin(Stdio::null()).output()?;
+    if !output.status.success() {
+        log::debug!(
+            "the notifier exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    Ok(output.status.success())
+}
+
+#[cfg(target_os = "macos")]
+fn send(summary: &str, body: &str, _: bool) -> io::Result<bool> {
+    // Passed as arguments, which spares quoting them in AppleScript.
+    let script = [
+        "on run argv",
+        "display notification (item 2 of argv) with title (item 1 of argv)",
+        "end run",
+    ];
+    let mut command = Command::new("osascript");
+    for line in script {
+        command.args(["-e", line]);
+    }
+    let body = if body.is_empty() { APP_NAME } else { body };
+    run(command.args([summary, body]))
+}
+
+#[cfg(windows)]
+fn send(summary: &str, body: &str, warning: bool) -> io::Result<bool> {
+    let quoted = |text: &str| format!("'{}'", text.replace('\'', "''"));
+    let icon = if warning { "Warning" } else { "Info" };
+    // The icon has to stay around while the balloon tip is shown.
+    let script = format!(
+        "Add-Type -AssemblyName System.Windows.Forms; \
+         $icon = New-Object System.Windows.Forms.NotifyIcon; \
+         $icon.Icon = [System.Drawing.SystemIcons]::Information; \
+         $icon.Visible = $true; \
+         $icon.ShowBalloonTip(10000, {}, {}, '{}'); \
+         Start-Sleep -Seconds 10; \
+         $icon.Dispose()",
+        quoted(summary),
+        quoted(if body.is_empty() { APP_NAME } else { body }),
+        icon
+    );
+    // Left running in the background, instead of holding up the exit.
+    Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(true)
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn send(summary: &str, body: &str, warning: bool) -> io::Result<bool> {
+    // Without a desktop session, `notify-send` could only fail, or hang starting a bus.
+    let session = ["DBUS_SESSION_BUS_ADDRESS", "DISPLAY", "WAYLAND_DISPLAY"];
+    if !session.iter().any(|var| std::env::var_os(var).is_some()) {
+        return Ok(false);
+    }
+    let urgency = if warning { "critical" } else { "normal" };
+    let mut command = Command::new("notify-send");
+    command.args(["--app-name", APP_NAME, "--urgency", urgency, summary]);
+    if !body.is_empty() {
+        command.arg(body);
+    }
+    match run(&mut command) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stragglers_make_a_warning() {
+        let all = message(&["LHB-4A3C91F2", "LHB-93D0B1E4"], &[]);
+        assert_eq!(all.0, "All 2 base stations are ON");
+        assert!(!all.2);
+        let (summary, body, warning) =
+            message(&["LHB-4A3C91F2"], &["LHB-93D0B1E4", "LHB-1D0E77B5"]);
+        assert_eq!(summary, "1 of 3 base stations are ON");
+        assert_eq!(body, "Not ON: LHB-93D0B1E4, LHB-1D0E77B5");
+        assert!(warning);
+    }
+}
@@ -0,0 +1,73 @@
+//! Pairing, for platforms that refuse the characteristics of base stations until they are
+//! paired, as Windows does on some machines. btleplug cannot pair, so it is done through the
+//! platform where it can be, which is only Windows.
+
+use btleplug::api::BDAddr;
+
+use crate::Error;
+
+/// The failures that mean the platform wants the base station paired first: Windows reports
+/// access denied, while BlueZ names the missing authentication, encryption or authorization.
+pub fn is_required(error: &btleplug::Error) -> bool {
+    const REFUSALS: [&str; 5] = [
+        "NotAuthorized",
+        "NotPermitted",
+        "AuthenticationFailed",
+        "Insufficient Authentication",
+        "Insufficient Encryption",
+    ];
+    match error {
+        btleplug::Error::PermissionDenied => true,
+        btleplug::Error::Other(error) => {
+            let message = error.to_string();
+            REFUSALS.iter().any(|refusal| message.contains(refusal))
+        }
+        _ => false,
+    }
+}
+
+/// Pairs with the base station, which may show a system dialog. One that is already paired is
+/// left as it is, so that its bond is not replaced.
+#[cfg(windows)]
+pub async fn pair(address: BDAddr) -> Result<(), Error> {
+    use windows::Devices::{Bluetooth::BluetoothLEDevice, Enumeration::DevicePairingResultStatus};
+
+    let failed = |error: windows::core::Error| Error::PairingFailed(error.to_string());
+    let device = BluetoothLEDevice::FromBluetoothAddressAsync(address.into())
+        .map_err(failed)?
+        .await
+        .map_err(failed)?;
+    let pairing = device
+        .DeviceInformation()
+        .and_then(|information| information.Pairing())
+        .map_err(failed)?;
+    if pairing.IsPaired().map_err(failed)? {
+        return Ok(());
+    }
+    if !pairing.CanPair().map_err(failed)? {
+        return Err(Error::PairingFailed(
+            "Windows cannot pair with it".to_owned(),
+        ));
+    }
+    let status = pairing
+        .PairAsync()
+        .map_err(failed)?
+        .await
+        .and_then(|result| result.Status())
+        .map_err(failed)?;
+    if status == DevicePairingResultStatus::Paired
+        || status == DevicePairingResultStatus::AlreadyPaired
+    {
+        Ok(())
+    } else {
+        Err(Error::PairingFailed(format!("status {}", status.0)))
+    }
+}
+
+#[cfg(not(windows))]
+pub async fn pair(_: BDAddr) -> Result<(), Error> {
+    Err(Error::PairingFailed(
+        "only supported on Windows, pair the base station in the Bluetooth settings instead"
+            .to_owned(),
+    ))
+}
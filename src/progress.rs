@@ -0,0 +1,56 @@
+//! The status line shown on a terminal while scanning, so that a scan with nothing in range does
+//! not look hung. It is drawn on stderr in place, and cleared before anything else is printed.
+
+use std::{cell::Cell, time::Duration};
+
+use tokio::time::Instant;
+
+/// How often the status line is drawn again.
+pub const INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct StatusLine {
+    started: Instant,
+    /// Whether the line is on the terminal, to be cleared.
+    shown: Cell<bool>,
+}
+
+impl StatusLine {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            shown: Cell::new(false),
+        }
+    }
+
+    /// Draws the line over the last one, given how many peripherals have been looked at and how
+    /// many of the base stations asked for are still outstanding.
+    pub fn draw(&self, considered: usize, outstanding: usize) {
+        let elapsed = Duration::from_secs(self.started.elapsed().as_secs());
+        let mut line = format!(
+            "scanning for {}, {} {} seen",
+            humantime::format_duration(elapsed),
+            considered,
+            if considered == 1 { "device" } else { "devices" }
+        );
+        match outstanding {
+            0 => {}
+            1 => line.push_str(", 1 base station outstanding"),
+            _ => line.push_str(&format!(", {} base stations outstanding", outstanding)),
+        }
+        eprint!("\r\x1b[K{}", line);
+        self.shown.set(true);
+    }
+
+    pub fn clear(&self) {
+        if self.shown.replace(false) {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+impl Drop for StatusLine {
+    /// Cleared when done, or when cancelled by a signal.
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
@@ -0,0 +1,116 @@
+//! The Bluetooth radios of Windows, which btleplug hides: it names every adapter `WinRT`, only
+//! ever scans on the default one, and lets a radio that is turned off fail deep inside discovery
+//! with an opaque WinRT error. The default adapter is checked here before scanning instead, so
+//! that the usual failures get a clear error up front.
+
+use anyhow::Result;
+
+/// A Bluetooth adapter as Windows names it, and whether its radio is on.
+#[cfg(windows)]
+pub struct Info {
+    pub name: String,
+    pub powered: Option<bool>,
+}
+
+/// Checks that the default adapter can scan for and connect to base stations: that it supports
+/// Bluetooth Low Energy as a central, that radios may be used, and that its radio is on.
+#[cfg(windows)]
+pub async fn check() -> Result<()> {
+    use lighthousectl::Error;
+    use windows::Devices::{
+        Bluetooth::BluetoothAdapter,
+        Radios::{Radio, RadioAccessStatus, RadioState},
+    };
+
+    // Fails rather than giving no adapter when there is none.
+    let adapter = match BluetoothAdapter::GetDefaultAsync()?.await {
+        Ok(adapter) => adapter,
+        Err(_) => {
+            let hint = Some("plug in a Bluetooth adapter, or enable it in the Device Manager");
+            return Err(Error::NoAdapter { hint }.into());
+        }
+    };
+    let unusable = |problem: &'static str, hint: &'static str| -> Result<()> {
+        Err(Error::AdapterUnusable { problem, hint }.into())
+    };
+    if !adapter.IsLowEnergySupported()? {
+        return unusable(
+            "does not support Bluetooth Low Energy, which base stations use",
+            "use an adapter supporting Bluetooth 4.0 or later",
+        );
+    }
+    if !adapter.IsCentralRoleSupported()? {
+        return unusable(
+            "cannot connect to Bluetooth Low Energy devices",
+            "update the driver of the adapter, or use another adapter",
+        );
+    }
+    match Radio::RequestAccessAsync()?.await? {
+        RadioAccessStatus::DeniedByUser => {
+            return unusable(
+                "may not be used by apps",
+                "allow apps to control device radios in Settings > Privacy & security > Radios",
+            )
+        }
+        RadioAccessStatus::DeniedBySystem => {
+            return unusable(
+                "may not be used by this app, which lacks the radios capability",
+                "run lighthousectl outside of the package or sandbox it is in",
+            )
+        }
+        _ => {}
+    }
+    let state = adapter.GetRadioAsync()?.await?.State()?;
+    if state == RadioState::Off {
+        return unusable(
+            "is turned off",
+            "turn Bluetooth on in the quick settings or in Settings > Bluetooth & devices",
+        );
+    }
+    if state == RadioState::Disabled {
+        return unusable("is disabled", "enable it in the Device Manager");
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub async fn check() -> Result<()> {
+    Ok(())
+}
+
+/// The Bluetooth adapters, the default one first, since it is the one scanned on.
+#[cfg(windows)]
+pub async fn list() -> Result<Vec<Info>> {
+    use windows::Devices::{
+        Bluetooth::BluetoothAdapter, Enumeration::DeviceInformation, Radios::RadioState,
+    };
+
+    let default = match BluetoothAdapter::GetDefaultAsync()?.await {
+        Ok(adapter) => Some(adapter.DeviceId()?),
+        Err(_) => None,
+    };
+    let found =
+        DeviceInformation::FindAllAsyncAqsFilter(&BluetoothAdapter::GetDeviceSelector()?)?.await?;
+    let mut radios = Vec::new();
+    for index in 0..found.Size()? {
+        let information = found.GetAt(index)?;
+        let id = information.Id()?;
+        // A radio that cannot be read is shown without a power state rather than failing.
+        let powered = match BluetoothAdapter::FromIdAsync(&id)?.await {
+            Ok(adapter) => match adapter.GetRadioAsync()?.await {
+                Ok(radio) => Some(radio.State()? == RadioState::On),
+                Err(_) => None,
+            },
+            Err(_) => None,
+        };
+        let radio = Info {
+            name: information.Name()?.to_string(),
+            powered,
+        };
+        match Some(&id) == default.as_ref() {
+            true => radios.insert(0, radio),
+            false => radios.push(radio),
+        }
+    }
+    Ok(radios)
+}
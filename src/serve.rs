@@ -0,0 +1,401 @@
+//! `serve`: an HTTP API answered from the base stations found in the background.
+//!
+//! - `GET /stations` lists the base stations, and `GET /stations/<name>` shows one.
+//! - `POST /stations/<name>/power` with a body of `on`, `sleep` or `standby` changes the power
+//!   state, and replies with the base station.
+//! - `GET /healthz` replies with `{"status":"ok"}` while running.
+//! - `GET /events` streams server-sent events as base stations are discovered, lost and change
+//!   their power states.
+//!
+//! Base stations are addressed by name or address, percent-encoded where needed, such as
+//! `LHB-4A3C91F2` or `AA%3ABB%3ACC%3ADD%3AEE%3AFF`. Errors are replied as `{"error":"..."}`.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    rc::Rc,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use btleplug::{
+    api::{Central, Peripheral},
+    platform::PeripheralId,
+};
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::{
+        broadcast::{self, error::RecvError},
+        oneshot,
+    },
+};
+
+use crate::{
+    discover, fold, http,
+    json::{Object, ToJson},
+    release,
+    stations::{self, Station, Stations, REFRESH_INTERVAL},
+    supervisor::Supervisor,
+    Filter, Options, PowerCommand, PowerState,
+};
+
+pub const DEFAULT_LISTEN: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8090));
+/// Events kept for a slow `/events` client before it misses some.
+const EVENT_BUFFER: usize = 256;
+/// How often an idle `/events` stream gets a comment, so that dead clients are noticed.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone)]
+struct Event {
+    sequence: u64,
+    kind: &'static str,
+    json: Rc<str>,
+}
+
+/// Numbers the events and passes them on to the `/events` clients. The sequence number goes up by
+/// one for each event, so that clients can tell when they missed some.
+struct Events {
+    sequence: Cell<u64>,
+    sender: broadcast::Sender<Event>,
+    /// The last reported power states, by folded name.
+    reported: RefCell<BTreeMap<String, u8>>,
+}
+
+impl Events {
+    fn new() -> Self {
+        Self {
+            sequence: Cell::new(0),
+            sender: broadcast::channel(EVENT_BUFFER).0,
+            reported: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    fn emit<P: Peripheral>(
+        &self,
+        kind: &'static str,
+        station: &Station<P>,
+        fields: impl FnOnce(&mut Object),
+    ) {
+        let sequence = self.sequence.get() + 1;
+        self.sequence.set(sequence);
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        let mut out = String::new();
+        let mut object = Object::new(&mut out);
+        object
+            .field("sequence", &sequence)
+            .field("event", kind)
+            .field("timestamp", &timestamp)
+            .field("name", &station.lh.name)
+            .field("address", &station.lh.shown_address());
+        fields(&mut object);
+        object.finish();
+        // Nobody may be listening.
+        let _ = self.sender.send(Event {
+            sequence,
+            kind,
+            json: out.into(),
+        });
+    }
+
+    /// Emits `state_changed` if the power state is not the one last reported.
+    fn observe<P: Peripheral>(&self, station: &Station<P>) {
+        let raw_state = match station.raw_state.get() {
+            Some(raw_state) => raw_state,
+            None => return,
+        };
+        let key = fold(&station.lh.name);
+        let previous = self.reported.borrow_mut().insert(key, raw_state);
+        if previous == Some(raw_state) {
+            return;
+        }
+        self.emit("state_changed", station, |object| {
+            object
+                .field("previous_state", &previous.map(PowerState::from))
+                .field("state", &PowerState::from(raw_state));
+        });
+    }
+
+    fn lost<P: Peripheral>(&self, station: &Station<P>) {
+        self.reported.borrow_mut().remove(&fold(&station.lh.name));
+        self.emit("lost", station, |_| {});
+    }
+}
+
+/// Runs until Ctrl-C or SIGTERM, then disconnects from every base station.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &Filter,
+    options: &Options,
+    listen: SocketAddr,
+    supervisor: &mut Supervisor,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("failed to listen on {}", listen))?;
+    log::info!("listening on http://{}", listen);
+    let stations: Stations<C::Peripheral> = RefCell::new(BTreeMap::new());
+    let events = Events::new();
+    let handled = Mutex::new(BTreeSet::new());
+    let unnamed = filter.addresses();
+    let mut discoveries = discover(central, options, &unnamed, &handled, false).await?;
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+    // Requests run side by side, so that base stations are controlled at once, bounded by
+    // `--concurrency`.
+    let mut tasks: FuturesUnordered<LocalBoxFuture<()>> = FuturesUnordered::new();
+    let mut signals = signals;
+    let result = loop {
+        tokio::select! {
+            discovery = discoveries.next() => match discovery {
+                Some(Ok(lh)) => if let Some(station) = stations::track(lh, filter, "server").await {
+                    stations.borrow_mut().insert(fold(&station.lh.name), station.clone());
+                    events.emit("discovered", &station, |_| {});
+                    supervisor.found(&station.lh.name, station.lh.address, stations.borrow().len());
+                    let refreshing = refresh_station(station, &stations, &handled, &events, options);
+                    tasks.push(refreshing.boxed_local());
+                },
+                Some(Err(error)) => log::warn!("{}: {}", error.name, error.cause),
+                None => break Err(anyhow::anyhow!("the adapter stopped reporting base stations")),
+            },
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tasks.push(serve(stream, &stations, &events, options).boxed_local());
+                }
+                Err(error) => log::warn!("accepting a connection failed: {}", error),
+            },
+            _ = refresh.tick() => {
+                for station in stations.borrow().values() {
+                    let refreshing =
+                        refresh_station(station.clone(), &stations, &handled, &events, options);
+                    tasks.push(refreshing.boxed_local());
+                }
+            }
+            _ = supervisor.due() => {
+                let count = stations.borrow().len();
+                supervisor.check(central, count, options.io_timeout).await;
+            }
+            Some(()) = tasks.next(), if !tasks.is_empty() => {}
+            Ok(signal) = &mut signals => {
+                log::info!("stopping on signal {}", signal);
+                break Ok(());
+            }
+        }
+    };
+    drop(tasks);
+    let tracked: Vec<_> = stations.borrow().values().cloned().collect();
+    supervisor
+        .stop(tracked.iter().map(|station| &station.lh), options)
+        .await;
+    release(central, options.io_timeout).await;
+    result
+}
+
+async fn refresh_station<P: Peripheral>(
+    station: Rc<Station<P>>,
+    stations: &Stations<P>,
+    handled: &Mutex<BTreeSet<PeripheralId>>,
+    events: &Events,
+    options: &Options,
+) {
+    if stations::refresh(&station, stations, handled, options).await {
+        events.observe(&station);
+    } else {
+        events.lost(&station);
+    }
+}
+
+async fn serve<P: Peripheral>(
+    mut stream: TcpStream,
+    stations: &Stations<P>,
+    events: &Events,
+    options: &Options,
+) {
+    let served = async {
+        let request = http::read_request(&mut stream).await?;
+        if request.method == "GET" && segments(&request) == ["events"] {
+            log::info!("GET /events");
+            return stream_events(&mut stream, events).await;
+        }
+        let (status, body) = route(&request, stations, events, options).await;
+        log::info!("{} {} {}", request.method, request.path, status);
+        http::respond(&mut stream, status, "application/json", &body).await
+    };
+    if let Err(error) = served.await {
+        log::warn!("serving a request failed: {}", error);
+    }
+}
+
+/// Writes the events as they happen, until the client goes away.
+async fn stream_events(stream: &mut TcpStream, events: &Events) -> io::Result<()> {
+    let mut receiver = events.sender.subscribe();
+    http::respond_streaming(stream, "text/event-stream").await?;
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+    loop {
+        let chunk = tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => format!(
+                    "id: {}\nevent: {}\ndata: {}\n\n",
+                    event.sequence, event.kind, event.json
+                ),
+                // The client tells from the sequence numbers.
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("an /events client missed {} events", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = keep_alive.tick() => ": keep-alive\n\n".to_owned(),
+        };
+        stream.write_all(chunk.as_bytes()).await?;
+    }
+}
+
+/// The segments of the path, without the query, each percent-decoded.
+fn segments(request: &http::Request) -> Vec<String> {
+    let path = request.path.split('?').next().unwrap_or_default();
+    path.trim_matches('/')
+        .split('/')
+        .map(http::percent_decode)
+        .collect()
+}
+
+async fn route<P: Peripheral>(
+    request: &http::Request,
+    stations: &Stations<P>,
+    events: &Events,
+    options: &Options,
+) -> (&'static str, String) {
+    let segments = segments(request);
+    let segments: Vec<_> = segments.iter().map(String::as_str).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["healthz"]) => ("200 OK", r#"{"status":"ok"}"#.to_owned()),
+        ("GET", ["stations"]) => {
+            let stations = stations.borrow();
+            let all: Vec<&Station<P>> = stations.values().map(|station| &**station).collect();
+            ("200 OK", all.to_json())
+        }
+        ("GET", ["stations", name]) => match stations::find(stations, name) {
+            Ok(station) => ("200 OK", station.to_json()),
+            Err(missing) => ("404 Not Found", error(&missing.to_string())),
+        },
+        ("POST", ["stations", name, "power"]) => {
+            let state = match request.body.trim().to_lowercase().as_str() {
+                "on" => PowerCommand::On,
+                "sleep" => PowerCommand::Sleep,
+                "standby" => PowerCommand::Standby,
+                body => {
+                    let message = format!("expected on, sleep or standby, not `{}`", body);
+                    return ("400 Bad Request", error(&message));
+                }
+            };
+            let station = match stations::find(stations, name) {
+                Ok(station) => station,
+                Err(missing) => return ("404 Not Found", error(&missing.to_string())),
+            };
+            match station.write(state, options).await {
+                Ok(()) => {
+                    events.observe(&station);
+                    ("200 OK", station.to_json())
+                }
+                Err(failure) => ("502 Bad Gateway", error(&failure.to_string())),
+            }
+        }
+        // `GET /events` is streamed before routing, so only the other methods end up here.
+        (
+            _,
+            ["healthz"] | ["events"] | ["stations"] | ["stations", _] | ["stations", _, "power"],
+        ) => ("405 Method Not Allowed", error("method not allowed")),
+        _ => ("404 Not Found", error("not found")),
+    }
+}
+
+fn error(message: &str) -> String {
+    let mut out = String::new();
+    Object::new(&mut out).field("error", message).finish();
+    out
+}
+
+impl<P: Peripheral> ToJson for Station<P> {
+    fn write_json(&self, out: &mut String) {
+        let raw_state = self.raw_state.get();
+        Object::new(out)
+            .field("name", &self.lh.name)
+            .field("address", &self.lh.shown_address())
+            .field("state", &raw_state.map(PowerState::from))
+            .field("raw_state", &raw_state)
+            .field("rssi", &self.lh.rssi)
+            .finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockCentral, MockPeripheral};
+
+    fn request(method: &str, path: &str, body: &str) -> http::Request {
+        http::Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            body: body.to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_routed_by_their_decoded_segments() {
+        let central = MockCentral::new(vec![MockPeripheral::base_station(1, "LHB-4A3C91F2", 0x00)]);
+        let options = Options::default();
+        let unnamed = BTreeSet::new();
+        let handled = Mutex::new(BTreeSet::new());
+        let mut discoveries = discover(&central, &options, &unnamed, &handled, false)
+            .await
+            .unwrap();
+        let Some(Ok(lh)) = discoveries.next().await else {
+            panic!("the base station was not discovered");
+        };
+        let filter = Filter::new(Vec::new(), false, Vec::new());
+        let station = stations::track(lh, &filter, "server").await.unwrap();
+        let stations = RefCell::new(BTreeMap::from([(fold(&station.lh.name), station)]));
+        let events = Events::new();
+        let route = |method, path, body| {
+            let request = request(method, path, body);
+            let (stations, events, options) = (&stations, &events, &options);
+            async move { route(&request, stations, events, options).await }
+        };
+
+        let (status, body) = route("GET", "/stations/00%3A00%3A00%3A00%3A00%3A01", "").await;
+        assert_eq!(status, "200 OK");
+        assert!(body.contains(r#""name":"LHB-4A3C91F2""#), "{}", body);
+        let (status, _) = route("GET", "/stations/%4chb-4a3c91f2?verbose", "").await;
+        assert_eq!(status, "200 OK");
+
+        let (status, body) = route("GET", "/stations/LHB%2D93D0B1E4", "").await;
+        assert_eq!(status, "404 Not Found");
+        assert_eq!(body, r#"{"error":"LHB-93D0B1E4 was not found"}"#);
+        assert_eq!(route("GET", "/nowhere", "").await.0, "404 Not Found");
+        assert_eq!(
+            route("POST", "/stations", "").await.0,
+            "405 Method Not Allowed"
+        );
+        let (status, _) = route("DELETE", "/stations/LHB-4A3C91F2", "").await;
+        assert_eq!(status, "405 Method Not Allowed");
+
+        let (status, body) = route("POST", "/stations/LHB-4A3C91F2/power", "off").await;
+        assert_eq!(status, "400 Bad Request");
+        assert_eq!(
+            body,
+            r#"{"error":"expected on, sleep or standby, not `off`"}"#
+        );
+        assert!(central.peripherals[0].writes().is_empty());
+        let (status, body) = route("POST", "/stations/LHB-4A3C91F2/power", "ON\n").await;
+        assert_eq!(status, "200 OK");
+        // Reported as booting by the mock, as by base stations waking up.
+        assert!(body.contains(r#""raw_state":1"#), "{}", body);
+        assert_eq!(central.peripherals[0].writes(), [vec![0x01]]);
+    }
+}
@@ -0,0 +1,90 @@
+//! Ctrl-C and SIGTERM handling, so that scanning can be stopped and connections released.
+
+use std::io;
+
+use tokio::sync::oneshot;
+
+/// Blocks SIGINT and SIGTERM in the calling thread and the threads it spawns later, and waits
+/// for them on a dedicated thread instead. The receiver gets the number of the first signal.
+/// A second signal, or one arriving after the receiver is dropped, exits immediately.
+///
+/// Must be called before any other thread is spawned, or those threads still get the signals.
+#[cfg(unix)]
+pub fn listen() -> io::Result<oneshot::Receiver<i32>> {
+    use std::{mem::MaybeUninit, process, ptr, thread};
+
+    let (sender, receiver) = oneshot::channel();
+    // SAFETY: the set is initialized by `sigemptyset` before use.
+    let set = unsafe {
+        let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        let mut set = set.assume_init();
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        let error = libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error));
+        }
+        set
+    };
+    thread::spawn(move || {
+        let mut sender = Some(sender);
+        loop {
+            let mut signal = 0;
+            // SAFETY: `set` is a valid signal set and `signal` outlives the call.
+            if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+                continue;
+            }
+            match sender.take().map(|sender| sender.send(signal)) {
+                Some(Ok(())) => {}
+                _ => process::exit(128 + signal),
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/// Handles Ctrl-C and Ctrl-Break, and closing the console, which Windows has in place of
+/// signals. The receiver gets them as the number of SIGINT, or of SIGTERM for closing. As on
+/// Unix, a second one, or one arriving after the receiver is dropped, exits immediately.
+#[cfg(windows)]
+pub fn listen() -> io::Result<oneshot::Receiver<i32>> {
+    use std::{process, sync::Mutex};
+
+    use windows::Win32::{
+        Foundation::BOOL,
+        System::Console::{SetConsoleCtrlHandler, CTRL_BREAK_EVENT, CTRL_C_EVENT},
+    };
+
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+    static SENDER: Mutex<Option<oneshot::Sender<i32>>> = Mutex::new(None);
+
+    // Called by Windows on a thread of its own.
+    unsafe extern "system" fn handle(event: u32) -> BOOL {
+        let signal = match event {
+            CTRL_C_EVENT | CTRL_BREAK_EVENT => SIGINT,
+            _ => SIGTERM,
+        };
+        let sender = SENDER.lock().unwrap().take();
+        match sender.map(|sender| sender.send(signal)) {
+            Some(Ok(())) => true.into(),
+            _ => process::exit(128 + signal),
+        }
+    }
+
+    let (sender, receiver) = oneshot::channel();
+    *SENDER.lock().unwrap() = Some(sender);
+    // SAFETY: `handle` stays valid for the whole process.
+    if !unsafe { SetConsoleCtrlHandler(Some(handle), true) }.as_bool() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(receiver)
+}
+
+/// Signals are left to their default handling, so the receiver never gets one.
+#[cfg(not(any(unix, windows)))]
+pub fn listen() -> io::Result<oneshot::Receiver<i32>> {
+    let (_, receiver) = oneshot::channel();
+    Ok(receiver)
+}
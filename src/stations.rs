@@ -0,0 +1,128 @@
+//! The base stations kept track of by the long-running modes, such as `daemon` and `mqtt`,
+//! which connect to a base station only while talking to it.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    rc::Rc,
+    sync::Mutex,
+    time::Duration,
+};
+
+use btleplug::{api::Peripheral, platform::PeripheralId};
+
+use crate::{fold, write_power, Error, Filter, Lighthouse, Options, PowerCommand, Protocol};
+
+/// How often the power states of the known base stations are read.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// After this many failed refreshes in a row, a base station is forgotten until it advertises
+/// again, such as when it comes back in range.
+const MAX_MISSES: u32 = 3;
+
+pub struct Station<P> {
+    pub lh: Lighthouse<P>,
+    /// Held while talking to the base station, so that refreshes and commands do not interleave.
+    lock: tokio::sync::Mutex<()>,
+    pub raw_state: Cell<Option<u8>>,
+    /// Failed refreshes in a row.
+    misses: Cell<u32>,
+}
+
+impl<P: Peripheral> Station<P> {
+    /// Connects only while talking to the base station, so that SteamVR can reach it in between.
+    pub async fn session<T>(
+        &self,
+        options: &Options,
+        operation: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let _lock = self.lock.lock().await;
+        self.lh.session(options, operation).await
+    }
+
+    pub async fn read(&self, options: &Options) -> Result<u8, Error> {
+        let reading = self.lh.read_power(options.io_timeout);
+        let raw_state = self.session(options, reading).await?;
+        self.raw_state.set(Some(raw_state));
+        Ok(raw_state)
+    }
+
+    pub async fn write(&self, command: PowerCommand, options: &Options) -> Result<(), Error> {
+        self.session(options, write_power(&self.lh, command, options))
+            .await?;
+        self.raw_state.set(Some(command.into()));
+        Ok(())
+    }
+}
+
+/// The known base stations by folded name.
+pub type Stations<P> = RefCell<BTreeMap<String, Rc<Station<P>>>>;
+
+/// Disconnects from a newly discovered base station, and keeps it if it is to be controlled.
+/// `mode` names the mode in the warning about V1 base stations.
+pub async fn track<P: Peripheral>(
+    mut lh: Lighthouse<P>,
+    filter: &Filter,
+    mode: &str,
+) -> Option<Rc<Station<P>>> {
+    lh.disconnect().await.ok();
+    if !filter.is_matched(&lh.name, lh.address) || filter.is_excluded(&lh.name, lh.address) {
+        return None;
+    }
+    if let Protocol::V1 = lh.protocol {
+        log::warn!(
+            name = lh.name.as_str(), address:% = lh.address;
+            "{}: V1 base stations are not supported by the {}",
+            lh.name,
+            mode
+        );
+        return None;
+    }
+    log::info!(name = lh.name.as_str(), address:% = lh.address; "found {}", lh.name);
+    Some(Rc::new(Station {
+        lh,
+        lock: tokio::sync::Mutex::new(()),
+        raw_state: Cell::new(None),
+        misses: Cell::new(0),
+    }))
+}
+
+/// Reads the power state, and forgets the base station if it keeps failing.
+/// Returns whether the base station is still known.
+pub async fn refresh<P: Peripheral>(
+    station: &Station<P>,
+    stations: &Stations<P>,
+    handled: &Mutex<BTreeSet<PeripheralId>>,
+    options: &Options,
+) -> bool {
+    let (name, address) = (station.lh.name.as_str(), station.lh.address);
+    let message = match station.read(options).await {
+        Ok(_) => {
+            station.misses.set(0);
+            return true;
+        }
+        Err(message) => message,
+    };
+    log::warn!(name, address:%; "{}: {}", name, message);
+    station.misses.set(station.misses.get() + 1);
+    if station.misses.get() >= MAX_MISSES {
+        log::info!(name, address:%; "forgetting {} until it advertises again", name);
+        stations.borrow_mut().remove(&fold(name));
+        handled.lock().unwrap().remove(&station.lh.peripheral.id());
+        return false;
+    }
+    true
+}
+
+/// Looks up a base station by name, or by address.
+pub fn find<P: Peripheral>(stations: &Stations<P>, name: &str) -> Result<Rc<Station<P>>, Error> {
+    let stations = stations.borrow();
+    let found = stations.get(&fold(name)).cloned().or_else(|| {
+        let mut all = stations.values();
+        all.find(|station| station.lh.shown_address().eq_ignore_ascii_case(name))
+            .cloned()
+    });
+    found.ok_or_else(|| Error::NotFound {
+        name: name.to_owned(),
+    })
+}
@@ -0,0 +1,317 @@
+//! `steamvr-watch`: turns the base stations on while SteamVR is running, and to sleep once it has
+//! been gone for the idle delay.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    io,
+    rc::Rc,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use btleplug::api::{Central, Peripheral};
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::{
+    discover, fold, release,
+    stations::{self, Station, Stations, REFRESH_INTERVAL},
+    supervisor::Supervisor,
+    Filter, Options, PowerCommand,
+};
+
+pub const DEFAULT_IDLE_DELAY: Duration = Duration::from_secs(5 * 60);
+/// How often the processes are listed, which bounds how long turning on takes after SteamVR
+/// starts.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Either of them running counts as SteamVR running.
+const PROCESSES: [&str; 2] = ["vrserver", "vrmonitor"];
+
+pub struct Policy {
+    /// The state to put the base stations in once SteamVR is gone, `sleep` or `standby`.
+    pub idle: PowerCommand,
+    /// How long SteamVR has to be gone before that, so that restarting it keeps them on.
+    pub idle_delay: Duration,
+    /// Only print what would be written, `--dry-run`.
+    pub dry_run: bool,
+    pub color: bool,
+}
+
+/// Whether a process name, as listed by the platform, is one of SteamVR's.
+fn is_steamvr(process: &str) -> bool {
+    let name = process
+        .trim()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default();
+    let lower = name.to_lowercase();
+    let name = lower.strip_suffix(".exe").unwrap_or(&lower);
+    PROCESSES.contains(&name)
+}
+
+/// The names of the running processes, from `/proc`.
+#[cfg(target_os = "linux")]
+fn processes() -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        let path = entry?.path();
+        // Processes may exit while they are listed.
+        if let Ok(name) = std::fs::read_to_string(path.join("comm")) {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// The names of the running processes, from `ps` or `tasklist`.
+#[cfg(not(target_os = "linux"))]
+fn processes() -> io::Result<Vec<String>> {
+    use std::process::{Command, Stdio};
+
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("tasklist");
+        command.args(["/FO", "CSV", "/NH"]);
+        command
+    } else {
+        let mut command = Command::new("ps");
+        command.args(["-A", "-o", "comm="]);
+        command
+    };
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "listing processes exited with {}",
+            output.status
+        )));
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    // `tasklist` quotes its columns, and the image name comes first.
+    let names = text.lines().map(|line| {
+        let name = line.split("\",").next().unwrap_or_default();
+        name.trim_matches('"').to_owned()
+    });
+    Ok(names.collect())
+}
+
+/// Lists the processes on a blocking thread, since `ps` may take a while.
+async fn steamvr_running() -> io::Result<bool> {
+    let listing = tokio::task::spawn_blocking(processes);
+    let names = listing.await.map_err(io::Error::other)??;
+    Ok(names.iter().any(|name| is_steamvr(name)))
+}
+
+/// Decides the power state from whether SteamVR is running.
+struct Tracker {
+    idle: PowerCommand,
+    idle_delay: Duration,
+    running: bool,
+    /// Since when SteamVR has been gone, until the base stations are put to sleep.
+    gone_since: Option<Instant>,
+    /// The state the base stations should be in, once decided.
+    wanted: Option<PowerCommand>,
+}
+
+impl Tracker {
+    /// Takes in whether SteamVR is running, and returns the wanted state if it just changed.
+    fn observe(&mut self, running: bool, now: Instant) -> Option<PowerCommand> {
+        if running != self.running {
+            self.running = running;
+            if running {
+                log::info!("SteamVR is running");
+                self.gone_since = None;
+            } else {
+                log::info!(
+                    "SteamVR is gone, turning the base stations to {} in {}",
+                    self.idle.porcelain(),
+                    humantime::format_duration(self.idle_delay)
+                );
+                self.gone_since = Some(now);
+            }
+        }
+        let wanted = match self.gone_since {
+            None => PowerCommand::On,
+            Some(since) if now.duration_since(since) >= self.idle_delay => self.idle,
+            Some(_) => return None,
+        };
+        if self.wanted == Some(wanted) {
+            return None;
+        }
+        self.wanted = Some(wanted);
+        Some(wanted)
+    }
+}
+
+/// Writes the power states, remembering which base stations are in the wanted state.
+struct Writer<'a> {
+    policy: &'a Policy,
+    options: &'a Options,
+    /// The state last written to each base station, by folded name.
+    applied: RefCell<BTreeMap<String, PowerCommand>>,
+}
+
+impl Writer<'_> {
+    fn applied<P>(&self, station: &Station<P>) -> Option<PowerCommand> {
+        self.applied.borrow().get(&fold(&station.lh.name)).copied()
+    }
+
+    async fn write<P: Peripheral>(&self, station: Rc<Station<P>>, state: PowerCommand) {
+        let lh = &station.lh;
+        let painted = state.painted(self.policy.color);
+        let result = if self.policy.dry_run {
+            Ok(())
+        } else {
+            station.write(state, self.options).await
+        };
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+        match result {
+            Ok(()) => {
+                let dry_run = if self.policy.dry_run {
+                    " (dry run)"
+                } else {
+                    ""
+                };
+                println!(
+                    "{} {} [{}]: {}{}",
+                    timestamp,
+                    lh.name,
+                    lh.shown_address(),
+                    painted,
+                    dry_run
+                );
+                self.applied.borrow_mut().insert(fold(&lh.name), state);
+            }
+            // Tried again at the next refresh.
+            Err(message) => log::warn!("{}: {}", lh.name, message),
+        }
+    }
+}
+
+/// Runs until Ctrl-C or SIGTERM, then disconnects from every base station.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &Filter,
+    options: &Options,
+    policy: &Policy,
+    supervisor: &mut Supervisor,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let stations: Stations<C::Peripheral> = RefCell::new(BTreeMap::new());
+    let handled = Mutex::new(BTreeSet::new());
+    let unnamed = filter.addresses();
+    let mut discoveries = discover(central, options, &unnamed, &handled, false).await?;
+    let running = steamvr_running().await?;
+    let mut tracker = Tracker {
+        idle: policy.idle,
+        idle_delay: policy.idle_delay,
+        // Without SteamVR at start, the base stations are put to sleep after the idle delay.
+        running: !running,
+        gone_since: None,
+        wanted: None,
+    };
+    tracker.observe(running, Instant::now());
+    let writer = Writer {
+        policy,
+        options,
+        applied: RefCell::new(BTreeMap::new()),
+    };
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+    // Base stations are written at once, bounded by `--concurrency`.
+    let mut writes: FuturesUnordered<LocalBoxFuture<()>> = FuturesUnordered::new();
+    let mut signals = signals;
+    let result = loop {
+        tokio::select! {
+            discovery = discoveries.next() => match discovery {
+                Some(Ok(lh)) => if let Some(station) = stations::track(lh, filter, "SteamVR watcher").await {
+                    stations.borrow_mut().insert(fold(&station.lh.name), station.clone());
+                    supervisor.found(&station.lh.name, station.lh.address, stations.borrow().len());
+                    if let Some(wanted) = tracker.wanted {
+                        writes.push(writer.write(station, wanted).boxed_local());
+                    }
+                },
+                Some(Err(error)) => log::warn!("{}: {}", error.name, error.cause),
+                None => break Err(anyhow::anyhow!("the adapter stopped reporting base stations")),
+            },
+            _ = poll.tick() => {
+                let running = match steamvr_running().await {
+                    Ok(running) => running,
+                    Err(error) => {
+                        log::warn!("failed to look for SteamVR: {}", error);
+                        continue;
+                    }
+                };
+                if let Some(wanted) = tracker.observe(running, Instant::now()) {
+                    for station in stations.borrow().values() {
+                        writes.push(writer.write(station.clone(), wanted).boxed_local());
+                    }
+                }
+            }
+            _ = refresh.tick() => if let Some(wanted) = tracker.wanted {
+                for station in stations.borrow().values() {
+                    if writer.applied(station) != Some(wanted) {
+                        writes.push(writer.write(station.clone(), wanted).boxed_local());
+                    }
+                }
+            },
+            _ = supervisor.due() => {
+                let count = stations.borrow().len();
+                supervisor.check(central, count, options.io_timeout).await;
+            }
+            Some(()) = writes.next(), if !writes.is_empty() => {}
+            Ok(signal) = &mut signals => {
+                log::info!("stopping on signal {}", signal);
+                break Ok(());
+            }
+        }
+    };
+    drop(writes);
+    let tracked: Vec<_> = stations.borrow().values().cloned().collect();
+    supervisor
+        .stop(tracked.iter().map(|station| &station.lh), options)
+        .await;
+    release(central, options.io_timeout).await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steamvr_processes() {
+        assert!(is_steamvr("vrserver\n"));
+        assert!(is_steamvr(
+            "/Users/me/Library/Application Support/Steam/vrmonitor"
+        ));
+        assert!(is_steamvr("vrserver.exe"));
+        assert!(is_steamvr("VRServer.EXE"));
+        assert!(!is_steamvr("vrserverd"));
+        assert!(!is_steamvr("steam"));
+    }
+
+    #[test]
+    fn restarting_within_the_idle_delay_keeps_them_on() {
+        let mut tracker = Tracker {
+            idle: PowerCommand::Sleep,
+            idle_delay: Duration::from_secs(300),
+            running: false,
+            gone_since: None,
+            wanted: None,
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(tracker.observe(true, at(0)), Some(PowerCommand::On));
+        assert_eq!(tracker.observe(false, at(10)), None);
+        assert_eq!(tracker.observe(true, at(20)), None);
+        assert_eq!(tracker.observe(false, at(30)), None);
+        assert_eq!(tracker.observe(false, at(329)), None);
+        assert_eq!(tracker.observe(false, at(330)), Some(PowerCommand::Sleep));
+        assert_eq!(tracker.observe(false, at(400)), None);
+        assert_eq!(tracker.observe(true, at(401)), Some(PowerCommand::On));
+    }
+}
@@ -0,0 +1,194 @@
+//! Supervision shared by the long-running modes: telling systemd when they are ready and that
+//! Bluetooth is still healthy, and turning the base stations off on exit with `--sleep-on-exit`.
+//!
+//! With `Type=notify`, readiness is reported once every named base station has been found, or
+//! after [`SETTLE_TIME`] otherwise. With `WatchdogSec=`, the watchdog is pinged as long as the
+//! adapter keeps answering.
+
+use std::{future, time::Duration};
+
+use btleplug::api::{BDAddr, Central, Peripheral};
+use futures::future::join_all;
+use tokio::time::{timeout, Instant};
+
+use crate::{write_power, Filter, Lighthouse, Options, PowerCommand};
+
+/// How long to scan for base stations before reporting ready anyway.
+const SETTLE_TIME: Duration = Duration::from_secs(10);
+
+pub struct Supervisor {
+    notifier: Option<Notifier>,
+    /// The named base stations not found yet.
+    pending: Filter,
+    ready: bool,
+    /// Ready by then at the latest.
+    settle_at: Instant,
+    /// Half the watchdog timeout, if systemd expects pings.
+    watchdog: Option<Duration>,
+    ping_at: Instant,
+    /// The state to turn the base stations to on exit.
+    sleep_on_exit: Option<PowerCommand>,
+}
+
+impl Supervisor {
+    pub fn new(filter: &Filter, sleep_on_exit: Option<PowerCommand>) -> Self {
+        let notifier = Notifier::from_env();
+        let watchdog = notifier.as_ref().and_then(|_| watchdog_interval());
+        let now = Instant::now();
+        Self {
+            notifier,
+            pending: filter.clone(),
+            ready: false,
+            settle_at: now + SETTLE_TIME,
+            watchdog,
+            ping_at: now,
+            sleep_on_exit,
+        }
+    }
+
+    /// Takes in a base station that is now tracked, the `count`th of them.
+    pub fn found(&mut self, name: &str, address: BDAddr, count: usize) {
+        self.pending.complete(name, address);
+        if !self.ready && self.pending.is_completed() {
+            self.ready(count);
+        }
+    }
+
+    fn ready(&mut self, count: usize) {
+        self.ready = true;
+        log::info!("ready, tracking {} base stations", count);
+        self.notify(&format!("READY=1\nSTATUS=tracking {} base stations", count));
+    }
+
+    /// Resolves once there is something for [`Supervisor::check`] to do.
+    pub async fn due(&self) {
+        let settle_at = (!self.ready).then_some(self.settle_at);
+        let ping_at = self.watchdog.map(|_| self.ping_at);
+        match settle_at.into_iter().chain(ping_at).min() {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None => future::pending().await,
+        }
+    }
+
+    /// Reports ready once the settle time has passed, and pings the watchdog if the adapter
+    /// still answers. Otherwise systemd restarts the service once the watchdog times out.
+    pub async fn check<C: Central>(&mut self, central: &C, count: usize, io_timeout: Duration) {
+        let now = Instant::now();
+        if !self.ready && now >= self.settle_at {
+            self.ready(count);
+        }
+        let interval = match self.watchdog {
+            Some(interval) if now >= self.ping_at => interval,
+            _ => return,
+        };
+        self.ping_at = now + interval;
+        match timeout(io_timeout, central.peripherals()).await {
+            Ok(Ok(_)) => self.notify("WATCHDOG=1"),
+            Ok(Err(error)) => log::warn!("the adapter failed, not pinging the watchdog: {}", error),
+            Err(_) => log::warn!("the adapter did not answer, not pinging the watchdog"),
+        }
+    }
+
+    /// Whether the base stations are turned off on exit, so that any being handled are to be
+    /// finished rather than dropped.
+    pub fn sleeps_on_exit(&self) -> bool {
+        self.sleep_on_exit.is_some()
+    }
+
+    /// Tells systemd that it is stopping, then turns the base stations off with
+    /// `--sleep-on-exit`.
+    pub async fn stop<'a, P: Peripheral + 'a>(
+        &self,
+        lighthouses: impl IntoIterator<Item = &'a Lighthouse<P>>,
+        options: &Options,
+    ) {
+        self.notify("STOPPING=1");
+        let state = match self.sleep_on_exit {
+            Some(state) => state,
+            None => return,
+        };
+        let writes = lighthouses.into_iter().map(|lh| async move {
+            match lh.session(options, write_power(lh, state, options)).await {
+                Ok(()) => log::info!("{}: {}", lh.name, state),
+                Err(message) => log::warn!("{}: {}", lh.name, message),
+            }
+        });
+        join_all(writes).await;
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some(notifier) = &self.notifier {
+            if let Err(error) = notifier.send(state) {
+                log::warn!("failed to notify systemd: {}", error);
+            }
+        }
+    }
+}
+
+/// `sd_notify`, over the datagram socket in `NOTIFY_SOCKET`.
+#[cfg(unix)]
+struct Notifier {
+    socket: std::os::unix::net::UnixDatagram,
+    path: std::ffi::OsString,
+}
+
+#[cfg(unix)]
+impl Notifier {
+    fn from_env() -> Option<Self> {
+        let path = std::env::var_os("NOTIFY_SOCKET")?;
+        match std::os::unix::net::UnixDatagram::unbound() {
+            Ok(socket) => Some(Self { socket, path }),
+            Err(error) => {
+                log::warn!("failed to create a socket to notify systemd: {}", error);
+                None
+            }
+        }
+    }
+
+    fn send(&self, state: &str) -> std::io::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = self.path.as_bytes();
+        // A leading `@` stands for a socket in the abstract namespace.
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.strip_prefix(b"@") {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            let address = SocketAddr::from_abstract_name(name)?;
+            return self
+                .socket
+                .send_to_addr(state.as_bytes(), &address)
+                .map(drop);
+        }
+        self.socket
+            .send_to(state.as_bytes(), std::ffi::OsStr::from_bytes(path))
+            .map(drop)
+    }
+}
+
+/// Without Unix sockets, there is no systemd to notify.
+#[cfg(not(unix))]
+struct Notifier;
+
+#[cfg(not(unix))]
+impl Notifier {
+    fn from_env() -> Option<Self> {
+        None
+    }
+
+    fn send(&self, _: &str) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How often to ping the watchdog, half of `WATCHDOG_USEC` as systemd recommends, unless the
+/// watchdog is meant for another process.
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID") {
+        if pid.to_str()?.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
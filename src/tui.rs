@@ -0,0 +1,511 @@
+//! `tui`: a terminal UI listing the base stations as they are discovered, with their power state,
+//! RSSI and RF channel, where the selected one can be turned on, to sleep or to standby, or made
+//! to blink.
+//!
+//! Like the daemon, it connects to a base station only while talking to it, so that SteamVR can
+//! reach them in between, and reads the power states again every few seconds.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Read, Write},
+    mem::MaybeUninit,
+    rc::Rc,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use btleplug::api::{Central, Peripheral};
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+
+use crate::{
+    discover, fold, identify, read_channel, release,
+    stations::{self, Station},
+    Error, Filter, Lighthouse, Options, PowerCommand, PowerState,
+};
+
+/// How often the power states are read again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+const HELP: &str = "up/down select  o on  s sleep  b standby  i identify  q quit";
+
+enum Key {
+    Up,
+    Down,
+    Char(char),
+}
+
+/// Reads the keys pressed, until stdin is closed or the receiver is dropped. Runs on a thread of
+/// its own, since reading the terminal blocks.
+fn read_keys(sender: mpsc::UnboundedSender<Key>) {
+    let mut stdin = io::stdin().lock();
+    let mut next = || {
+        let mut byte = [0];
+        matches!(stdin.read(&mut byte), Ok(1)).then_some(byte[0])
+    };
+    while let Some(byte) = next() {
+        let key = match byte {
+            // The arrow keys, as sent by terminals in their default mode.
+            0x1b => match (next(), next()) {
+                (Some(b'['), Some(b'A')) => Key::Up,
+                (Some(b'['), Some(b'B')) => Key::Down,
+                _ => continue,
+            },
+            b'k' => Key::Up,
+            b'j' => Key::Down,
+            byte => Key::Char(byte.to_ascii_lowercase() as char),
+        };
+        if sender.send(key).is_err() {
+            return;
+        }
+    }
+}
+
+/// The terminal taken over for the UI: read key by key without echo, with the screen switched to
+/// the alternate one. Restored when dropped, even on panic.
+struct Terminal {
+    saved: libc::termios,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Self> {
+        // SAFETY: `tcgetattr` initializes the attributes on success.
+        let saved = unsafe {
+            let mut saved = MaybeUninit::uninit();
+            if libc::tcgetattr(libc::STDIN_FILENO, saved.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            saved.assume_init()
+        };
+        let mut raw = saved;
+        // Ctrl-C still sends SIGINT, which quits like `q`.
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: `raw` is a valid set of attributes, copied from the current ones.
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut stdout = io::stdout();
+        stdout.write_all(b"\x1b[?1049h\x1b[?25l")?;
+        stdout.flush()?;
+        Ok(Self { saved })
+    }
+
+    /// The number of columns, 80 if the terminal does not tell.
+    fn width() -> usize {
+        // SAFETY: `TIOCGWINSZ` fills in the size on success.
+        let size = unsafe {
+            let mut size = MaybeUninit::<libc::winsize>::zeroed();
+            match libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, size.as_mut_ptr()) {
+                0 => size.assume_init().ws_col,
+                _ => 0,
+            }
+        };
+        match size {
+            0 => 80,
+            size => size.into(),
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(b"\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+        // SAFETY: `saved` holds the attributes read in `enter`.
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved) };
+    }
+}
+
+/// What the selected base station was asked to do.
+#[derive(Clone, Copy)]
+enum Request {
+    Power(PowerCommand),
+    Identify,
+}
+
+impl Request {
+    fn from_key(key: char) -> Option<Self> {
+        Some(match key {
+            'o' => Request::Power(PowerCommand::On),
+            's' => Request::Power(PowerCommand::Sleep),
+            'b' => Request::Power(PowerCommand::Standby),
+            'i' => Request::Identify,
+            _ => return None,
+        })
+    }
+
+    fn describe(self) -> String {
+        match self {
+            Request::Power(state) => format!("switching to {}...", state.porcelain()),
+            Request::Identify => "identifying...".to_owned(),
+        }
+    }
+}
+
+/// How talking to a base station went.
+enum Done {
+    Read {
+        rssi: Option<i16>,
+        /// The power state, and the channel if it was asked for.
+        result: Result<(u8, Option<u8>), Error>,
+    },
+    Request(Request, Result<(), Error>),
+}
+
+/// A base station as shown.
+struct Row {
+    name: String,
+    address: String,
+    raw_state: Option<u8>,
+    rssi: Option<i16>,
+    channel: Option<u8>,
+    /// Whether the power state is being read, so that refreshes do not pile up.
+    reading: bool,
+    /// The request being carried out, until which the others are ignored.
+    pending: Option<Request>,
+    /// Why talking to the base station last failed.
+    error: Option<String>,
+}
+
+struct Screen {
+    /// The rows by folded name.
+    rows: BTreeMap<String, Row>,
+    /// The folded name of the selected row, so that the selection stays put as base stations
+    /// are found.
+    selected: Option<String>,
+    /// The last discovery error, shown below the rows.
+    status: Option<String>,
+    color: bool,
+}
+
+impl Screen {
+    fn add<P: Peripheral>(&mut self, lh: &Lighthouse<P>) {
+        let key = fold(&lh.name);
+        self.selected.get_or_insert_with(|| key.clone());
+        self.rows.insert(
+            key,
+            Row {
+                name: lh.name.clone(),
+                address: lh.shown_address(),
+                raw_state: None,
+                rssi: lh.rssi,
+                channel: None,
+                reading: true,
+                pending: None,
+                error: None,
+            },
+        );
+    }
+
+    fn select(&mut self, down: bool) {
+        let keys: Vec<_> = self.rows.keys().collect();
+        let at = keys
+            .iter()
+            .position(|key| Some(*key) == self.selected.as_ref());
+        let at = match (at, down) {
+            (Some(at), true) => (at + 1).min(keys.len() - 1),
+            (Some(at), false) => at.saturating_sub(1),
+            (None, _) => return,
+        };
+        self.selected = Some(keys[at].clone());
+    }
+
+    /// Marks the selected base station as carrying out `request`, returning its folded name
+    /// unless it is already busy with another.
+    fn start(&mut self, request: Request) -> Option<String> {
+        let key = self.selected.clone()?;
+        let row = self.rows.get_mut(&key)?;
+        if row.pending.is_some() {
+            return None;
+        }
+        row.pending = Some(request);
+        Some(key)
+    }
+
+    fn finish(&mut self, key: &str, done: Done) {
+        let Some(row) = self.rows.get_mut(key) else {
+            return;
+        };
+        let result = match done {
+            Done::Read { rssi, result } => {
+                row.reading = false;
+                row.rssi = rssi.or(row.rssi);
+                result.map(|(raw_state, channel)| {
+                    row.raw_state = Some(raw_state);
+                    row.channel = channel.or(row.channel);
+                })
+            }
+            Done::Request(request, result) => {
+                row.pending = None;
+                result.map(|()| {
+                    if let Request::Power(state) = request {
+                        row.raw_state = Some(state.into());
+                    }
+                })
+            }
+        };
+        row.error = result.err().map(|error| error.to_string());
+    }
+
+    /// The lines to show, cut to `width` columns.
+    fn render(&self, width: usize) -> Vec<String> {
+        let mut cells = vec![[
+            "NAME".to_owned(),
+            "ADDRESS".to_owned(),
+            "STATE".to_owned(),
+            "RSSI".to_owned(),
+            "CHANNEL".to_owned(),
+        ]];
+        cells.extend(self.rows.values().map(|row| {
+            let n_a = || "n/a".to_owned();
+            [
+                row.name.clone(),
+                row.address.clone(),
+                row.raw_state
+                    .map_or_else(n_a, |raw_state| PowerState::from(raw_state).to_string()),
+                row.rssi.map_or_else(n_a, |rssi| format!("{} dBm", rssi)),
+                row.channel.map_or_else(n_a, |channel| channel.to_string()),
+            ]
+        }));
+        let mut widths = [0; 5];
+        for row in &cells {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let rows = std::iter::once(None).chain(self.rows.iter().map(Some));
+        let mut lines = Vec::new();
+        for (cells, row) in cells.iter().zip(rows) {
+            let selected = row.is_some_and(|(key, _)| Some(key) == self.selected.as_ref());
+            let mut line = String::from(if selected { "> " } else { "  " });
+            let mut used = 2;
+            for (i, (cell, width)) in cells.iter().zip(widths).enumerate() {
+                let padding = " ".repeat(width - cell.chars().count() + 2);
+                match row.and_then(|(_, row)| row.raw_state) {
+                    // Painted like the other commands do.
+                    Some(raw_state) if i == 2 => {
+                        line.push_str(&PowerState::from(raw_state).painted(self.color).to_string())
+                    }
+                    _ => line.push_str(cell),
+                }
+                line.push_str(&padding);
+                used += width + 2;
+            }
+            let note = match row {
+                Some((_, row)) => match (row.pending, &row.error) {
+                    (Some(request), _) => Some((request.describe(), false)),
+                    (None, Some(error)) => Some((error.clone(), true)),
+                    (None, None) => None,
+                },
+                None => None,
+            };
+            match note {
+                Some((note, error)) => {
+                    let note: String = note.chars().take(width.saturating_sub(used)).collect();
+                    if error && self.color {
+                        line.push_str(&format!("\x1b[31m{}\x1b[0m", note));
+                    } else {
+                        line.push_str(&note);
+                    }
+                }
+                None => line.truncate(line.trim_end().len()),
+            }
+            lines.push(line);
+        }
+        lines.push(String::new());
+        let found = match self.rows.len() {
+            1 => "scanning, 1 base station found".to_owned(),
+            count => format!("scanning, {} base stations found", count),
+        };
+        lines.push(self.status.clone().unwrap_or(found));
+        lines.push(HELP.to_owned());
+        lines
+    }
+
+    fn draw(&self) -> io::Result<()> {
+        let mut out = String::from("\x1b[H");
+        for line in self.render(Terminal::width()) {
+            out.push_str(&line);
+            out.push_str("\x1b[K\r\n");
+        }
+        out.push_str("\x1b[J");
+        let mut stdout = io::stdout();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+/// Reads the power state, and the channel with `channel`. The RSSI comes from the advertisements,
+/// without connecting.
+async fn read<P: Peripheral>(station: Rc<Station<P>>, options: &Options, channel: bool) -> Done {
+    let lh = &station.lh;
+    let rssi = match timeout(options.io_timeout, lh.peripheral.properties()).await {
+        Ok(Ok(Some(props))) => props.rssi,
+        _ => None,
+    };
+    let reading = async {
+        let raw_state = lh.read_power(options.io_timeout).await?;
+        let channel = match channel {
+            true => read_channel(lh, options.io_timeout).await.ok(),
+            false => None,
+        };
+        Ok((raw_state, channel))
+    };
+    let result = station.session(options, reading).await;
+    if let Ok((raw_state, channel)) = result {
+        station.raw_state.set(Some(raw_state));
+        if let Some(channel) = channel {
+            options.cache.set_channel(&lh.name, channel);
+        }
+    }
+    Done::Read { rssi, result }
+}
+
+async fn carry_out<P: Peripheral>(
+    station: Rc<Station<P>>,
+    options: &Options,
+    request: Request,
+) -> Done {
+    let result = match request {
+        Request::Power(state) => station.write(state, options).await,
+        Request::Identify => {
+            station
+                .session(options, identify(&station.lh, options))
+                .await
+        }
+    };
+    Done::Request(request, result)
+}
+
+/// Runs until `q`, Ctrl-C or SIGTERM, then disconnects from every base station.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &Filter,
+    options: &Options,
+    color: bool,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let handled = Mutex::new(BTreeSet::new());
+    let unnamed = filter.addresses();
+    let mut discoveries = discover(central, options, &unnamed, &handled, false).await?;
+    let terminal = Terminal::enter()?;
+    let (sender, mut keys) = mpsc::unbounded_channel();
+    // Not a blocking task of the runtime, which would wait for it to read a key on exit.
+    std::thread::spawn(move || read_keys(sender));
+    let mut stations: BTreeMap<String, Rc<Station<C::Peripheral>>> = BTreeMap::new();
+    let mut screen = Screen {
+        rows: BTreeMap::new(),
+        selected: None,
+        status: None,
+        color,
+    };
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+    refresh.tick().await;
+    let mut tasks: FuturesUnordered<LocalBoxFuture<(String, Done)>> = FuturesUnordered::new();
+    let mut signals = signals;
+    let result = loop {
+        if let Err(error) = screen.draw() {
+            break Err(error.into());
+        }
+        tokio::select! {
+            discovery = discoveries.next() => match discovery {
+                Some(Ok(lh)) => if let Some(station) = stations::track(lh, filter, "TUI").await {
+                    let key = fold(&station.lh.name);
+                    screen.add(&station.lh);
+                    stations.insert(key.clone(), station.clone());
+                    let reading = read(station, options, true);
+                    tasks.push(reading.map(|done| (key, done)).boxed_local());
+                },
+                Some(Err(error)) => {
+                    if filter.is_matched(&error.name, error.address.unwrap_or_default()) {
+                        screen.status = Some(format!("{}: {}", error.name, error.cause));
+                    }
+                }
+                None => break Err(anyhow!("the adapter stopped reporting base stations")),
+            },
+            Some(key) = keys.recv() => match key {
+                Key::Up => screen.select(false),
+                Key::Down => screen.select(true),
+                Key::Char('q') => break Ok(()),
+                Key::Char(key) => {
+                    let Some(request) = Request::from_key(key) else {
+                        continue;
+                    };
+                    if let Some(key) = screen.start(request) {
+                        let doing = carry_out(stations[&key].clone(), options, request);
+                        tasks.push(doing.map(|done| (key, done)).boxed_local());
+                    }
+                }
+            },
+            _ = refresh.tick() => for (key, row) in &mut screen.rows {
+                if !row.reading {
+                    row.reading = true;
+                    let reading = read(stations[key].clone(), options, row.channel.is_none());
+                    let key = key.clone();
+                    tasks.push(reading.map(|done| (key, done)).boxed_local());
+                }
+            },
+            Some((key, done)) = tasks.next(), if !tasks.is_empty() => screen.finish(&key, done),
+            Ok(signal) = &mut signals => {
+                log::info!("stopping on signal {}", signal);
+                break Ok(());
+            }
+        }
+    };
+    drop(tasks);
+    drop(terminal);
+    release(central, options.io_timeout).await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_show_state_and_errors() {
+        let row = |name: &str, raw_state, error: Option<&str>| Row {
+            name: name.to_owned(),
+            address: "AA:BB:CC:DD:EE:FF".to_owned(),
+            raw_state,
+            rssi: raw_state.map(|_| -60),
+            channel: raw_state.map(|_| 7),
+            reading: false,
+            pending: None,
+            error: error.map(str::to_owned),
+        };
+        let mut screen = Screen {
+            rows: BTreeMap::new(),
+            selected: Some("lhb-93d0b1e4".to_owned()),
+            status: None,
+            color: false,
+        };
+        screen.rows.insert(
+            "lhb-4a3c91f2".to_owned(),
+            row("LHB-4A3C91F2", Some(0x0b), None),
+        );
+        screen.rows.insert(
+            "lhb-93d0b1e4".to_owned(),
+            row("LHB-93D0B1E4", None, Some("connect timed out")),
+        );
+        assert_eq!(
+            screen.render(72),
+            [
+                "  NAME          ADDRESS            STATE  RSSI     CHANNEL",
+                "  LHB-4A3C91F2  AA:BB:CC:DD:EE:FF  ON     -60 dBm  7",
+                "> LHB-93D0B1E4  AA:BB:CC:DD:EE:FF  n/a    n/a      n/a      connect time",
+                "",
+                "scanning, 2 base stations found",
+                HELP,
+            ]
+        );
+        screen.select(false);
+        assert_eq!(screen.selected.as_deref(), Some("lhb-4a3c91f2"));
+    }
+}
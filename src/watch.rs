@@ -0,0 +1,253 @@
+//! `watch`: stays connected to the base stations and shows each power state change as it
+//! happens, using notifications where the firmware supports them.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::Infallible,
+    rc::Rc,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use btleplug::api::{BDAddr, Central, CharPropFlags, Peripheral};
+use futures::{future, stream::FuturesUnordered, StreamExt};
+use tokio::sync::oneshot;
+
+use crate::{
+    attempt, discover, history::Source, hook::Hook, release, supervisor::Supervisor, timestamp,
+    Error, Filter, History, Lighthouse, Operation, Options, OutputMode, PowerState, Protocol,
+    Report, WriteFields,
+};
+
+/// How often the power state is read without notifications, and how often the connection is
+/// checked with them.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Runs until Ctrl-C or SIGTERM, then disconnects from every base station.
+pub async fn run<C: Central>(
+    central: &C,
+    filter: &Filter,
+    options: &Options,
+    report: &Report,
+    hook: Option<&Hook>,
+    supervisor: &mut Supervisor,
+    signals: oneshot::Receiver<i32>,
+) -> Result<()> {
+    let hooks = async {
+        match hook {
+            Some(hook) => hook.run().await,
+            None => future::pending().await,
+        }
+    };
+    tokio::pin!(hooks);
+    let handled = Mutex::new(BTreeSet::new());
+    let unnamed = filter.addresses();
+    let mut discoveries = discover(central, options, &unnamed, &handled, false).await?;
+    // Kept by address, so that base stations lost and found again carry on their history.
+    let histories = Mutex::new(BTreeMap::new());
+    let mut watching = FuturesUnordered::new();
+    // The base stations being watched, to be turned off on exit with `--sleep-on-exit`.
+    let mut tracked: BTreeMap<BDAddr, Rc<Lighthouse<C::Peripheral>>> = BTreeMap::new();
+    let mut signals = signals;
+    loop {
+        tokio::select! {
+            discovery = discoveries.next() => match discovery {
+                Some(Ok(mut lh)) => {
+                    let matched = filter.is_matched(&lh.name, lh.address)
+                        && !filter.is_excluded(&lh.name, lh.address);
+                    if !matched || matches!(lh.protocol, Protocol::V1) {
+                        lh.disconnect().await.ok();
+                        continue;
+                    }
+                    // Stays connected for good, which should not hold up connecting to others.
+                    lh.connection = None;
+                    let lh = Rc::new(lh);
+                    tracked.insert(lh.address, lh.clone());
+                    supervisor.found(&lh.name, lh.address, tracked.len());
+                    let histories = &histories;
+                    watching.push(async move {
+                        let Err(error) = follow(&lh, options, report, hook, histories).await;
+                        (lh, error)
+                    });
+                }
+                Some(Err(error)) => {
+                    if filter.is_matched(&error.name, error.address.unwrap_or_default()) {
+                        eprintln!("{}: {}", error.name, error.cause);
+                    }
+                }
+                None => bail!("the adapter stopped reporting base stations"),
+            },
+            Some((lh, error)) = watching.next(), if !watching.is_empty() => {
+                tracked.remove(&lh.address);
+                lost(report, &lh, &error);
+                lh.peripheral.disconnect().await.ok();
+                // Discovered again once it advertises, such as when it comes back in range.
+                handled.lock().unwrap().remove(&lh.peripheral.id());
+            }
+            _ = supervisor.due() => {
+                supervisor.check(central, tracked.len(), options.io_timeout).await;
+            }
+            _ = &mut hooks => {}
+            Ok(_) = &mut signals => break,
+        }
+    }
+    drop(watching);
+    supervisor
+        .stop(tracked.values().map(|lh| lh.as_ref()), options)
+        .await;
+    release(central, options.io_timeout).await;
+    Ok(())
+}
+
+/// Shows the power state and then every change, until the base station is lost.
+async fn follow<P: Peripheral>(
+    lh: &Lighthouse<P>,
+    options: &Options,
+    report: &Report,
+    hook: Option<&Hook>,
+    histories: &Mutex<BTreeMap<BDAddr, History>>,
+) -> Result<Infallible, Error> {
+    let characteristic = lh.power_characteristic();
+    let read = || lh.read_power(options.io_timeout);
+    let mut raw_state = read().await?;
+    PowerState::check(raw_state, &lh.name, options);
+    let (history, _) = History::observe_in(histories, lh.address, raw_state.into());
+    changed(report, lh, None, raw_state.into(), history, None);
+    let mut notifications = None;
+    if characteristic.properties.contains(CharPropFlags::NOTIFY) {
+        let subscribing = async {
+            let stream = lh.peripheral.notifications().await?;
+            lh.peripheral.subscribe(characteristic).await?;
+            Ok(stream)
+        };
+        match attempt(Operation::Subscribe, options.io_timeout, subscribing).await {
+            Ok(stream) => notifications = Some(stream),
+            Err(message) => log::info!("{}: {}, polling instead", lh.name, message),
+        }
+    }
+    let mut ticks = tokio::time::interval(WATCH_INTERVAL);
+    ticks.tick().await;
+    loop {
+        let (next, source) = match notifications.as_mut() {
+            Some(stream) => tokio::select! {
+                notification = stream.next() => match notification {
+                    Some(notification) if notification.uuid == characteristic.uuid => {
+                        log::trace!("{}: notified {:02x?}", lh.name, notification.value);
+                        let next = *notification.value.first().ok_or_else(|| Error::NoData {
+                            operation: Operation::Notification,
+                        })?;
+                        (next, Source::Notification)
+                    }
+                    Some(_) => continue,
+                    None => return Err(Error::Lost("notifications stopped")),
+                },
+                // Notifications alone do not tell that the base station went away.
+                _ = ticks.tick() => {
+                    let checking = lh.peripheral.is_connected();
+                    if !attempt(Operation::CheckConnection, options.io_timeout, checking).await? {
+                        return Err(Error::Lost("disconnected"));
+                    }
+                    continue;
+                }
+            },
+            None => {
+                ticks.tick().await;
+                (read().await?, Source::Poll)
+            }
+        };
+        let (history, lasted) = History::observe_in(histories, lh.address, next.into());
+        if next != raw_state {
+            PowerState::check(next, &lh.name, options);
+            changed(
+                report,
+                lh,
+                Some(raw_state.into()),
+                next.into(),
+                history,
+                lasted,
+            );
+            report.transition(lh, raw_state.into(), next.into(), source);
+            if let Some(hook) = hook {
+                let address = lh.shown_address();
+                hook.changed(&lh.name, &address, raw_state.into(), next.into());
+            }
+            raw_state = next;
+        }
+    }
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+}
+
+/// Shows the state the base station is first seen in, or changed to after `lasted` in the
+/// previous one.
+fn changed<P: Peripheral>(
+    report: &Report,
+    lh: &Lighthouse<P>,
+    previous: Option<PowerState>,
+    state: PowerState,
+    history: History,
+    lasted: Option<Duration>,
+) {
+    if let OutputMode::JsonLines = report.mode {
+        let kind = match previous {
+            Some(_) => "state_changed",
+            None => "watching",
+        };
+        return report.event(kind, |object| {
+            object
+                .field("name", &lh.name)
+                .field("address", &lh.shown_address());
+            if let Some(previous) = previous {
+                object.field("previous_state", &previous);
+            }
+            object
+                .field("state", &state)
+                .field("last_seen", &timestamp(history.last_seen));
+            history.write_fields(object);
+        });
+    }
+    let station = format!("{} {} [{}]", now(), lh.name, lh.shown_address());
+    let state = state.painted(report.color);
+    match (previous, lasted) {
+        (Some(previous), Some(lasted)) => {
+            let lasted = humantime::format_duration(Duration::from_secs(lasted.as_secs()));
+            let previous = previous.painted(report.color);
+            let changes = history.changes();
+            println!(
+                "{}: {} -> {} (after {}, {})",
+                station, previous, state, lasted, changes
+            )
+        }
+        (Some(previous), None) => {
+            println!(
+                "{}: {} -> {}",
+                station,
+                previous.painted(report.color),
+                state
+            )
+        }
+        (None, _) => println!("{}: {} ({})", station, state, history.describe()),
+    }
+}
+
+fn lost<P: Peripheral>(report: &Report, lh: &Lighthouse<P>, error: &Error) {
+    if let OutputMode::JsonLines = report.mode {
+        report.event("lost", |object| {
+            object
+                .field("name", &lh.name)
+                .field("address", &lh.shown_address())
+                .field("error", &error.to_string());
+        });
+    } else {
+        println!(
+            "{} {} [{}]: lost, {}",
+            now(),
+            lh.name,
+            lh.shown_address(),
+            error
+        );
+    }
+}